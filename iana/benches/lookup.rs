@@ -0,0 +1,50 @@
+#![feature(test)]
+#![cfg(feature = "builtin-db")]
+
+extern crate iana;
+extern crate test;
+
+use std::net::IpAddr;
+use test::Bencher;
+
+
+#[bench]
+fn bench_lookup_ipv4(b: &mut Bencher) {
+    let addrs: Vec<IpAddr> = ["1.0.0.1", "41.0.0.1", "114.114.114.114", "185.199.108.153", "223.255.255.1"]
+        .iter().map(|s| s.parse().unwrap()).collect();
+    b.iter(|| {
+        for addr in addrs.iter() {
+            test::black_box(iana::number::lookup(*addr));
+        }
+    });
+}
+
+#[bench]
+fn bench_lookup_ipv6(b: &mut Bencher) {
+    let addrs: Vec<IpAddr> = ["2001:200::1", "2400:cb00::1", "2a00:1450::1", "fd00::1", "ff02::1"]
+        .iter().map(|s| s.parse().unwrap()).collect();
+    b.iter(|| {
+        for addr in addrs.iter() {
+            test::black_box(iana::number::lookup(*addr));
+        }
+    });
+}
+
+#[bench]
+fn bench_country_ranges(b: &mut Bencher) {
+    b.iter(|| test::black_box(iana::number::ipv4_ranges("CN")));
+}
+
+#[bench]
+fn bench_packed_lookup_ipv4(b: &mut Bencher) {
+    let mut bytes = vec![];
+    iana::number::Database::builtin().write_packed(&mut bytes).unwrap();
+    let db = iana::number::PackedDatabase::from_bytes(bytes).unwrap();
+    let addrs: Vec<IpAddr> = ["1.0.0.1", "41.0.0.1", "114.114.114.114", "185.199.108.153", "223.255.255.1"]
+        .iter().map(|s| s.parse().unwrap()).collect();
+    b.iter(|| {
+        for addr in addrs.iter() {
+            test::black_box(db.lookup(*addr));
+        }
+    });
+}
@@ -0,0 +1,58 @@
+extern crate iana;
+
+use std::env;
+use std::fs::File;
+use std::io::BufWriter;
+use std::process;
+
+use iana::number::{Database, PackedDatabase};
+
+
+const USAGE: &'static str = "\
+usage: packdb [--builtin] OUTPUT FILE...
+
+Converts RIR delegated-extended files, or other packed databases, into a
+packed database. Later files replace the registries of earlier ones.
+
+    --builtin    start from the compiled-in tables";
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let builtin = args.iter().any(|arg| arg == "--builtin");
+    args.retain(|arg| arg != "--builtin");
+    if args.is_empty() || args.iter().any(|arg| arg.starts_with('-')) || (args.len() == 1 && !builtin) {
+        println!("{}", USAGE);
+        process::exit(1);
+    }
+
+    let mut db = if builtin { builtin_tables() } else { Database::new() };
+    for filename in args[1..].iter() {
+        if let Err(e) = db.load(filename) {
+            println!("{}", e);
+            process::exit(1);
+        }
+    }
+
+    let output = &args[0];
+    let result = File::create(output)
+        .and_then(|file| db.write_packed(BufWriter::new(file)))
+        .and_then(|_| PackedDatabase::open(output));
+    match result {
+        Ok(_) => println!("{} {:?}", output, db),
+        Err(e) => {
+            println!("{}: {}", output, e);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(feature = "builtin-db")]
+fn builtin_tables() -> Database {
+    Database::builtin()
+}
+
+#[cfg(not(feature = "builtin-db"))]
+fn builtin_tables() -> Database {
+    println!("--builtin needs the builtin-db feature");
+    process::exit(1);
+}
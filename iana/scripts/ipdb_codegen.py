@@ -415,7 +415,17 @@ def main():
     rust_mod_code = "\n\n"
     for x in _registries:
         if x != "ietf":
-            rust_mod_code += "pub mod %s;\n" % x
+            rust_mod_code += "#[cfg(feature = \"builtin-db\")]\npub mod %s;\n" % x
+
+    rust_mod_code += "\n/// Country codes of `scripts/parse.py`, the tables store an index into it.\n"
+    rust_mod_code += "pub static COUNTRY_CODES: [&'static str; %d] = [\n    " % (len(COUNTRY_CODES), )
+    idx = 0
+    for x in COUNTRY_CODES:
+        if idx > 0 and idx % 15 == 0:
+            rust_mod_code += "\n    "
+        idx += 1
+        rust_mod_code += "\"%s\", " % x
+    rust_mod_code += "\n];\n"
 
     open(mod_path, "wb").write(rust_mod_code.encode("UTF-8"))
     
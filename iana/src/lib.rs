@@ -1,5 +1,7 @@
 #![feature(i128_type)]
 
+extern crate memmap;
+
 mod country;
 pub mod number;
 
@@ -0,0 +1,319 @@
+use std::fmt;
+use std::io::{self, BufRead, Read, Write};
+use std::fs::File;
+use std::path::Path;
+use std::borrow::Cow;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use super::{Registry, Status, IP4AN, IP6AN};
+use super::query::{self, Filter, Record, Records};
+use super::packed::{self, PackedDatabase};
+
+
+/// Delegations read at runtime from RIR statistics files.
+///
+/// Reads the "delegated-extended" exchange format, as published by every RIR:
+///
+/// ```text
+/// ftp://ftp.apnic.net/public/stats/apnic/delegated-apnic-extended-latest
+/// ftp://ftp.apnic.net/public/stats/afrinic/README-EXTENDED.txt
+/// ```
+///
+/// A file replaces the tables of the registries it lists, so fresh files can be
+/// laid over `Database::builtin()`. Country codes missing from `db::COUNTRY_CODES`
+/// are read as `ZZ`. `write_packed` saves the result as a `PackedDatabase`.
+pub struct Database {
+    ipv4: Vec<(Registry, Cow<'static, [IP4AN]>)>,
+    ipv6: Vec<(Registry, Cow<'static, [IP6AN]>)>,
+}
+
+impl Database {
+    pub fn new() -> Database {
+        Database { ipv4: vec![], ipv6: vec![] }
+    }
+
+    /// The tables compiled into the crate, nothing is copied.
+    #[cfg(feature = "builtin-db")]
+    pub fn builtin() -> Database {
+        use super::db;
+
+        Database {
+            ipv4: vec![
+                (Registry::Afrinic, Cow::Borrowed(&db::afrinic::IPV4_NUMBERS[..])),
+                (Registry::Apnic, Cow::Borrowed(&db::apnic::IPV4_NUMBERS[..])),
+                (Registry::Arin, Cow::Borrowed(&db::arin::IPV4_NUMBERS[..])),
+                (Registry::Lacnic, Cow::Borrowed(&db::lacnic::IPV4_NUMBERS[..])),
+                (Registry::Ripencc, Cow::Borrowed(&db::ripencc::IPV4_NUMBERS[..])),
+                (Registry::Iana, Cow::Borrowed(&db::iana::IPV4_NUMBERS[..])),
+            ],
+            ipv6: vec![
+                (Registry::Afrinic, Cow::Borrowed(&db::afrinic::IPV6_NUMBERS[..])),
+                (Registry::Apnic, Cow::Borrowed(&db::apnic::IPV6_NUMBERS[..])),
+                (Registry::Arin, Cow::Borrowed(&db::arin::IPV6_NUMBERS[..])),
+                (Registry::Lacnic, Cow::Borrowed(&db::lacnic::IPV6_NUMBERS[..])),
+                (Registry::Ripencc, Cow::Borrowed(&db::ripencc::IPV6_NUMBERS[..])),
+                (Registry::Iana, Cow::Borrowed(&db::iana::IPV6_NUMBERS[..])),
+            ],
+        }
+    }
+
+    /// Reads a delegated-extended file or a packed database.
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<(), io::Error> {
+        let path = path.as_ref();
+        let mut bytes = vec![];
+        try!(File::open(path).and_then(|mut file| file.read_to_end(&mut bytes))
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e))));
+        let result = if bytes.starts_with(packed::MAGIC) {
+            PackedDatabase::from_bytes(&bytes[..]).map(|db| self.load_packed(&db))
+        } else {
+            self.parse(&bytes[..])
+        };
+        result.map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    }
+
+    /// Copies the tables of a packed database, replacing those of the same registries.
+    pub fn load_packed<B: AsRef<[u8]>>(&mut self, db: &PackedDatabase<B>) {
+        for (registry, entries) in db.ipv4_tables() {
+            replace(&mut self.ipv4, registry, Cow::Owned(entries));
+        }
+        for (registry, entries) in db.ipv6_tables() {
+            replace(&mut self.ipv6, registry, Cow::Owned(entries));
+        }
+    }
+
+    /// Saves the tables for `PackedDatabase`.
+    pub fn write_packed<W: Write>(&self, writer: W) -> Result<(), io::Error> {
+        packed::write(&self.ipv4, &self.ipv6, writer)
+    }
+
+    pub fn parse<R: BufRead>(&mut self, reader: R) -> Result<(), io::Error> {
+        let mut ipv4: Vec<(Registry, Vec<IP4AN>)> = vec![];
+        let mut ipv6: Vec<(Registry, Vec<IP6AN>)> = vec![];
+        let mut header = false;
+
+        for (n, line) in reader.lines().enumerate() {
+            let line = try!(line);
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('|').collect();
+            let error = |message: &str| io::Error::new(io::ErrorKind::InvalidData,
+                                                      format!("line {}: {}", n + 1, message));
+            if !header {
+                // 2|apnic|20171109|52473|19830613|20171108|+1000
+                match fields[0].parse::<f32>() {
+                    Ok(version) if version >= 2.0 && version < 3.0 => header = true,
+                    _ => return Err(error("expected a version line"))
+                }
+                continue;
+            }
+            // apnic|*|ipv4|*|40412|summary
+            if fields.last() == Some(&"summary") {
+                continue;
+            }
+            if fields.len() < 7 {
+                return Err(error("expected registry|cc|type|start|value|date|status"));
+            }
+            let registry = try!(Registry::from_str(fields[0]).map_err(|_| error("unknown registry")));
+            let status = try!(Status::from_str(fields[6]).map_err(|_| error("unknown status")));
+            let country = super::country_index(fields[1])
+                .or(super::country_index("ZZ"))
+                .unwrap();
+
+            match fields[2] {
+                "asn" => {},
+                "ipv4" => {
+                    let start = try!(fields[3].parse::<Ipv4Addr>().map_err(|_| error("invalid IPv4 address")));
+                    let start = u32::from(start);
+                    let count = try!(fields[4].parse::<u32>().map_err(|_| error("invalid address count")));
+                    let last = match start.checked_add(count.wrapping_sub(1)) {
+                        Some(last) if count > 0 => last,
+                        _ => return Err(error("address count out of range"))
+                    };
+                    table(&mut ipv4, registry).push((start, last, country, status.to_u8()));
+                },
+                "ipv6" => {
+                    let start = try!(fields[3].parse::<Ipv6Addr>().map_err(|_| error("invalid IPv6 address")));
+                    let start = u128::from(start);
+                    let prefix = try!(fields[4].parse::<u32>().map_err(|_| error("invalid prefix length")));
+                    if prefix > 128 {
+                        return Err(error("prefix length out of range"));
+                    }
+                    let host = match 1u128.checked_shl(128 - prefix) {
+                        Some(size) => size - 1,
+                        None => !0
+                    };
+                    table(&mut ipv6, registry).push((start & !host, start | host, country, status.to_u8()));
+                },
+                _ => return Err(error("unknown type"))
+            }
+        }
+        if !header {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "no version line"));
+        }
+
+        for (registry, mut entries) in ipv4 {
+            entries.sort();
+            entries.dedup();
+            replace(&mut self.ipv4, registry, Cow::Owned(entries));
+        }
+        for (registry, mut entries) in ipv6 {
+            entries.sort();
+            entries.dedup();
+            replace(&mut self.ipv6, registry, Cow::Owned(entries));
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ipv4.is_empty() && self.ipv6.is_empty()
+    }
+
+    /// The block `addr` belongs to.
+    pub fn lookup(&self, addr: IpAddr) -> Option<Record> {
+        query::lookup_in(&self.ipv4, &self.ipv6, addr)
+    }
+
+    /// Every block delegated to a two letter country code, nothing for an unknown code.
+    pub fn by_country(&self, code: &str) -> Records {
+        Records::new(&self.ipv4, &self.ipv6, super::country_index(code).map(Filter::Country))
+    }
+
+    /// Every block listed by a registry.
+    pub fn by_registry(&self, registry: Registry) -> Records {
+        Records::new(&self.ipv4, &self.ipv6, Some(Filter::Registry(registry)))
+    }
+}
+
+impl fmt::Debug for Database {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ipv4: Vec<(Registry, usize)> = self.ipv4.iter().map(|&(r, ref t)| (r, t.len())).collect();
+        let ipv6: Vec<(Registry, usize)> = self.ipv6.iter().map(|&(r, ref t)| (r, t.len())).collect();
+        f.debug_struct("Database").field("ipv4", &ipv4).field("ipv6", &ipv6).finish()
+    }
+}
+
+fn table<T>(tables: &mut Vec<(Registry, Vec<T>)>, registry: Registry) -> &mut Vec<T> {
+    let index = match tables.iter().position(|&(r, _)| r == registry) {
+        Some(index) => index,
+        None => {
+            tables.push((registry, vec![]));
+            tables.len() - 1
+        }
+    };
+    &mut tables[index].1
+}
+
+/// Swap in the table of `registry`, `Registry::Iana` stays last for lookups.
+fn replace<T: Clone>(tables: &mut Vec<(Registry, Cow<'static, [T]>)>, registry: Registry,
+                     entries: Cow<'static, [T]>) {
+    tables.retain(|&(r, _)| r != registry);
+    tables.push((registry, entries));
+    tables.sort_by_key(|&(r, _)| r == Registry::Iana);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn loaded() -> Database {
+        let mut db = Database::new();
+        db.load(fixture("delegated-apnic-extended-latest")).unwrap();
+        db.load(fixture("delegated-iana-latest")).unwrap();
+        db
+    }
+
+    #[test]
+    fn test_load() {
+        let db = loaded();
+        let record = db.lookup(ip("1.0.2.200")).unwrap();
+        assert_eq!(record.registry, Registry::Apnic);
+        assert_eq!(record.country_code, "CN");
+        assert_eq!(record.status, Status::Allocated);
+        assert_eq!((record.first, record.last), (ip("1.0.2.0"), ip("1.0.3.255")));
+
+        let record = db.lookup(ip("2001:250:1::1")).unwrap();
+        assert_eq!((record.first, record.last), (ip("2001:250::"), ip("2001:250:1fff:ffff:ffff:ffff:ffff:ffff")));
+
+        // Only IANA knows about it.
+        let record = db.lookup(ip("10.0.0.1")).unwrap();
+        assert_eq!(record.registry, Registry::Iana);
+        assert_eq!(record.status, Status::Ietf);
+        assert_eq!(db.lookup(ip("1.0.64.1")).unwrap().status, Status::Apnic);
+        assert_eq!(db.lookup(ip("11.0.0.1")), None);
+    }
+
+    #[test]
+    fn test_iteration() {
+        let db = loaded();
+        assert_eq!(db.by_country("cn").ipv4_ranges(), vec![(16777472, 16777727), (16777728, 16778239),
+                                                           (16779264, 16781311)]);
+        assert_eq!(db.by_country("CN").ipv6_ranges().len(), 1);
+        // Available blocks are listed but not in use.
+        assert_eq!(db.by_country("ZZ").filter(|record| record.registry == Registry::Apnic).count(), 1);
+        assert_eq!(db.by_registry(Registry::Iana).count(), 5);
+        assert_eq!(db.by_country("XX").count(), 0);
+    }
+
+    #[test]
+    fn test_replace() {
+        let mut db = loaded();
+        db.parse("2|apnic|20180101|1|19830613|20171231|+1000\n\
+                  apnic|JP|ipv4|1.0.0.0|256|20110811|assigned|A91872ED\n".as_bytes()).unwrap();
+        assert_eq!(db.lookup(ip("1.0.0.1")).unwrap().country_code, "JP");
+        assert_eq!(db.lookup(ip("1.0.2.200")).unwrap().registry, Registry::Iana);
+    }
+
+    #[test]
+    fn test_load_packed() {
+        let path = ::std::env::temp_dir().join("iana-test-load-packed.db");
+        loaded().write_packed(File::create(&path).unwrap()).unwrap();
+        let mut db = Database::new();
+        db.load(&path).unwrap();
+        assert_eq!(db.lookup(ip("1.0.2.200")), loaded().lookup(ip("1.0.2.200")));
+        assert_eq!(PackedDatabase::open(&path).unwrap().lookup(ip("10.0.0.1")), db.lookup(ip("10.0.0.1")));
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_errors() {
+        let parse = |text: &str| Database::new().parse(text.as_bytes());
+        assert!(parse("apnic|CN|ipv4|1.0.1.0|256|20110414|allocated\n").is_err());
+        assert!(parse("# nothing\n").is_err());
+        let header = "2.3|apnic|20171109|1|19830613|20171108|+1000\n";
+        assert!(parse(header).is_ok());
+        let e = parse(&format!("{}apnic|CN|ipv4|1.0.1.300|256|20110414|allocated\n", header)).unwrap_err();
+        assert_eq!(format!("{}", e), "line 2: invalid IPv4 address");
+        assert!(parse(&format!("{}apnic|CN|ipv4|255.255.255.0|512|20110414|allocated\n", header)).is_err());
+        assert!(parse(&format!("{}apnic|CN|ipv4|1.0.1.0|0|20110414|allocated\n", header)).is_err());
+        assert!(parse(&format!("{}apnic|CN|ipv6|2001:250::|129|20000426|allocated\n", header)).is_err());
+        assert!(parse(&format!("{}apnic|CN|ipv4|1.0.1.0|256|20110414|stolen\n", header)).is_err());
+        assert!(parse(&format!("{}nic|CN|ipv4|1.0.1.0|256|20110414|allocated\n", header)).is_err());
+        // Codes the tables don't know about.
+        let mut db = Database::new();
+        db.parse(format!("{}apnic|QQ|ipv4|1.0.1.0|256|20110414|allocated\n", header).as_bytes()).unwrap();
+        assert_eq!(db.lookup(ip("1.0.1.1")).unwrap().country_code, "ZZ");
+    }
+
+    #[cfg(feature = "builtin-db")]
+    #[test]
+    fn test_over_builtin() {
+        let mut db = Database::builtin();
+        assert_eq!(db.lookup(ip("1.0.0.1")), super::super::lookup(ip("1.0.0.1")));
+        db.load(fixture("delegated-apnic-extended-latest")).unwrap();
+        assert_eq!(db.lookup(ip("1.0.2.200")).unwrap().registry, Registry::Apnic);
+        // ARIN still comes from the compiled-in tables.
+        assert_eq!(db.lookup(ip("8.8.8.8")).unwrap().registry, Registry::Arin);
+    }
+}
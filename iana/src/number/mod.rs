@@ -17,6 +17,21 @@ pub mod registry;
 pub mod status;
 
 pub mod db;
+mod query;
+mod database;
+mod packed;
 
 pub use self::registry::Registry;
 pub use self::status::Status;
+pub use self::query::{Record, Records};
+#[cfg(feature = "builtin-db")]
+pub use self::query::{lookup, by_country, by_registry, ipv4_ranges, ipv6_ranges};
+pub use self::database::Database;
+pub use self::packed::{PackedDatabase, PackedRecords};
+
+
+/// Index of a two letter country code (`CN`) in the tables.
+pub fn country_index(code: &str) -> Option<u8> {
+    let code = code.to_uppercase();
+    db::COUNTRY_CODES.iter().position(|c| *c == code).map(|index| index as u8)
+}
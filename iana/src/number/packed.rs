@@ -0,0 +1,469 @@
+use std::io::{self, Write};
+use std::fs::File;
+use std::path::Path;
+use std::net::IpAddr;
+
+use memmap::Mmap;
+
+use super::{db, Registry, IP4AN, IP6AN};
+use super::query::{Filter, Record};
+
+
+/// Binary form of the delegation tables, queried in place.
+///
+/// Everything is big endian, the tables in order of precedence and each sorted
+/// by first address:
+///
+/// ```text
+/// magic      8 bytes   "IANANUM\0"
+/// version    u16       1
+/// tables     u16
+/// checksum   u32       CRC-32 of everything after these 16 bytes
+///
+/// per table  12 bytes  family u8 (4 or 6), registry u8, 0u16,
+///                      offset u32 (from the start of the file), entries u32
+///
+/// IPv4 entry 11 bytes  first u32, last u32, country [u8; 2], status u8
+/// IPv6 entry 35 bytes  first u128, last u128, country [u8; 2], status u8
+/// ```
+///
+/// Countries are stored as their two letter code, so files outlive changes to
+/// `db::COUNTRY_CODES`. Codes missing from it are read as `ZZ`.
+pub struct PackedDatabase<B = Mmap> {
+    bytes: B,
+    tables: Vec<Table>,
+}
+
+pub const MAGIC: &'static [u8; 8] = b"IANANUM\0";
+pub const VERSION: u16 = 1;
+
+const HEADER_LEN: usize = 16;
+const TABLE_LEN: usize = 12;
+const IPV4_LEN: usize = 11;
+const IPV6_LEN: usize = 35;
+
+
+#[derive(Debug, Copy, Clone)]
+struct Table {
+    ipv6: bool,
+    registry: Registry,
+    offset: usize,
+    len: usize,
+}
+
+impl Table {
+    fn entry<'a>(&self, bytes: &'a [u8], index: usize) -> &'a [u8] {
+        let size = if self.ipv6 { IPV6_LEN } else { IPV4_LEN };
+        let start = self.offset + index * size;
+        &bytes[start..start + size]
+    }
+
+    fn first(&self, entry: &[u8]) -> u128 {
+        if self.ipv6 { read_u128(entry, 0) } else { read_u32(entry, 0) as u128 }
+    }
+
+    fn last(&self, entry: &[u8]) -> u128 {
+        if self.ipv6 { read_u128(entry, 16) } else { read_u32(entry, 4) as u128 }
+    }
+
+    fn code<'a>(&self, entry: &'a [u8]) -> &'a [u8] {
+        if self.ipv6 { &entry[32..34] } else { &entry[8..10] }
+    }
+
+    fn record(&self, entry: &[u8]) -> Record {
+        if self.ipv6 {
+            Record::ipv6(self.registry, &ipv6_entry(entry))
+        } else {
+            Record::ipv4(self.registry, &ipv4_entry(entry))
+        }
+    }
+
+    /// Last entry starting at or before `addr`, if it also ends after it.
+    fn search<'a>(&self, bytes: &'a [u8], addr: u128) -> Option<&'a [u8]> {
+        // First entry starting after `addr`.
+        let (mut low, mut high) = (0, self.len);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            if self.first(self.entry(bytes, middle)) <= addr {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+        if low == 0 {
+            return None;
+        }
+        let entry = self.entry(bytes, low - 1);
+        if self.last(entry) >= addr {
+            Some(entry)
+        } else {
+            None
+        }
+    }
+}
+
+impl PackedDatabase<Mmap> {
+    /// Maps the file into memory. Opening reads it once for the checksum,
+    /// queries touch only the pages they search.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<PackedDatabase<Mmap>, io::Error> {
+        let path = path.as_ref();
+        let error = |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", path.display(), e));
+        let file = try!(File::open(path).map_err(&error));
+        // Empty files can't be mapped.
+        if try!(file.metadata().map_err(&error)).len() < HEADER_LEN as u64 {
+            return Err(error(invalid("truncated header")));
+        }
+        let bytes = try!(unsafe { Mmap::map(&file) }.map_err(&error));
+        PackedDatabase::from_bytes(bytes).map_err(&error)
+    }
+}
+
+impl<B: AsRef<[u8]>> PackedDatabase<B> {
+    /// Checks the header, checksum and table bounds of a file already in memory.
+    pub fn from_bytes(bytes: B) -> Result<PackedDatabase<B>, io::Error> {
+        let tables = try!(tables(bytes.as_ref()));
+        Ok(PackedDatabase { bytes: bytes, tables: tables })
+    }
+
+    /// The block `addr` belongs to, a binary search per registry.
+    pub fn lookup(&self, addr: IpAddr) -> Option<Record> {
+        let (ipv6, addr) = match addr {
+            IpAddr::V4(addr) => (false, u32::from(addr) as u128),
+            IpAddr::V6(addr) => (true, u128::from(addr)),
+        };
+        let bytes = self.bytes.as_ref();
+        self.tables.iter()
+            .filter(|table| table.ipv6 == ipv6)
+            .filter_map(|table| table.search(bytes, addr).map(|entry| table.record(entry)))
+            .next()
+    }
+
+    /// Every block delegated to a two letter country code, nothing for an unknown code.
+    pub fn by_country(&self, code: &str) -> PackedRecords {
+        self.records(super::country_index(code).map(Filter::Country))
+    }
+
+    /// Every block listed by a registry.
+    pub fn by_registry(&self, registry: Registry) -> PackedRecords {
+        self.records(Some(Filter::Registry(registry)))
+    }
+
+    fn records(&self, filter: Option<Filter>) -> PackedRecords {
+        PackedRecords {
+            bytes: self.bytes.as_ref(),
+            tables: if filter.is_some() { &self.tables } else { &[] },
+            filter: filter.unwrap_or(Filter::Country(0)),
+            table: 0,
+            index: 0,
+        }
+    }
+
+    /// The IPv4 tables decoded, in order of precedence.
+    pub(crate) fn ipv4_tables(&self) -> Vec<(Registry, Vec<IP4AN>)> {
+        let bytes = self.bytes.as_ref();
+        self.tables.iter()
+            .filter(|table| !table.ipv6)
+            .map(|table| (table.registry, (0..table.len).map(|i| ipv4_entry(table.entry(bytes, i))).collect()))
+            .collect()
+    }
+
+    pub(crate) fn ipv6_tables(&self) -> Vec<(Registry, Vec<IP6AN>)> {
+        let bytes = self.bytes.as_ref();
+        self.tables.iter()
+            .filter(|table| table.ipv6)
+            .map(|table| (table.registry, (0..table.len).map(|i| ipv6_entry(table.entry(bytes, i))).collect()))
+            .collect()
+    }
+}
+
+
+/// Blocks of a packed database, IPv4 first, each family ordered per registry.
+#[derive(Debug, Clone)]
+pub struct PackedRecords<'a> {
+    bytes: &'a [u8],
+    tables: &'a [Table],
+    filter: Filter,
+    table: usize,
+    index: usize,
+}
+
+impl<'a> PackedRecords<'a> {
+    fn matches(&self, table: &Table, entry: &[u8]) -> bool {
+        match self.filter {
+            Filter::Country(index) => {
+                let code = db::COUNTRY_CODES[index as usize];
+                let stored = table.code(entry);
+                stored == code.as_bytes() || (code == "ZZ" && country(stored).is_none())
+            },
+            Filter::Registry(registry) => registry == table.registry,
+        }
+    }
+}
+
+impl<'a> Iterator for PackedRecords<'a> {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        loop {
+            let table = match self.tables.get(self.table) {
+                Some(table) => *table,
+                None => return None,
+            };
+            if self.index == table.len {
+                self.table += 1;
+                self.index = 0;
+                continue;
+            }
+            let entry = table.entry(self.bytes, self.index);
+            self.index += 1;
+            if self.matches(&table, entry) {
+                return Some(table.record(entry));
+            }
+        }
+    }
+}
+
+
+/// Writes tables in order of precedence, as `Database` keeps them.
+pub fn write<A, B, W>(ipv4: &[(Registry, A)], ipv6: &[(Registry, B)], mut writer: W) -> Result<(), io::Error>
+        where A: AsRef<[IP4AN]>, B: AsRef<[IP6AN]>, W: Write {
+    let count = ipv4.len() + ipv6.len();
+    if count > u16::max_value() as usize {
+        return Err(invalid("too many tables"));
+    }
+
+    let mut directory = vec![];
+    let mut entries = vec![];
+    let mut offset = HEADER_LEN + count * TABLE_LEN;
+    for &(registry, ref table) in ipv4.iter() {
+        let table = table.as_ref();
+        try!(table_header(&mut directory, 4, registry, offset, table.len()));
+        for &(first, last, country, status) in table.iter() {
+            entries.extend_from_slice(&u32_bytes(first));
+            entries.extend_from_slice(&u32_bytes(last));
+            entries.extend_from_slice(code(country));
+            entries.push(status);
+        }
+        offset += table.len() * IPV4_LEN;
+    }
+    for &(registry, ref table) in ipv6.iter() {
+        let table = table.as_ref();
+        try!(table_header(&mut directory, 6, registry, offset, table.len()));
+        for &(first, last, country, status) in table.iter() {
+            entries.extend_from_slice(&u128_bytes(first));
+            entries.extend_from_slice(&u128_bytes(last));
+            entries.extend_from_slice(code(country));
+            entries.push(status);
+        }
+        offset += table.len() * IPV6_LEN;
+    }
+
+    let mut header = vec![];
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&[(VERSION >> 8) as u8, VERSION as u8, (count >> 8) as u8, count as u8]);
+    header.extend_from_slice(&u32_bytes(crc32(crc32(0, &directory), &entries)));
+
+    try!(writer.write_all(&header));
+    try!(writer.write_all(&directory));
+    try!(writer.write_all(&entries));
+    writer.flush()
+}
+
+fn table_header(directory: &mut Vec<u8>, family: u8, registry: Registry, offset: usize, len: usize)
+        -> Result<(), io::Error> {
+    let size = if family == 6 { IPV6_LEN } else { IPV4_LEN };
+    if offset + len * size > u32::max_value() as usize {
+        return Err(invalid("tables too large"));
+    }
+    directory.extend_from_slice(&[family, registry.to_u8(), 0, 0]);
+    directory.extend_from_slice(&u32_bytes(offset as u32));
+    directory.extend_from_slice(&u32_bytes(len as u32));
+    Ok(())
+}
+
+fn tables(bytes: &[u8]) -> Result<Vec<Table>, io::Error> {
+    if bytes.len() < HEADER_LEN {
+        return Err(invalid("truncated header"));
+    }
+    if &bytes[..8] != MAGIC {
+        return Err(invalid("not a packed delegation database"));
+    }
+    let version = read_u16(bytes, 8);
+    if version != VERSION {
+        return Err(invalid(&format!("unsupported version {}", version)));
+    }
+    if crc32(0, &bytes[HEADER_LEN..]) != read_u32(bytes, 12) {
+        return Err(invalid("checksum mismatch"));
+    }
+
+    let count = read_u16(bytes, 10) as usize;
+    if bytes.len() < HEADER_LEN + count * TABLE_LEN {
+        return Err(invalid("truncated table directory"));
+    }
+    let mut tables = vec![];
+    for i in 0..count {
+        let at = HEADER_LEN + i * TABLE_LEN;
+        let (ipv6, size) = match bytes[at] {
+            4 => (false, IPV4_LEN),
+            6 => (true, IPV6_LEN),
+            _ => return Err(invalid("unknown address family")),
+        };
+        let registry = try!(Registry::new(bytes[at + 1]).map_err(|_| invalid("unknown registry")));
+        let offset = read_u32(bytes, at + 4) as usize;
+        let len = read_u32(bytes, at + 8) as usize;
+        match len.checked_mul(size).and_then(|n| n.checked_add(offset)) {
+            Some(end) if end <= bytes.len() => {},
+            _ => return Err(invalid("table out of bounds")),
+        }
+        tables.push(Table { ipv6: ipv6, registry: registry, offset: offset, len: len });
+    }
+    Ok(tables)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn ipv4_entry(entry: &[u8]) -> IP4AN {
+    (read_u32(entry, 0), read_u32(entry, 4), country(&entry[8..10]).unwrap_or(zz()), entry[10])
+}
+
+fn ipv6_entry(entry: &[u8]) -> IP6AN {
+    (read_u128(entry, 0), read_u128(entry, 16), country(&entry[32..34]).unwrap_or(zz()), entry[34])
+}
+
+/// Index of a stored country code.
+fn country(code: &[u8]) -> Option<u8> {
+    db::COUNTRY_CODES.iter().position(|c| c.as_bytes() == code).map(|index| index as u8)
+}
+
+fn zz() -> u8 {
+    country(b"ZZ").unwrap()
+}
+
+fn code(country: u8) -> &'static [u8] {
+    db::COUNTRY_CODES.get(country as usize).unwrap_or(&"ZZ").as_bytes()
+}
+
+fn read_u16(bytes: &[u8], at: usize) -> u16 {
+    (bytes[at] as u16) << 8 | bytes[at + 1] as u16
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    bytes[at..at + 4].iter().fold(0, |n, &b| n << 8 | b as u32)
+}
+
+fn read_u128(bytes: &[u8], at: usize) -> u128 {
+    bytes[at..at + 16].iter().fold(0, |n, &b| n << 8 | b as u128)
+}
+
+fn u32_bytes(n: u32) -> [u8; 4] {
+    [(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]
+}
+
+fn u128_bytes(n: u128) -> [u8; 16] {
+    let mut bytes = [0u8; 16];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = (n >> (120 - i * 8)) as u8;
+    }
+    bytes
+}
+
+/// CRC-32 as zlib computes it, `crc` is the checksum of the bytes before.
+fn crc32(crc: u32, bytes: &[u8]) -> u32 {
+    let mut table = [0u32; 256];
+    for (n, slot) in table.iter_mut().enumerate() {
+        let mut c = n as u32;
+        for _ in 0..8 {
+            c = if c & 1 == 1 { 0xedb88320 ^ (c >> 1) } else { c >> 1 };
+        }
+        *slot = c;
+    }
+    let mut crc = !crc;
+    for &b in bytes.iter() {
+        crc = table[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{Database, Status};
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn packed() -> PackedDatabase<Vec<u8>> {
+        let mut db = Database::new();
+        db.load(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/delegated-apnic-extended-latest")).unwrap();
+        db.load(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/delegated-iana-latest")).unwrap();
+        let mut bytes = vec![];
+        db.write_packed(&mut bytes).unwrap();
+        PackedDatabase::from_bytes(bytes).unwrap()
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(0, b"123456789"), 0xcbf43926);
+        assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xcbf43926);
+    }
+
+    #[test]
+    fn test_lookup() {
+        let db = packed();
+        let record = db.lookup(ip("1.0.2.200")).unwrap();
+        assert_eq!(record.registry, Registry::Apnic);
+        assert_eq!(record.country_code, "CN");
+        assert_eq!(record.status, Status::Allocated);
+        assert_eq!((record.first, record.last), (ip("1.0.2.0"), ip("1.0.3.255")));
+        let record = db.lookup(ip("2001:250:1::1")).unwrap();
+        assert_eq!((record.first, record.last), (ip("2001:250::"), ip("2001:250:1fff:ffff:ffff:ffff:ffff:ffff")));
+        assert_eq!(db.lookup(ip("10.0.0.1")).unwrap().registry, Registry::Iana);
+        assert_eq!(db.lookup(ip("11.0.0.1")), None);
+        assert_eq!(db.lookup(ip("0.0.0.0")).unwrap().first, ip("0.0.0.0"));
+    }
+
+    #[test]
+    fn test_iteration() {
+        let db = packed();
+        let ranges: Vec<(IpAddr, IpAddr)> = db.by_country("cn")
+            .filter(|record| record.in_use() && record.first.is_ipv4())
+            .map(|record| (record.first, record.last))
+            .collect();
+        assert_eq!(ranges, vec![(ip("1.0.1.0"), ip("1.0.1.255")), (ip("1.0.2.0"), ip("1.0.3.255")),
+                                (ip("1.0.8.0"), ip("1.0.15.255"))]);
+        assert_eq!(db.by_registry(Registry::Iana).count(), 5);
+        assert_eq!(db.by_country("XX").count(), 0);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let db = packed();
+        let mut copy = Database::new();
+        copy.load_packed(&db);
+        let mut bytes = vec![];
+        copy.write_packed(&mut bytes).unwrap();
+        assert_eq!(&bytes[..], &db.bytes[..]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let bytes = packed().bytes;
+        assert!(PackedDatabase::from_bytes(&bytes[..10]).is_err());
+        assert!(PackedDatabase::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+
+        let mut corrupt = bytes.clone();
+        corrupt[100] ^= 1;
+        let e = PackedDatabase::from_bytes(corrupt).err().unwrap();
+        assert_eq!(format!("{}", e), "checksum mismatch");
+
+        let mut newer = bytes.clone();
+        newer[9] = 2;
+        let e = PackedDatabase::from_bytes(newer).err().unwrap();
+        assert_eq!(format!("{}", e), "unsupported version 2");
+        assert!(PackedDatabase::from_bytes(&b"2|apnic|20171109|52473|19830613"[..]).is_err());
+    }
+}
@@ -0,0 +1,355 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use Country;
+use super::{db, Registry, Status, IP4AN, IP6AN};
+
+
+/// The regional registries first, `db::iana` only tells which one an address
+/// block was delegated to and answers for the rest.
+#[cfg(feature = "builtin-db")]
+static IPV4_TABLES: [(Registry, &'static [IP4AN]); 6] = [
+    (Registry::Afrinic, &db::afrinic::IPV4_NUMBERS),
+    (Registry::Apnic, &db::apnic::IPV4_NUMBERS),
+    (Registry::Arin, &db::arin::IPV4_NUMBERS),
+    (Registry::Lacnic, &db::lacnic::IPV4_NUMBERS),
+    (Registry::Ripencc, &db::ripencc::IPV4_NUMBERS),
+    (Registry::Iana, &db::iana::IPV4_NUMBERS),
+];
+
+#[cfg(feature = "builtin-db")]
+static IPV6_TABLES: [(Registry, &'static [IP6AN]); 6] = [
+    (Registry::Afrinic, &db::afrinic::IPV6_NUMBERS),
+    (Registry::Apnic, &db::apnic::IPV6_NUMBERS),
+    (Registry::Arin, &db::arin::IPV6_NUMBERS),
+    (Registry::Lacnic, &db::lacnic::IPV6_NUMBERS),
+    (Registry::Ripencc, &db::ripencc::IPV6_NUMBERS),
+    (Registry::Iana, &db::iana::IPV6_NUMBERS),
+];
+
+
+/// One address block of the registries.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub registry: Registry,
+    /// Two letter code, `ZZ` when the block is not delegated to a country.
+    pub country_code: &'static str,
+    /// `None` for codes newer than ISO 3166-1993, such as `RS`.
+    pub country: Option<Country>,
+    pub status: Status,
+    pub first: IpAddr,
+    pub last: IpAddr,
+}
+
+impl Record {
+    fn new(registry: Registry, country: u8, status: u8, first: IpAddr, last: IpAddr) -> Record {
+        let country_code = db::COUNTRY_CODES.get(country as usize).map(|code| *code).unwrap_or("ZZ");
+        Record {
+            registry: registry,
+            country_code: country_code,
+            country: Country::from_code(country_code).ok(),
+            status: Status::new(status).unwrap_or(Status::Reserved),
+            first: first,
+            last: last,
+        }
+    }
+
+    pub(crate) fn ipv4(registry: Registry, record: &IP4AN) -> Record {
+        let (first, last, country, status) = *record;
+        Record::new(registry, country, status,
+                    IpAddr::V4(Ipv4Addr::from(first)), IpAddr::V4(Ipv4Addr::from(last)))
+    }
+
+    pub(crate) fn ipv6(registry: Registry, record: &IP6AN) -> Record {
+        let (first, last, country, status) = *record;
+        Record::new(registry, country, status,
+                    IpAddr::V6(Ipv6Addr::from(first)), IpAddr::V6(Ipv6Addr::from(last)))
+    }
+
+    /// Allocated or assigned, as opposed to reserved or still available.
+    pub fn in_use(&self) -> bool {
+        in_use(self.status.to_u8())
+    }
+
+    /// The block as the fewest CIDR networks, `(network address, prefix length)`.
+    pub fn networks(&self) -> Vec<(IpAddr, u8)> {
+        match (self.first, self.last) {
+            (IpAddr::V4(first), IpAddr::V4(last)) => {
+                networks(32, u32::from(first) as u128, u32::from(last) as u128).into_iter()
+                    .map(|(addr, prefix)| (IpAddr::V4(Ipv4Addr::from(addr as u32)), prefix))
+                    .collect()
+            },
+            (IpAddr::V6(first), IpAddr::V6(last)) => {
+                networks(128, u128::from(first), u128::from(last)).into_iter()
+                    .map(|(addr, prefix)| (IpAddr::V6(Ipv6Addr::from(addr)), prefix))
+                    .collect()
+            },
+            _ => vec![]
+        }
+    }
+}
+
+/// Host part of a `bits - host` prefix.
+fn host_mask(host: u32) -> u128 {
+    match 1u128.checked_shl(host) {
+        Some(size) => size - 1,
+        None => !0
+    }
+}
+
+fn networks(bits: u32, first: u128, last: u128) -> Vec<(u128, u8)> {
+    let mut networks = vec![];
+    let mut start = first;
+    loop {
+        let mut host = bits;
+        while start & host_mask(host) != 0 || start | host_mask(host) > last {
+            host -= 1;
+        }
+        networks.push((start, (bits - host) as u8));
+        let end = start | host_mask(host);
+        if end >= last {
+            return networks;
+        }
+        start = end + 1;
+    }
+}
+
+/// Last entry of a table sorted by first address which may contain `addr`.
+fn search<T: Copy + Ord>(table: &[(T, T, u8, u8)], addr: T) -> Option<&(T, T, u8, u8)> {
+    let index = match table.binary_search_by(|entry| entry.0.cmp(&addr)) {
+        Ok(index) => index,
+        Err(0) => return None,
+        Err(index) => index - 1,
+    };
+    let entry = &table[index];
+    if entry.1 >= addr {
+        Some(entry)
+    } else {
+        None
+    }
+}
+
+/// `lookup` over any set of tables, in order of precedence.
+pub fn lookup_in<A, B>(ipv4: &[(Registry, A)], ipv6: &[(Registry, B)], addr: IpAddr) -> Option<Record>
+        where A: AsRef<[IP4AN]>, B: AsRef<[IP6AN]> {
+    match addr {
+        IpAddr::V4(addr) => {
+            let addr = u32::from(addr);
+            for &(registry, ref table) in ipv4.iter() {
+                if let Some(record) = search(table.as_ref(), addr) {
+                    return Some(Record::ipv4(registry, record));
+                }
+            }
+            None
+        },
+        IpAddr::V6(addr) => {
+            let addr = u128::from(addr);
+            for &(registry, ref table) in ipv6.iter() {
+                if let Some(record) = search(table.as_ref(), addr) {
+                    return Some(Record::ipv6(registry, record));
+                }
+            }
+            None
+        }
+    }
+}
+
+
+#[derive(Debug, Copy, Clone)]
+pub enum Filter {
+    Country(u8),
+    Registry(Registry),
+}
+
+/// Blocks of a country or registry, IPv4 first, each family ordered per registry.
+#[derive(Debug, Clone)]
+pub struct Records<'a> {
+    filter: Filter,
+    ipv4: Vec<(Registry, &'a [IP4AN])>,
+    ipv6: Vec<(Registry, &'a [IP6AN])>,
+    in_ipv6: bool,
+    table: usize,
+    index: usize,
+}
+
+impl<'a> Records<'a> {
+    /// Over any set of tables, nothing without a filter.
+    pub fn new<A, B>(ipv4: &'a [(Registry, A)], ipv6: &'a [(Registry, B)], filter: Option<Filter>) -> Records<'a>
+            where A: AsRef<[IP4AN]>, B: AsRef<[IP6AN]> {
+        let (filter, ipv4, ipv6) = match filter {
+            Some(filter) => (filter,
+                             ipv4.iter().map(|&(registry, ref table)| (registry, table.as_ref())).collect(),
+                             ipv6.iter().map(|&(registry, ref table)| (registry, table.as_ref())).collect()),
+            None => (Filter::Country(0), vec![], vec![])
+        };
+        Records { filter: filter, ipv4: ipv4, ipv6: ipv6, in_ipv6: false, table: 0, index: 0 }
+    }
+
+    fn matches(&self, registry: Registry, country: u8) -> bool {
+        match self.filter {
+            Filter::Country(index) => index == country,
+            Filter::Registry(r) => r == registry,
+        }
+    }
+
+    /// The allocated or assigned IPv4 blocks, first and last address.
+    /// Unordered and possibly overlapping across registries.
+    pub fn ipv4_ranges(self) -> Vec<(u32, u32)> {
+        self.filter(|record| record.in_use())
+            .filter_map(|record| match (record.first, record.last) {
+                (IpAddr::V4(first), IpAddr::V4(last)) => Some((u32::from(first), u32::from(last))),
+                _ => None
+            })
+            .collect()
+    }
+
+    /// IPv6 counterpart of `ipv4_ranges`.
+    pub fn ipv6_ranges(self) -> Vec<(u128, u128)> {
+        self.filter(|record| record.in_use())
+            .filter_map(|record| match (record.first, record.last) {
+                (IpAddr::V6(first), IpAddr::V6(last)) => Some((u128::from(first), u128::from(last))),
+                _ => None
+            })
+            .collect()
+    }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        loop {
+            if !self.in_ipv6 {
+                if self.table == self.ipv4.len() {
+                    self.in_ipv6 = true;
+                    self.table = 0;
+                    continue;
+                }
+                let (registry, table) = self.ipv4[self.table];
+                if self.index == table.len() {
+                    self.table += 1;
+                    self.index = 0;
+                    continue;
+                }
+                let entry = &table[self.index];
+                self.index += 1;
+                if self.matches(registry, entry.2) {
+                    return Some(Record::ipv4(registry, entry));
+                }
+            } else {
+                if self.table == self.ipv6.len() {
+                    return None;
+                }
+                let (registry, table) = self.ipv6[self.table];
+                if self.index == table.len() {
+                    self.table += 1;
+                    self.index = 0;
+                    continue;
+                }
+                let entry = &table[self.index];
+                self.index += 1;
+                if self.matches(registry, entry.2) {
+                    return Some(Record::ipv6(registry, entry));
+                }
+            }
+        }
+    }
+}
+
+fn in_use(status: u8) -> bool {
+    status == Status::Allocated.to_u8() || status == Status::Assigned.to_u8()
+}
+
+
+/// The block `addr` belongs to, a binary search per registry.
+#[cfg(feature = "builtin-db")]
+pub fn lookup(addr: IpAddr) -> Option<Record> {
+    lookup_in(&IPV4_TABLES, &IPV6_TABLES, addr)
+}
+
+/// Every block delegated to a two letter country code, nothing for an unknown code.
+#[cfg(feature = "builtin-db")]
+pub fn by_country(code: &str) -> Records<'static> {
+    Records::new(&IPV4_TABLES, &IPV6_TABLES, super::country_index(code).map(Filter::Country))
+}
+
+/// Every block listed by a registry.
+#[cfg(feature = "builtin-db")]
+pub fn by_registry(registry: Registry) -> Records<'static> {
+    Records::new(&IPV4_TABLES, &IPV6_TABLES, Some(Filter::Registry(registry)))
+}
+
+/// IPv4 ranges allocated or assigned to the country, first and last address.
+/// Unordered and possibly overlapping across registries, empty for an unknown code.
+#[cfg(feature = "builtin-db")]
+pub fn ipv4_ranges(code: &str) -> Vec<(u32, u32)> {
+    by_country(code).ipv4_ranges()
+}
+
+/// IPv6 counterpart of `ipv4_ranges`.
+#[cfg(feature = "builtin-db")]
+pub fn ipv6_ranges(code: &str) -> Vec<(u128, u128)> {
+    by_country(code).ipv6_ranges()
+}
+
+
+#[cfg(all(test, feature = "builtin-db"))]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_lookup() {
+        // 1.0.0.0/24, APNIC research prefix.
+        let record = lookup(ip("1.0.0.1")).unwrap();
+        assert_eq!(record.registry, Registry::Apnic);
+        assert_eq!(record.country_code, "AU");
+        assert_eq!(record.country, Some(Country::AU));
+        assert_eq!(record.first, ip("1.0.0.0"));
+        assert_eq!(record.last, ip("1.0.0.255"));
+
+        assert_eq!(lookup(ip("41.0.0.1")).unwrap().registry, Registry::Afrinic);
+        assert_eq!(lookup(ip("2001:200::1")).unwrap().country_code, "JP");
+
+        // Private use, only the IANA table knows about it.
+        let record = lookup(ip("10.1.2.3")).unwrap();
+        assert_eq!(record.registry, Registry::Iana);
+        assert_eq!(record.status, Status::Ietf);
+        assert!(!record.in_use());
+    }
+
+    #[test]
+    fn test_edges() {
+        assert_eq!(lookup(ip("0.0.0.0")).unwrap().first, ip("0.0.0.0"));
+        assert_eq!(lookup(ip("255.255.255.255")).unwrap().last, ip("255.255.255.255"));
+        for &(registry, table) in IPV4_TABLES.iter() {
+            let (first, last, _, _) = table[table.len() - 1];
+            assert_eq!(lookup(IpAddr::V4(Ipv4Addr::from(first))).unwrap().registry, registry);
+            assert_eq!(lookup(IpAddr::V4(Ipv4Addr::from(last))).unwrap().registry, registry);
+        }
+    }
+
+    #[test]
+    fn test_iteration() {
+        let records: Vec<Record> = by_country("jp").collect();
+        assert!(records.iter().any(|record| record.first.is_ipv4()));
+        assert!(records.iter().any(|record| record.first.is_ipv6()));
+        assert!(records.iter().all(|record| record.country_code == "JP"));
+        assert_eq!(by_country("XX").count(), 0);
+
+        let arin = by_registry(Registry::Arin).count();
+        assert_eq!(arin, db::arin::IPV4_NUMBERS.len() + db::arin::IPV6_NUMBERS.len());
+    }
+
+    #[test]
+    fn test_networks() {
+        let record = Record::ipv4(Registry::Apnic, &(16777216, 16778239, 0, 1));
+        assert_eq!(record.networks(), vec![(ip("1.0.0.0"), 22)]);
+        let record = Record::ipv4(Registry::Apnic, &(16777216, 16778495, 0, 1));
+        assert_eq!(record.networks(), vec![(ip("1.0.0.0"), 22), (ip("1.0.4.0"), 24)]);
+        let record = Record::ipv6(Registry::Iana, &(0, !0, 0, 9));
+        assert_eq!(record.networks(), vec![(ip("::"), 0)]);
+    }
+}
@@ -9,6 +9,10 @@ extern crate ansi_term;
 
 
 use std::fmt;
+use std::io::{self, Write};
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use std::sync::Mutex;
 use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 
 static MAX_MODULE_WIDTH: AtomicUsize = ATOMIC_USIZE_INIT;
@@ -28,30 +32,75 @@ impl fmt::Display for Level {
 }
 
 
-pub fn init(level: Option<&str>) -> Result<(), log::SetLoggerError> {
+fn builder(level: Option<&str>) -> env_logger::LogBuilder {
     let mut builder = env_logger::LogBuilder::new();
+    if let Ok(s) = ::std::env::var("RUST_LOG") {
+        builder.parse(&s);
+    } else if level.is_some() {
+        builder.parse(level.unwrap());
+    }
+    builder
+}
+
+fn pad_module_path(record: &log::LogRecord) -> String {
+    let mut module_path = record.location().module_path().to_string();
+    let max_width = MAX_MODULE_WIDTH.load(Ordering::Relaxed);
+    if max_width > module_path.len() {
+        let diff = max_width - module_path.len();
+        module_path.extend(::std::iter::repeat(' ').take(diff));
+    } else {
+        MAX_MODULE_WIDTH.store(module_path.len(), Ordering::Relaxed);
+    }
+    module_path
+}
+
+pub fn init(level: Option<&str>) -> Result<(), log::SetLoggerError> {
+    let mut builder = builder(level);
 
     builder.format(|record| {
-        let mut module_path = record.location().module_path().to_string();
-        let max_width = MAX_MODULE_WIDTH.load(Ordering::Relaxed);
-        if max_width > module_path.len() {
-            let diff = max_width - module_path.len();
-            module_path.extend(::std::iter::repeat(' ').take(diff));
-        } else {
-            MAX_MODULE_WIDTH.store(module_path.len(), Ordering::Relaxed);
-        }
         format!("[{} {}] {} {}",
                 time::now().strftime("%Y-%m-%d %H:%M:%S.%f").unwrap(),
                 Level(record.level()),
-                ansi_term::Style::new().bold().paint(module_path),
+                ansi_term::Style::new().bold().paint(pad_module_path(record)),
                 record.args())
     });
 
-    if let Ok(s) = ::std::env::var("RUST_LOG") {
-        builder.parse(&s);
-    } else if level.is_some() {
-        builder.parse(level.unwrap());
-    }
     builder.init()
 }
 
+
+/// Appends plain records to a file, the level filter is the env_logger one.
+struct FileLogger {
+    filter: env_logger::Logger,
+    file: Mutex<File>,
+}
+
+impl log::Log for FileLogger {
+    fn enabled(&self, metadata: &log::LogMetadata) -> bool {
+        log::Log::enabled(&self.filter, metadata)
+    }
+
+    fn log(&self, record: &log::LogRecord) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format!("[{} {:>5}] {} {}\n",
+                           time::now().strftime("%Y-%m-%d %H:%M:%S.%f").unwrap(),
+                           record.level(),
+                           pad_module_path(record),
+                           record.args());
+        if let Ok(mut file) = self.file.lock() {
+            let _ = file.write_all(line.as_bytes());
+        }
+    }
+}
+
+/// Like `init`, but log to `filename` (appending) instead of stderr.
+pub fn init_with_file(level: Option<&str>, filename: &Path) -> Result<(), io::Error> {
+    let file = try!(OpenOptions::new().create(true).append(true).open(filename));
+    let filter = builder(level).build();
+    log::set_logger(|max_level| {
+        max_level.set(filter.filter());
+        Box::new(FileLogger { filter: filter, file: Mutex::new(file) })
+    }).map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{}", e)))
+}
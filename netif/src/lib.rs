@@ -19,6 +19,10 @@ pub mod sys;
 pub mod interface;
 pub mod neighbor;
 pub mod route;
+#[cfg(target_os = "linux")]
+pub mod netlink;
+#[cfg(target_os = "linux")]
+pub mod nftables;
 mod raw_socket;
 
 pub use hwaddr::HwAddr;
@@ -0,0 +1,926 @@
+#![cfg(target_os = "linux")]
+
+// rtnetlink(7) client: links, addresses, routes and neighbors.
+//
+// Every call opens its own NETLINK_ROUTE socket, sends a single request and
+// reads the answer until NLMSG_DONE (dumps) or the kernel acknowledgement.
+// Requests fail with the errno carried by NLMSG_ERROR.
+
+use sys;
+use HwAddr;
+use interface::Flags;
+use ipnetwork::{IpNetwork, Ipv4Network, Ipv6Network};
+
+use std::io;
+use std::mem;
+use std::ptr;
+use std::slice;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+
+// https://github.com/torvalds/linux/blob/master/include/uapi/linux/netlink.h
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16  = 3;
+
+const NLM_F_REQUEST: u16           = 0x001;
+const NLM_F_MULTI: u16             = 0x002;
+pub(crate) const NLM_F_ACK: u16    = 0x004;
+pub(crate) const NLM_F_DUMP: u16   = 0x300;
+const NLM_F_REPLACE: u16           = 0x100;
+const NLM_F_EXCL: u16              = 0x200;
+pub(crate) const NLM_F_CREATE: u16 = 0x400;
+pub(crate) const NLM_F_APPEND: u16 = 0x800;
+
+// https://github.com/torvalds/linux/blob/master/include/uapi/linux/rtnetlink.h
+const RTM_NEWLINK: u16  = 16;
+const RTM_GETLINK: u16  = 18;
+const RTM_NEWADDR: u16  = 20;
+const RTM_DELADDR: u16  = 21;
+const RTM_GETADDR: u16  = 22;
+const RTM_NEWROUTE: u16 = 24;
+const RTM_DELROUTE: u16 = 25;
+const RTM_GETROUTE: u16 = 26;
+const RTM_GETNEIGH: u16 = 30;
+
+const RTA_DST: u16      = 1;
+const RTA_OIF: u16      = 4;
+const RTA_GATEWAY: u16  = 5;
+const RTA_PRIORITY: u16 = 6;
+const RTA_TABLE: u16    = 15;
+
+// Multicast groups of change notifications.
+pub(crate) const RTMGRP_IPV4_IFADDR: u32 = 0x10;
+pub(crate) const RTMGRP_IPV4_ROUTE: u32  = 0x40;
+pub(crate) const RTMGRP_IPV6_IFADDR: u32 = 0x100;
+pub(crate) const RTMGRP_IPV6_ROUTE: u32  = 0x400;
+
+pub const RT_TABLE_MAIN: u32 = 254;
+const RTPROT_BOOT: u8        = 3;
+const RT_SCOPE_UNIVERSE: u8  = 0;
+const RT_SCOPE_LINK: u8      = 253;
+const RT_SCOPE_NOWHERE: u8   = 255;
+const RTN_UNICAST: u8        = 1;
+
+// https://github.com/torvalds/linux/blob/master/include/uapi/linux/if_link.h
+const IFLA_ADDRESS: u16 = 1;
+const IFLA_IFNAME: u16  = 3;
+const IFLA_MTU: u16     = 4;
+
+// https://github.com/torvalds/linux/blob/master/include/uapi/linux/if_addr.h
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16   = 2;
+
+// https://github.com/torvalds/linux/blob/master/include/uapi/linux/neighbour.h
+const NDA_DST: u16    = 1;
+const NDA_LLADDR: u16 = 2;
+
+const NLMSG_HDRLEN: usize = 16;
+pub(crate) const RTA_HDRLEN: usize = 4;
+
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct nlmsghdr {
+    nlmsg_len: u32,
+    nlmsg_type: u16,
+    nlmsg_flags: u16,
+    nlmsg_seq: u32,
+    nlmsg_pid: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct sockaddr_nl {
+    nl_family: sys::sa_family_t,
+    nl_pad: u16,
+    nl_pid: u32,
+    nl_groups: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct ifinfomsg {
+    ifi_family: u8,
+    ifi_pad: u8,
+    ifi_type: u16,
+    ifi_index: i32,
+    ifi_flags: u32,
+    ifi_change: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct ifaddrmsg {
+    ifa_family: u8,
+    ifa_prefixlen: u8,
+    ifa_flags: u8,
+    ifa_scope: u8,
+    ifa_index: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct rtmsg {
+    rtm_family: u8,
+    rtm_dst_len: u8,
+    rtm_src_len: u8,
+    rtm_tos: u8,
+    rtm_table: u8,
+    rtm_protocol: u8,
+    rtm_scope: u8,
+    rtm_type: u8,
+    rtm_flags: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct ndmsg {
+    ndm_family: u8,
+    ndm_pad1: u8,
+    ndm_pad2: u16,
+    ndm_ifindex: i32,
+    ndm_state: u16,
+    ndm_flags: u8,
+    ndm_type: u8,
+}
+
+
+pub(crate) fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+pub(crate) fn as_bytes<T>(value: &T) -> &[u8] {
+    unsafe { slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+pub(crate) fn read<T: Copy>(buf: &[u8]) -> Option<T> {
+    if buf.len() < mem::size_of::<T>() {
+        None
+    } else {
+        Some(unsafe { ptr::read_unaligned(buf.as_ptr() as *const T) })
+    }
+}
+
+pub(crate) fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Route attributes following a fixed size message header.
+pub(crate) fn attributes(buf: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut attrs = vec![];
+    let mut pos = 0;
+    while pos + RTA_HDRLEN <= buf.len() {
+        let len = read::<u16>(&buf[pos..]).unwrap() as usize;
+        let kind = read::<u16>(&buf[pos + 2..]).unwrap();
+        if len < RTA_HDRLEN || pos + len > buf.len() {
+            break;
+        }
+        attrs.push((kind, &buf[pos + RTA_HDRLEN..pos + len]));
+        pos += align(len);
+    }
+    attrs
+}
+
+fn ip_from_bytes(data: &[u8]) -> Option<IpAddr> {
+    match data.len() {
+        4 => Some(IpAddr::V4(Ipv4Addr::new(data[0], data[1], data[2], data[3]))),
+        16 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(data);
+            Some(IpAddr::V6(Ipv6Addr::from(octets)))
+        },
+        _ => None
+    }
+}
+
+fn ip_to_bytes(addr: &IpAddr) -> Vec<u8> {
+    match *addr {
+        IpAddr::V4(addr) => addr.octets().to_vec(),
+        IpAddr::V6(addr) => addr.octets().to_vec(),
+    }
+}
+
+fn family(addr: &IpAddr) -> u8 {
+    match *addr {
+        IpAddr::V4(_) => sys::AF_INET as u8,
+        IpAddr::V6(_) => sys::AF_INET6 as u8,
+    }
+}
+
+fn network(addr: IpAddr, prefix: u8) -> Result<IpNetwork, io::Error> {
+    let network = match addr {
+        IpAddr::V4(addr) => Ipv4Network::new(addr, prefix).map(IpNetwork::V4),
+        IpAddr::V6(addr) => Ipv6Network::new(addr, prefix).map(IpNetwork::V6),
+    };
+    network.map_err(|_| invalid("invalid prefix length"))
+}
+
+fn hwaddr(data: &[u8]) -> Option<HwAddr> {
+    if data.len() == 6 {
+        Some(HwAddr::from([data[0], data[1], data[2], data[3], data[4], data[5]]))
+    } else {
+        None
+    }
+}
+
+
+pub(crate) struct Request {
+    buf: Vec<u8>,
+}
+
+impl Request {
+    pub(crate) fn new<T>(kind: u16, flags: u16, header: &T) -> Request {
+        let hdr = nlmsghdr {
+            nlmsg_len: 0,
+            nlmsg_type: kind,
+            nlmsg_flags: NLM_F_REQUEST | flags,
+            nlmsg_seq: 0,
+            nlmsg_pid: 0,
+        };
+        let mut buf = as_bytes(&hdr).to_vec();
+        buf.extend_from_slice(as_bytes(header));
+        let len = align(buf.len());
+        buf.resize(len, 0);
+        Request { buf: buf }
+    }
+
+    pub(crate) fn attr(mut self, kind: u16, data: &[u8]) -> Request {
+        let len = (RTA_HDRLEN + data.len()) as u16;
+        self.buf.extend_from_slice(as_bytes(&len));
+        self.buf.extend_from_slice(as_bytes(&kind));
+        self.buf.extend_from_slice(data);
+        let len = align(self.buf.len());
+        self.buf.resize(len, 0);
+        self
+    }
+
+    fn flags(&self) -> u16 {
+        read::<nlmsghdr>(&self.buf).unwrap().nlmsg_flags
+    }
+}
+
+
+pub(crate) struct Socket {
+    fd: sys::c_int,
+    seq: u32,
+}
+
+impl Socket {
+    fn open() -> Result<Socket, io::Error> {
+        Socket::with_protocol(sys::NETLINK_ROUTE)
+    }
+
+    pub(crate) fn with_protocol(protocol: sys::c_int) -> Result<Socket, io::Error> {
+        let fd = unsafe { sys::socket(sys::AF_NETLINK, sys::SOCK_RAW | sys::SOCK_CLOEXEC, protocol) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = Socket { fd: fd, seq: 0 };
+
+        let mut addr: sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = sys::AF_NETLINK as sys::sa_family_t;
+        let ret = unsafe {
+            sys::bind(fd, &addr as *const sockaddr_nl as *const sys::sockaddr,
+                      mem::size_of::<sockaddr_nl>() as sys::socklen_t)
+        };
+        if ret == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(socket)
+    }
+
+    /// Number `req` and fill in its length.
+    fn seal(&mut self, req: &mut Request) -> u32 {
+        self.seq += 1;
+        let len = req.buf.len() as u32;
+        let hdr = req.buf.as_mut_ptr() as *mut nlmsghdr;
+        unsafe {
+            (*hdr).nlmsg_len = len;
+            (*hdr).nlmsg_seq = self.seq;
+        }
+        self.seq
+    }
+
+    fn send(&self, buf: &[u8]) -> Result<(), io::Error> {
+        let mut kernel: sockaddr_nl = unsafe { mem::zeroed() };
+        kernel.nl_family = sys::AF_NETLINK as sys::sa_family_t;
+        let ret = unsafe {
+            sys::sendto(self.fd, buf.as_ptr() as *const sys::c_void, buf.len(), 0,
+                        &kernel as *const sockaddr_nl as *const sys::sockaddr,
+                        mem::size_of::<sockaddr_nl>() as sys::socklen_t)
+        };
+        if ret == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        loop {
+            let size = unsafe { sys::recv(self.fd, buf.as_mut_ptr() as *mut sys::c_void, buf.len(), 0) };
+            if size != -1 {
+                return Ok(size as usize);
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+    }
+
+    /// Send `req` and collect the payloads of the answer, without their `nlmsghdr`.
+    pub(crate) fn request(&mut self, mut req: Request) -> Result<Vec<Vec<u8>>, io::Error> {
+        let seq = self.seal(&mut req);
+        let dump = req.flags() & NLM_F_DUMP == NLM_F_DUMP;
+        try!(self.send(&req.buf));
+
+        let mut messages = vec![];
+        let mut buf = vec![0u8; 32 * 1024];
+        loop {
+            let size = try!(self.recv(&mut buf));
+            let data = &buf[..size];
+
+            let mut pos = 0;
+            while pos + NLMSG_HDRLEN <= data.len() {
+                let hdr: nlmsghdr = read(&data[pos..]).unwrap();
+                let len = hdr.nlmsg_len as usize;
+                if len < NLMSG_HDRLEN || pos + len > data.len() {
+                    return Err(invalid("truncated netlink message"));
+                }
+                let payload = &data[pos + NLMSG_HDRLEN..pos + len];
+                pos += align(len);
+
+                if hdr.nlmsg_seq != seq {
+                    continue;
+                }
+                match hdr.nlmsg_type {
+                    NLMSG_DONE => return Ok(messages),
+                    NLMSG_ERROR => {
+                        let errno = match read::<i32>(payload) {
+                            Some(errno) => errno,
+                            None => return Err(invalid("truncated netlink error"))
+                        };
+                        if errno == 0 {
+                            // Acknowledgement
+                            return Ok(messages);
+                        }
+                        return Err(io::Error::from_raw_os_error(-errno));
+                    },
+                    _ => {
+                        messages.push(payload.to_vec());
+                        if !dump && hdr.nlmsg_flags & NLM_F_MULTI == 0 && req.flags() & NLM_F_ACK == 0 {
+                            return Ok(messages);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Socket {
+    /// Send `reqs` in one datagram, as nfnetlink(7) batches go, and wait for the
+    /// acknowledgement of each request flagged `NLM_F_ACK`. Fails with the index
+    /// of the request the kernel rejected, 0 when nothing was processed.
+    pub(crate) fn batch(&mut self, mut reqs: Vec<Request>) -> Result<(), (usize, io::Error)> {
+        let mut buf = vec![];
+        let mut pending = vec![];
+        for req in reqs.iter_mut() {
+            let seq = self.seal(req);
+            if req.flags() & NLM_F_ACK != 0 {
+                pending.push(seq);
+            }
+            buf.extend_from_slice(&req.buf);
+        }
+        let first = self.seq - reqs.len() as u32 + 1;
+        try!(self.send(&buf).map_err(|e| (0, e)));
+
+        let mut buf = vec![0u8; 32 * 1024];
+        while !pending.is_empty() {
+            let size = try!(self.recv(&mut buf).map_err(|e| (0, e)));
+            let data = &buf[..size];
+
+            let mut pos = 0;
+            while pos + NLMSG_HDRLEN <= data.len() {
+                let hdr: nlmsghdr = read(&data[pos..]).unwrap();
+                let len = hdr.nlmsg_len as usize;
+                if len < NLMSG_HDRLEN || pos + len > data.len() {
+                    return Err((0, invalid("truncated netlink message")));
+                }
+                let payload = &data[pos + NLMSG_HDRLEN..pos + len];
+                pos += align(len);
+
+                // Errors may come for unacknowledged requests too, the batch ends.
+                if hdr.nlmsg_type != NLMSG_ERROR || hdr.nlmsg_seq < first || hdr.nlmsg_seq > self.seq {
+                    continue;
+                }
+                let index = (hdr.nlmsg_seq - first) as usize;
+                match read::<i32>(payload) {
+                    Some(0) => pending.retain(|seq| *seq != hdr.nlmsg_seq),
+                    Some(errno) => return Err((index, io::Error::from_raw_os_error(-errno))),
+                    None => return Err((index, invalid("truncated netlink error"))),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        unsafe { sys::close(self.fd) };
+    }
+}
+
+/// Non-blocking socket joined to the `RTMGRP_*` notification `groups`, owned by the caller.
+pub(crate) fn subscribe(groups: u32) -> Result<sys::c_int, io::Error> {
+    let fd = unsafe { sys::socket(sys::AF_NETLINK, sys::SOCK_RAW | sys::SOCK_NONBLOCK | sys::SOCK_CLOEXEC,
+                                  sys::NETLINK_ROUTE) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut addr: sockaddr_nl = unsafe { mem::zeroed() };
+    addr.nl_family = sys::AF_NETLINK as sys::sa_family_t;
+    addr.nl_groups = groups;
+    let ret = unsafe {
+        sys::bind(fd, &addr as *const sockaddr_nl as *const sys::sockaddr,
+                  mem::size_of::<sockaddr_nl>() as sys::socklen_t)
+    };
+    if ret == -1 {
+        let e = io::Error::last_os_error();
+        unsafe { sys::close(fd) };
+        return Err(e);
+    }
+    Ok(fd)
+}
+
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Link {
+    pub index: u32,
+    pub name: String,
+    pub flags: Flags,
+    pub mtu: u32,
+    pub hwaddr: Option<HwAddr>,
+}
+
+/// Address assigned to an interface, with its prefix length.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Address {
+    pub index: u32,
+    pub network: IpNetwork,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Route {
+    pub destination: IpNetwork,
+    pub gateway: Option<IpAddr>,
+    /// Output interface index.
+    pub ifindex: Option<u32>,
+    pub priority: Option<u32>,
+    pub table: u32,
+}
+
+impl Route {
+    /// Route in the main table, without gateway nor interface yet.
+    pub fn new(destination: IpNetwork) -> Route {
+        Route {
+            destination: destination,
+            gateway: None,
+            ifindex: None,
+            priority: None,
+            table: RT_TABLE_MAIN,
+        }
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.destination.prefix() == 0
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Neighbor {
+    pub index: u32,
+    pub ipaddr: IpAddr,
+    pub hwaddr: Option<HwAddr>,
+    /// `NUD_*` state, see rtnetlink(7).
+    pub state: u16,
+}
+
+
+fn ifinfo(index: u32) -> ifinfomsg {
+    ifinfomsg {
+        ifi_family: sys::AF_UNSPEC as u8,
+        ifi_pad: 0,
+        ifi_type: 0,
+        ifi_index: index as i32,
+        ifi_flags: 0,
+        ifi_change: 0,
+    }
+}
+
+pub fn links() -> Result<Vec<Link>, io::Error> {
+    let mut socket = try!(Socket::open());
+    let req = Request::new(RTM_GETLINK, NLM_F_DUMP, &ifinfo(0));
+    let mut links = vec![];
+    for msg in try!(socket.request(req)) {
+        let info: ifinfomsg = match read(&msg) {
+            Some(info) => info,
+            None => return Err(invalid("truncated ifinfomsg"))
+        };
+        let mut link = Link {
+            index: info.ifi_index as u32,
+            name: String::new(),
+            flags: Flags::from_bits_truncate(info.ifi_flags as _),
+            mtu: 0,
+            hwaddr: None,
+        };
+        for (kind, data) in attributes(&msg[align(mem::size_of::<ifinfomsg>())..]) {
+            match kind {
+                IFLA_IFNAME => {
+                    let name = data.split(|byte| *byte == 0).next().unwrap_or(&[]);
+                    link.name = String::from_utf8_lossy(name).into_owned();
+                },
+                IFLA_MTU => link.mtu = read::<u32>(data).unwrap_or(0),
+                IFLA_ADDRESS => link.hwaddr = hwaddr(data),
+                _ => { }
+            }
+        }
+        links.push(link);
+    }
+    Ok(links)
+}
+
+pub fn link(name: &str) -> Result<Link, io::Error> {
+    match try!(links()).into_iter().find(|link| link.name == name) {
+        Some(link) => Ok(link),
+        None => Err(io::Error::new(io::ErrorKind::NotFound, format!("no such interface {:?}", name)))
+    }
+}
+
+pub fn set_mtu(index: u32, mtu: u32) -> Result<(), io::Error> {
+    let mut socket = try!(Socket::open());
+    let req = Request::new(RTM_NEWLINK, NLM_F_ACK, &ifinfo(index)).attr(IFLA_MTU, as_bytes(&mtu));
+    socket.request(req).map(|_| ())
+}
+
+/// Set the bits of `flags` selected by `mask`, the others are left alone.
+pub fn set_flags(index: u32, flags: Flags, mask: Flags) -> Result<(), io::Error> {
+    let mut socket = try!(Socket::open());
+    let mut info = ifinfo(index);
+    info.ifi_flags = (flags & mask).bits() as u32;
+    info.ifi_change = mask.bits() as u32;
+    socket.request(Request::new(RTM_NEWLINK, NLM_F_ACK, &info)).map(|_| ())
+}
+
+pub fn set_up(index: u32, up: bool) -> Result<(), io::Error> {
+    let flags = if up { Flags::IFF_UP } else { Flags::empty() };
+    set_flags(index, flags, Flags::IFF_UP)
+}
+
+
+pub fn addresses() -> Result<Vec<Address>, io::Error> {
+    let mut socket = try!(Socket::open());
+    let header = ifaddrmsg { ifa_family: sys::AF_UNSPEC as u8, ifa_prefixlen: 0, ifa_flags: 0, ifa_scope: 0, ifa_index: 0 };
+    let mut addresses = vec![];
+    for msg in try!(socket.request(Request::new(RTM_GETADDR, NLM_F_DUMP, &header))) {
+        let ifa: ifaddrmsg = match read(&msg) {
+            Some(ifa) => ifa,
+            None => return Err(invalid("truncated ifaddrmsg"))
+        };
+        let mut address: Option<IpAddr> = None;
+        let mut local: Option<IpAddr> = None;
+        for (kind, data) in attributes(&msg[align(mem::size_of::<ifaddrmsg>())..]) {
+            match kind {
+                IFA_ADDRESS => address = ip_from_bytes(data),
+                // IFA_ADDRESS is the peer address on point to point links.
+                IFA_LOCAL => local = ip_from_bytes(data),
+                _ => { }
+            }
+        }
+        if let Some(addr) = local.or(address) {
+            addresses.push(Address { index: ifa.ifa_index, network: try!(network(addr, ifa.ifa_prefixlen)) });
+        }
+    }
+    Ok(addresses)
+}
+
+fn address_request(kind: u16, flags: u16, index: u32, network: &IpNetwork) -> Request {
+    let ip = network.ip();
+    let header = ifaddrmsg {
+        ifa_family: family(&ip),
+        ifa_prefixlen: network.prefix(),
+        ifa_flags: 0,
+        ifa_scope: RT_SCOPE_UNIVERSE,
+        ifa_index: index,
+    };
+    Request::new(kind, flags, &header)
+        .attr(IFA_LOCAL, &ip_to_bytes(&ip))
+        .attr(IFA_ADDRESS, &ip_to_bytes(&ip))
+}
+
+pub fn add_address(index: u32, network: &IpNetwork) -> Result<(), io::Error> {
+    let mut socket = try!(Socket::open());
+    let req = address_request(RTM_NEWADDR, NLM_F_ACK | NLM_F_CREATE | NLM_F_EXCL, index, network);
+    socket.request(req).map(|_| ())
+}
+
+pub fn delete_address(index: u32, network: &IpNetwork) -> Result<(), io::Error> {
+    let mut socket = try!(Socket::open());
+    socket.request(address_request(RTM_DELADDR, NLM_F_ACK, index, network)).map(|_| ())
+}
+
+
+fn parse_route(msg: &[u8]) -> Result<(rtmsg, Route), io::Error> {
+    let rtm: rtmsg = match read(msg) {
+        Some(rtm) => rtm,
+        None => return Err(invalid("truncated rtmsg"))
+    };
+    let mut destination: Option<IpAddr> = None;
+    let mut route = Route::new(IpNetwork::V4(Ipv4Network::new(Ipv4Addr::new(0, 0, 0, 0), 0).unwrap()));
+    route.table = rtm.rtm_table as u32;
+    for (kind, data) in attributes(&msg[align(mem::size_of::<rtmsg>())..]) {
+        match kind {
+            RTA_DST => destination = ip_from_bytes(data),
+            RTA_GATEWAY => route.gateway = ip_from_bytes(data),
+            RTA_OIF => route.ifindex = read::<u32>(data),
+            RTA_PRIORITY => route.priority = read::<u32>(data),
+            RTA_TABLE => route.table = read::<u32>(data).unwrap_or(route.table),
+            _ => { }
+        }
+    }
+    let destination = match destination {
+        Some(destination) => destination,
+        // Default routes carry no RTA_DST.
+        None if rtm.rtm_family == sys::AF_INET6 as u8 => IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)),
+        None => IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+    };
+    route.destination = try!(network(destination, rtm.rtm_dst_len));
+    Ok((rtm, route))
+}
+
+pub fn routes() -> Result<Vec<Route>, io::Error> {
+    let mut socket = try!(Socket::open());
+    let header: rtmsg = unsafe { mem::zeroed() };
+    let mut routes = vec![];
+    for msg in try!(socket.request(Request::new(RTM_GETROUTE, NLM_F_DUMP, &header))) {
+        let (rtm, route) = try!(parse_route(&msg));
+        // Local, broadcast and other special routes are managed by the kernel.
+        if rtm.rtm_type == RTN_UNICAST {
+            routes.push(route);
+        }
+    }
+    Ok(routes)
+}
+
+/// Route the kernel would use to reach `addr`, as `ip route get` does.
+///
+/// The destination of the result is `addr` itself, not the matching table entry.
+pub fn get_route(addr: &IpAddr) -> Result<Route, io::Error> {
+    let mut socket = try!(Socket::open());
+    let mut header: rtmsg = unsafe { mem::zeroed() };
+    header.rtm_family = family(addr);
+    header.rtm_dst_len = match *addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    };
+    let req = Request::new(RTM_GETROUTE, 0, &header).attr(RTA_DST, &ip_to_bytes(addr));
+    match try!(socket.request(req)).first() {
+        Some(msg) => parse_route(msg).map(|(_, route)| route),
+        None => Err(invalid("empty route lookup answer"))
+    }
+}
+
+fn route_request(kind: u16, flags: u16, route: &Route) -> Request {
+    let dst = route.destination.ip();
+    let table = if route.table < 256 { route.table as u8 } else { 0 };
+    let mut header = rtmsg {
+        rtm_family: family(&dst),
+        rtm_dst_len: route.destination.prefix(),
+        rtm_src_len: 0,
+        rtm_tos: 0,
+        rtm_table: table,
+        rtm_protocol: RTPROT_BOOT,
+        // Without a gateway the destination is directly reachable on the interface.
+        rtm_scope: if route.gateway.is_some() { RT_SCOPE_UNIVERSE } else { RT_SCOPE_LINK },
+        rtm_type: RTN_UNICAST,
+        rtm_flags: 0,
+    };
+    if kind == RTM_DELROUTE {
+        // Match any scope, protocol and type like `ip route del`, only what is set in `route` counts.
+        header.rtm_protocol = 0;
+        header.rtm_scope = RT_SCOPE_NOWHERE;
+        header.rtm_type = 0;
+    }
+    let mut req = Request::new(kind, flags, &header).attr(RTA_DST, &ip_to_bytes(&dst));
+    if table == 0 {
+        req = req.attr(RTA_TABLE, as_bytes(&route.table));
+    }
+    if let Some(ref gateway) = route.gateway {
+        req = req.attr(RTA_GATEWAY, &ip_to_bytes(gateway));
+    }
+    if let Some(ref ifindex) = route.ifindex {
+        req = req.attr(RTA_OIF, as_bytes(ifindex));
+    }
+    if let Some(ref priority) = route.priority {
+        req = req.attr(RTA_PRIORITY, as_bytes(priority));
+    }
+    req
+}
+
+/// Fails with `AlreadyExists` when the same route is in the table.
+pub fn add_route(route: &Route) -> Result<(), io::Error> {
+    let mut socket = try!(Socket::open());
+    socket.request(route_request(RTM_NEWROUTE, NLM_F_ACK | NLM_F_CREATE | NLM_F_EXCL, route)).map(|_| ())
+}
+
+pub fn replace_route(route: &Route) -> Result<(), io::Error> {
+    let mut socket = try!(Socket::open());
+    socket.request(route_request(RTM_NEWROUTE, NLM_F_ACK | NLM_F_CREATE | NLM_F_REPLACE, route)).map(|_| ())
+}
+
+pub fn delete_route(route: &Route) -> Result<(), io::Error> {
+    let mut socket = try!(Socket::open());
+    socket.request(route_request(RTM_DELROUTE, NLM_F_ACK, route)).map(|_| ())
+}
+
+
+pub fn neighbors() -> Result<Vec<Neighbor>, io::Error> {
+    let mut socket = try!(Socket::open());
+    let header: ndmsg = unsafe { mem::zeroed() };
+    let mut neighbors = vec![];
+    for msg in try!(socket.request(Request::new(RTM_GETNEIGH, NLM_F_DUMP, &header))) {
+        let ndm: ndmsg = match read(&msg) {
+            Some(ndm) => ndm,
+            None => return Err(invalid("truncated ndmsg"))
+        };
+        let mut ipaddr: Option<IpAddr> = None;
+        let mut lladdr: Option<HwAddr> = None;
+        for (kind, data) in attributes(&msg[align(mem::size_of::<ndmsg>())..]) {
+            match kind {
+                NDA_DST => ipaddr = ip_from_bytes(data),
+                NDA_LLADDR => lladdr = hwaddr(data),
+                _ => { }
+            }
+        }
+        if let Some(ipaddr) = ipaddr {
+            neighbors.push(Neighbor {
+                index: ndm.ndm_ifindex as u32,
+                ipaddr: ipaddr,
+                hwaddr: lladdr,
+                state: ndm.ndm_state,
+            });
+        }
+    }
+    Ok(neighbors)
+}
+
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::panic;
+    use std::str::FromStr;
+
+    /// Write to stderr without taking the lock of `io::stderr`, which another
+    /// test thread may have held when the child was forked.
+    pub fn write_stderr(msg: &str) {
+        unsafe { sys::write(2, msg.as_ptr() as *const sys::c_void, msg.len()) };
+    }
+
+    /// Run `f` in a forked child inside a new user and network namespace,
+    /// where it owns the network stack without being root on the host.
+    ///
+    /// Tests using it are `#[ignore]`d since they need unprivileged user namespaces,
+    /// run them with `cargo test -p netif -- --ignored --test-threads 1`.
+    pub fn in_netns<F: FnOnce() -> Result<(), io::Error>>(f: F) {
+        unsafe {
+            let pid = sys::fork();
+            assert!(pid != -1, "fork failed");
+            if pid == 0 {
+                // Nothing may unwind into the copy of the test harness.
+                panic::set_hook(Box::new(|info| write_stderr(&format!("{}\n", info))));
+                if sys::unshare(sys::CLONE_NEWUSER | sys::CLONE_NEWNET) == -1 {
+                    sys::_exit(77);
+                }
+                let code = match panic::catch_unwind(panic::AssertUnwindSafe(f)) {
+                    Ok(Ok(())) => 0,
+                    Ok(Err(e)) => {
+                        write_stderr(&format!("{}\n", e));
+                        1
+                    },
+                    Err(_) => 1
+                };
+                sys::_exit(code);
+            }
+            let mut status = 0;
+            assert_eq!(sys::waitpid(pid, &mut status, 0), pid);
+            assert!(sys::WIFEXITED(status), "test killed in the network namespace");
+            match sys::WEXITSTATUS(status) {
+                0 => { },
+                77 => panic!("can't create a user namespace, are unprivileged user namespaces enabled?"),
+                _ => panic!("test failed in the network namespace")
+            }
+        }
+    }
+
+    fn check(ok: bool, msg: &str) -> Result<(), io::Error> {
+        if ok { Ok(()) } else { Err(io::Error::new(io::ErrorKind::Other, msg.to_string())) }
+    }
+
+    #[test]
+    #[ignore]
+    fn test_links() {
+        in_netns(|| {
+            let lo = try!(link("lo"));
+            try!(check(lo.flags.contains(Flags::IFF_LOOPBACK), "lo is not a loopback"));
+            try!(check(!lo.flags.contains(Flags::IFF_UP), "lo is up in a new namespace"));
+
+            try!(set_up(lo.index, true));
+            try!(set_mtu(lo.index, 1500));
+            let lo = try!(link("lo"));
+            try!(check(lo.flags.contains(Flags::IFF_UP), "lo is still down"));
+            check(lo.mtu == 1500, "mtu not changed")
+        });
+    }
+
+    #[test]
+    #[ignore]
+    fn test_addresses() {
+        in_netns(|| {
+            let lo = try!(link("lo"));
+            try!(set_up(lo.index, true));
+            let network = IpNetwork::from_str("10.9.8.7/24").unwrap();
+            try!(add_address(lo.index, &network));
+            try!(check(try!(addresses()).contains(&Address { index: lo.index, network: network }),
+                       "address not listed"));
+            try!(check(add_address(lo.index, &network).unwrap_err().kind() == io::ErrorKind::AlreadyExists,
+                       "address added twice"));
+            try!(delete_address(lo.index, &network));
+            check(!try!(addresses()).iter().any(|address| address.network == network), "address not deleted")
+        });
+    }
+
+    #[test]
+    #[ignore]
+    fn test_routes() {
+        in_netns(|| {
+            let lo = try!(link("lo"));
+            try!(set_up(lo.index, true));
+            try!(add_address(lo.index, &IpNetwork::from_str("10.9.8.7/24").unwrap()));
+
+            let mut route = Route::new(IpNetwork::from_str("192.168.77.0/24").unwrap());
+            route.gateway = Some(IpAddr::from_str("10.9.8.1").unwrap());
+            route.ifindex = Some(lo.index);
+            try!(add_route(&route));
+            try!(check(try!(routes()).contains(&route), "route not listed"));
+            try!(check(add_route(&route).unwrap_err().kind() == io::ErrorKind::AlreadyExists,
+                       "route added twice"));
+
+            let mut default = Route::new(IpNetwork::from_str("0.0.0.0/0").unwrap());
+            default.ifindex = Some(lo.index);
+            try!(replace_route(&default));
+            try!(replace_route(&default));
+            try!(check(try!(routes()).iter().any(|route| route.is_default()), "default route not listed"));
+
+            let found = try!(get_route(&IpAddr::from_str("192.168.77.5").unwrap()));
+            try!(check(found.ifindex == Some(lo.index), "wrong route lookup"));
+
+            try!(delete_route(&route));
+            try!(check(!try!(routes()).contains(&route), "route not deleted"));
+            try!(delete_route(&Route::new(default.destination)));
+            try!(check(!try!(routes()).iter().any(|route| route.is_default()), "default route not deleted"));
+            try!(check(get_route(&IpAddr::from_str("192.168.77.5").unwrap()).is_err(), "route lookup without route"));
+            check(delete_route(&route).is_err(), "route deleted twice")
+        });
+    }
+
+    #[test]
+    #[ignore]
+    fn test_subscribe() {
+        in_netns(|| {
+            let fd = try!(subscribe(RTMGRP_IPV4_IFADDR | RTMGRP_IPV4_ROUTE));
+            let mut buf = [0u8; 4096];
+            let recv = |buf: &mut [u8]| unsafe {
+                sys::recv(fd, buf.as_mut_ptr() as *mut sys::c_void, buf.len(), 0)
+            };
+            try!(check(recv(&mut buf) == -1, "notified before any change"));
+
+            let lo = try!(link("lo"));
+            try!(set_up(lo.index, true));
+            try!(add_address(lo.index, &IpNetwork::from_str("10.9.8.7/24").unwrap()));
+            let ret = recv(&mut buf);
+            unsafe { sys::close(fd) };
+            check(ret > 0, "no notification for a new address")
+        });
+    }
+
+    #[test]
+    #[ignore]
+    fn test_neighbors() {
+        in_netns(|| {
+            // Only checks the dump parses, a new namespace has no neighbors.
+            neighbors().map(|_| ())
+        });
+    }
+}
@@ -0,0 +1,349 @@
+#![cfg(target_os = "linux")]
+
+// nf_tables client over NETLINK_NETFILTER, the messages nft(8) sends.
+//
+// Changes go to the kernel as one batch, applied atomically. Every request of
+// the batch is acknowledged, so a failure names the request it came from.
+
+use sys;
+use netlink::{self, Request, Socket};
+use ipnetwork::IpNetwork;
+
+use std::io;
+use std::mem;
+
+
+// https://github.com/torvalds/linux/blob/master/include/uapi/linux/netfilter/nfnetlink.h
+const NFNL_SUBSYS_NFTABLES: u16 = 10;
+const NFNL_MSG_BATCH_BEGIN: u16 = 0x10;
+const NFNL_MSG_BATCH_END: u16   = 0x11;
+
+// https://github.com/torvalds/linux/blob/master/include/uapi/linux/netfilter/nf_tables.h
+const NFT_MSG_NEWTABLE: u16 = 0;
+const NFT_MSG_GETTABLE: u16 = 1;
+const NFT_MSG_DELTABLE: u16 = 2;
+const NFT_MSG_NEWCHAIN: u16 = 3;
+const NFT_MSG_NEWRULE: u16  = 6;
+
+const NFTA_TABLE_NAME: u16 = 1;
+
+const NFTA_CHAIN_TABLE: u16 = 1;
+const NFTA_CHAIN_NAME: u16  = 3;
+const NFTA_CHAIN_HOOK: u16  = 4;
+const NFTA_CHAIN_TYPE: u16  = 7;
+
+const NFTA_HOOK_HOOKNUM: u16  = 1;
+const NFTA_HOOK_PRIORITY: u16 = 2;
+
+const NFTA_RULE_TABLE: u16       = 1;
+const NFTA_RULE_CHAIN: u16       = 2;
+const NFTA_RULE_EXPRESSIONS: u16 = 4;
+
+const NFTA_LIST_ELEM: u16 = 1;
+const NFTA_EXPR_NAME: u16 = 1;
+const NFTA_EXPR_DATA: u16 = 2;
+
+const NFTA_META_DREG: u16 = 1;
+const NFTA_META_KEY: u16  = 2;
+
+const NFTA_PAYLOAD_DREG: u16   = 1;
+const NFTA_PAYLOAD_BASE: u16   = 2;
+const NFTA_PAYLOAD_OFFSET: u16 = 3;
+const NFTA_PAYLOAD_LEN: u16    = 4;
+
+const NFTA_BITWISE_SREG: u16 = 1;
+const NFTA_BITWISE_DREG: u16 = 2;
+const NFTA_BITWISE_LEN: u16  = 3;
+const NFTA_BITWISE_MASK: u16 = 4;
+const NFTA_BITWISE_XOR: u16  = 5;
+
+const NFTA_CMP_SREG: u16 = 1;
+const NFTA_CMP_OP: u16   = 2;
+const NFTA_CMP_DATA: u16 = 3;
+
+const NFTA_DATA_VALUE: u16 = 1;
+
+const NFT_REG_1: u32                  = 1;
+const NFT_META_OIFNAME: u32           = 7;
+const NFT_PAYLOAD_NETWORK_HEADER: u32 = 1;
+const NFT_CMP_EQ: u32                 = 0;
+
+// https://github.com/torvalds/linux/blob/master/include/uapi/linux/netfilter.h
+const NFPROTO_IPV4: u8 = 2;
+const NFPROTO_IPV6: u8 = 10;
+const NF_INET_POST_ROUTING: u32 = 4;
+const NF_IP_PRI_NAT_SRC: i32 = 100;
+
+const NLA_F_NESTED: u16 = 0x8000;
+const IFNAMSIZ: usize = 16;
+
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct nfgenmsg {
+    nfgen_family: u8,
+    version: u8,
+    res_id: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Family {
+    Ipv4,
+    Ipv6,
+}
+
+impl Family {
+    pub fn of(network: &IpNetwork) -> Family {
+        match *network {
+            IpNetwork::V4(_) => Family::Ipv4,
+            IpNetwork::V6(_) => Family::Ipv6,
+        }
+    }
+
+    fn nfproto(&self) -> u8 {
+        match *self {
+            Family::Ipv4 => NFPROTO_IPV4,
+            Family::Ipv6 => NFPROTO_IPV6,
+        }
+    }
+
+    /// As nft(8) names it.
+    fn name(&self) -> &'static str {
+        match *self {
+            Family::Ipv4 => "ip",
+            Family::Ipv6 => "ip6",
+        }
+    }
+}
+
+
+/// Attributes nested in another, laid out as `Request::attr` does.
+struct Nest {
+    buf: Vec<u8>,
+}
+
+impl Nest {
+    fn new() -> Nest {
+        Nest { buf: vec![] }
+    }
+
+    fn attr(mut self, kind: u16, data: &[u8]) -> Nest {
+        let len = (netlink::RTA_HDRLEN + data.len()) as u16;
+        self.buf.extend_from_slice(netlink::as_bytes(&len));
+        self.buf.extend_from_slice(netlink::as_bytes(&kind));
+        self.buf.extend_from_slice(data);
+        let len = netlink::align(self.buf.len());
+        self.buf.resize(len, 0);
+        self
+    }
+
+    fn nest(self, kind: u16, nest: Nest) -> Nest {
+        self.attr(kind | NLA_F_NESTED, &nest.buf)
+    }
+
+    fn u32(self, kind: u16, value: u32) -> Nest {
+        self.attr(kind, &be32(value))
+    }
+
+    fn string(self, kind: u16, value: &str) -> Nest {
+        self.attr(kind, &string(value))
+    }
+
+    /// One expression of a rule, `data` being its own attributes.
+    fn expr(self, name: &str, data: Nest) -> Nest {
+        let mut expr = Nest::new().string(NFTA_EXPR_NAME, name);
+        if !data.buf.is_empty() {
+            expr = expr.nest(NFTA_EXPR_DATA, data);
+        }
+        self.nest(NFTA_LIST_ELEM, expr)
+    }
+}
+
+/// Attributes are in network byte order.
+fn be32(value: u32) -> [u8; 4] {
+    [(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8]
+}
+
+fn string(value: &str) -> Vec<u8> {
+    let mut bytes = value.as_bytes().to_vec();
+    bytes.push(0);
+    bytes
+}
+
+fn message(kind: u16, flags: u16, family: u8) -> Request {
+    let header = nfgenmsg { nfgen_family: family, version: 0, res_id: 0 };
+    Request::new(NFNL_SUBSYS_NFTABLES << 8 | kind, flags, &header)
+}
+
+fn batch_message(kind: u16) -> Request {
+    let header = nfgenmsg { nfgen_family: sys::AF_UNSPEC as u8, version: 0, res_id: NFNL_SUBSYS_NFTABLES.to_be() };
+    Request::new(kind, 0, &header)
+}
+
+/// Send `steps` as one batch, an error names the step the kernel refused.
+fn commit(steps: Vec<(String, Request)>) -> Result<(), io::Error> {
+    let mut socket = try!(Socket::with_protocol(sys::NETLINK_NETFILTER));
+    let mut names = vec!["nf_tables batch".to_string()];
+    let mut reqs = vec![batch_message(NFNL_MSG_BATCH_BEGIN)];
+    for (name, req) in steps {
+        names.push(name);
+        reqs.push(req);
+    }
+    reqs.push(batch_message(NFNL_MSG_BATCH_END));
+    names.push("nf_tables batch".to_string());
+
+    socket.batch(reqs).map_err(|(index, e)| io::Error::new(e.kind(), format!("{}: {}", names[index], e)))
+}
+
+/// Adding then deleting works whether the table exists or not.
+fn flush_steps(family: Family, table: &str) -> Vec<(String, Request)> {
+    let add = message(NFT_MSG_NEWTABLE, netlink::NLM_F_CREATE | netlink::NLM_F_ACK, family.nfproto())
+        .attr(NFTA_TABLE_NAME, &string(table));
+    let delete = message(NFT_MSG_DELTABLE, netlink::NLM_F_ACK, family.nfproto())
+        .attr(NFTA_TABLE_NAME, &string(table));
+    vec![(format!("add table {} {}", family.name(), table), add),
+         (format!("delete table {} {}", family.name(), table), delete)]
+}
+
+
+/// Names of the tables of every family, `(family, name)`.
+pub fn tables() -> Result<Vec<(u8, String)>, io::Error> {
+    let mut socket = try!(Socket::with_protocol(sys::NETLINK_NETFILTER));
+    let mut tables = vec![];
+    for msg in try!(socket.request(message(NFT_MSG_GETTABLE, netlink::NLM_F_DUMP, sys::AF_UNSPEC as u8))) {
+        let header: nfgenmsg = match netlink::read(&msg) {
+            Some(header) => header,
+            None => return Err(netlink::invalid("truncated nfgenmsg"))
+        };
+        for (kind, data) in netlink::attributes(&msg[netlink::align(mem::size_of::<nfgenmsg>())..]) {
+            if kind == NFTA_TABLE_NAME {
+                let name = data.split(|byte| *byte == 0).next().unwrap_or(&[]);
+                tables.push((header.nfgen_family, String::from_utf8_lossy(name).into_owned()));
+            }
+        }
+    }
+    Ok(tables)
+}
+
+/// Whether the kernel speaks nf_tables, nothing is changed.
+pub fn available() -> bool {
+    tables().is_ok()
+}
+
+/// Replace `table` of the family of `source` with one masquerading the
+/// packets from `source` leaving through `oifname`:
+///
+/// ```text
+/// table ip <table> {
+///     chain postrouting {
+///         type nat hook postrouting priority 100;
+///         oifname <oifname> ip saddr <source> masquerade
+///     }
+/// }
+/// ```
+pub fn masquerade(table: &str, source: &IpNetwork, oifname: &str) -> Result<(), io::Error> {
+    if oifname.is_empty() || oifname.len() >= IFNAMSIZ {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid interface name {:?}", oifname)));
+    }
+    let family = Family::of(source);
+    let chain = "postrouting";
+    let mut steps = flush_steps(family, table);
+
+    let add_table = message(NFT_MSG_NEWTABLE, netlink::NLM_F_CREATE | netlink::NLM_F_ACK, family.nfproto())
+        .attr(NFTA_TABLE_NAME, &string(table));
+    steps.push((format!("add table {} {}", family.name(), table), add_table));
+
+    let hook = Nest::new()
+        .u32(NFTA_HOOK_HOOKNUM, NF_INET_POST_ROUTING)
+        .u32(NFTA_HOOK_PRIORITY, NF_IP_PRI_NAT_SRC as u32);
+    let add_chain = message(NFT_MSG_NEWCHAIN, netlink::NLM_F_CREATE | netlink::NLM_F_ACK, family.nfproto())
+        .attr(NFTA_CHAIN_TABLE, &string(table))
+        .attr(NFTA_CHAIN_NAME, &string(chain))
+        .attr(NFTA_CHAIN_HOOK | NLA_F_NESTED, &hook.buf)
+        .attr(NFTA_CHAIN_TYPE, &string("nat"));
+    steps.push((format!("add chain {} {} {} (nat postrouting)", family.name(), table, chain), add_chain));
+
+    let mut name = [0u8; IFNAMSIZ];
+    name[..oifname.len()].copy_from_slice(oifname.as_bytes());
+    let (offset, addr, mask) = match *source {
+        // Source address offset in the IPv4 and IPv6 headers.
+        IpNetwork::V4(net) => (12, net.network().octets().to_vec(), net.mask().octets().to_vec()),
+        IpNetwork::V6(net) => (8, net.network().octets().to_vec(), net.mask().octets().to_vec()),
+    };
+    let expressions = Nest::new()
+        .expr("meta", Nest::new()
+              .u32(NFTA_META_KEY, NFT_META_OIFNAME)
+              .u32(NFTA_META_DREG, NFT_REG_1))
+        .expr("cmp", Nest::new()
+              .u32(NFTA_CMP_SREG, NFT_REG_1)
+              .u32(NFTA_CMP_OP, NFT_CMP_EQ)
+              .nest(NFTA_CMP_DATA, Nest::new().attr(NFTA_DATA_VALUE, &name)))
+        .expr("payload", Nest::new()
+              .u32(NFTA_PAYLOAD_DREG, NFT_REG_1)
+              .u32(NFTA_PAYLOAD_BASE, NFT_PAYLOAD_NETWORK_HEADER)
+              .u32(NFTA_PAYLOAD_OFFSET, offset)
+              .u32(NFTA_PAYLOAD_LEN, addr.len() as u32))
+        .expr("bitwise", Nest::new()
+              .u32(NFTA_BITWISE_SREG, NFT_REG_1)
+              .u32(NFTA_BITWISE_DREG, NFT_REG_1)
+              .u32(NFTA_BITWISE_LEN, addr.len() as u32)
+              .nest(NFTA_BITWISE_MASK, Nest::new().attr(NFTA_DATA_VALUE, &mask))
+              .nest(NFTA_BITWISE_XOR, Nest::new().attr(NFTA_DATA_VALUE, &vec![0u8; addr.len()])))
+        .expr("cmp", Nest::new()
+              .u32(NFTA_CMP_SREG, NFT_REG_1)
+              .u32(NFTA_CMP_OP, NFT_CMP_EQ)
+              .nest(NFTA_CMP_DATA, Nest::new().attr(NFTA_DATA_VALUE, &addr)))
+        .expr("masq", Nest::new());
+    let add_rule = message(NFT_MSG_NEWRULE,
+                           netlink::NLM_F_CREATE | netlink::NLM_F_APPEND | netlink::NLM_F_ACK,
+                           family.nfproto())
+        .attr(NFTA_RULE_TABLE, &string(table))
+        .attr(NFTA_RULE_CHAIN, &string(chain))
+        .attr(NFTA_RULE_EXPRESSIONS | NLA_F_NESTED, &expressions.buf);
+    steps.push((format!("add rule {} {} {} oifname {} saddr {} masquerade",
+                        family.name(), table, chain, oifname, source), add_rule));
+
+    commit(steps)
+}
+
+/// Delete `table` and everything in it, a missing table is not an error.
+pub fn delete_table(table: &str, family: Family) -> Result<(), io::Error> {
+    commit(flush_steps(family, table))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use netlink::tests::in_netns;
+    use std::str::FromStr;
+
+    fn listed(family: Family, table: &str) -> Result<bool, io::Error> {
+        Ok(try!(tables()).iter().any(|&(nfproto, ref name)| nfproto == family.nfproto() && name == table))
+    }
+
+    #[test]
+    #[ignore]
+    fn test_masquerade() {
+        in_netns(|| {
+            if !available() {
+                return Err(io::Error::new(io::ErrorKind::NotFound, "nf_tables is not available"));
+            }
+            let source = IpNetwork::from_str("10.9.8.0/24").unwrap();
+            try!(masquerade("exodus_test", &source, "lo"));
+            // Replaced, not added twice.
+            try!(masquerade("exodus_test", &source, "lo"));
+            try!(masquerade("exodus_test", &IpNetwork::from_str("fd00::/64").unwrap(), "lo"));
+            assert!(try!(listed(Family::Ipv4, "exodus_test")));
+            assert!(try!(listed(Family::Ipv6, "exodus_test")));
+
+            let e = masquerade("exodus_test", &source, "an-interface-name-too-long").unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+
+            try!(delete_table("exodus_test", Family::Ipv4));
+            try!(delete_table("exodus_test", Family::Ipv4));
+            assert!(!try!(listed(Family::Ipv4, "exodus_test")));
+            delete_table("exodus_test", Family::Ipv6)
+        });
+    }
+}
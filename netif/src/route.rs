@@ -1,15 +1,21 @@
-#![cfg(any(target_os = "macos", target_os = "freebsd"))]
+#![cfg(any(target_os = "macos", target_os = "freebsd", target_os = "linux"))]
 
 use sys;
 use HwAddr;
 use ipnetwork::IpNetwork;
+#[cfg(target_os = "linux")]
+use netlink;
 
 
 use std::io;
-use std::ptr;
-use std::mem;
 use std::fmt;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::error;
+use std::net::IpAddr;
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+use std::{ptr, mem, slice};
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+use std::net::{Ipv4Addr, Ipv6Addr};
 
 #[cfg(target_os = "macos")]
 bitflags! {
@@ -78,6 +84,7 @@ impl fmt::Display for Flags {
 
 
 
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
 bitflags! {
     pub struct RtmAddrFlags: i32 {
         const RTA_DST = sys::RTA_DST;
@@ -91,6 +98,7 @@ bitflags! {
     }
 }
 
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
 impl fmt::Display for RtmAddrFlags {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}",
@@ -99,12 +107,14 @@ impl fmt::Display for RtmAddrFlags {
 }
 
 
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum SockAddr {
     IpAddr(IpAddr),
     HwAddr(HwAddr),
 }
 
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
 impl SockAddr {
     pub fn from_libc_sockaddr_bytes(sa: &[u8]) -> Option<SockAddr> {
         let sa_len = sa[0];
@@ -116,11 +126,12 @@ impl SockAddr {
                 Some(SockAddr::IpAddr(IpAddr::V4(Ipv4Addr::from(in_addr))))
             }
             sys::AF_INET6 => {
+                // sin6_len, sin6_family, sin6_port, sin6_flowinfo then sin6_addr.
                 let in6_addr = [
-                    sa[7+0], sa[7+1], sa[7+2], sa[7+3],
-                    sa[7+4], sa[7+5], sa[7+6], sa[7+7],
-                    sa[7+8], sa[7+9], sa[7+10], sa[7+11],
-                    sa[7+12], sa[7+13], sa[7+14], sa[7+15],
+                    sa[8+0], sa[8+1], sa[8+2], sa[8+3],
+                    sa[8+4], sa[8+5], sa[8+6], sa[8+7],
+                    sa[8+8], sa[8+9], sa[8+10], sa[8+11],
+                    sa[8+12], sa[8+13], sa[8+14], sa[8+15],
                 ];
                 Some(SockAddr::IpAddr(IpAddr::V6(Ipv6Addr::from(in6_addr))))
             }
@@ -231,6 +242,158 @@ impl fmt::Display for Table {
 }
 
 
+impl Destination {
+    /// Host addresses as `/32` or `/128` networks.
+    pub fn network(&self) -> IpNetwork {
+        match *self {
+            Destination::IpNetwork(nw) => nw,
+            Destination::IpAddress(ip @ IpAddr::V4(_)) => IpNetwork::new(ip, 32).unwrap(),
+            Destination::IpAddress(ip @ IpAddr::V6(_)) => IpNetwork::new(ip, 128).unwrap(),
+        }
+    }
+
+    pub fn is_host(&self) -> bool {
+        match *self {
+            Destination::IpNetwork(IpNetwork::V4(nw)) => nw.prefix() == 32,
+            Destination::IpNetwork(IpNetwork::V6(nw)) => nw.prefix() == 128,
+            Destination::IpAddress(_) => true
+        }
+    }
+}
+
+impl Table {
+    pub fn destination(&self) -> &Destination {
+        &self.destination
+    }
+
+    pub fn gateway(&self) -> &Gateway {
+        &self.gateway
+    }
+
+    pub fn ifname(&self) -> &str {
+        &self.ifname
+    }
+
+    pub fn flags(&self) -> Flags {
+        self.flags
+    }
+}
+
+
+#[derive(Debug)]
+pub enum Error {
+    /// No such route, or no route at all to the destination.
+    NotFound,
+    AlreadyExists,
+    /// The gateway is not on any directly connected network.
+    Unreachable,
+    PermissionDenied,
+    NoSuchInterface(String),
+    /// Route the kernel interface can't express, e.g. mixed address families.
+    Unsupported(&'static str),
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::NotFound => write!(f, "no such route"),
+            Error::AlreadyExists => write!(f, "route already exists"),
+            Error::Unreachable => write!(f, "gateway unreachable"),
+            Error::PermissionDenied => write!(f, "permission denied"),
+            Error::NoSuchInterface(ref ifname) => write!(f, "no such interface {}", ifname),
+            Error::Unsupported(msg) => write!(f, "unsupported route: {}", msg),
+            Error::Io(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::NotFound => "no such route",
+            Error::AlreadyExists => "route already exists",
+            Error::Unreachable => "gateway unreachable",
+            Error::PermissionDenied => "permission denied",
+            Error::NoSuchInterface(_) => "no such interface",
+            Error::Unsupported(msg) => msg,
+            Error::Io(ref e) => e.description(),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        match e.raw_os_error() {
+            Some(sys::ESRCH) | Some(sys::ENOENT) => Error::NotFound,
+            Some(sys::EEXIST) => Error::AlreadyExists,
+            Some(sys::ENETUNREACH) | Some(sys::EHOSTUNREACH) => Error::Unreachable,
+            Some(sys::EPERM) | Some(sys::EACCES) => Error::PermissionDenied,
+            _ => Error::Io(e)
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        let kind = match e {
+            Error::NotFound => io::ErrorKind::NotFound,
+            Error::AlreadyExists => io::ErrorKind::AlreadyExists,
+            Error::PermissionDenied => io::ErrorKind::PermissionDenied,
+            Error::NoSuchInterface(_) => io::ErrorKind::NotFound,
+            Error::Unsupported(_) => io::ErrorKind::InvalidInput,
+            Error::Unreachable => io::ErrorKind::Other,
+            Error::Io(e) => return e,
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+
+fn if_index(ifname: &str) -> Result<u32, Error> {
+    match sys::if_name_to_index(ifname) {
+        0 => Err(Error::NoSuchInterface(ifname.to_string())),
+        ifindex => Ok(ifindex)
+    }
+}
+
+fn check(destination: &Destination, gateway: &Gateway) -> Result<(), Error> {
+    match *gateway {
+        Gateway::IpAddress(ip) if ip.is_ipv4() != destination.network().ip().is_ipv4() => {
+            Err(Error::Unsupported("gateway and destination address families differ"))
+        },
+        Gateway::HwAddr(_) => Err(Error::Unsupported("hardware address gateway")),
+        _ => Ok(())
+    }
+}
+
+/// Add a route, `interface` is the one to reach the gateway through.
+///
+/// IPv6 link local gateways need it, it is optional otherwise.
+pub fn add(destination: &Destination, gateway: &Gateway, interface: Option<&str>) -> Result<(), Error> {
+    try!(check(destination, gateway));
+    platform::add(destination, gateway, interface)
+}
+
+/// Add a route, or change the one to `destination` if any.
+pub fn replace(destination: &Destination, gateway: &Gateway, interface: Option<&str>) -> Result<(), Error> {
+    try!(check(destination, gateway));
+    platform::replace(destination, gateway, interface)
+}
+
+/// Delete the route `add` or `replace` made, other routes to `destination` are left alone.
+pub fn delete(destination: &Destination, gateway: &Gateway, interface: Option<&str>) -> Result<(), Error> {
+    try!(check(destination, gateway));
+    platform::delete(destination, gateway, interface)
+}
+
+/// Route the kernel picks for `addr`, `Error::NotFound` when there is none.
+pub fn get(addr: &IpAddr) -> Result<Table, Error> {
+    platform::get(addr)
+}
+
+
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
 pub fn list() -> Result<Vec<Table>, io::Error>{
     // inet4: libc::AF_INET, inet6: libc::AF_INET6, all: 0
     let family = 0;
@@ -352,3 +515,494 @@ pub fn list() -> Result<Vec<Table>, io::Error>{
     Ok(routing_table)
 }
 
+#[cfg(target_os = "linux")]
+pub fn list() -> Result<Vec<Table>, io::Error>{
+    let links = try!(netlink::links());
+    Ok(try!(netlink::routes()).into_iter()
+        .filter(|route| route.table == netlink::RT_TABLE_MAIN)
+        .map(|route| platform::table(route, &links))
+        .collect())
+}
+
+/// Socket readable whenever a route or an interface address changes, to be
+/// polled along with other sockets. What changed is left to look up.
+#[derive(Debug)]
+pub struct Monitor {
+    fd: sys::c_int,
+}
+
+impl Monitor {
+    #[cfg(any(target_os = "macos", target_os = "freebsd"))]
+    pub fn new() -> Result<Monitor, io::Error> {
+        // Every routing socket gets a copy of the messages, see route(4).
+        let fd = unsafe { sys::socket(sys::AF_ROUTE, sys::SOCK_RAW, 0) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let monitor = Monitor { fd: fd };
+        if unsafe { sys::fcntl(fd, sys::F_SETFL, sys::O_NONBLOCK) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        if unsafe { sys::fcntl(fd, sys::F_SETFD, sys::FD_CLOEXEC) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(monitor)
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn new() -> Result<Monitor, io::Error> {
+        let groups = netlink::RTMGRP_IPV4_ROUTE | netlink::RTMGRP_IPV6_ROUTE |
+                     netlink::RTMGRP_IPV4_IFADDR | netlink::RTMGRP_IPV6_IFADDR;
+        netlink::subscribe(groups).map(|fd| Monitor { fd: fd })
+    }
+
+    /// Read the pending notifications, returns whether there were any.
+    pub fn drain(&self) -> bool {
+        let mut buf = [0u8; 8192];
+        let mut changed = false;
+        loop {
+            let ret = unsafe { sys::recv(self.fd, buf.as_mut_ptr() as *mut sys::c_void, buf.len(), 0) };
+            if ret > 0 {
+                changed = true;
+                continue;
+            }
+            // Notifications overflowing the socket buffer are lost, but something changed.
+            if ret == -1 && io::Error::last_os_error().raw_os_error() == Some(sys::ENOBUFS) {
+                changed = true;
+                continue;
+            }
+            return changed;
+        }
+    }
+}
+
+impl AsRawFd for Monitor {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for Monitor {
+    fn drop(&mut self) {
+        unsafe { sys::close(self.fd) };
+    }
+}
+
+
+// Routing socket, see route(4).
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+mod platform {
+    use super::*;
+
+    const RTM_VERSION: u8 = 5;
+    const RTM_ADD: u8     = 0x1;
+    const RTM_DELETE: u8  = 0x2;
+    const RTM_CHANGE: u8  = 0x3;
+    const RTM_GET: u8     = 0x4;
+
+    const SOCKADDR_DL_LEN: usize = 20;
+
+    #[cfg(target_os = "macos")]
+    const SA_ALIGN: usize = 4;
+    #[cfg(target_os = "freebsd")]
+    const SA_ALIGN: usize = 8;
+
+    fn sa_roundup(len: usize) -> usize {
+        if len == 0 { SA_ALIGN } else { 1 + ((len - 1) | (SA_ALIGN - 1)) }
+    }
+
+    fn push_sockaddr(buf: &mut Vec<u8>, sa: &[u8]) {
+        buf.extend_from_slice(sa);
+        let len = buf.len() - sa.len() + sa_roundup(sa.len());
+        buf.resize(len, 0);
+    }
+
+    fn sockaddr(ip: &IpAddr, scope: Option<u32>) -> Vec<u8> {
+        match *ip {
+            IpAddr::V4(ip) => {
+                let mut sa = vec![0u8; 16];
+                sa[0] = 16;
+                sa[1] = sys::AF_INET as u8;
+                sa[4..8].copy_from_slice(&ip.octets());
+                sa
+            },
+            IpAddr::V6(ip) => {
+                let mut sa = vec![0u8; 28];
+                sa[0] = 28;
+                sa[1] = sys::AF_INET6 as u8;
+                sa[8..24].copy_from_slice(&ip.octets());
+                if let Some(ifindex) = scope {
+                    if ip.segments()[0] & 0xffc0 == 0xfe80 {
+                        // KAME: the kernel wants the scope of link local addresses in the address itself.
+                        sa[10] = (ifindex >> 8) as u8;
+                        sa[11] = ifindex as u8;
+                    }
+                }
+                sa
+            }
+        }
+    }
+
+    fn netmask(network: &IpNetwork) -> Vec<u8> {
+        sockaddr(&network.mask(), None)
+    }
+
+    fn sockaddr_dl(ifindex: u32) -> Vec<u8> {
+        let mut sa = vec![0u8; SOCKADDR_DL_LEN];
+        sa[0] = SOCKADDR_DL_LEN as u8;
+        sa[1] = sys::AF_LINK as u8;
+        let index = ifindex as u16;
+        sa[2..4].copy_from_slice(unsafe { &mem::transmute::<u16, [u8; 2]>(index) });
+        sa
+    }
+
+    /// Send one message and wait for the kernel to echo it back.
+    fn request(kind: u8, flags: Flags, addrs: RtmAddrFlags, sockaddrs: &[u8]) -> Result<Vec<u8>, Error> {
+        let fd = unsafe { sys::socket(sys::AF_ROUTE, sys::SOCK_RAW, 0) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let ret = exchange(fd, kind, flags, addrs, sockaddrs);
+        unsafe { sys::close(fd) };
+        ret
+    }
+
+    fn exchange(fd: sys::c_int, kind: u8, flags: Flags, addrs: RtmAddrFlags, sockaddrs: &[u8]) -> Result<Vec<u8>, Error> {
+        let pid = unsafe { sys::getpid() };
+        let seq = 1;
+        let hdr_len = mem::size_of::<sys::rt_msghdr>();
+
+        let mut rtm: sys::rt_msghdr = unsafe { mem::zeroed() };
+        rtm.rtm_msglen = (hdr_len + sockaddrs.len()) as sys::c_ushort;
+        rtm.rtm_version = RTM_VERSION;
+        rtm.rtm_type = kind;
+        rtm.rtm_flags = flags.bits();
+        rtm.rtm_addrs = addrs.bits();
+        rtm.rtm_pid = pid;
+        rtm.rtm_seq = seq;
+
+        let mut msg = unsafe { slice::from_raw_parts(&rtm as *const sys::rt_msghdr as *const u8, hdr_len) }.to_vec();
+        msg.extend_from_slice(sockaddrs);
+
+        // Failures come back as the errno of write(2).
+        let ret = unsafe { sys::write(fd, msg.as_ptr() as *const sys::c_void, msg.len()) };
+        if ret == -1 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        // Every routing socket sees every change, wait for our own message.
+        let mut buf = vec![0u8; 2048];
+        loop {
+            let size = unsafe { sys::read(fd, buf.as_mut_ptr() as *mut sys::c_void, buf.len()) };
+            if size == -1 {
+                return Err(io::Error::last_os_error().into());
+            }
+            let size = size as usize;
+            if size < hdr_len {
+                continue;
+            }
+            let reply = unsafe { ptr::read_unaligned(buf.as_ptr() as *const sys::rt_msghdr) };
+            if reply.rtm_pid != pid || reply.rtm_seq != seq || reply.rtm_type != kind {
+                continue;
+            }
+            if reply.rtm_errno != 0 {
+                return Err(io::Error::from_raw_os_error(reply.rtm_errno).into());
+            }
+            buf.truncate(size);
+            return Ok(buf);
+        }
+    }
+
+    fn change(kind: u8, destination: &Destination, gateway: &Gateway, interface: Option<&str>) -> Result<(), Error> {
+        let network = destination.network();
+        let mut flags = Flags::RTF_UP | Flags::RTF_STATIC;
+        let mut addrs = RtmAddrFlags::RTA_DST | RtmAddrFlags::RTA_GATEWAY;
+        let ifindex = match interface {
+            Some(ifname) => Some(try!(if_index(ifname))),
+            None => None
+        };
+
+        let mut sockaddrs = vec![];
+        push_sockaddr(&mut sockaddrs, &sockaddr(&network.ip(), None));
+        match *gateway {
+            Gateway::IpAddress(ref ip) => {
+                flags |= Flags::RTF_GATEWAY;
+                push_sockaddr(&mut sockaddrs, &sockaddr(ip, ifindex));
+            },
+            Gateway::Interface(ref ifname) => push_sockaddr(&mut sockaddrs, &sockaddr_dl(try!(if_index(ifname)))),
+            Gateway::Link(ifindex) => push_sockaddr(&mut sockaddrs, &sockaddr_dl(ifindex)),
+            Gateway::HwAddr(_) => return Err(Error::Unsupported("hardware address gateway")),
+        }
+        if destination.is_host() {
+            flags |= Flags::RTF_HOST;
+        } else {
+            addrs |= RtmAddrFlags::RTA_NETMASK;
+            push_sockaddr(&mut sockaddrs, &netmask(&network));
+        }
+        if let (Some(ifindex), &Gateway::IpAddress(_)) = (ifindex, gateway) {
+            addrs |= RtmAddrFlags::RTA_IFP;
+            push_sockaddr(&mut sockaddrs, &sockaddr_dl(ifindex));
+        }
+        request(kind, flags, addrs, &sockaddrs).map(|_| ())
+    }
+
+    pub fn add(destination: &Destination, gateway: &Gateway, interface: Option<&str>) -> Result<(), Error> {
+        change(RTM_ADD, destination, gateway, interface)
+    }
+
+    pub fn replace(destination: &Destination, gateway: &Gateway, interface: Option<&str>) -> Result<(), Error> {
+        match change(RTM_CHANGE, destination, gateway, interface) {
+            Err(Error::NotFound) => change(RTM_ADD, destination, gateway, interface),
+            ret => ret
+        }
+    }
+
+    pub fn delete(destination: &Destination, gateway: &Gateway, interface: Option<&str>) -> Result<(), Error> {
+        change(RTM_DELETE, destination, gateway, interface)
+    }
+
+    pub fn get(addr: &IpAddr) -> Result<Table, Error> {
+        let mut sockaddrs = vec![];
+        push_sockaddr(&mut sockaddrs, &sockaddr(addr, None));
+        // An empty link address asks for the name of the outgoing interface.
+        push_sockaddr(&mut sockaddrs, &sockaddr_dl(0));
+        let addrs = RtmAddrFlags::RTA_DST | RtmAddrFlags::RTA_IFP;
+        let reply = try!(request(RTM_GET, Flags::RTF_UP | Flags::RTF_HOST, addrs, &sockaddrs));
+
+        let hdr_len = mem::size_of::<sys::rt_msghdr>();
+        let rtm = unsafe { ptr::read_unaligned(reply.as_ptr() as *const sys::rt_msghdr) };
+        let flags = Flags::from_bits_truncate(rtm.rtm_flags);
+        let addrs = RtmAddrFlags::from_bits_truncate(rtm.rtm_addrs);
+
+        // Sockaddrs follow the header in RTA_* bit order, each only when its bit is set.
+        let mut found: Vec<(RtmAddrFlags, [u8; 128])> = vec![];
+        let mut pos = hdr_len;
+        for bit in 0..8 {
+            let flag = RtmAddrFlags::from_bits_truncate(1 << bit);
+            if !addrs.contains(flag) || pos >= reply.len() {
+                continue;
+            }
+            let len = reply[pos] as usize;
+            // Short sockaddrs (netmasks) are zero padded, so they all parse the same way.
+            let mut sa = [0u8; 128];
+            let end = ::std::cmp::min(pos + len, reply.len());
+            sa[..end - pos].copy_from_slice(&reply[pos..end]);
+            found.push((flag, sa));
+            pos += sa_roundup(len);
+        }
+        let sockaddr_of = |flag: RtmAddrFlags| found.iter().find(|&&(f, _)| f == flag).map(|&(_, sa)| sa);
+
+        let dst = match sockaddr_of(RtmAddrFlags::RTA_DST).and_then(|sa| SockAddr::from_libc_sockaddr_bytes(&sa)) {
+            Some(SockAddr::IpAddr(ip)) => ip,
+            _ => return Err(Error::NotFound)
+        };
+        let ifname = match sockaddr_of(RtmAddrFlags::RTA_IFP) {
+            Some(sa) if sa[1] as i32 == sys::AF_LINK && sa[5] > 0 => {
+                String::from_utf8_lossy(&sa[8..8 + sa[5] as usize]).into_owned()
+            },
+            _ => sys::if_index_to_name(rtm.rtm_index as u32)
+        };
+        let gateway = match sockaddr_of(RtmAddrFlags::RTA_GATEWAY).and_then(|sa| SockAddr::from_libc_sockaddr_bytes(&sa)) {
+            Some(SockAddr::IpAddr(ip)) => Gateway::IpAddress(ip),
+            Some(SockAddr::HwAddr(hw)) => Gateway::HwAddr(hw),
+            None => Gateway::Interface(ifname.clone())
+        };
+        let destination = if flags.contains(Flags::RTF_HOST) {
+            Destination::IpAddress(dst)
+        } else {
+            let prefix = match sockaddr_of(RtmAddrFlags::RTA_NETMASK) {
+                Some(sa) => {
+                    let mask = if dst.is_ipv4() { &sa[4..8] } else { &sa[8..24] };
+                    mask.iter().map(|byte| byte.count_ones()).sum::<u32>() as u8
+                },
+                None => 0
+            };
+            Destination::IpNetwork(IpNetwork::new(dst, prefix).unwrap())
+        };
+
+        Ok(Table {
+            destination: destination,
+            gateway: gateway,
+            ifname: ifname,
+            flags: flags,
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use super::*;
+
+    pub fn table(route: netlink::Route, links: &[netlink::Link]) -> Table {
+        let ifname = links.iter()
+            .find(|link| Some(link.index) == route.ifindex)
+            .map(|link| link.name.clone())
+            .unwrap_or_default();
+        let mut flags = Flags::RTF_UP;
+        let gateway = match route.gateway {
+            Some(ip) => {
+                flags |= Flags::RTF_GATEWAY;
+                Gateway::IpAddress(ip)
+            },
+            None => Gateway::Interface(ifname.clone())
+        };
+        let destination = Destination::IpNetwork(route.destination);
+        let destination = if destination.is_host() {
+            flags |= Flags::RTF_HOST;
+            Destination::IpAddress(route.destination.ip())
+        } else {
+            destination
+        };
+        Table {
+            destination: destination,
+            gateway: gateway,
+            ifname: ifname,
+            flags: flags,
+        }
+    }
+
+    fn route(destination: &Destination, gateway: &Gateway, interface: Option<&str>) -> Result<netlink::Route, Error> {
+        let mut route = netlink::Route::new(destination.network());
+        match *gateway {
+            Gateway::IpAddress(ip) => route.gateway = Some(ip),
+            Gateway::Interface(ref ifname) => route.ifindex = Some(try!(if_index(ifname))),
+            Gateway::Link(ifindex) => route.ifindex = Some(ifindex),
+            Gateway::HwAddr(_) => return Err(Error::Unsupported("hardware address gateway")),
+        }
+        if let Some(ifname) = interface {
+            route.ifindex = Some(try!(if_index(ifname)));
+        }
+        Ok(route)
+    }
+
+    pub fn add(destination: &Destination, gateway: &Gateway, interface: Option<&str>) -> Result<(), Error> {
+        let route = try!(route(destination, gateway, interface));
+        netlink::add_route(&route).map_err(Error::from)
+    }
+
+    pub fn replace(destination: &Destination, gateway: &Gateway, interface: Option<&str>) -> Result<(), Error> {
+        let route = try!(route(destination, gateway, interface));
+        netlink::replace_route(&route).map_err(Error::from)
+    }
+
+    pub fn delete(destination: &Destination, gateway: &Gateway, interface: Option<&str>) -> Result<(), Error> {
+        // With the gateway and the interface set only the matching route goes, not the first one
+        // to the destination.
+        let route = try!(route(destination, gateway, interface));
+        netlink::delete_route(&route).map_err(Error::from)
+    }
+
+    pub fn get(addr: &IpAddr) -> Result<Table, Error> {
+        let route = match netlink::get_route(addr).map_err(Error::from) {
+            Ok(route) => route,
+            // Lookups without a matching route fail with ENETUNREACH.
+            Err(Error::Unreachable) => return Err(Error::NotFound),
+            Err(e) => return Err(e)
+        };
+        let links = try!(netlink::links());
+        Ok(table(route, &links))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(target_os = "linux")]
+    use netlink::tests::in_netns;
+
+    fn from_errno(errno: i32) -> Error {
+        Error::from(io::Error::from_raw_os_error(errno))
+    }
+
+    #[test]
+    fn test_errors() {
+        for &errno in [sys::ESRCH, sys::ENOENT].iter() {
+            match from_errno(errno) { Error::NotFound => { }, e => panic!("errno {}: {:?}", errno, e) }
+        }
+        match from_errno(sys::EEXIST) { Error::AlreadyExists => { }, e => panic!("{:?}", e) }
+        for &errno in [sys::ENETUNREACH, sys::EHOSTUNREACH].iter() {
+            match from_errno(errno) { Error::Unreachable => { }, e => panic!("errno {}: {:?}", errno, e) }
+        }
+        for &errno in [sys::EPERM, sys::EACCES].iter() {
+            match from_errno(errno) { Error::PermissionDenied => { }, e => panic!("errno {}: {:?}", errno, e) }
+        }
+        // Anything else keeps its errno, there and back.
+        match from_errno(sys::EINVAL) {
+            Error::Io(e) => assert_eq!(e.raw_os_error(), Some(sys::EINVAL)),
+            e => panic!("{:?}", e)
+        }
+        assert_eq!(io::Error::from(from_errno(sys::EINVAL)).raw_os_error(), Some(sys::EINVAL));
+        assert_eq!(io::Error::from(from_errno(sys::ESRCH)).kind(), io::ErrorKind::NotFound);
+        assert_eq!(io::Error::from(Error::NoSuchInterface("tun9".to_string())).kind(), io::ErrorKind::NotFound);
+        assert_eq!(io::Error::from(Error::Unsupported("test")).kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_check() {
+        let destination = Destination::IpNetwork("192.168.77.0/24".parse().unwrap());
+        match add(&destination, &Gateway::IpAddress("fe80::1".parse().unwrap()), None) {
+            Err(Error::Unsupported(_)) => { },
+            ret => panic!("{:?}", ret)
+        }
+        match delete(&destination, &Gateway::Interface("an-interface-that-does-not-exist".to_string()), None) {
+            Err(Error::NoSuchInterface(_)) => { },
+            ret => panic!("{:?}", ret)
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn gateway_of(destination: &Destination) -> Result<Option<Gateway>, io::Error> {
+        let tables = try!(list());
+        Ok(tables.iter().find(|table| table.destination() == destination).map(|table| table.gateway().clone()))
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[ignore]
+    fn test_round_trip() {
+        in_netns(|| {
+            let lo = try!(netlink::link("lo"));
+            try!(netlink::set_up(lo.index, true));
+            try!(netlink::add_address(lo.index, &"10.9.8.7/24".parse().unwrap()));
+
+            let destination = Destination::IpNetwork("192.168.77.0/24".parse().unwrap());
+            let addr: IpAddr = "192.168.77.5".parse().unwrap();
+            let gateway = Gateway::IpAddress("10.9.8.1".parse().unwrap());
+            let other = Gateway::IpAddress("10.9.8.2".parse().unwrap());
+
+            // Without any route the lookup fails with ENETUNREACH.
+            match get(&addr) { Err(Error::NotFound) => { }, ret => panic!("{:?}", ret) }
+
+            try!(add(&destination, &gateway, Some("lo")));
+            match add(&destination, &gateway, Some("lo")) {
+                Err(Error::AlreadyExists) => { },
+                ret => panic!("{:?}", ret)
+            }
+            assert_eq!(try!(get(&addr)).ifname(), "lo");
+            assert_eq!(try!(gateway_of(&destination)), Some(gateway.clone()));
+
+            try!(replace(&destination, &other, Some("lo")));
+            assert_eq!(try!(gateway_of(&destination)), Some(other.clone()));
+
+            // The route through the first gateway is gone, the other one stays.
+            match delete(&destination, &gateway, Some("lo")) {
+                Err(Error::NotFound) => { },
+                ret => panic!("{:?}", ret)
+            }
+            assert_eq!(try!(gateway_of(&destination)), Some(other.clone()));
+
+            try!(delete(&destination, &other, Some("lo")));
+            assert_eq!(try!(gateway_of(&destination)), None);
+            match get(&addr) { Err(Error::NotFound) => { }, ret => panic!("{:?}", ret) }
+
+            // Replacing a missing route adds it.
+            let lo_gateway = Gateway::Interface("lo".to_string());
+            try!(replace(&destination, &lo_gateway, None));
+            assert_eq!(try!(gateway_of(&destination)), Some(lo_gateway.clone()));
+            try!(delete(&destination, &lo_gateway, None));
+            assert_eq!(try!(gateway_of(&destination)), None);
+            Ok(())
+        });
+    }
+}
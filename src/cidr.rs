@@ -0,0 +1,212 @@
+/// Turns include and exclude lists into the smallest set of routes.
+///
+/// The networks are flattened into address ranges, excluded ranges are cut
+/// out and what is left is split back into aligned blocks, the fewest that
+/// cover it. A whole address family comes out as two `/1` halves: they win
+/// over the default route without replacing it.
+use std::cmp;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use ipnetwork::{IpNetwork, Ipv4Network, Ipv6Network};
+
+
+/// Inclusive address range, `bits` wide addresses stored in a `u128`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Range {
+    start: u128,
+    end: u128,
+}
+
+/// Host part of a `bits - host` prefix.
+fn host_mask(host: u32) -> u128 {
+    match 1u128.checked_shl(host) {
+        Some(size) => size - 1,
+        None => !0
+    }
+}
+
+fn range(bits: u32, addr: u128, prefix: u8) -> Range {
+    let host = host_mask(bits - prefix as u32);
+    Range { start: addr & !host, end: addr | host }
+}
+
+/// Sorted, non overlapping and non adjacent.
+fn merge(mut ranges: Vec<Range>) -> Vec<Range> {
+    ranges.sort();
+    let mut merged: Vec<Range> = vec![];
+    for r in ranges {
+        if let Some(last) = merged.last_mut() {
+            if last.end == !0 || r.start <= last.end + 1 {
+                last.end = cmp::max(last.end, r.end);
+                continue;
+            }
+        }
+        merged.push(r);
+    }
+    merged
+}
+
+/// `include` minus `exclude`, both merged.
+fn subtract(include: &[Range], exclude: &[Range]) -> Vec<Range> {
+    let mut left = vec![];
+    for r in include.iter() {
+        let mut start = r.start;
+        let mut done = false;
+        for e in exclude.iter() {
+            if e.end < start || e.start > r.end {
+                continue;
+            }
+            if e.start > start {
+                left.push(Range { start: start, end: e.start - 1 });
+            }
+            if e.end >= r.end {
+                done = true;
+                break;
+            }
+            start = e.end + 1;
+        }
+        if !done {
+            left.push(Range { start: start, end: r.end });
+        }
+    }
+    left
+}
+
+/// Fewest aligned blocks covering `r`, as `(address, prefix)`.
+fn blocks(bits: u32, r: Range) -> Vec<(u128, u8)> {
+    let mut blocks = vec![];
+    let mut start = r.start;
+    loop {
+        // Largest block aligned on `start` which stays inside the range.
+        let mut host = bits;
+        while start & host_mask(host) != 0 || start | host_mask(host) > r.end {
+            host -= 1;
+        }
+        if host == bits {
+            // The whole family, as two halves.
+            let half = 1u128 << (bits - 1);
+            blocks.push((0, 1));
+            blocks.push((half, 1));
+        } else {
+            blocks.push((start, (bits - host) as u8));
+        }
+        let end = start | host_mask(host);
+        if end >= r.end {
+            return blocks;
+        }
+        start = end + 1;
+    }
+}
+
+fn routes_of(bits: u32, include: Vec<Range>, exclude: Vec<Range>) -> Vec<(u128, u8)> {
+    let include = merge(include);
+    let exclude = merge(exclude);
+    let mut routes = vec![];
+    for r in subtract(&include, &exclude) {
+        routes.extend(blocks(bits, r));
+    }
+    routes
+}
+
+fn ranges(networks: &[IpNetwork]) -> (Vec<Range>, Vec<Range>) {
+    let (mut ranges4, mut ranges6) = (vec![], vec![]);
+    for network in networks.iter() {
+        match *network {
+            IpNetwork::V4(net) => ranges4.push(range(32, u32::from(net.ip()) as u128, net.prefix())),
+            IpNetwork::V6(net) => ranges6.push(range(128, u128::from(net.ip()), net.prefix())),
+        }
+    }
+    (ranges4, ranges6)
+}
+
+/// Networks to route through the tunnel: everything in `include` except what
+/// is in `exclude`. Host bits of the given networks are ignored, families are
+/// handled separately and IPv4 comes first.
+pub fn routes(include: &[IpNetwork], exclude: &[IpNetwork]) -> Vec<IpNetwork> {
+    let (include4, include6) = ranges(include);
+    let (exclude4, exclude6) = ranges(exclude);
+
+    let mut networks = vec![];
+    for (addr, prefix) in routes_of(32, include4, exclude4) {
+        networks.push(IpNetwork::V4(Ipv4Network::new(Ipv4Addr::from(addr as u32), prefix).unwrap()));
+    }
+    for (addr, prefix) in routes_of(128, include6, exclude6) {
+        networks.push(IpNetwork::V6(Ipv6Network::new(Ipv6Addr::from(addr), prefix).unwrap()));
+    }
+    networks
+}
+
+/// Fewest networks covering IPv4 ranges given as first and last address,
+/// which may overlap or come in any order.
+pub fn from_ipv4_ranges(ranges: &[(u32, u32)]) -> Vec<IpNetwork> {
+    let ranges = ranges.iter().map(|&(start, end)| Range { start: start as u128, end: end as u128 }).collect();
+    routes_of(32, ranges, vec![]).into_iter()
+        .map(|(addr, prefix)| IpNetwork::V4(Ipv4Network::new(Ipv4Addr::from(addr as u32), prefix).unwrap()))
+        .collect()
+}
+
+/// IPv6 counterpart of `from_ipv4_ranges`.
+pub fn from_ipv6_ranges(ranges: &[(u128, u128)]) -> Vec<IpNetwork> {
+    let ranges = ranges.iter().map(|&(start, end)| Range { start: start, end: end }).collect();
+    routes_of(128, ranges, vec![]).into_iter()
+        .map(|(addr, prefix)| IpNetwork::V6(Ipv6Network::new(Ipv6Addr::from(addr), prefix).unwrap()))
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn nets(list: &[&str]) -> Vec<IpNetwork> {
+        list.iter().map(|s| IpNetwork::from_str(s).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_full_tunnel() {
+        assert_eq!(routes(&nets(&["0.0.0.0/0", "::/0"]), &[]),
+                   nets(&["0.0.0.0/1", "128.0.0.0/1", "::/1", "8000::/1"]));
+        assert_eq!(routes(&[], &nets(&["10.0.0.0/8"])), vec![]);
+    }
+
+    #[test]
+    fn test_include() {
+        // Adjacent and overlapping networks are merged, host bits dropped.
+        assert_eq!(routes(&nets(&["10.0.0.0/9", "10.128.0.0/9", "10.1.2.3/16", "172.16.0.0/12"]), &[]),
+                   nets(&["10.0.0.0/8", "172.16.0.0/12"]));
+    }
+
+    #[test]
+    fn test_exclude() {
+        assert_eq!(routes(&nets(&["0.0.0.0/0"]), &nets(&["192.168.0.0/16", "10.0.0.0/8"])),
+                   nets(&["0.0.0.0/5", "8.0.0.0/7", "11.0.0.0/8", "12.0.0.0/6", "16.0.0.0/4",
+                          "32.0.0.0/3", "64.0.0.0/2", "128.0.0.0/2", "192.0.0.0/9",
+                          "192.128.0.0/11", "192.160.0.0/13", "192.169.0.0/16", "192.170.0.0/15",
+                          "192.172.0.0/14", "192.176.0.0/12", "192.192.0.0/10", "193.0.0.0/8",
+                          "194.0.0.0/7", "196.0.0.0/6", "200.0.0.0/5", "208.0.0.0/4", "224.0.0.0/3"]));
+        assert_eq!(routes(&nets(&["10.0.0.0/8"]), &nets(&["10.0.0.0/9"])), nets(&["10.128.0.0/9"]));
+        assert_eq!(routes(&nets(&["10.0.0.0/8"]), &nets(&["0.0.0.0/0"])), vec![]);
+        assert_eq!(routes(&nets(&["::/0"]), &nets(&["8000::/1"])), nets(&["::/1"]));
+        assert_eq!(routes(&nets(&["::/0"]), &nets(&["::/128"])),
+                   (1..129).rev().map(|prefix| {
+                       IpNetwork::V6(Ipv6Network::new(Ipv6Addr::from(1u128 << (128 - prefix)), prefix).unwrap())
+                   }).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_edges() {
+        assert_eq!(routes(&nets(&["255.255.255.255/32", "0.0.0.0/32"]), &[]),
+                   nets(&["0.0.0.0/32", "255.255.255.255/32"]));
+        assert_eq!(routes(&nets(&["0.0.0.0/0"]), &nets(&["255.255.255.255/32", "0.0.0.0/32"])).len(), 62);
+    }
+
+    #[test]
+    fn test_from_ranges() {
+        // 10.0.0.0 - 10.0.2.255 and an overlapping duplicate.
+        assert_eq!(from_ipv4_ranges(&[(167772416, 167772927), (167772160, 167772415), (167772160, 167772415)]),
+                   nets(&["10.0.0.0/23", "10.0.2.0/24"]));
+        assert_eq!(from_ipv6_ranges(&[(1 << 127, !0)]), nets(&["8000::/1"]));
+        assert_eq!(from_ipv4_ranges(&[]), vec![]);
+    }
+}
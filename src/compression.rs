@@ -1,7 +1,19 @@
 
 use snap;
 
+use std::io;
 use std::str;
+use std::fmt;
+
+
+/// Packets shorter than this are sent as is, the saving can't pay for the work.
+pub const MIN_COMPRESS_LEN: usize = 128;
+/// Largest packet we are willing to inflate, nothing on the tunnel is bigger.
+pub const MAX_DECOMPRESS_LEN: usize = 65535;
+/// Bytes inspected by the entropy heuristic.
+const ENTROPY_SAMPLE_LEN: usize = 512;
+/// Bits per byte above which a payload is considered compressed or encrypted already.
+const ENTROPY_THRESHOLD: f64 = 7.0;
 
 
 pub fn compress(input: &[u8], output: &mut [u8]) -> Result<usize, snap::Error> {
@@ -14,6 +26,130 @@ pub fn decompress(input: &[u8], output: &mut [u8]) -> Result<usize, snap::Error>
     decoder.decompress(input, output)
 }
 
+/// Shannon entropy of the first bytes of `input`, in bits per byte.
+pub fn entropy(input: &[u8]) -> f64 {
+    let sample = &input[..::std::cmp::min(input.len(), ENTROPY_SAMPLE_LEN)];
+    if sample.len() == 0 {
+        return 0.0;
+    }
+    let mut counts = [0usize; 256];
+    for byte in sample {
+        counts[*byte as usize] += 1;
+    }
+    let len = sample.len() as f64;
+    counts.iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let p = *count as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// TLS, SSH, media and archives look like noise, snappy can't do anything about them.
+pub fn looks_incompressible(input: &[u8]) -> bool {
+    entropy(input) > ENTROPY_THRESHOLD
+}
+
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Stats {
+    /// Packets handed to `Compressor::compress`.
+    pub packets: u64,
+    /// Packets actually sent compressed.
+    pub compressed: u64,
+    /// Packets skipped by the entropy heuristic.
+    pub skipped: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+impl Stats {
+    /// Output bytes over input bytes, `1.0` until something was sent.
+    pub fn ratio(&self) -> f64 {
+        if self.bytes_in == 0 {
+            1.0
+        } else {
+            self.bytes_out as f64 / self.bytes_in as f64
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "packets: {} compressed: {} skipped: {} bytes: {} -> {} ratio: {:.3}",
+               self.packets, self.compressed, self.skipped,
+               self.bytes_in, self.bytes_out, self.ratio())
+    }
+}
+
+pub struct Compressor {
+    encoder: snap::Encoder,
+    decoder: snap::Decoder,
+    buf: Vec<u8>,
+    pub stats: Stats,
+}
+
+impl Compressor {
+    pub fn new() -> Compressor {
+        Compressor {
+            encoder: snap::Encoder::new(),
+            decoder: snap::Decoder::new(),
+            buf: vec![],
+            stats: Stats::default(),
+        }
+    }
+
+    /// Returns the compressed payload, or `None` when it should be sent as is.
+    pub fn compress(&mut self, input: &[u8]) -> Option<Vec<u8>> {
+        self.stats.packets += 1;
+        self.stats.bytes_in += input.len() as u64;
+
+        let output = if input.len() < MIN_COMPRESS_LEN {
+            None
+        } else if looks_incompressible(input) {
+            self.stats.skipped += 1;
+            None
+        } else {
+            let max_len = snap::max_compress_len(input.len());
+            if self.buf.len() < max_len {
+                self.buf.resize(max_len, 0);
+            }
+            match self.encoder.compress(input, &mut self.buf) {
+                Ok(size) if size < input.len() => Some(self.buf[..size].to_vec()),
+                _ => None
+            }
+        };
+
+        match output {
+            Some(ref output) => {
+                self.stats.compressed += 1;
+                self.stats.bytes_out += output.len() as u64;
+            },
+            None => {
+                self.stats.bytes_out += input.len() as u64;
+            }
+        }
+        output
+    }
+
+    pub fn decompress(&mut self, input: &[u8]) -> Result<Vec<u8>, io::Error> {
+        let len = match snap::decompress_len(input) {
+            Ok(len) if len <= MAX_DECOMPRESS_LEN => len,
+            Ok(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "decompressed payload is too large")),
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}", e)))
+        };
+        let mut output = vec![0u8; len];
+        match self.decoder.decompress(input, &mut output) {
+            Ok(size) => {
+                output.truncate(size);
+                Ok(output)
+            },
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}", e)))
+        }
+    }
+}
+
 
 
 fn test_snap_comress(){
@@ -132,4 +268,78 @@ fn test_snap_decompress(){
 fn main() {
     test_snap_comress();
     test_snap_decompress();
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::Rng;
+
+    #[test]
+    fn test_compress_roundtrip() {
+        let input: Vec<u8> = b"GET /index.html HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\n\r\n".iter()
+            .cycle().take(1400).cloned().collect();
+        let mut compressor = Compressor::new();
+        let compressed = compressor.compress(&input).unwrap();
+        assert!(compressed.len() < input.len());
+        assert_eq!(compressor.decompress(&compressed).unwrap(), input);
+
+        assert_eq!(compressor.stats.packets, 1);
+        assert_eq!(compressor.stats.compressed, 1);
+        assert_eq!(compressor.stats.skipped, 0);
+        assert_eq!(compressor.stats.bytes_in, 1400);
+        assert_eq!(compressor.stats.bytes_out, compressed.len() as u64);
+        assert!(compressor.stats.ratio() < 1.0);
+    }
+
+    #[test]
+    fn test_compress_not_worth_it() {
+        let mut compressor = Compressor::new();
+        assert_eq!(compressor.stats.ratio(), 1.0);
+
+        // Too short, however repetitive.
+        assert_eq!(compressor.compress(&[0u8; MIN_COMPRESS_LEN - 1]), None);
+
+        // 6 bits per byte passes the heuristic, but snappy finds nothing to reuse.
+        let input = Rng::new().bytes(200, 64);
+        assert!(!looks_incompressible(&input));
+        assert_eq!(compressor.compress(&input), None);
+
+        assert_eq!(compressor.stats.packets, 2);
+        assert_eq!(compressor.stats.compressed, 0);
+        assert_eq!(compressor.stats.skipped, 0);
+        assert_eq!(compressor.stats.bytes_out, compressor.stats.bytes_in);
+        assert_eq!(compressor.stats.ratio(), 1.0);
+    }
+
+    #[test]
+    fn test_compress_skips_noise() {
+        assert_eq!(entropy(&[]), 0.0);
+        assert_eq!(entropy(&[7u8; 1000]), 0.0);
+        let input = Rng::new().bytes(1400, 256);
+        assert!(entropy(&input) > ENTROPY_THRESHOLD);
+
+        let mut compressor = Compressor::new();
+        assert_eq!(compressor.compress(&input), None);
+        assert_eq!(compressor.stats.skipped, 1);
+        assert_eq!(compressor.stats.compressed, 0);
+    }
+
+    #[test]
+    fn test_decompress_limits() {
+        let mut compressor = Compressor::new();
+
+        let input = vec![0u8; MAX_DECOMPRESS_LEN + 1];
+        let mut output = vec![0u8; snap::max_compress_len(input.len())];
+        let size = compress(&input, &mut output).unwrap();
+        assert!(compressor.decompress(&output[..size]).is_err());
+
+        let input = vec![0u8; MAX_DECOMPRESS_LEN];
+        let size = compress(&input, &mut output).unwrap();
+        assert_eq!(compressor.decompress(&output[..size]).unwrap().len(), MAX_DECOMPRESS_LEN);
+
+        // Only the header, claiming 64 KiB.
+        assert!(compressor.decompress(&[0x80, 0x80, 0x04]).is_err());
+        assert!(compressor.decompress(&[0x05, 0xff, 0xff]).is_err());
+    }
+}
@@ -0,0 +1,444 @@
+/// TOML configuration file shared by vpn and vpnd.
+///
+/// Keys are addressed with dotted paths (`tun.ifname`). Command line flags
+/// win over the file, the file wins over the flag defaults.
+use clap::ArgMatches;
+use toml;
+
+use std::io;
+use std::fmt;
+use std::str::FromStr;
+use std::collections::BTreeMap;
+
+use crypto;
+
+
+/// Keys understood by vpn.
+pub const CLIENT_KEYS: &'static [&'static str] = &[
+    "verbose", "daemon", "pidfile", "log_file", "user", "port", "dns", "dns6", "no_auto_config", "journal",
+    "server.addr", "server.key",
+    "tun.ifname", "tun.mtu", "tun.fragment", "tun.no_mss_clamp",
+    "network.default_ifname", "network.default_gateway", "network.default_networkservice",
+    "crypto.disable", "crypto.key",
+    "compression.disable",
+    "keepalive.interval", "keepalive.timeout",
+    "routes.include", "routes.exclude", "routes.include_countries", "routes.exclude_countries",
+    "routes.delegation_files",
+];
+
+/// Keys understood by vpnd.
+pub const SERVER_KEYS: &'static [&'static str] = &[
+    "verbose", "daemon", "pidfile", "log_file", "user", "port", "no_auto_config", "journal",
+    "tun.ifname", "tun.mtu", "tun.fragment", "tun.no_mss_clamp", "tun.network", "tun.network6",
+    "network.default_ifname",
+    "crypto.disable", "crypto.key", "crypto.authorized_keys",
+    "compression.disable",
+    "lease.timeout", "lease.reservations",
+    "keepalive.timeout",
+    "peers[].fingerprint", "peers[].address", "peers[].networks",
+];
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    /// Offending key, `--flag` when the value came from the command line.
+    pub key: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    pub fn new(key: &str, message: &str) -> ConfigError {
+        ConfigError { key: key.to_string(), message: message.to_string() }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.key.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "`{}`: {}", self.key, self.message)
+        }
+    }
+}
+
+impl From<ConfigError> for io::Error {
+    fn from(e: ConfigError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("{}", e))
+    }
+}
+
+
+#[derive(Debug, Clone)]
+pub struct ConfigFile {
+    root: toml::value::Table,
+}
+
+impl ConfigFile {
+    pub fn load(filename: &str) -> Result<ConfigFile, ConfigError> {
+        let data = match crypto::read_file(filename) {
+            Ok(data) => data,
+            Err(e) => return Err(ConfigError::new("", &format!("{}: {}", filename, e)))
+        };
+        match String::from_utf8(data) {
+            Ok(text) => ConfigFile::parse(&text),
+            Err(_) => Err(ConfigError::new("", &format!("{}: not UTF-8", filename)))
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<ConfigFile, ConfigError> {
+        match text.parse::<toml::Value>() {
+            Ok(toml::Value::Table(root)) => Ok(ConfigFile { root: root }),
+            Ok(_) => Err(ConfigError::new("", "top level must be a table")),
+            Err(e) => Err(ConfigError::new("", &format!("{}", e)))
+        }
+    }
+
+    /// Reject keys outside of `known`, catches typos which would be ignored otherwise.
+    /// Arrays of tables are checked with `name[].field`.
+    pub fn check_keys(&self, known: &[&str]) -> Result<(), ConfigError> {
+        let mut keys: Vec<String> = vec![];
+        collect_keys("", &self.root, &mut keys);
+        for key in keys {
+            if !known.iter().any(|known| *known == key) {
+                return Err(ConfigError::new(&key, "unknown key"));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, key: &str) -> Option<&toml::Value> {
+        let mut table = &self.root;
+        let mut parts = key.split('.').peekable();
+        while let Some(part) = parts.next() {
+            let value = match table.get(part) {
+                Some(value) => value,
+                None => return None
+            };
+            if parts.peek().is_none() {
+                return Some(value);
+            }
+            table = match *value {
+                toml::Value::Table(ref table) => table,
+                _ => return None
+            };
+        }
+        None
+    }
+
+    /// Scalar value rendered as a string, the way it would be given on the command line.
+    pub fn scalar(&self, key: &str) -> Result<Option<String>, ConfigError> {
+        match self.get(key) {
+            Some(value) => match scalar(value) {
+                Some(s) => Ok(Some(s)),
+                None => Err(ConfigError::new(key, &format!("expected a value, found {}", value.type_str())))
+            },
+            None => Ok(None)
+        }
+    }
+
+    pub fn bool(&self, key: &str) -> Result<Option<bool>, ConfigError> {
+        match self.get(key) {
+            Some(&toml::Value::Boolean(b)) => Ok(Some(b)),
+            Some(value) => Err(ConfigError::new(key, &format!("expected a boolean, found {}", value.type_str()))),
+            None => Ok(None)
+        }
+    }
+
+    /// Array of values, empty when the key is missing.
+    pub fn list<T: FromStr>(&self, key: &str) -> Result<Vec<T>, ConfigError> {
+        match self.get(key) {
+            Some(value) => parse_list(key, value),
+            None => Ok(vec![])
+        }
+    }
+
+    /// Array of tables, such as `[[peers]]`.
+    pub fn tables(&self, key: &str) -> Result<Vec<Table>, ConfigError> {
+        match self.get(key) {
+            Some(&toml::Value::Array(ref items)) => {
+                let mut tables = vec![];
+                for (i, item) in items.iter().enumerate() {
+                    match *item {
+                        toml::Value::Table(ref table) => tables.push(Table {
+                            key: format!("{}[{}]", key, i),
+                            table: table.clone(),
+                        }),
+                        _ => return Err(ConfigError::new(&format!("{}[{}]", key, i), "expected a table"))
+                    }
+                }
+                Ok(tables)
+            },
+            Some(value) => Err(ConfigError::new(key, &format!("expected an array of tables, found {}",
+                                                             value.type_str()))),
+            None => Ok(vec![])
+        }
+    }
+}
+
+/// One entry of an array of tables.
+#[derive(Debug, Clone)]
+pub struct Table {
+    pub key: String,
+    table: toml::value::Table,
+}
+
+impl Table {
+    pub fn parse<T: FromStr>(&self, field: &str) -> Result<T, ConfigError> {
+        match try!(self.optional(field)) {
+            Some(v) => Ok(v),
+            None => Err(ConfigError::new(&format!("{}.{}", self.key, field), "missing"))
+        }
+    }
+
+    pub fn optional<T: FromStr>(&self, field: &str) -> Result<Option<T>, ConfigError> {
+        let key = format!("{}.{}", self.key, field);
+        match self.table.get(field) {
+            Some(value) => parse_value(&key, value).map(Some),
+            None => Ok(None)
+        }
+    }
+
+    /// Array of values, empty when the field is missing.
+    pub fn list<T: FromStr>(&self, field: &str) -> Result<Vec<T>, ConfigError> {
+        match self.table.get(field) {
+            Some(value) => parse_list(&format!("{}.{}", self.key, field), value),
+            None => Ok(vec![])
+        }
+    }
+}
+
+fn parse_list<T: FromStr>(key: &str, value: &toml::Value) -> Result<Vec<T>, ConfigError> {
+    match *value {
+        toml::Value::Array(ref items) => {
+            let mut values = vec![];
+            for (i, item) in items.iter().enumerate() {
+                values.push(try!(parse_value(&format!("{}[{}]", key, i), item)));
+            }
+            Ok(values)
+        },
+        _ => Err(ConfigError::new(key, &format!("expected an array, found {}", value.type_str())))
+    }
+}
+
+fn parse_value<T: FromStr>(key: &str, value: &toml::Value) -> Result<T, ConfigError> {
+    match scalar(value) {
+        Some(s) => match s.parse::<T>() {
+            Ok(v) => Ok(v),
+            Err(_) => Err(ConfigError::new(key, &format!("invalid value {:?}", s)))
+        },
+        None => Err(ConfigError::new(key, &format!("expected a value, found {}", value.type_str())))
+    }
+}
+
+fn scalar(value: &toml::Value) -> Option<String> {
+    match *value {
+        toml::Value::String(ref s) => Some(s.clone()),
+        toml::Value::Integer(n) => Some(format!("{}", n)),
+        toml::Value::Float(n) => Some(format!("{}", n)),
+        toml::Value::Boolean(b) => Some(format!("{}", b)),
+        _ => None
+    }
+}
+
+fn collect_keys(prefix: &str, table: &BTreeMap<String, toml::Value>, keys: &mut Vec<String>) {
+    for (name, value) in table.iter() {
+        let key = if prefix.is_empty() { name.clone() } else { format!("{}.{}", prefix, name) };
+        match *value {
+            toml::Value::Table(ref table) => collect_keys(&key, table, keys),
+            toml::Value::Array(ref items) if items.iter().any(|item| item.is_table()) => {
+                for item in items.iter() {
+                    if let toml::Value::Table(ref table) = *item {
+                        collect_keys(&format!("{}[]", key), table, keys);
+                    }
+                }
+            },
+            _ => keys.push(key)
+        }
+    }
+}
+
+
+/// Command line flags layered over an optional config file.
+pub struct Settings<'a> {
+    matches: &'a ArgMatches<'a>,
+    file: Option<ConfigFile>,
+}
+
+impl<'a> Settings<'a> {
+    pub fn new(matches: &'a ArgMatches<'a>, file: Option<ConfigFile>) -> Settings<'a> {
+        Settings { matches: matches, file: file }
+    }
+
+    pub fn file(&self) -> Option<&ConfigFile> {
+        self.file.as_ref()
+    }
+
+    fn from_command_line(&self, arg: &str) -> bool {
+        self.matches.occurrences_of(arg) > 0
+    }
+
+    /// Value of `--arg`, else of `key` in the file, else the flag default.
+    /// Returns the value together with the name to blame if it is invalid.
+    fn raw(&self, arg: &str, key: &str) -> Result<Option<(String, String)>, ConfigError> {
+        if !self.from_command_line(arg) {
+            if let Some(ref file) = self.file {
+                if let Some(value) = try!(file.scalar(key)) {
+                    return Ok(Some((value, key.to_string())));
+                }
+            }
+        }
+        Ok(self.matches.value_of(arg).map(|value| (value.to_string(), format!("--{}", arg))))
+    }
+
+    pub fn string(&self, arg: &str, key: &str) -> Result<Option<String>, ConfigError> {
+        Ok(try!(self.raw(arg, key)).map(|(value, _)| value))
+    }
+
+    pub fn parse<T: FromStr>(&self, arg: &str, key: &str) -> Result<Option<T>, ConfigError> {
+        match try!(self.raw(arg, key)) {
+            Some((value, source)) => match value.parse::<T>() {
+                Ok(v) => Ok(Some(v)),
+                Err(_) => Err(ConfigError::new(&source, &format!("invalid value {:?}", value)))
+            },
+            None => Ok(None)
+        }
+    }
+
+    /// Like `parse`, but missing from both the command line and the file is an error.
+    pub fn require<T: FromStr>(&self, arg: &str, key: &str) -> Result<T, ConfigError> {
+        match try!(self.parse(arg, key)) {
+            Some(v) => Ok(v),
+            None => Err(ConfigError::new(key, &format!("missing, set it in the config file or pass --{}", arg)))
+        }
+    }
+
+    /// Every `--arg` given on the command line, else the array `key` in the file.
+    pub fn list<T: FromStr>(&self, arg: &str, key: &str) -> Result<Vec<T>, ConfigError> {
+        match self.matches.values_of(arg) {
+            Some(values) => {
+                let mut list = vec![];
+                for value in values {
+                    match value.parse::<T>() {
+                        Ok(v) => list.push(v),
+                        Err(_) => return Err(ConfigError::new(&format!("--{}", arg),
+                                                              &format!("invalid value {:?}", value)))
+                    }
+                }
+                Ok(list)
+            },
+            None => match self.file {
+                Some(ref file) => file.list(key),
+                None => Ok(vec![])
+            }
+        }
+    }
+
+    /// A switch given on the command line, or a boolean in the file.
+    pub fn flag(&self, arg: &str, key: &str) -> Result<bool, ConfigError> {
+        if self.matches.is_present(arg) {
+            return Ok(true);
+        }
+        match self.file {
+            Some(ref file) => Ok(try!(file.bool(key)).unwrap_or(false)),
+            None => Ok(false)
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddr};
+    use ipnetwork::Ipv4Network;
+
+    #[test]
+    fn test_sample_configs() {
+        let vpn = ConfigFile::parse(include_str!("../conf/vpn.toml")).unwrap();
+        vpn.check_keys(CLIENT_KEYS).unwrap();
+        assert_eq!(vpn.scalar("server.addr").unwrap(), Some("35.200.200.111:9050".to_string()));
+        assert!(vpn.scalar("server.addr").unwrap().unwrap().parse::<SocketAddr>().is_ok());
+        assert_eq!(vpn.bool("compression.disable").unwrap(), Some(false));
+        assert_eq!(vpn.scalar("port").unwrap(), Some("9050".to_string()));
+
+        let vpnd = ConfigFile::parse(include_str!("../conf/vpnd.toml")).unwrap();
+        vpnd.check_keys(SERVER_KEYS).unwrap();
+        let peers = vpnd.tables("peers").unwrap();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].parse::<Ipv4Addr>("address").unwrap(), Ipv4Addr::new(172, 16, 0, 10));
+    }
+
+    #[test]
+    fn test_errors_name_the_key() {
+        let file = ConfigFile::parse("[tun]\nifnmae = \"tun9\"\n").unwrap();
+        assert_eq!(file.check_keys(CLIENT_KEYS).unwrap_err().key, "tun.ifnmae");
+
+        let file = ConfigFile::parse("[crypto]\ndisable = \"yes\"\n").unwrap();
+        assert_eq!(file.bool("crypto.disable").unwrap_err().key, "crypto.disable");
+
+        let file = ConfigFile::parse("[server]\naddr = [1, 2]\n").unwrap();
+        assert_eq!(file.scalar("server.addr").unwrap_err().key, "server.addr");
+
+        let file = ConfigFile::parse("[[peers]]\nfingerprint = \"00\"\naddress = \"172.16.0.300\"\n").unwrap();
+        let peers = file.tables("peers").unwrap();
+        assert_eq!(peers[0].parse::<Ipv4Addr>("address").unwrap_err().key, "peers[0].address");
+
+        let file = ConfigFile::parse("[[peers]]\nnetworks = [\"10.0.0.0/8\", \"10.0.0.0/33\"]\n").unwrap();
+        let peers = file.tables("peers").unwrap();
+        assert_eq!(peers[0].list::<Ipv4Network>("networks").unwrap_err().key, "peers[0].networks[1]");
+        assert_eq!(peers[0].optional::<Ipv4Addr>("address").unwrap(), None);
+
+        assert!(ConfigFile::parse("port = ").is_err());
+    }
+
+    #[test]
+    fn test_command_line_wins() {
+        use clap::{App, Arg};
+
+        let app = || App::new("test")
+            .arg(Arg::with_name("port").long("port").takes_value(true).default_value("9050"))
+            .arg(Arg::with_name("disable-crypto").long("disable-crypto"));
+        let file = ConfigFile::parse("port = 9999\n[crypto]\ndisable = true\n").unwrap();
+
+        let matches = app().get_matches_from(vec!["test"]);
+        let settings = Settings::new(&matches, Some(file.clone()));
+        assert_eq!(settings.parse::<u16>("port", "port").unwrap(), Some(9999));
+        assert_eq!(settings.flag("disable-crypto", "crypto.disable").unwrap(), true);
+
+        let matches = app().get_matches_from(vec!["test", "--port", "1234"]);
+        let settings = Settings::new(&matches, Some(file));
+        assert_eq!(settings.parse::<u16>("port", "port").unwrap(), Some(1234));
+
+        let matches = app().get_matches_from(vec!["test", "--port", "x"]);
+        let settings = Settings::new(&matches, None);
+        assert_eq!(settings.parse::<u16>("port", "port").unwrap_err().key, "--port");
+        assert_eq!(settings.require::<u16>("missing", "missing").unwrap_err().key, "missing");
+    }
+
+    #[test]
+    fn test_list() {
+        use clap::{App, Arg};
+
+        let app = || App::new("test")
+            .arg(Arg::with_name("include").long("include").takes_value(true).multiple(true).number_of_values(1));
+        let file = ConfigFile::parse("[routes]\ninclude = [\"10.0.0.0/8\", \"172.16.0.0/12\"]\n").unwrap();
+
+        let matches = app().get_matches_from(vec!["test"]);
+        let settings = Settings::new(&matches, Some(file.clone()));
+        assert_eq!(settings.list::<Ipv4Network>("include", "routes.include").unwrap().len(), 2);
+        assert_eq!(settings.list::<Ipv4Network>("include", "routes.exclude").unwrap(), vec![]);
+
+        let matches = app().get_matches_from(vec!["test", "--include", "192.168.0.0/16"]);
+        let settings = Settings::new(&matches, Some(file));
+        assert_eq!(settings.list::<Ipv4Network>("include", "routes.include").unwrap(),
+                   vec!["192.168.0.0/16".parse::<Ipv4Network>().unwrap()]);
+
+        let matches = app().get_matches_from(vec!["test", "--include", "10.0.0.0/33"]);
+        let settings = Settings::new(&matches, None);
+        assert_eq!(settings.list::<Ipv4Network>("include", "routes.include").unwrap_err().key, "--include");
+
+        let file = ConfigFile::parse("[routes]\nexclude = \"10.0.0.0/8\"\n").unwrap();
+        assert_eq!(file.list::<Ipv4Network>("routes.exclude").unwrap_err().key, "routes.exclude");
+    }
+}
@@ -40,13 +40,49 @@ pub fn write_file(filename: &str, data: &[u8]) -> Result<(), io::Error> {
     }
 }
 
+pub fn random_bytes(size: usize) -> Vec<u8> {
+    let mut buf: Vec<u8> = vec![0u8; size];
+    match openssl::rand::rand_bytes(&mut buf) {
+        Ok(_) => buf,
+        Err(_) => unreachable!()
+    }
+}
+
+pub fn sha256(input: &[u8]) -> Vec<u8> {
+    match openssl::hash::hash(openssl::hash::MessageDigest::sha256(), input) {
+        Ok(digest) => digest.to_vec(),
+        Err(_) => unreachable!()
+    }
+}
+
+pub fn to_hex(input: &[u8]) -> String {
+    input.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<String>>().join("")
+}
+
+pub fn from_hex(input: &str) -> Option<Vec<u8>> {
+    if input.len() % 2 != 0 || !input.is_ascii() {
+        return None;
+    }
+    let mut output: Vec<u8> = Vec::with_capacity(input.len() / 2);
+    for i in 0..input.len() / 2 {
+        match u8::from_str_radix(&input[i*2..i*2+2], 16) {
+            Ok(byte) => output.push(byte),
+            Err(_) => return None
+        }
+    }
+    Some(output)
+}
+
 #[allow(non_snake_case)]
 pub mod rsa {
-    use super::{io, Read, Write, read_file, write_file, openssl};
+    use super::{io, Read, Write, read_file, write_file, sha256, openssl};
 
     use openssl::rsa::Rsa;
     use openssl::rsa::{NO_PADDING, PKCS1_OAEP_PADDING, PKCS1_PADDING};
     use openssl::error::ErrorStack;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::sign::{Signer, Verifier};
 
 
     #[derive(Debug)]
@@ -112,6 +148,31 @@ pub mod rsa {
         pub fn decrypt(&self, input: &[u8], output: &mut [u8]) -> Result<usize, ErrorStack> {
             self.inner.public_decrypt(input, output, PKCS1_PADDING)
         }
+
+        pub fn encrypt_oaep(&self, input: &[u8], output: &mut [u8]) -> Result<usize, ErrorStack> {
+            self.inner.public_encrypt(input, output, PKCS1_OAEP_PADDING)
+        }
+
+        /// Check a signature made by `PriKey::sign` over `data`.
+        pub fn verify(&self, data: &[u8], signature: &[u8]) -> bool {
+            let pkey = match Rsa::public_key_from_der(&self.to_der()).and_then(PKey::from_rsa) {
+                Ok(pkey) => pkey,
+                Err(_) => return false
+            };
+            let mut verifier = match Verifier::new(MessageDigest::sha256(), &pkey) {
+                Ok(verifier) => verifier,
+                Err(_) => return false
+            };
+            match verifier.update(data) {
+                Ok(_) => verifier.verify(signature).unwrap_or(false),
+                Err(_) => false
+            }
+        }
+
+        /// SHA-256 of the DER encoding, used to identify peers.
+        pub fn fingerprint(&self) -> Vec<u8> {
+            sha256(&self.to_der())
+        }
     }
 
     impl PriKey {
@@ -175,6 +236,18 @@ pub mod rsa {
         pub fn decrypt(&self, input: &[u8], output: &mut [u8]) -> Result<usize, ErrorStack> {
             self.inner.private_decrypt(input, output, PKCS1_PADDING)
         }
+
+        pub fn decrypt_oaep(&self, input: &[u8], output: &mut [u8]) -> Result<usize, ErrorStack> {
+            self.inner.private_decrypt(input, output, PKCS1_OAEP_PADDING)
+        }
+
+        /// RSASSA-PKCS1-v1_5 signature over the SHA-256 of `data`.
+        pub fn sign(&self, data: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+            let pkey = try!(Rsa::private_key_from_der(&self.to_der()).and_then(PKey::from_rsa));
+            let mut signer = try!(Signer::new(MessageDigest::sha256(), &pkey));
+            try!(signer.update(data));
+            signer.sign_to_vec()
+        }
     }
 }
 
@@ -259,6 +332,240 @@ pub mod aes {
 }
 
 
+/// Per-session AES-256-GCM cipher for tunnel datagrams.
+///
+/// Every sealed message is numbered by a 64-bit counter which is used as the
+/// nonce and checked against a sliding replay window on the receiving side.
+/// Each direction uses its own key, so the counters never collide.
+#[allow(non_snake_case)]
+pub mod aead {
+    use super::{io, sha256, openssl};
+
+    use openssl::symm;
+    use byteorder::{ByteOrder, NetworkEndian};
+
+
+    pub const KEY_LEN: usize = 32;
+    pub const NONCE_LEN: usize = 12;
+    pub const TAG_LEN: usize = 16;
+    pub const COUNTER_LEN: usize = 8;
+    /// How far behind the highest seen counter a packet may arrive.
+    pub const WINDOW_SIZE: u64 = 64;
+
+    pub fn method() -> symm::Cipher {
+        symm::Cipher::aes_256_gcm()
+    }
+
+    #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+    pub enum Role {
+        Client,
+        Server,
+    }
+
+    #[derive(Debug, Clone, Eq, PartialEq)]
+    pub struct ReplayWindow {
+        last: u64,
+        // bit N set: counter `last - N` has been seen.
+        bitmap: u64,
+    }
+
+    impl ReplayWindow {
+        pub fn new() -> ReplayWindow {
+            ReplayWindow { last: 0, bitmap: 0 }
+        }
+
+        /// Whether `counter` is fresh, does not modify the window.
+        pub fn check(&self, counter: u64) -> bool {
+            if counter == 0 {
+                return false;
+            }
+            if counter > self.last {
+                return true;
+            }
+            let diff = self.last - counter;
+            if diff >= WINDOW_SIZE {
+                return false;
+            }
+            self.bitmap & (1u64 << diff) == 0
+        }
+
+        /// Mark `counter` as seen, only call this once the packet is authenticated.
+        pub fn update(&mut self, counter: u64) {
+            if counter > self.last {
+                let shift = counter - self.last;
+                self.bitmap = if shift >= WINDOW_SIZE { 0 } else { self.bitmap << shift };
+                self.bitmap |= 1;
+                self.last = counter;
+            } else {
+                let diff = self.last - counter;
+                if diff < WINDOW_SIZE {
+                    self.bitmap |= 1u64 << diff;
+                }
+            }
+        }
+    }
+
+    pub struct SessionCipher {
+        seal_key: Vec<u8>,
+        open_key: Vec<u8>,
+        counter: u64,
+        window: ReplayWindow,
+    }
+
+    fn derive_key(session_key: &[u8], label: &[u8]) -> Vec<u8> {
+        let mut input = session_key.to_vec();
+        input.extend_from_slice(label);
+        sha256(&input)
+    }
+
+    fn nonce(counter: u64) -> [u8; NONCE_LEN] {
+        let mut iv = [0u8; NONCE_LEN];
+        NetworkEndian::write_u64(&mut iv[NONCE_LEN - COUNTER_LEN..], counter);
+        iv
+    }
+
+    impl SessionCipher {
+        pub fn new(session_key: &[u8], role: Role) -> SessionCipher {
+            let client_key = derive_key(session_key, b"client->server");
+            let server_key = derive_key(session_key, b"server->client");
+            let (seal_key, open_key) = match role {
+                Role::Client => (client_key, server_key),
+                Role::Server => (server_key, client_key),
+            };
+            SessionCipher {
+                seal_key: seal_key,
+                open_key: open_key,
+                counter: 0,
+                window: ReplayWindow::new(),
+            }
+        }
+
+        /// Allocate the counter for the next sealed message.
+        pub fn next_counter(&mut self) -> Result<u64, io::Error> {
+            if self.counter == u64::max_value() {
+                return Err(io::Error::new(io::ErrorKind::Other, "nonce counter exhausted"));
+            }
+            self.counter += 1;
+            Ok(self.counter)
+        }
+
+        /// Output: ciphertext | tag
+        ///
+        /// `aad` is authenticated but not encrypted, it must carry `counter`.
+        pub fn seal(&self, counter: u64, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, io::Error> {
+            let mut tag = [0u8; TAG_LEN];
+            let iv = nonce(counter);
+            match symm::encrypt_aead(method(), &self.seal_key, Some(&iv), aad, plaintext, &mut tag) {
+                Ok(mut ciphertext) => {
+                    ciphertext.extend_from_slice(&tag);
+                    Ok(ciphertext)
+                },
+                Err(_) => Err(io::Error::new(io::ErrorKind::Other, "AEAD encrypt failed"))
+            }
+        }
+
+        /// Reverse of `seal`, rejects forged, truncated and replayed messages.
+        pub fn open(&mut self, counter: u64, aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, io::Error> {
+            if sealed.len() < TAG_LEN {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated message"));
+            }
+            if !self.window.check(counter) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "replayed message"));
+            }
+
+            let ciphertext = &sealed[..sealed.len() - TAG_LEN];
+            let tag = &sealed[sealed.len() - TAG_LEN..];
+            let iv = nonce(counter);
+            match symm::decrypt_aead(method(), &self.open_key, Some(&iv), aad, ciphertext, tag) {
+                Ok(plaintext) => {
+                    self.window.update(counter);
+                    Ok(plaintext)
+                },
+                Err(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "message authentication failed"))
+            }
+        }
+    }
+}
+
+
+
+/// Ephemeral ECDH on P-256 for the handshake, so that the session keys stay
+/// secret even if the long-term RSA keys leak later on.
+#[allow(non_snake_case)]
+pub mod ecdh {
+    use super::{io, openssl};
+
+    use openssl::bn::{BigNum, BigNumContext};
+    use openssl::ec::{EcGroup, EcKey, EcPoint, POINT_CONVERSION_UNCOMPRESSED};
+    use openssl::error::ErrorStack;
+    use openssl::nid;
+
+
+    /// Uncompressed curve point.
+    pub const SHARE_LEN: usize = 65;
+    pub const SECRET_LEN: usize = 32;
+
+    fn group() -> Result<EcGroup, ErrorStack> {
+        EcGroup::from_curve_name(nid::X9_62_PRIME256V1)
+    }
+
+    /// Key pair of a single handshake, consumed when the shared secret is computed.
+    pub struct EphemeralKey {
+        inner: EcKey
+    }
+
+    impl EphemeralKey {
+        pub fn gen() -> EphemeralKey {
+            match group().and_then(|group| EcKey::generate(&group)) {
+                Ok(key) => EphemeralKey { inner: key },
+                Err(_) => unreachable!()
+            }
+        }
+
+        /// Public half, sent to the peer.
+        pub fn share(&self) -> Vec<u8> {
+            match public_bytes(&self.inner) {
+                Ok(bytes) => bytes,
+                Err(_) => unreachable!()
+            }
+        }
+
+        /// Shared secret with the share of the peer, the x coordinate of the product.
+        pub fn agree(self, peer_share: &[u8]) -> Result<Vec<u8>, io::Error> {
+            if peer_share.len() != SHARE_LEN {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed ECDH share"));
+            }
+            match shared_secret(&self.inner, peer_share) {
+                Ok(secret) => Ok(secret),
+                Err(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "invalid ECDH share"))
+            }
+        }
+    }
+
+    fn public_bytes(key: &EcKey) -> Result<Vec<u8>, ErrorStack> {
+        let group = try!(group());
+        let mut ctx = try!(BigNumContext::new());
+        // Keys made by `EphemeralKey::gen` always have both halves.
+        key.public_key().unwrap().to_bytes(&group, POINT_CONVERSION_UNCOMPRESSED, &mut ctx)
+    }
+
+    fn shared_secret(key: &EcKey, peer_share: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+        let group = try!(group());
+        let mut ctx = try!(BigNumContext::new());
+        // Rejects points off the curve.
+        let peer = try!(EcPoint::from_bytes(&group, peer_share, &mut ctx));
+        let mut product = try!(EcPoint::new(&group));
+        try!(product.mul(&group, &peer, key.private_key().unwrap(), &ctx));
+
+        let mut x = try!(BigNum::new());
+        let mut y = try!(BigNum::new());
+        try!(product.affine_coordinates_gfp(&group, &mut x, &mut y, &mut ctx));
+        let x = x.to_vec();
+        let mut secret: Vec<u8> = vec![0u8; SECRET_LEN - x.len()];
+        secret.extend_from_slice(&x);
+        Ok(secret)
+    }
+}
 
 
 
@@ -321,4 +628,155 @@ fn test_aes() {
 fn main() {
     test_rsa();
     test_aes();
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::aead::*;
+    use super::ecdh::*;
+    use super::rsa::PriKey;
+
+    fn ciphers() -> (SessionCipher, SessionCipher) {
+        let session_key = super::random_bytes(32);
+        (SessionCipher::new(&session_key, Role::Client), SessionCipher::new(&session_key, Role::Server))
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let mut window = ReplayWindow::new();
+        assert!(!window.check(0));
+        assert!(window.check(1));
+        window.update(1);
+        // Duplicate.
+        assert!(!window.check(1));
+
+        window.update(100);
+        assert!(!window.check(100));
+        // 64 behind the highest falls out of the window, 63 is still inside.
+        assert!(!window.check(100 - WINDOW_SIZE));
+        assert!(!window.check(1));
+        assert!(window.check(100 - WINDOW_SIZE + 1));
+
+        // Out of order inside the window, accepted once.
+        assert!(window.check(90));
+        window.update(90);
+        assert!(!window.check(90));
+        assert!(window.check(91));
+
+        // A jump forward keeps what is still in reach.
+        window.update(120);
+        assert!(!window.check(90));
+        assert!(!window.check(100));
+        assert!(window.check(99));
+        window.update(120 + WINDOW_SIZE);
+        assert!(!window.check(120));
+        assert!(window.check(121));
+    }
+
+    #[test]
+    fn test_seal_open() {
+        let (mut client, mut server) = ciphers();
+        let aad = b"header and counter";
+
+        let counter = client.next_counter().unwrap();
+        let sealed = client.seal(counter, aad, b"hello").unwrap();
+        assert_eq!(sealed.len(), 5 + TAG_LEN);
+        assert_eq!(server.open(counter, aad, &sealed).unwrap(), b"hello".to_vec());
+        // Replayed.
+        assert!(server.open(counter, aad, &sealed).is_err());
+
+        // Out of order, each accepted exactly once.
+        let first: Vec<(u64, Vec<u8>)> = (0..3).map(|i| {
+            let counter = client.next_counter().unwrap();
+            (counter, client.seal(counter, aad, &[i]).unwrap())
+        }).collect();
+        for &i in [2usize, 0, 1].iter() {
+            let (counter, ref sealed) = first[i];
+            assert_eq!(server.open(counter, aad, sealed).unwrap(), vec![i as u8]);
+        }
+        for &(counter, ref sealed) in first.iter() {
+            assert!(server.open(counter, aad, sealed).is_err());
+        }
+    }
+
+    #[test]
+    fn test_tampered() {
+        let (mut client, mut server) = ciphers();
+        let aad = b"header and counter";
+        let counter = client.next_counter().unwrap();
+        let sealed = client.seal(counter, aad, b"hello").unwrap();
+
+        let mut tag = sealed.clone();
+        let len = tag.len();
+        tag[len - 1] ^= 1;
+        assert!(server.open(counter, aad, &tag).is_err());
+
+        let mut ciphertext = sealed.clone();
+        ciphertext[0] ^= 1;
+        assert!(server.open(counter, aad, &ciphertext).is_err());
+
+        assert!(server.open(counter, b"header and counter!", &sealed).is_err());
+        assert!(server.open(counter + 1, aad, &sealed).is_err());
+        assert!(server.open(counter, aad, &sealed[..TAG_LEN - 1]).is_err());
+
+        // Forgeries don't burn the counter.
+        assert_eq!(server.open(counter, aad, &sealed).unwrap(), b"hello".to_vec());
+    }
+
+    #[test]
+    fn test_directions() {
+        let (mut client, mut server) = ciphers();
+        let aad = b"header and counter";
+
+        // Both sides start at counter 1, with different keys.
+        let client_counter = client.next_counter().unwrap();
+        let server_counter = server.next_counter().unwrap();
+        assert_eq!(client_counter, server_counter);
+        let from_client = client.seal(client_counter, aad, b"same").unwrap();
+        let from_server = server.seal(server_counter, aad, b"same").unwrap();
+        assert!(from_client != from_server);
+
+        // Reflected back to its sender, a packet doesn't open.
+        assert!(client.open(client_counter, aad, &from_client).is_err());
+        assert!(server.open(server_counter, aad, &from_server).is_err());
+        assert_eq!(server.open(client_counter, aad, &from_client).unwrap(), b"same".to_vec());
+        assert_eq!(client.open(server_counter, aad, &from_server).unwrap(), b"same".to_vec());
+    }
+
+    #[test]
+    fn test_sign_verify() {
+        let prikey = PriKey::gen(1024).unwrap();
+        let signature = prikey.sign(b"transcript").unwrap();
+        assert!(prikey.pubkey().verify(b"transcript", &signature));
+        assert!(!prikey.pubkey().verify(b"transcript!", &signature));
+        assert!(!PriKey::gen(1024).unwrap().pubkey().verify(b"transcript", &signature));
+
+        // A bare PKCS#1 block over the digest, without DigestInfo, is not a signature.
+        let digest = super::sha256(b"transcript");
+        let mut raw: Vec<u8> = vec![0u8; prikey.size()];
+        let size = prikey.encrypt(&digest, &mut raw).unwrap();
+        assert!(!prikey.pubkey().verify(b"transcript", &raw[..size]));
+    }
+
+    #[test]
+    fn test_ecdh() {
+        let client = EphemeralKey::gen();
+        let server = EphemeralKey::gen();
+        let client_share = client.share();
+        let server_share = server.share();
+        assert_eq!(client_share.len(), SHARE_LEN);
+        assert!(client_share != server_share);
+
+        let client_secret = client.agree(&server_share).unwrap();
+        let server_secret = server.agree(&client_share).unwrap();
+        assert_eq!(client_secret, server_secret);
+        assert_eq!(client_secret.len(), SECRET_LEN);
+
+        // Off the curve, compressed and truncated shares.
+        let mut bad = client_share.clone();
+        bad[SHARE_LEN - 1] ^= 1;
+        assert!(EphemeralKey::gen().agree(&bad).is_err());
+        assert!(EphemeralKey::gen().agree(&client_share[..33]).is_err());
+        assert!(EphemeralKey::gen().agree(&[]).is_err());
+    }
+}
@@ -0,0 +1,192 @@
+/// Daemon mode shared by vpn and vpnd.
+///
+/// `start` locks the pidfile and detaches from the terminal, `drop_privileges`
+/// is called later, once the tun device and the sockets are opened.
+use libc;
+
+use config::{ConfigError, Settings};
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::env;
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::os::unix::io::AsRawFd;
+
+
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    pub daemon: bool,
+    pub pidfile: Option<PathBuf>,
+    pub log_file: Option<PathBuf>,
+    /// Run as this user once the privileged setup is done.
+    pub user: Option<String>,
+}
+
+impl Options {
+    pub fn from_settings(settings: &Settings) -> Result<Options, ConfigError> {
+        let daemon = try!(settings.flag("daemon", "daemon"));
+        let pidfile = try!(settings.string("pidfile", "pidfile")).map(|path| absolute(&path));
+        let log_file = try!(settings.string("log-file", "log_file")).map(|path| absolute(&path));
+        let user = try!(settings.string("user", "user"));
+        if let Some(ref user) = user {
+            if lookup_user(user).is_none() {
+                return Err(ConfigError::new("user", &format!("no such user {:?}", user)));
+            }
+        }
+        Ok(Options {
+            daemon: daemon,
+            pidfile: pidfile,
+            log_file: log_file,
+            user: user,
+        })
+    }
+}
+
+/// We chdir to `/` when detaching, relative paths have to be resolved first.
+pub fn absolute(path: &str) -> PathBuf {
+    let path = PathBuf::from(path);
+    if path.is_absolute() {
+        path
+    } else {
+        match env::current_dir() {
+            Ok(dir) => dir.join(path),
+            Err(_) => path
+        }
+    }
+}
+
+fn lookup_user(user: &str) -> Option<(libc::uid_t, libc::gid_t)> {
+    let name = match CString::new(user) {
+        Ok(name) => name,
+        Err(_) => return None
+    };
+    unsafe {
+        let passwd = libc::getpwnam(name.as_ptr());
+        if passwd.is_null() {
+            None
+        } else {
+            Some(((*passwd).pw_uid, (*passwd).pw_gid))
+        }
+    }
+}
+
+
+/// Pidfile locked with `flock` for the lifetime of the process.
+///
+/// A pidfile left behind by a process which died is not locked anymore,
+/// so it is taken over instead of blocking the start.
+#[derive(Debug)]
+pub struct Pidfile {
+    path: PathBuf,
+    file: File,
+}
+
+impl Pidfile {
+    pub fn lock(path: &Path) -> Result<Pidfile, io::Error> {
+        let mut file = try!(OpenOptions::new().read(true).write(true).create(true).open(path));
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() != Some(libc::EWOULDBLOCK) {
+                return Err(e);
+            }
+            let mut pid = String::new();
+            let _ = file.read_to_string(&mut pid);
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                      format!("{} is locked, already running as pid {}",
+                                              path.display(), pid.trim())));
+        }
+        Ok(Pidfile { path: path.to_path_buf(), file: file })
+    }
+
+    pub fn write_pid(&mut self) -> Result<(), io::Error> {
+        try!(self.file.set_len(0));
+        try!(self.file.seek(SeekFrom::Start(0)));
+        try!(write!(self.file, "{}\n", unsafe { libc::getpid() }));
+        self.file.sync_all()
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        // Fails after dropping privileges, the next start takes the stale file over.
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+
+fn check(ret: libc::c_int) -> Result<libc::c_int, io::Error> {
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Fork twice with a `setsid` in between, so the daemon is not a session
+/// leader and can never acquire a controlling terminal again. Standard input
+/// is read from /dev/null, output goes to the log file when there is one.
+pub fn daemonize(log_file: Option<&Path>) -> Result<(), io::Error> {
+    unsafe {
+        if try!(check(libc::fork())) > 0 {
+            libc::_exit(0);
+        }
+        try!(check(libc::setsid()));
+        if try!(check(libc::fork())) > 0 {
+            libc::_exit(0);
+        }
+        libc::umask(0o027);
+    }
+    try!(env::set_current_dir("/"));
+
+    let null = try!(OpenOptions::new().read(true).write(true).open("/dev/null"));
+    let output = match log_file {
+        Some(path) => try!(OpenOptions::new().create(true).append(true).open(path)),
+        None => try!(null.try_clone())
+    };
+    unsafe {
+        try!(check(libc::dup2(null.as_raw_fd(), libc::STDIN_FILENO)));
+        try!(check(libc::dup2(output.as_raw_fd(), libc::STDOUT_FILENO)));
+        try!(check(libc::dup2(output.as_raw_fd(), libc::STDERR_FILENO)));
+    }
+    Ok(())
+}
+
+/// Lock the pidfile and detach, in that order so a second instance fails in the foreground.
+///
+/// Must run before any thread is spawned, threads do not survive `fork`.
+pub fn start(options: &Options) -> Result<Option<Pidfile>, io::Error> {
+    let mut pidfile = match options.pidfile {
+        Some(ref path) => Some(try!(Pidfile::lock(path))),
+        None => None
+    };
+    if options.daemon {
+        try!(daemonize(options.log_file.as_ref().map(|path| path.as_path())));
+    }
+    if let Some(ref mut pidfile) = pidfile {
+        try!(pidfile.write_pid());
+    }
+    Ok(pidfile)
+}
+
+/// Switch to `options.user`, if any. Supplementary groups are dropped as well.
+pub fn drop_privileges(options: &Options) -> Result<(), io::Error> {
+    let user = match options.user {
+        Some(ref user) => user,
+        None => return Ok(())
+    };
+    let (uid, gid) = match lookup_user(user) {
+        Some(ids) => ids,
+        None => return Err(io::Error::new(io::ErrorKind::NotFound, format!("no such user {:?}", user)))
+    };
+    unsafe {
+        try!(check(libc::setgroups(1, &gid)));
+        try!(check(libc::setgid(gid)));
+        try!(check(libc::setuid(uid)));
+        if uid != 0 && libc::setuid(0) != -1 {
+            return Err(io::Error::new(io::ErrorKind::Other, "still able to regain root privileges"));
+        }
+    }
+    info!("running as {} (uid: {} gid: {})", user, uid, gid);
+    Ok(())
+}
@@ -0,0 +1,135 @@
+/// Forwarding and masquerading for vpnd, without sysctl and iptables when
+/// the kernel speaks nf_tables.
+///
+/// The rules live in their own `exodus` table (`EXODUS` chain for iptables),
+/// replaced as a whole on start and deleted by the journal on exit, so rules
+/// of the administrator are never touched. Errors name the step which failed.
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::process;
+use std::str::FromStr;
+
+use ipnetwork::IpNetwork;
+use netif::nftables;
+
+
+pub const TABLE: &'static str = "exodus";
+/// iptables chain names are upper case by convention.
+pub const CHAIN: &'static str = "EXODUS";
+
+const IPV4_FORWARDING: &'static str = "/proc/sys/net/ipv4/ip_forward";
+const IPV6_FORWARDING: &'static str = "/proc/sys/net/ipv6/conf/all/forwarding";
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// nf_tables over netlink.
+    Nftables,
+    /// The `iptables` and `ip6tables` commands, for kernels without nf_tables.
+    Iptables,
+}
+
+impl Backend {
+    pub fn detect() -> Result<Backend, io::Error> {
+        if nftables::available() {
+            return Ok(Backend::Nftables);
+        }
+        // iptables -t nat -S POSTROUTING
+        match run("iptables", &["-t", "nat", "-S", "POSTROUTING"]) {
+            Ok(_) => Ok(Backend::Iptables),
+            Err(e) => Err(io::Error::new(io::ErrorKind::NotFound,
+                                         format!("neither nf_tables nor iptables is usable: {}", e)))
+        }
+    }
+
+    /// Masquerade the packets from `source` leaving through `oifname`,
+    /// replacing whatever the backend had for the family of `source`.
+    pub fn masquerade(&self, source: &IpNetwork, oifname: &str) -> Result<(), io::Error> {
+        match *self {
+            Backend::Nftables => nftables::masquerade(TABLE, source, oifname),
+            Backend::Iptables => {
+                let family = nftables::Family::of(source);
+                let command = iptables(family);
+                let source = format!("{}", source);
+                try!(self.remove(family));
+                // sudo iptables -t nat -N EXODUS
+                try!(run(command, &["-t", "nat", "-N", CHAIN]));
+                // sudo iptables -t nat -A EXODUS -s 10.0.0.0/24 -o eth0 -j MASQUERADE
+                try!(run(command, &["-t", "nat", "-A", CHAIN, "-s", &source, "-o", oifname, "-j", "MASQUERADE"]));
+                // sudo iptables -t nat -A POSTROUTING -j EXODUS
+                run(command, &["-t", "nat", "-A", "POSTROUTING", "-j", CHAIN])
+            }
+        }
+    }
+
+    /// Delete the table or chain of a family, nothing happens if it is missing.
+    pub fn remove(&self, family: nftables::Family) -> Result<(), io::Error> {
+        match *self {
+            Backend::Nftables => nftables::delete_table(TABLE, family),
+            Backend::Iptables => {
+                let command = iptables(family);
+                // sudo iptables -t nat -S EXODUS
+                if run(command, &["-t", "nat", "-S", CHAIN]).is_err() {
+                    return Ok(());
+                }
+                // sudo iptables -t nat -D POSTROUTING -j EXODUS
+                while run(command, &["-t", "nat", "-C", "POSTROUTING", "-j", CHAIN]).is_ok() {
+                    try!(run(command, &["-t", "nat", "-D", "POSTROUTING", "-j", CHAIN]));
+                }
+                // sudo iptables -t nat -F EXODUS
+                try!(run(command, &["-t", "nat", "-F", CHAIN]));
+                // sudo iptables -t nat -X EXODUS
+                run(command, &["-t", "nat", "-X", CHAIN])
+            }
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Backend::Nftables => write!(f, "nftables"),
+            Backend::Iptables => write!(f, "iptables"),
+        }
+    }
+}
+
+impl FromStr for Backend {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Backend, io::Error> {
+        match s {
+            "nftables" => Ok(Backend::Nftables),
+            "iptables" => Ok(Backend::Iptables),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unknown firewall backend {:?}", s)))
+        }
+    }
+}
+
+fn iptables(family: nftables::Family) -> &'static str {
+    match family {
+        nftables::Family::Ipv4 => "iptables",
+        nftables::Family::Ipv6 => "ip6tables",
+    }
+}
+
+/// Run a command, a failure carries the command line and its error output.
+fn run(command: &str, args: &[&str]) -> Result<(), io::Error> {
+    let line = format!("{} {}", command, args.join(" "));
+    let output = try!(process::Command::new(command).args(args).output()
+                          .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", line, e))));
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::Other,
+                           format!("{}: {}, {}", line, output.status,
+                                   String::from_utf8_lossy(&output.stderr).trim())))
+    }
+}
+
+
+/// The sysctl turning on forwarding, `net.ipv4.ip_forward` or `net.ipv6.conf.all.forwarding`.
+pub fn forwarding_path(ipv6: bool) -> &'static Path {
+    Path::new(if ipv6 { IPV6_FORWARDING } else { IPV4_FORWARDING })
+}
@@ -0,0 +1,210 @@
+/// Protocol level fragmentation, for packets too big for the tunnel which
+/// should get through anyway: IPv6 over a path below 1280 bytes, or hosts
+/// behind a firewall eating ICMP errors.
+///
+/// A fragment is a data message with the `FRAGMENT` flag, its payload starts with
+///
+/// ```text
+///     0         2         3         4
+///     +---------+---------+---------+-------------
+///     |   id    |  index  |  count  | piece ...
+///     +---------+---------+---------+-------------
+/// ```
+///
+/// Packets are split after compression and the pieces sealed one by one,
+/// the peer joins them before decompressing.
+use std::io;
+use std::time::{Duration, Instant};
+use std::collections::HashMap;
+
+use byteorder::{ByteOrder, NetworkEndian};
+
+
+pub const HEADER_LEN: usize = 4;
+/// `count` is a byte.
+pub const MAX_FRAGMENTS: usize = 255;
+/// Nothing on the tunnel is bigger, a reassembled packet can't be either.
+pub const MAX_PACKET_LEN: usize = 65535;
+/// Incomplete packets are forgotten after this long.
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Packets being reassembled at once, the oldest is forgotten first.
+const MAX_PENDING: usize = 64;
+
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[derive(Debug)]
+pub struct Fragmenter {
+    next_id: u16,
+}
+
+impl Fragmenter {
+    pub fn new() -> Fragmenter {
+        Fragmenter { next_id: 0 }
+    }
+
+    /// Split `packet` into payloads of at most `max_len` bytes, headers included.
+    /// `None` when it takes more than `MAX_FRAGMENTS` pieces.
+    pub fn split(&mut self, packet: &[u8], max_len: usize) -> Option<Vec<Vec<u8>>> {
+        if max_len <= HEADER_LEN || packet.is_empty() {
+            return None;
+        }
+        let piece_len = max_len - HEADER_LEN;
+        let count = (packet.len() + piece_len - 1) / piece_len;
+        if count > MAX_FRAGMENTS {
+            return None;
+        }
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        let fragments = packet.chunks(piece_len).enumerate()
+            .map(|(index, piece)| {
+                let mut fragment = vec![0u8; HEADER_LEN];
+                NetworkEndian::write_u16(&mut fragment[0..2], id);
+                fragment[2] = index as u8;
+                fragment[3] = count as u8;
+                fragment.extend_from_slice(piece);
+                fragment
+            })
+            .collect();
+        Some(fragments)
+    }
+}
+
+#[derive(Debug)]
+struct Pending {
+    pieces: Vec<Option<Vec<u8>>>,
+    received: usize,
+    len: usize,
+    since: Instant,
+}
+
+/// Fragments of one peer waiting for the rest of their packet.
+#[derive(Debug)]
+pub struct Reassembler {
+    pending: HashMap<u16, Pending>,
+}
+
+impl Reassembler {
+    pub fn new() -> Reassembler {
+        Reassembler { pending: HashMap::new() }
+    }
+
+    /// Add the payload of a fragment, returns the packet once every piece arrived.
+    pub fn push(&mut self, fragment: &[u8], now: Instant) -> Result<Option<Vec<u8>>, io::Error> {
+        if fragment.len() <= HEADER_LEN {
+            return Err(invalid("truncated fragment"));
+        }
+        let id = NetworkEndian::read_u16(&fragment[0..2]);
+        let index = fragment[2] as usize;
+        let count = fragment[3] as usize;
+        if index >= count {
+            return Err(invalid("fragment index out of range"));
+        }
+        let piece = &fragment[HEADER_LEN..];
+
+        self.pending.retain(|_, pending| now.duration_since(pending.since) < REASSEMBLY_TIMEOUT);
+        // Left over from an id used before the counter wrapped.
+        let stale = match self.pending.get(&id) {
+            Some(pending) => pending.pieces.len() != count,
+            None => false
+        };
+        if stale {
+            self.pending.remove(&id);
+        }
+        if !self.pending.contains_key(&id) && self.pending.len() >= MAX_PENDING {
+            let oldest = self.pending.iter()
+                .min_by_key(|&(_, pending)| pending.since)
+                .map(|(id, _)| *id);
+            if let Some(oldest) = oldest {
+                self.pending.remove(&oldest);
+            }
+        }
+
+        let complete = {
+            let pending = self.pending.entry(id).or_insert_with(|| Pending {
+                pieces: vec![None; count],
+                received: 0,
+                len: 0,
+                since: now,
+            });
+            if pending.pieces[index].is_none() {
+                pending.len += piece.len();
+                pending.received += 1;
+                pending.pieces[index] = Some(piece.to_vec());
+            }
+            if pending.len > MAX_PACKET_LEN {
+                None
+            } else {
+                Some(pending.received == count)
+            }
+        };
+        match complete {
+            None => {
+                self.pending.remove(&id);
+                Err(invalid("reassembled packet too big"))
+            },
+            Some(false) => Ok(None),
+            Some(true) => {
+                let pending = self.pending.remove(&id).unwrap();
+                let mut packet = Vec::with_capacity(pending.len);
+                for piece in pending.pieces.into_iter() {
+                    packet.extend_from_slice(&piece.unwrap());
+                }
+                Ok(Some(packet))
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_and_reassemble() {
+        let packet: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+        let mut fragmenter = Fragmenter::new();
+        let fragments = fragmenter.split(&packet, 1400).unwrap();
+        assert_eq!(fragments.len(), 3);
+        assert!(fragments.iter().all(|fragment| fragment.len() <= 1400));
+
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new();
+        assert_eq!(reassembler.push(&fragments[2], now).unwrap(), None);
+        assert_eq!(reassembler.push(&fragments[0], now).unwrap(), None);
+        // Duplicates are ignored.
+        assert_eq!(reassembler.push(&fragments[0], now).unwrap(), None);
+        assert_eq!(reassembler.push(&fragments[1], now).unwrap(), Some(packet));
+        assert!(reassembler.pending.is_empty());
+
+        // Every packet gets its own id.
+        let next = fragmenter.split(&[1, 2, 3], 1400).unwrap();
+        assert_eq!(NetworkEndian::read_u16(&next[0][0..2]), 1);
+    }
+
+    #[test]
+    fn test_split_limits() {
+        let mut fragmenter = Fragmenter::new();
+        assert_eq!(fragmenter.split(&[0u8; 100], HEADER_LEN), None);
+        assert_eq!(fragmenter.split(&[0u8; 256], HEADER_LEN + 1), None);
+        assert_eq!(fragmenter.split(&[0u8; 255], HEADER_LEN + 1).unwrap().len(), 255);
+    }
+
+    #[test]
+    fn test_reassembly_errors() {
+        let now = Instant::now();
+        let mut reassembler = Reassembler::new();
+        assert!(reassembler.push(&[0, 1, 0], now).is_err());
+        assert!(reassembler.push(&[0, 1, 2, 2, 0xff], now).is_err());
+
+        // Incomplete packets time out.
+        assert_eq!(reassembler.push(&[0, 1, 0, 2, 0xff], now).unwrap(), None);
+        let later = now + REASSEMBLY_TIMEOUT;
+        assert_eq!(reassembler.push(&[0, 1, 1, 2, 0xff], later).unwrap(), None);
+        assert_eq!(reassembler.pending[&1].received, 1);
+    }
+}
@@ -0,0 +1,574 @@
+/// Authenticated key exchange between vpn and vpnd.
+///
+/// Both sides own an RSA key pair. The client pins the server public key,
+/// the server only talks to clients listed in its allow-list.
+///
+///     client                                      server
+///     INIT      client_pubkey, client_random, client_share  -->
+///               <--  CHALLENGE  server_pubkey, server_share, enc(server_random), sig(H1)
+///     RESPONSE  enc(client_secret), sig(H2)   -->
+///               <--  LEASE      lease, confirm
+///
+///     H1 = sha256(client_pubkey | client_random | client_share |
+///                 server_pubkey | server_share | enc(server_random))
+///     H2 = sha256(H1 | enc(client_secret))
+///     session_key = sha256(client_random | server_random | client_secret | ecdh_secret | H2)
+///
+/// The shares are ephemeral ECDH keys, a leaked RSA key doesn't open recorded sessions.
+/// The address lease is only handed out after the RESPONSE signature checks out.
+use crypto;
+use crypto::ecdh::EphemeralKey;
+use crypto::rsa::{PriKey, PubKey};
+use protocol::{ErrorCode, Hello, Lease, Message};
+
+use std::io;
+use std::net::SocketAddr;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+
+pub const RANDOM_LEN: usize = 32;
+
+const MAX_PENDING: usize = 1024;
+/// Handshakes in progress from a single address, enough for a few clients behind a NAT.
+const MAX_PENDING_PER_IP: usize = 8;
+/// The client gives up on a handshake after as long.
+const PENDING_TIMEOUT: Duration = Duration::from_secs(10);
+
+
+/// Established session, as seen by the server.
+#[derive(Debug)]
+pub struct Session {
+    pub pubkey: PubKey,
+    pub key: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum Step {
+    /// Send this message back to the peer and wait for the next one.
+    Reply(Message),
+    /// The peer is authenticated, a lease can be handed out.
+    Established(Session),
+}
+
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn denied(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, msg.to_string())
+}
+
+fn unexpected(msg: &Message) -> io::Error {
+    match *msg {
+        Message::Error { ref reason, .. } => denied(&format!("handshake rejected by peer: {}", reason)),
+        _ => invalid("unexpected handshake message")
+    }
+}
+
+fn concat(parts: &[&[u8]]) -> Vec<u8> {
+    let mut buf: Vec<u8> = vec![];
+    for part in parts {
+        buf.extend_from_slice(part);
+    }
+    buf
+}
+
+fn confirm(key: &[u8], lease: &[u8]) -> Vec<u8> {
+    crypto::sha256(&concat(&[key, b"lease", lease]))
+}
+
+fn seal(pubkey: &PubKey, secret: &[u8]) -> Result<Vec<u8>, io::Error> {
+    let mut output: Vec<u8> = vec![0u8; pubkey.size()];
+    match pubkey.encrypt_oaep(secret, &mut output) {
+        Ok(size) => {
+            output.truncate(size);
+            Ok(output)
+        },
+        Err(_) => Err(io::Error::new(io::ErrorKind::Other, "RSA encrypt failed"))
+    }
+}
+
+fn open(prikey: &PriKey, sealed: &[u8]) -> Result<Vec<u8>, io::Error> {
+    let mut output: Vec<u8> = vec![0u8; prikey.size()];
+    match prikey.decrypt_oaep(sealed, &mut output) {
+        Ok(size) if size == RANDOM_LEN => {
+            output.truncate(size);
+            Ok(output)
+        },
+        _ => Err(denied("can't decrypt handshake secret"))
+    }
+}
+
+fn sign(prikey: &PriKey, data: &[u8]) -> Result<Vec<u8>, io::Error> {
+    match prikey.sign(data) {
+        Ok(signature) => Ok(signature),
+        Err(_) => Err(io::Error::new(io::ErrorKind::Other, "RSA sign failed"))
+    }
+}
+
+pub fn plain_lease(lease: &Lease) -> Message {
+    Message::Lease { lease: *lease, confirm: vec![] }
+}
+
+pub fn reject(reason: &str) -> Message {
+    Message::Error { code: ErrorCode::Unauthorized, reason: reason.to_string() }
+}
+
+
+pub struct Client<'a> {
+    prikey: &'a PriKey,
+    server_pubkey: &'a PubKey,
+    client_random: Vec<u8>,
+    client_share: Vec<u8>,
+    ephemeral: Option<EphemeralKey>,
+    key: Option<Vec<u8>>,
+}
+
+impl<'a> Client<'a> {
+    pub fn new(prikey: &'a PriKey, server_pubkey: &'a PubKey) -> Client<'a> {
+        let ephemeral = EphemeralKey::gen();
+        Client {
+            prikey: prikey,
+            server_pubkey: server_pubkey,
+            client_random: crypto::random_bytes(RANDOM_LEN),
+            client_share: ephemeral.share(),
+            ephemeral: Some(ephemeral),
+            key: None,
+        }
+    }
+
+    pub fn init(&self) -> Message {
+        Message::Hello(Hello::Init {
+            pubkey: self.prikey.pubkey().to_der(),
+            random: self.client_random.clone(),
+            share: self.client_share.clone(),
+        })
+    }
+
+    pub fn respond(&mut self, challenge: &Message) -> Result<Message, io::Error> {
+        let (server_pubkey_der, server_share, sealed_server_random, server_signature) = match *challenge {
+            Message::Hello(Hello::Challenge { ref pubkey, ref share, ref sealed_random, ref signature }) => {
+                (pubkey, share, sealed_random, signature)
+            },
+            _ => return Err(unexpected(challenge))
+        };
+
+        if &server_pubkey_der[..] != &self.server_pubkey.to_der()[..] {
+            return Err(denied("server public key does not match the pinned key"));
+        }
+
+        let client_pubkey_der = self.prikey.pubkey().to_der();
+        let h1 = crypto::sha256(&concat(&[&client_pubkey_der, &self.client_random, &self.client_share,
+                                          server_pubkey_der, server_share, sealed_server_random]));
+        if !self.server_pubkey.verify(&h1, server_signature) {
+            return Err(denied("bad server signature"));
+        }
+
+        let ephemeral = match self.ephemeral.take() {
+            Some(ephemeral) => ephemeral,
+            None => return Err(invalid("challenge received twice"))
+        };
+        let ecdh_secret = try!(ephemeral.agree(server_share));
+        let server_random = try!(open(self.prikey, sealed_server_random));
+        let client_secret = crypto::random_bytes(RANDOM_LEN);
+        let sealed_client_secret = try!(seal(self.server_pubkey, &client_secret));
+
+        let h2 = crypto::sha256(&concat(&[&h1, &sealed_client_secret]));
+        let client_signature = try!(sign(self.prikey, &h2));
+
+        self.key = Some(crypto::sha256(&concat(&[&self.client_random, &server_random,
+                                                 &client_secret, &ecdh_secret, &h2])));
+
+        Ok(Message::Hello(Hello::Response {
+            sealed_secret: sealed_client_secret,
+            signature: client_signature,
+        }))
+    }
+
+    /// Returns the lease together with the session key.
+    pub fn finish(&self, msg: &Message) -> Result<(Lease, Vec<u8>), io::Error> {
+        let (lease, tag) = match *msg {
+            Message::Lease { ref lease, ref confirm } => (lease, confirm),
+            _ => return Err(unexpected(msg))
+        };
+
+        let key = match self.key {
+            Some(ref key) => key.clone(),
+            None => return Err(invalid("lease received before the challenge")),
+        };
+
+        if &tag[..] != &confirm(&key, &lease.to_bytes())[..] {
+            return Err(denied("lease is not bound to this session"));
+        }
+        Ok((*lease, key))
+    }
+}
+
+
+struct Pending {
+    client_pubkey: PubKey,
+    client_random: Vec<u8>,
+    server_random: Vec<u8>,
+    ecdh_secret: Vec<u8>,
+    h1: Vec<u8>,
+    created: Instant,
+}
+
+pub struct Server<'a> {
+    prikey: &'a PriKey,
+    authorized_keys: &'a [PubKey],
+    pending: HashMap<SocketAddr, Pending>,
+}
+
+impl<'a> Server<'a> {
+    pub fn new(prikey: &'a PriKey, authorized_keys: &'a [PubKey]) -> Server<'a> {
+        Server {
+            prikey: prikey,
+            authorized_keys: authorized_keys,
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn is_authorized(&self, pubkey_der: &[u8]) -> bool {
+        self.authorized_keys.iter().any(|pubkey| &pubkey.to_der()[..] == pubkey_der)
+    }
+
+    pub fn handle(&mut self, peer: SocketAddr, hello: &Hello) -> Result<Step, io::Error> {
+        match *hello {
+            Hello::Init { ref pubkey, ref random, ref share } => self.challenge(peer, pubkey, random, share),
+            Hello::Response { ref sealed_secret, ref signature } => self.accept(peer, sealed_secret, signature),
+            Hello::Plain => Err(denied("crypto is required by this server")),
+            _ => Err(invalid("unexpected handshake message"))
+        }
+    }
+
+    fn challenge(&mut self, peer: SocketAddr, client_pubkey_der: &[u8], client_random: &[u8],
+                 client_share: &[u8]) -> Result<Step, io::Error> {
+        if client_random.len() != RANDOM_LEN {
+            return Err(invalid("malformed client random"));
+        }
+        if !self.is_authorized(client_pubkey_der) {
+            return Err(denied("client public key is not authorized"));
+        }
+        let client_pubkey = match PubKey::from_bytes(client_pubkey_der) {
+            Ok(pubkey) => pubkey,
+            Err(_) => return Err(invalid("malformed client public key"))
+        };
+
+        let ephemeral = EphemeralKey::gen();
+        let server_share = ephemeral.share();
+        let ecdh_secret = try!(ephemeral.agree(client_share));
+
+        let server_pubkey_der = self.prikey.pubkey().to_der();
+        let server_random = crypto::random_bytes(RANDOM_LEN);
+        let sealed_server_random = try!(seal(&client_pubkey, &server_random));
+
+        let h1 = crypto::sha256(&concat(&[client_pubkey_der, client_random, client_share,
+                                          &server_pubkey_der, &server_share, &sealed_server_random]));
+        let server_signature = try!(sign(self.prikey, &h1));
+
+        self.make_room(peer, Instant::now());
+        self.pending.insert(peer, Pending {
+            client_pubkey: client_pubkey,
+            client_random: client_random.to_vec(),
+            server_random: server_random,
+            ecdh_secret: ecdh_secret,
+            h1: h1,
+            created: Instant::now(),
+        });
+
+        Ok(Step::Reply(Message::Hello(Hello::Challenge {
+            pubkey: server_pubkey_der,
+            share: server_share,
+            sealed_random: sealed_server_random,
+            signature: server_signature,
+        })))
+    }
+
+    fn accept(&mut self, peer: SocketAddr, sealed_client_secret: &[u8], client_signature: &[u8])
+            -> Result<Step, io::Error> {
+        let pending = match self.pending.remove(&peer) {
+            Some(pending) => pending,
+            None => return Err(invalid("no handshake in progress for this peer"))
+        };
+        if pending.created.elapsed() >= PENDING_TIMEOUT {
+            return Err(invalid("handshake timed out"));
+        }
+
+        let h2 = crypto::sha256(&concat(&[&pending.h1, sealed_client_secret]));
+        if !pending.client_pubkey.verify(&h2, client_signature) {
+            return Err(denied("bad client signature"));
+        }
+        let client_secret = try!(open(self.prikey, sealed_client_secret));
+
+        let key = crypto::sha256(&concat(&[&pending.client_random, &pending.server_random,
+                                           &client_secret, &pending.ecdh_secret, &h2]));
+        Ok(Step::Established(Session {
+            pubkey: pending.client_pubkey,
+            key: key,
+        }))
+    }
+
+    /// Make room for a handshake from `peer` without dropping every other one: expired
+    /// handshakes go first, then the oldest of the same address, then the oldest overall.
+    fn make_room(&mut self, peer: SocketAddr, now: Instant) {
+        self.pending.retain(|_, pending| now.duration_since(pending.created) < PENDING_TIMEOUT);
+        if self.pending.contains_key(&peer) {
+            return;
+        }
+        let same_ip = self.pending.keys().filter(|addr| addr.ip() == peer.ip()).count();
+        if same_ip >= MAX_PENDING_PER_IP {
+            self.evict_oldest(|addr| addr.ip() == peer.ip());
+        }
+        if self.pending.len() >= MAX_PENDING {
+            self.evict_oldest(|_| true);
+        }
+    }
+
+    fn evict_oldest<F: Fn(&SocketAddr) -> bool>(&mut self, filter: F) {
+        let oldest = self.pending.iter()
+            .filter(|&(addr, _)| filter(addr))
+            .min_by_key(|&(_, pending)| pending.created)
+            .map(|(addr, _)| *addr);
+        if let Some(addr) = oldest {
+            self.pending.remove(&addr);
+        }
+    }
+
+    pub fn lease(&self, session: &Session, lease: &Lease) -> Message {
+        Message::Lease {
+            lease: *lease,
+            confirm: confirm(&session.key, &lease.to_bytes()),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    fn peer() -> SocketAddr {
+        "127.0.0.1:9050".parse().unwrap()
+    }
+
+    fn lease() -> Lease {
+        Lease {
+            tun_ip: "172.16.0.2".parse().unwrap(),
+            public_ip: "203.0.113.1".parse().unwrap(),
+            server_tun_ip: "172.16.0.1".parse().unwrap(),
+            tun_netmask: "255.255.0.0".parse().unwrap(),
+            ipv6: None,
+        }
+    }
+
+    fn hello(msg: &Message) -> &Hello {
+        match *msg {
+            Message::Hello(ref hello) => hello,
+            _ => panic!("not a hello: {:?}", msg)
+        }
+    }
+
+    fn reply(step: Step) -> Message {
+        match step {
+            Step::Reply(msg) => msg,
+            Step::Established(_) => panic!("handshake established too early")
+        }
+    }
+
+    fn established(step: Step) -> Session {
+        match step {
+            Step::Established(session) => session,
+            Step::Reply(msg) => panic!("handshake not established: {:?}", msg)
+        }
+    }
+
+    #[test]
+    fn test_handshake() {
+        let server_prikey = PriKey::gen(1024).unwrap();
+        let client_prikey = PriKey::gen(1024).unwrap();
+        let authorized_keys = vec![client_prikey.pubkey()];
+        let server_pubkey = server_prikey.pubkey();
+
+        let mut server = Server::new(&server_prikey, &authorized_keys);
+        let mut client = Client::new(&client_prikey, &server_pubkey);
+
+        let challenge = reply(server.handle(peer(), hello(&client.init())).unwrap());
+        let response = client.respond(&challenge).unwrap();
+        let session = established(server.handle(peer(), hello(&response)).unwrap());
+        assert_eq!(&session.pubkey.to_der()[..], &client_prikey.pubkey().to_der()[..]);
+
+        let (client_lease, key) = client.finish(&server.lease(&session, &lease())).unwrap();
+        assert_eq!(client_lease, lease());
+        assert_eq!(key, session.key);
+        assert_eq!(key.len(), 32);
+
+        // A lease not bound to the session, as an on-path attacker would send it.
+        assert_eq!(client.finish(&plain_lease(&lease())).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn test_unauthorized_client() {
+        let server_prikey = PriKey::gen(1024).unwrap();
+        let client_prikey = PriKey::gen(1024).unwrap();
+        let authorized_keys = vec![PriKey::gen(1024).unwrap().pubkey()];
+        let server_pubkey = server_prikey.pubkey();
+
+        let mut server = Server::new(&server_prikey, &authorized_keys);
+        let client = Client::new(&client_prikey, &server_pubkey);
+        let e = server.handle(peer(), hello(&client.init())).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+
+        // Nothing is pending, a response alone goes nowhere.
+        let response = Hello::Response { sealed_secret: vec![0u8; 128], signature: vec![0u8; 128] };
+        assert!(server.handle(peer(), &response).is_err());
+        assert!(server.handle(peer(), &Hello::Plain).is_err());
+    }
+
+    #[test]
+    fn test_bad_client_signature() {
+        let server_prikey = PriKey::gen(1024).unwrap();
+        let client_prikey = PriKey::gen(1024).unwrap();
+        let authorized_keys = vec![client_prikey.pubkey()];
+        let server_pubkey = server_prikey.pubkey();
+
+        let mut server = Server::new(&server_prikey, &authorized_keys);
+        let mut client = Client::new(&client_prikey, &server_pubkey);
+        let challenge = reply(server.handle(peer(), hello(&client.init())).unwrap());
+        let response = match client.respond(&challenge).unwrap() {
+            Message::Hello(Hello::Response { sealed_secret, mut signature }) => {
+                signature[0] ^= 1;
+                Hello::Response { sealed_secret: sealed_secret, signature: signature }
+            },
+            msg => panic!("not a response: {:?}", msg)
+        };
+        let e = server.handle(peer(), &response).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn test_replayed_response() {
+        let server_prikey = PriKey::gen(1024).unwrap();
+        let client_prikey = PriKey::gen(1024).unwrap();
+        let authorized_keys = vec![client_prikey.pubkey()];
+        let server_pubkey = server_prikey.pubkey();
+
+        let mut server = Server::new(&server_prikey, &authorized_keys);
+        let mut client = Client::new(&client_prikey, &server_pubkey);
+        let init = client.init();
+        let challenge = reply(server.handle(peer(), hello(&init)).unwrap());
+        let response = client.respond(&challenge).unwrap();
+        established(server.handle(peer(), hello(&response)).unwrap());
+
+        // The handshake is over, the same response again is rejected.
+        assert!(server.handle(peer(), hello(&response)).is_err());
+
+        // Replaying the recorded init and response: the server picks a new random,
+        // the old signature doesn't cover it.
+        reply(server.handle(peer(), hello(&init)).unwrap());
+        let e = server.handle(peer(), hello(&response)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn test_wrong_server_key() {
+        let server_prikey = PriKey::gen(1024).unwrap();
+        let client_prikey = PriKey::gen(1024).unwrap();
+        let authorized_keys = vec![client_prikey.pubkey()];
+        let pinned_pubkey = PriKey::gen(1024).unwrap().pubkey();
+
+        let mut server = Server::new(&server_prikey, &authorized_keys);
+        let mut client = Client::new(&client_prikey, &pinned_pubkey);
+        let challenge = reply(server.handle(peer(), hello(&client.init())).unwrap());
+        let e = client.respond(&challenge).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+
+        // An impostor presenting the pinned key can't sign with it.
+        let forged = match challenge {
+            Message::Hello(Hello::Challenge { share, sealed_random, signature, .. }) => {
+                Message::Hello(Hello::Challenge {
+                    pubkey: pinned_pubkey.to_der(),
+                    share: share,
+                    sealed_random: sealed_random,
+                    signature: signature,
+                })
+            },
+            msg => panic!("not a challenge: {:?}", msg)
+        };
+        assert_eq!(client.respond(&forged).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+        // Errors of the server surface as such.
+        let rejected = Message::Error { code: ErrorCode::Unauthorized, reason: "nope".to_string() };
+        assert_eq!(client.respond(&rejected).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn test_tampered_share() {
+        let server_prikey = PriKey::gen(1024).unwrap();
+        let client_prikey = PriKey::gen(1024).unwrap();
+        let authorized_keys = vec![client_prikey.pubkey()];
+        let server_pubkey = server_prikey.pubkey();
+
+        let mut server = Server::new(&server_prikey, &authorized_keys);
+        let mut client = Client::new(&client_prikey, &server_pubkey);
+
+        // Not a curve point.
+        let init = match client.init() {
+            Message::Hello(Hello::Init { pubkey, random, share }) => {
+                Hello::Init { pubkey: pubkey, random: random, share: share[..33].to_vec() }
+            },
+            msg => panic!("not an init: {:?}", msg)
+        };
+        assert_eq!(server.handle(peer(), &init).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // A man in the middle swapping in his own share breaks the server signature.
+        let challenge = match reply(server.handle(peer(), hello(&client.init())).unwrap()) {
+            Message::Hello(Hello::Challenge { pubkey, sealed_random, signature, .. }) => {
+                Message::Hello(Hello::Challenge {
+                    pubkey: pubkey,
+                    share: EphemeralKey::gen().share(),
+                    sealed_random: sealed_random,
+                    signature: signature,
+                })
+            },
+            msg => panic!("not a challenge: {:?}", msg)
+        };
+        assert_eq!(client.respond(&challenge).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn test_pending_flood() {
+        let server_prikey = PriKey::gen(1024).unwrap();
+        let client_prikey = PriKey::gen(1024).unwrap();
+        let authorized_keys = vec![client_prikey.pubkey()];
+        let server_pubkey = server_prikey.pubkey();
+
+        let mut server = Server::new(&server_prikey, &authorized_keys);
+        let mut client = Client::new(&client_prikey, &server_pubkey);
+        let challenge = reply(server.handle(peer(), hello(&client.init())).unwrap());
+
+        // Authorized public keys are public, anyone can send inits with them.
+        let init = Client::new(&client_prikey, &server_pubkey).init();
+        for port in 0..MAX_PENDING as u16 + 1 {
+            let spoofed = SocketAddr::new("198.51.100.7".parse().unwrap(), 10000 + port);
+            reply(server.handle(spoofed, hello(&init)).unwrap());
+        }
+        assert_eq!(server.pending.len(), MAX_PENDING_PER_IP + 1);
+
+        let response = client.respond(&challenge).unwrap();
+        established(server.handle(peer(), hello(&response)).unwrap());
+
+        // From as many addresses, only the oldest handshakes make way.
+        for i in 0..MAX_PENDING as u32 + 1 {
+            let spoofed = SocketAddr::new(Ipv4Addr::from(0x0a00_0000 + i).into(), 9050);
+            reply(server.handle(spoofed, hello(&init)).unwrap());
+        }
+        assert_eq!(server.pending.len(), MAX_PENDING);
+        assert!(!server.pending.contains_key(&"198.51.100.7:11024".parse().unwrap()));
+        assert!(server.pending.contains_key(&"10.0.4.0:9050".parse().unwrap()));
+    }
+}
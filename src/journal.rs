@@ -0,0 +1,558 @@
+/// Undo journal of the system changes made by auto config.
+///
+/// How to undo a change is written to disk before the change is made, so a
+/// journal left on disk belongs to a run which died before cleaning up, and
+/// `vpn --recover` (`vpnd --cleanup`) replays it. `rollback` undoes the changes last first, and
+/// dropping a journal rolls it back too, panics included.
+use toml;
+use ipnetwork::IpNetwork;
+use netif::route::{self, Destination, Gateway};
+#[cfg(target_os = "linux")]
+use netif::nftables;
+#[cfg(target_os = "linux")]
+use firewall;
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+#[cfg(target_os = "macos")]
+use std::process;
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Undo {
+    /// Delete a route which did not exist before, only the one through `gateway` or `interface`.
+    DeleteRoute {
+        destination: IpNetwork,
+        gateway: Option<IpAddr>,
+        interface: Option<String>,
+    },
+    /// Put back the route a change replaced.
+    RestoreRoute {
+        destination: IpNetwork,
+        gateway: Option<IpAddr>,
+        interface: Option<String>,
+    },
+    /// Write back the previous contents of a file.
+    RestoreFile {
+        path: PathBuf,
+        contents: String,
+    },
+    /// Set the nameservers of a macOS network service back, `Empty` when they came from DHCP.
+    RestoreDns {
+        networkservice: String,
+        servers: Vec<String>,
+    },
+    /// Delete the masquerading vpnd set up for a tunnel network, `backend` is a `firewall::Backend`.
+    RemoveMasquerade {
+        backend: String,
+        network: IpNetwork,
+    },
+}
+
+impl fmt::Display for Undo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Undo::DeleteRoute { ref destination, ref gateway, ref interface } |
+            Undo::RestoreRoute { ref destination, ref gateway, ref interface } => {
+                let verb = match *self {
+                    Undo::DeleteRoute { .. } => "delete",
+                    _ => "restore",
+                };
+                try!(write!(f, "{} route {}", verb, destination));
+                if let Some(ref gateway) = *gateway {
+                    try!(write!(f, " via {}", gateway));
+                }
+                if let Some(ref interface) = *interface {
+                    try!(write!(f, " dev {}", interface));
+                }
+                Ok(())
+            },
+            Undo::RestoreFile { ref path, .. } => write!(f, "restore {}", path.display()),
+            Undo::RestoreDns { ref networkservice, ref servers } => {
+                write!(f, "restore nameservers of {} to {}", networkservice, servers.join(" "))
+            },
+            Undo::RemoveMasquerade { ref backend, ref network } => {
+                write!(f, "remove masquerading of {} ({})", network, backend)
+            }
+        }
+    }
+}
+
+impl Undo {
+    fn apply(&self) -> Result<(), io::Error> {
+        match *self {
+            Undo::DeleteRoute { ref destination, ref gateway, ref interface } => {
+                let ret = match (*gateway, interface) {
+                    (Some(gateway), _) => route::delete(&Destination::IpNetwork(*destination),
+                                                        &Gateway::IpAddress(gateway),
+                                                        interface.as_ref().map(|ifname| ifname.as_str())),
+                    (None, &Some(ref ifname)) => route::delete(&Destination::IpNetwork(*destination),
+                                                               &Gateway::Interface(ifname.clone()), None),
+                    (None, &None) => return Err(invalid("route without gateway nor interface"))
+                };
+                match ret {
+                    // Gone already, along with its interface for instance.
+                    Ok(()) | Err(route::Error::NotFound) | Err(route::Error::NoSuchInterface(_)) => Ok(()),
+                    Err(e) => Err(e.into())
+                }
+            },
+            Undo::RestoreRoute { ref destination, ref gateway, ref interface } => {
+                let ret = match (*gateway, interface) {
+                    (Some(gateway), _) => route::replace(&Destination::IpNetwork(*destination),
+                                                         &Gateway::IpAddress(gateway),
+                                                         interface.as_ref().map(|ifname| ifname.as_str())),
+                    (None, &Some(ref ifname)) => route::replace(&Destination::IpNetwork(*destination),
+                                                                &Gateway::Interface(ifname.clone()), None),
+                    (None, &None) => return Err(invalid("route without gateway nor interface"))
+                };
+                ret.map_err(io::Error::from)
+            },
+            Undo::RestoreFile { ref path, ref contents } => write_file(path, contents),
+            #[cfg(target_os = "macos")]
+            Undo::RestoreDns { ref networkservice, ref servers } => set_dns_servers(networkservice, servers),
+            #[cfg(not(target_os = "macos"))]
+            Undo::RestoreDns { .. } => Err(invalid("network services are macOS only")),
+            #[cfg(target_os = "linux")]
+            Undo::RemoveMasquerade { ref backend, ref network } => {
+                let backend: firewall::Backend = try!(backend.parse());
+                backend.remove(nftables::Family::of(network))
+            },
+            #[cfg(not(target_os = "linux"))]
+            Undo::RemoveMasquerade { .. } => Err(invalid("masquerading is Linux only")),
+        }
+    }
+
+    fn to_toml(&self) -> toml::Value {
+        let mut fields: Vec<(&str, String)> = vec![];
+        match *self {
+            Undo::DeleteRoute { ref destination, ref gateway, ref interface } |
+            Undo::RestoreRoute { ref destination, ref gateway, ref interface } => {
+                let undo = match *self {
+                    Undo::DeleteRoute { .. } => "delete-route",
+                    _ => "restore-route",
+                };
+                fields.push(("undo", undo.to_string()));
+                fields.push(("destination", format!("{}", destination)));
+                if let Some(ref gateway) = *gateway {
+                    fields.push(("gateway", format!("{}", gateway)));
+                }
+                if let Some(ref interface) = *interface {
+                    fields.push(("interface", interface.clone()));
+                }
+            },
+            Undo::RestoreFile { ref path, ref contents } => {
+                fields.push(("undo", "restore-file".to_string()));
+                fields.push(("path", format!("{}", path.display())));
+                fields.push(("contents", contents.clone()));
+            },
+            Undo::RestoreDns { ref networkservice, ref servers } => {
+                fields.push(("undo", "restore-dns".to_string()));
+                fields.push(("networkservice", networkservice.clone()));
+                fields.push(("servers", servers.join(" ")));
+            },
+            Undo::RemoveMasquerade { ref backend, ref network } => {
+                fields.push(("undo", "remove-masquerade".to_string()));
+                fields.push(("backend", backend.clone()));
+                fields.push(("network", format!("{}", network)));
+            }
+        }
+        let mut table = toml::value::Table::new();
+        for (key, value) in fields {
+            table.insert(key.to_string(), toml::Value::String(value));
+        }
+        toml::Value::Table(table)
+    }
+
+    fn from_toml(value: &toml::Value) -> Result<Undo, io::Error> {
+        let table = match *value {
+            toml::Value::Table(ref table) => table,
+            _ => return Err(invalid("journal step is not a table"))
+        };
+        let undo: String = try!(field(table, "undo"));
+        match undo.as_str() {
+            "delete-route" => Ok(Undo::DeleteRoute {
+                destination: try!(field(table, "destination")),
+                gateway: try!(optional(table, "gateway")),
+                interface: try!(optional(table, "interface")),
+            }),
+            "restore-route" => Ok(Undo::RestoreRoute {
+                destination: try!(field(table, "destination")),
+                gateway: try!(optional(table, "gateway")),
+                interface: try!(optional(table, "interface")),
+            }),
+            "restore-file" => Ok(Undo::RestoreFile {
+                path: PathBuf::from(try!(field::<String>(table, "path"))),
+                contents: try!(field(table, "contents")),
+            }),
+            "restore-dns" => Ok(Undo::RestoreDns {
+                networkservice: try!(field(table, "networkservice")),
+                servers: try!(field::<String>(table, "servers")).split_whitespace().map(String::from).collect(),
+            }),
+            "remove-masquerade" => Ok(Undo::RemoveMasquerade {
+                backend: try!(field(table, "backend")),
+                network: try!(field(table, "network")),
+            }),
+            _ => Err(invalid(&format!("unknown journal step {:?}", undo)))
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn optional<T: FromStr>(table: &toml::value::Table, key: &str) -> Result<Option<T>, io::Error> {
+    match table.get(key) {
+        Some(&toml::Value::String(ref value)) => match value.parse() {
+            Ok(value) => Ok(Some(value)),
+            Err(_) => Err(invalid(&format!("invalid {} {:?} in journal", key, value)))
+        },
+        Some(_) => Err(invalid(&format!("{} in journal is not a string", key))),
+        None => Ok(None)
+    }
+}
+
+fn field<T: FromStr>(table: &toml::value::Table, key: &str) -> Result<T, io::Error> {
+    match try!(optional(table, key)) {
+        Some(value) => Ok(value),
+        None => Err(invalid(&format!("journal step without {}", key)))
+    }
+}
+
+
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    steps: Vec<Undo>,
+}
+
+impl Journal {
+    /// Start an empty journal, refuses to overwrite one left by a previous run.
+    pub fn create(path: &Path) -> Result<Journal, io::Error> {
+        if path.exists() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                      format!("{} is left from a previous run, restore it first",
+                                              path.display())));
+        }
+        let journal = Journal { path: path.to_path_buf(), steps: vec![] };
+        try!(journal.save());
+        Ok(journal)
+    }
+
+    pub fn load(path: &Path) -> Result<Journal, io::Error> {
+        let mut text = String::new();
+        try!(try!(File::open(path)).read_to_string(&mut text));
+        let steps = match text.parse::<toml::Value>() {
+            Ok(toml::Value::Table(root)) => match root.get("steps") {
+                Some(&toml::Value::Array(ref steps)) => steps.clone(),
+                Some(_) => return Err(invalid("steps is not an array")),
+                None => vec![]
+            },
+            Ok(_) => return Err(invalid("top level must be a table")),
+            Err(e) => return Err(invalid(&format!("{}", e)))
+        };
+        let mut journal = Journal { path: path.to_path_buf(), steps: vec![] };
+        for step in steps.iter() {
+            journal.steps.push(try!(Undo::from_toml(step)));
+        }
+        Ok(journal)
+    }
+
+    pub fn steps(&self) -> &[Undo] {
+        &self.steps
+    }
+
+    fn save(&self) -> Result<(), io::Error> {
+        let mut root = toml::value::Table::new();
+        root.insert("steps".to_string(), toml::Value::Array(self.steps.iter().map(Undo::to_toml).collect()));
+        let text = match toml::to_string(&toml::Value::Table(root)) {
+            Ok(text) => text,
+            Err(e) => return Err(io::Error::new(io::ErrorKind::Other, format!("{}", e)))
+        };
+
+        // Renamed over the journal, a crash never leaves half of one.
+        let tmp = PathBuf::from(format!("{}.tmp", self.path.display()));
+        {
+            let mut file = try!(OpenOptions::new().write(true).create(true).truncate(true).open(&tmp));
+            try!(file.write_all(text.as_bytes()));
+            try!(file.sync_all());
+        }
+        fs::rename(&tmp, &self.path)
+    }
+
+    fn record(&mut self, undo: Undo) -> Result<(), io::Error> {
+        debug!("journal: {}", undo);
+        self.steps.push(undo);
+        self.save()
+    }
+
+    /// Route `destination` through `gateway`, rollback puts back the route it had before, if any.
+    pub fn replace_route(&mut self, destination: &Destination, gateway: &Gateway,
+                         interface: Option<&str>) -> Result<(), io::Error> {
+        let network = destination.network();
+        let undo = match find_route(&try!(route::list()), &network) {
+            Some(undo) => undo,
+            None => delete_route(network, gateway, interface)
+        };
+        try!(self.record(undo));
+        route::replace(destination, gateway, interface).map_err(|e| route_error(&network, e))
+    }
+
+    /// Route `destination` unless a route to it exists, rollback only deletes a route it added.
+    pub fn add_route(&mut self, destination: &Destination, gateway: &Gateway,
+                     interface: Option<&str>) -> Result<(), io::Error> {
+        let network = destination.network();
+        if find_route(&try!(route::list()), &network).is_some() {
+            return Ok(());
+        }
+        try!(self.record(delete_route(network, gateway, interface)));
+        route::add(destination, gateway, interface).map_err(|e| route_error(&network, e))
+    }
+
+    /// `replace_route` for many destinations, journaled with a single write before the first change.
+    pub fn replace_routes(&mut self, destinations: &[Destination], gateway: &Gateway,
+                          interface: Option<&str>) -> Result<(), io::Error> {
+        let tables = try!(route::list());
+        for destination in destinations.iter() {
+            let network = destination.network();
+            let undo = match find_route(&tables, &network) {
+                Some(undo) => undo,
+                None => delete_route(network, gateway, interface)
+            };
+            debug!("journal: {}", undo);
+            self.steps.push(undo);
+        }
+        try!(self.save());
+        for destination in destinations.iter() {
+            try!(route::replace(destination, gateway, interface)
+                 .map_err(|e| route_error(&destination.network(), e)));
+        }
+        Ok(())
+    }
+
+    pub fn write_file(&mut self, path: &Path, contents: &str) -> Result<(), io::Error> {
+        let mut previous = String::new();
+        try!(try!(File::open(path)).read_to_string(&mut previous));
+        try!(self.record(Undo::RestoreFile { path: path.to_path_buf(), contents: previous }));
+        write_file(path, contents)
+    }
+
+    #[cfg(target_os = "macos")]
+    pub fn set_dns_servers(&mut self, networkservice: &str, servers: &[String]) -> Result<(), io::Error> {
+        let previous = try!(get_dns_servers(networkservice));
+        try!(self.record(Undo::RestoreDns { networkservice: networkservice.to_string(), servers: previous }));
+        set_dns_servers(networkservice, servers)
+    }
+
+    /// Masquerade `source` leaving through `oifname`, rollback deletes the rules.
+    #[cfg(target_os = "linux")]
+    pub fn masquerade(&mut self, backend: firewall::Backend, source: &IpNetwork,
+                      oifname: &str) -> Result<(), io::Error> {
+        try!(self.record(Undo::RemoveMasquerade { backend: backend.to_string(), network: *source }));
+        backend.masquerade(source, oifname)
+    }
+
+    /// Undo every change, last first.
+    ///
+    /// The journal is removed from disk once everything is undone. Steps which
+    /// failed are kept there for `--recover`, but not retried when dropped.
+    pub fn rollback(&mut self) -> Result<(), io::Error> {
+        let mut failed = vec![];
+        while let Some(undo) = self.steps.pop() {
+            match undo.apply() {
+                Ok(()) => debug!("journal: {} [OK]", undo),
+                Err(e) => {
+                    error!("can't {}: {}", undo, e);
+                    failed.push(undo);
+                }
+            }
+        }
+        if failed.is_empty() {
+            return match fs::remove_file(&self.path) {
+                Ok(()) => Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                Err(e) => Err(e)
+            };
+        }
+
+        failed.reverse();
+        let count = failed.len();
+        self.steps = failed;
+        let ret = self.save();
+        self.steps.clear();
+        try!(ret);
+        Err(io::Error::new(io::ErrorKind::Other,
+                           format!("{} changes not undone, kept in {}", count, self.path.display())))
+    }
+}
+
+impl Drop for Journal {
+    fn drop(&mut self) {
+        if !self.steps.is_empty() {
+            warn!("rolling back {} system changes", self.steps.len());
+            let _ = self.rollback();
+        }
+    }
+}
+
+/// Undo what a previous run left in the journal at `path`, returns false when there is none.
+pub fn recover(path: &Path) -> Result<bool, io::Error> {
+    if !path.exists() {
+        return Ok(false);
+    }
+    let mut journal = try!(Journal::load(path));
+    try!(journal.rollback());
+    Ok(true)
+}
+
+
+fn route_error(network: &IpNetwork, e: route::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("can't route {}: {}", network, e))
+}
+
+/// Undo record deleting the route to `network` about to be added through `gateway`.
+fn delete_route(network: IpNetwork, gateway: &Gateway, interface: Option<&str>) -> Undo {
+    let (gateway, interface) = match *gateway {
+        Gateway::IpAddress(ip) => (Some(ip), interface.map(String::from)),
+        Gateway::Interface(ref ifname) => (None, Some(ifname.clone())),
+        _ => (None, interface.map(String::from))
+    };
+    Undo::DeleteRoute { destination: network, gateway: gateway, interface: interface }
+}
+
+/// Undo record putting back the route to exactly `network`, found in `tables`.
+fn find_route(tables: &[route::Table], network: &IpNetwork) -> Option<Undo> {
+    for table in tables.iter() {
+        let found = match *table.destination() {
+            Destination::IpNetwork(nw) => nw == *network,
+            // The BSD table has no netmask for network routes, only host routes have an address alone.
+            Destination::IpAddress(ip) => {
+                let host = Destination::IpNetwork(*network).is_host();
+                ip == network.ip() && table.flags().contains(route::Flags::RTF_HOST) == host
+            }
+        };
+        if !found {
+            continue;
+        }
+        let interface = if table.ifname().is_empty() { None } else { Some(table.ifname().to_string()) };
+        let gateway = match *table.gateway() {
+            Gateway::IpAddress(ip) => Some(ip),
+            _ => None
+        };
+        return Some(Undo::RestoreRoute { destination: *network, gateway: gateway, interface: interface });
+    }
+    None
+}
+
+fn write_file(path: &Path, contents: &str) -> Result<(), io::Error> {
+    let mut file = try!(OpenOptions::new().write(true).create(true).truncate(true).open(path));
+    file.write_all(contents.as_bytes())
+}
+
+#[cfg(target_os = "macos")]
+fn get_dns_servers(networkservice: &str) -> Result<Vec<String>, io::Error> {
+    // networksetup -getdnsservers Wi-Fi
+    // There aren't any DNS Servers set on Wi-Fi.
+    let output = try!(process::Command::new("networksetup")
+                      .arg("-getdnsservers")
+                      .arg(networkservice)
+                      .output());
+    if !output.status.success() {
+        return Err(io::Error::new(io::ErrorKind::Other, "networksetup -getdnsservers failed"));
+    }
+    let servers: Vec<String> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| line.trim().parse::<IpAddr>().is_ok())
+        .map(|line| line.trim().to_string())
+        .collect();
+    if servers.is_empty() {
+        Ok(vec!["Empty".to_string()])
+    } else {
+        Ok(servers)
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn set_dns_servers(networkservice: &str, servers: &[String]) -> Result<(), io::Error> {
+    // sudo networksetup -setdnsservers Wi-Fi 8.8.8.8
+    let status = try!(process::Command::new("networksetup")
+                      .arg("-setdnsservers")
+                      .arg(networkservice)
+                      .args(servers)
+                      .status());
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::Other, "networksetup -setdnsservers failed"))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libc;
+    use std::env;
+    use std::mem;
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("exodus-{}-{}", unsafe { libc::getpid() }, name))
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let path = temp_path("round-trip.journal");
+        let mut journal = Journal::create(&path).unwrap();
+        journal.record(Undo::DeleteRoute {
+            destination: "0.0.0.0/1".parse().unwrap(),
+            gateway: None,
+            interface: Some("utun4".to_string()),
+        }).unwrap();
+        journal.record(Undo::RestoreRoute {
+            destination: "0.0.0.0/0".parse().unwrap(),
+            gateway: Some("fe80::1".parse().unwrap()),
+            interface: Some("en0".to_string()),
+        }).unwrap();
+        journal.record(Undo::RestoreFile {
+            path: PathBuf::from("/etc/resolv.conf"),
+            contents: "# \"quoted\"\nnameserver 192.168.1.1\n".to_string(),
+        }).unwrap();
+        journal.record(Undo::RestoreDns {
+            networkservice: "USB 10/100 LAN".to_string(),
+            servers: vec!["Empty".to_string()],
+        }).unwrap();
+        journal.record(Undo::RemoveMasquerade {
+            backend: "nftables".to_string(),
+            network: "fd00:6578:6f64::/64".parse().unwrap(),
+        }).unwrap();
+
+        assert!(Journal::create(&path).is_err());
+        assert_eq!(Journal::load(&path).unwrap().steps(), journal.steps());
+        fs::remove_file(&path).unwrap();
+        mem::forget(journal);
+    }
+
+    #[test]
+    fn test_recover() {
+        let path = temp_path("recover.journal");
+        let file = temp_path("recover.conf");
+        write_file(&file, "before").unwrap();
+
+        let mut journal = Journal::create(&path).unwrap();
+        journal.write_file(&file, "during").unwrap();
+        // Dies without rolling back.
+        mem::forget(journal);
+
+        assert_eq!(recover(&path).unwrap(), true);
+        let mut contents = String::new();
+        File::open(&file).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "before");
+        assert!(!path.exists());
+        assert_eq!(recover(&path).unwrap(), false);
+        fs::remove_file(&file).unwrap();
+    }
+}
@@ -0,0 +1,180 @@
+/// TCP MSS clamping, so inner connections never send segments too big for the
+/// tunnel even when the ICMP errors of `mtu` are dropped on the way.
+///
+/// The MSS option of SYN and SYN-ACK segments crossing the tunnel is lowered in
+/// place and the TCP checksum adjusted incrementally (RFC 1624). IP fragments,
+/// IPv6 extension headers and malformed segments are left alone.
+use byteorder::{ByteOrder, NetworkEndian};
+use smoltcp::wire;
+
+use mtu;
+
+
+const TCP_HEADER_LEN: usize = 20;
+const TCP_CHECKSUM: usize = 16;
+
+
+/// Largest MSS of a segment fitting an MTU of `mtu` bytes.
+pub fn max_mss(mtu: usize, ipv6: bool) -> u16 {
+    let ip_header_len = if ipv6 { mtu::IPV6_HEADER_LEN } else { mtu::IPV4_HEADER_LEN };
+    mtu.saturating_sub(ip_header_len + TCP_HEADER_LEN) as u16
+}
+
+/// Lower the MSS option of a TCP SYN in `packet` to fit `mtu`, returns whether it changed.
+pub fn clamp(packet: &mut [u8], mtu: usize) -> bool {
+    match wire::IpVersion::of_packet(packet) {
+        Ok(wire::IpVersion::Ipv4) => {
+            let mut ipv4_packet = match wire::Ipv4Packet::new_checked(&mut packet[..]) {
+                Ok(ipv4_packet) => ipv4_packet,
+                Err(_) => return false
+            };
+            if ipv4_packet.protocol() != wire::IpProtocol::Tcp
+                || ipv4_packet.more_frags() || ipv4_packet.frag_offset() != 0 {
+                return false;
+            }
+            clamp_segment(ipv4_packet.payload_mut(), max_mss(mtu, false))
+        },
+        Ok(wire::IpVersion::Ipv6) => {
+            let mut ipv6_packet = match wire::Ipv6Packet::new_checked(&mut packet[..]) {
+                Ok(ipv6_packet) => ipv6_packet,
+                Err(_) => return false
+            };
+            if ipv6_packet.next_header() != wire::IpProtocol::Tcp {
+                return false;
+            }
+            clamp_segment(ipv6_packet.payload_mut(), max_mss(mtu, true))
+        },
+        _ => false
+    }
+}
+
+fn clamp_segment(segment: &mut [u8], max_mss: u16) -> bool {
+    let (offset, mss, checksum) = {
+        let tcp_packet = match wire::TcpPacket::new_checked(&segment[..]) {
+            Ok(tcp_packet) => tcp_packet,
+            Err(_) => return false
+        };
+        if !tcp_packet.syn() {
+            return false;
+        }
+        match find_mss(tcp_packet.options()) {
+            Some((offset, mss)) if mss > max_mss => (TCP_HEADER_LEN + offset, mss, tcp_packet.checksum()),
+            _ => return false
+        }
+    };
+    NetworkEndian::write_u16(&mut segment[offset..offset + 2], max_mss);
+    // A word at an odd offset adds to the sum with its bytes swapped.
+    let (old, new) = if offset % 2 == 0 { (mss, max_mss) } else { (mss.swap_bytes(), max_mss.swap_bytes()) };
+    NetworkEndian::write_u16(&mut segment[TCP_CHECKSUM..TCP_CHECKSUM + 2], adjust_checksum(checksum, old, new));
+    true
+}
+
+/// Offset of the MSS value within the TCP options and the value itself.
+fn find_mss(options: &[u8]) -> Option<(usize, u16)> {
+    let mut rest = options;
+    while !rest.is_empty() {
+        let offset = options.len() - rest.len();
+        match wire::TcpOption::parse(rest) {
+            Ok((_, wire::TcpOption::EndOfList)) => return None,
+            Ok((_, wire::TcpOption::MaxSegmentSize(mss))) => return Some((offset + 2, mss)),
+            Ok((next, _)) => rest = next,
+            Err(_) => return None
+        }
+    }
+    None
+}
+
+/// HC' = ~(~HC + ~m + m'), RFC 1624 eqn. 3.
+fn adjust_checksum(checksum: u16, old: u16, new: u16) -> u16 {
+    let mut sum = (!checksum) as u32 + (!old) as u32 + new as u32;
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 172.16.0.2:51234 > 93.184.216.34:443 [S] mss 1460,sackOK,TS,nop,wscale 7
+    const SYN_IPV4: [u8; 60] = [
+        0x45, 0x00, 0x00, 0x3c, 0x1c, 0x46, 0x40, 0x00, 0x40, 0x06, 0x3c, 0x89, 0xac, 0x10, 0x00, 0x02,
+        0x5d, 0xb8, 0xd8, 0x22, 0xc8, 0x22, 0x01, 0xbb, 0x3f, 0x1a, 0x2b, 0x4c, 0x00, 0x00, 0x00, 0x00,
+        0xa0, 0x02, 0xfa, 0xf0, 0x00, 0x86, 0x00, 0x00, 0x02, 0x04, 0x05, 0xb4, 0x04, 0x02, 0x08, 0x0a,
+        0x0a, 0x1b, 0x2c, 0x3d, 0x00, 0x00, 0x00, 0x00, 0x01, 0x03, 0x03, 0x07,
+    ];
+
+    // 2606:2800:220:1:248:1893:25c8:1946.443 > fd00:6578:646f:6400::2.51234 [S.] mss 1440,sackOK,TS,nop,wscale 9
+    const SYN_ACK_IPV6: [u8; 80] = [
+        0x60, 0x00, 0x00, 0x00, 0x00, 0x28, 0x06, 0x40, 0x26, 0x06, 0x28, 0x00, 0x02, 0x20, 0x00, 0x01,
+        0x02, 0x48, 0x18, 0x93, 0x25, 0xc8, 0x19, 0x46, 0xfd, 0x00, 0x65, 0x78, 0x64, 0x6f, 0x64, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x01, 0xbb, 0xc8, 0x22, 0x77, 0x66, 0x55, 0x44,
+        0x3f, 0x1a, 0x2b, 0x4d, 0xa0, 0x12, 0xfe, 0x88, 0xf8, 0xcf, 0x00, 0x00, 0x02, 0x04, 0x05, 0xa0,
+        0x04, 0x02, 0x08, 0x0a, 0x11, 0x22, 0x33, 0x44, 0x0a, 0x1b, 0x2c, 0x3d, 0x01, 0x03, 0x03, 0x09,
+    ];
+
+    // 172.16.0.2:40000 > 10.0.0.1:22 [S] nop,mss 1460,nop,sackOK
+    const SYN_ODD_MSS: [u8; 48] = [
+        0x45, 0x00, 0x00, 0x30, 0x1c, 0x46, 0x40, 0x00, 0x40, 0x06, 0x68, 0x6f, 0xac, 0x10, 0x00, 0x02,
+        0x0a, 0x00, 0x00, 0x01, 0x9c, 0x40, 0x00, 0x16, 0x01, 0x02, 0x03, 0x04, 0x00, 0x00, 0x00, 0x00,
+        0x70, 0x02, 0x72, 0x10, 0x0a, 0x50, 0x00, 0x00, 0x01, 0x02, 0x04, 0x05, 0xb4, 0x01, 0x04, 0x02,
+    ];
+
+    fn ipv4_segment(packet: &[u8]) -> (u16, bool) {
+        let ipv4_packet = wire::Ipv4Packet::new_checked(packet).unwrap();
+        let tcp_packet = wire::TcpPacket::new_checked(ipv4_packet.payload()).unwrap();
+        let valid = tcp_packet.verify_checksum(&ipv4_packet.src_addr().into(), &ipv4_packet.dst_addr().into());
+        (find_mss(tcp_packet.options()).unwrap().1, valid)
+    }
+
+    #[test]
+    fn test_clamp_ipv4() {
+        let mut packet = SYN_IPV4;
+        assert_eq!(ipv4_segment(&packet), (1460, true));
+        assert!(clamp(&mut packet, 1400));
+        assert_eq!(ipv4_segment(&packet), (1360, true));
+        // Only the MSS and the checksum change.
+        assert_eq!(packet[..36], SYN_IPV4[..36]);
+        assert_eq!(packet[44..], SYN_IPV4[44..]);
+
+        // Already small enough.
+        assert!(!clamp(&mut packet, 1400));
+        assert!(!clamp(&mut packet, 1500));
+        assert_eq!(ipv4_segment(&packet), (1360, true));
+
+        let mut packet = SYN_ODD_MSS;
+        assert!(clamp(&mut packet, 1280));
+        assert_eq!(ipv4_segment(&packet), (1240, true));
+    }
+
+    #[test]
+    fn test_clamp_ipv6() {
+        let mut packet = SYN_ACK_IPV6;
+        assert!(clamp(&mut packet, 1280));
+
+        let ipv6_packet = wire::Ipv6Packet::new_checked(&packet[..]).unwrap();
+        let tcp_packet = wire::TcpPacket::new_checked(ipv6_packet.payload()).unwrap();
+        assert!(tcp_packet.verify_checksum(&ipv6_packet.src_addr().into(), &ipv6_packet.dst_addr().into()));
+        assert_eq!(find_mss(tcp_packet.options()), Some((2, 1220)));
+    }
+
+    #[test]
+    fn test_clamp_ignored() {
+        // Not a SYN.
+        let mut packet = SYN_IPV4;
+        packet[33] = 0x10;
+        assert!(!clamp(&mut packet, 1280));
+
+        // A first fragment, the checksum covers bytes we don't have.
+        let mut packet = SYN_IPV4;
+        packet[6] = 0x20;
+        assert!(!clamp(&mut packet, 1280));
+
+        // Truncated.
+        let mut packet = SYN_IPV4;
+        assert!(!clamp(&mut packet[..40], 1280));
+        assert_eq!(packet[..], SYN_IPV4[..]);
+    }
+}
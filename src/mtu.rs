@@ -0,0 +1,580 @@
+/// Tunnel MTU, path MTU discovery and packets too big for the tunnel.
+///
+/// A packet read from the tun device becomes the payload of a data message,
+/// which has to fit the path MTU to the peer along with its headers:
+///
+/// ```text
+///     +----------+-----+-------------+------------------+-----+
+///     | outer IP | UDP | data header | payload          | tag |
+///     +----------+-----+-------------+------------------+-----+
+///       20 / 40     8        20        <= tunnel MTU      16
+/// ```
+///
+/// The path MTU starts at the MTU of the outer interface and is probed with
+/// padded probe messages sent with DF set, the largest one acknowledged wins.
+/// A packet which doesn't fit gets the ICMP "fragmentation needed" or "packet
+/// too big" error a router would send back, or is fragmented when IPv4 allows it.
+use crypto;
+use protocol;
+
+use std::cmp;
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::os::unix::io::RawFd;
+use std::time::{Duration, Instant};
+
+use byteorder::{ByteOrder, NetworkEndian};
+use libc;
+use netif::interface::Interface;
+
+
+pub const IPV4_HEADER_LEN: usize = 20;
+pub const IPV6_HEADER_LEN: usize = 40;
+pub const UDP_HEADER_LEN: usize = 8;
+/// Every IPv4 host accepts datagrams this big.
+pub const IPV4_MIN_MTU: usize = 576;
+/// Smallest MTU of an IPv6 link, Linux turns IPv6 off on interfaces below it.
+pub const IPV6_MIN_MTU: usize = 1280;
+/// Large enough for whatever the tun device or the socket hand over.
+pub const BUFFER_LEN: usize = 65536;
+/// Assumed when the MTU of the outer interface can't be read.
+const ETHERNET_MTU: usize = 1500;
+
+/// Usual MTUs below Ethernet, the RFC 1191 plateaus with PPPoE and tunnels.
+const PLATEAUS: [usize; 9] = [1500, 1492, 1480, 1460, 1440, 1400, 1280, 1006, 576];
+const PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Probes of a size lost in a row before trying the next smaller one.
+const PROBE_ATTEMPTS: usize = 2;
+/// The path may have grown again, or shrunk without anybody telling us.
+const REPROBE_INTERVAL: Duration = Duration::from_secs(600);
+
+const IPV4_DF: u16 = 0x4000;
+const IPV4_MF: u16 = 0x2000;
+const IPV4_OFFSET: u16 = 0x1fff;
+const IPPROTO_ICMP: u8 = 1;
+const IPPROTO_ICMPV6: u8 = 58;
+
+// IP_MTU_DISCOVER = IP_PMTUDISC_PROBE: DF is set and the kernel neither fragments
+// nor shrinks packets after an ICMP error, the probes take care of that.
+#[cfg(target_os = "linux")]
+const IP_DONTFRAG: (libc::c_int, libc::c_int) = (10, 3);
+#[cfg(target_os = "linux")]
+const IPV6_DONTFRAG: (libc::c_int, libc::c_int) = (23, 3);
+#[cfg(target_os = "macos")]
+const IP_DONTFRAG: (libc::c_int, libc::c_int) = (28, 1);
+#[cfg(target_os = "macos")]
+const IPV6_DONTFRAG: (libc::c_int, libc::c_int) = (62, 1);
+
+
+fn ip_header_len(ipv6: bool) -> usize {
+    if ipv6 { IPV6_HEADER_LEN } else { IPV4_HEADER_LEN }
+}
+
+/// Outer IP, UDP and protocol headers around the payload of a data message.
+pub fn overhead(outer_ipv6: bool, encrypted: bool) -> usize {
+    let tag_len = if encrypted { crypto::aead::TAG_LEN } else { 0 };
+    ip_header_len(outer_ipv6) + UDP_HEADER_LEN + protocol::DATA_HEADER_LEN + tag_len
+}
+
+/// Largest payload of a data message fitting `path_mtu`.
+pub fn tunnel_mtu(path_mtu: usize, outer_ipv6: bool, encrypted: bool) -> usize {
+    path_mtu.saturating_sub(overhead(outer_ipv6, encrypted))
+}
+
+/// MTU of the tun device: the configured one, else the tunnel MTU. Raised to
+/// the IPv6 minimum when protocol fragmentation makes up the difference.
+pub fn tun_mtu(configured: Option<usize>, tunnel_mtu: usize, ipv6: bool, fragment: bool) -> usize {
+    let mtu = match configured {
+        Some(mtu) => mtu,
+        None if ipv6 && fragment => cmp::max(tunnel_mtu, IPV6_MIN_MTU),
+        None => tunnel_mtu
+    };
+    if ipv6 && mtu < IPV6_MIN_MTU {
+        warn!("tunnel MTU {} is below the {} bytes IPv6 needs, enable fragmentation (--fragment)",
+              mtu, IPV6_MIN_MTU);
+    }
+    mtu
+}
+
+pub fn interface_mtu(ifname: &str) -> usize {
+    match Interface::with_name(ifname) {
+        Ok(interface) => interface.mtu() as usize,
+        Err(e) => {
+            warn!("can't read the MTU of {}: {}, assume {}", ifname, e, ETHERNET_MTU);
+            ETHERNET_MTU
+        }
+    }
+}
+
+/// Set DF on everything sent through the socket, packets too big for the path
+/// are dropped rather than fragmented. A dual-stack socket does it for IPv4 peers
+/// too, where the system allows it.
+pub fn set_dont_fragment(fd: RawFd, ipv6: bool) -> Result<(), io::Error> {
+    if ipv6 {
+        try!(setsockopt(fd, libc::IPPROTO_IPV6, IPV6_DONTFRAG));
+        let _ = setsockopt(fd, libc::IPPROTO_IP, IP_DONTFRAG);
+        Ok(())
+    } else {
+        setsockopt(fd, libc::IPPROTO_IP, IP_DONTFRAG)
+    }
+}
+
+fn setsockopt(fd: RawFd, level: libc::c_int, (name, value): (libc::c_int, libc::c_int)) -> Result<(), io::Error> {
+    let ret = unsafe {
+        libc::setsockopt(fd, level, name, &value as *const libc::c_int as *const libc::c_void,
+                         mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+
+/// Path MTU discovery towards one peer, probing the plateaus below the MTU
+/// of the outer interface from the largest down (RFC 4821).
+#[derive(Debug)]
+pub struct Prober {
+    /// Outer packet sizes to try, largest first.
+    sizes: Vec<usize>,
+    outer_ipv6: bool,
+    /// Index in `sizes` of the size being tried.
+    next: usize,
+    attempts: usize,
+    /// Nonce and send time of the probe in flight.
+    pending: Option<(u64, Instant)>,
+    /// End of the last search, `None` while searching.
+    settled: Option<Instant>,
+    path_mtu: usize,
+}
+
+impl Prober {
+    /// `max` is the MTU of the outer interface, assumed until a probe says otherwise.
+    pub fn new(max: usize, outer_ipv6: bool) -> Prober {
+        let mut sizes = vec![max];
+        sizes.extend(PLATEAUS.iter().filter(|size| **size < max));
+        Prober {
+            sizes: sizes,
+            outer_ipv6: outer_ipv6,
+            next: 0,
+            attempts: 0,
+            pending: None,
+            settled: None,
+            path_mtu: max,
+        }
+    }
+
+    pub fn path_mtu(&self) -> usize {
+        self.path_mtu
+    }
+
+    /// UDP payload length of a probe of the size being tried.
+    fn probe_len(&self) -> usize {
+        self.sizes[self.next] - ip_header_len(self.outer_ipv6) - UDP_HEADER_LEN
+    }
+
+    /// Nonce and length of the probe to send now, if one is due.
+    pub fn poll(&mut self, now: Instant) -> Option<(u64, usize)> {
+        if let Some(settled) = self.settled {
+            if now.duration_since(settled) < REPROBE_INTERVAL {
+                return None;
+            }
+            self.settled = None;
+            self.next = 0;
+            self.attempts = 0;
+        }
+        if let Some((_, sent)) = self.pending {
+            if now.duration_since(sent) < PROBE_TIMEOUT {
+                return None;
+            }
+            self.attempts += 1;
+            if self.attempts >= PROBE_ATTEMPTS {
+                self.attempts = 0;
+                self.next += 1;
+            }
+        }
+        if self.next >= self.sizes.len() {
+            // Nothing came back, the peer may not be listening. Keep what we have.
+            self.pending = None;
+            self.settled = Some(now);
+            return None;
+        }
+        let nonce = NetworkEndian::read_u64(&crypto::random_bytes(8));
+        self.pending = Some((nonce, now));
+        Some((nonce, self.probe_len()))
+    }
+
+    /// A probe was acknowledged. Returns whether the path MTU changed.
+    pub fn ack(&mut self, nonce: u64, len: usize, now: Instant) -> bool {
+        match self.pending {
+            Some((pending, _)) if pending == nonce && len == self.probe_len() => { },
+            _ => return false
+        }
+        let path_mtu = self.sizes[self.next];
+        self.pending = None;
+        self.settled = Some(now);
+        if path_mtu == self.path_mtu {
+            return false;
+        }
+        self.path_mtu = path_mtu;
+        true
+    }
+}
+
+
+/// What becomes of a packet too big for the tunnel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Oversized {
+    /// The ICMP error for its sender, written back to the tun device.
+    Reply(Vec<u8>),
+    /// IPv4 fragments fitting the tunnel.
+    Fragments(Vec<Vec<u8>>),
+    Drop,
+}
+
+/// Handle a packet bigger than `mtu` the way a router would. `source` and
+/// `source6` are the addresses ICMP errors come from, the far end of the tunnel.
+pub fn oversized(packet: &[u8], mtu: usize, source: Ipv4Addr, source6: Option<Ipv6Addr>) -> Oversized {
+    match packet.first().map(|byte| byte >> 4) {
+        Some(4) => {
+            if packet.len() < IPV4_HEADER_LEN {
+                return Oversized::Drop;
+            }
+            let flags = NetworkEndian::read_u16(&packet[6..8]);
+            let result = if flags & IPV4_DF != 0 {
+                fragmentation_needed(packet, mtu, source).map(Oversized::Reply)
+            } else {
+                fragment_ipv4(packet, mtu).map(Oversized::Fragments)
+            };
+            result.unwrap_or(Oversized::Drop)
+        },
+        Some(6) => match source6 {
+            Some(source6) => packet_too_big(packet, mtu, source6).map(Oversized::Reply).unwrap_or(Oversized::Drop),
+            None => Oversized::Drop
+        },
+        _ => Oversized::Drop
+    }
+}
+
+/// Internet checksum, `sum` carries a pseudo header.
+fn checksum(mut sum: u32, data: &[u8]) -> u16 {
+    for chunk in data.chunks(2) {
+        let word = if chunk.len() == 2 {
+            NetworkEndian::read_u16(chunk)
+        } else {
+            (chunk[0] as u16) << 8
+        };
+        sum += word as u32;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// ICMP destination unreachable, fragmentation needed (RFC 1191).
+fn fragmentation_needed(packet: &[u8], mtu: usize, source: Ipv4Addr) -> Option<Vec<u8>> {
+    let header_len = ((packet[0] & 0x0f) as usize) * 4;
+    if header_len < IPV4_HEADER_LEN || header_len > packet.len() {
+        return None;
+    }
+    // Only the first fragment is answered.
+    if NetworkEndian::read_u16(&packet[6..8]) & IPV4_OFFSET != 0 {
+        return None;
+    }
+    // Never an error about an ICMP error.
+    if packet[9] == IPPROTO_ICMP {
+        match packet.get(header_len) {
+            Some(&kind) if kind == 0 || kind == 8 || kind == 13 || kind == 14 => { },
+            _ => return None
+        }
+    }
+    let destination = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+    if destination.is_unspecified() || destination.is_broadcast() || destination.is_multicast() {
+        return None;
+    }
+
+    let quote = &packet[..cmp::min(packet.len(), IPV4_MIN_MTU - IPV4_HEADER_LEN - 8)];
+    let len = IPV4_HEADER_LEN + 8 + quote.len();
+    let mut reply = vec![0u8; IPV4_HEADER_LEN + 8];
+    reply[0] = 0x45;
+    NetworkEndian::write_u16(&mut reply[2..4], len as u16);
+    reply[8] = 64;
+    reply[9] = IPPROTO_ICMP;
+    reply[12..16].copy_from_slice(&source.octets());
+    reply[16..20].copy_from_slice(&destination.octets());
+    let sum = checksum(0, &reply[..IPV4_HEADER_LEN]);
+    NetworkEndian::write_u16(&mut reply[10..12], sum);
+
+    reply[20] = 3;
+    reply[21] = 4;
+    NetworkEndian::write_u16(&mut reply[26..28], cmp::min(mtu, 0xffff) as u16);
+    reply.extend_from_slice(quote);
+    let sum = checksum(0, &reply[IPV4_HEADER_LEN..]);
+    NetworkEndian::write_u16(&mut reply[22..24], sum);
+    Some(reply)
+}
+
+/// ICMPv6 packet too big (RFC 4443).
+fn packet_too_big(packet: &[u8], mtu: usize, source: Ipv6Addr) -> Option<Vec<u8>> {
+    if packet.len() < IPV6_HEADER_LEN {
+        return None;
+    }
+    if packet[6] == IPPROTO_ICMPV6 {
+        match packet.get(IPV6_HEADER_LEN) {
+            Some(&kind) if kind >= 128 => { },
+            _ => return None
+        }
+    }
+    let mut octets = [0u8; 16];
+    octets.copy_from_slice(&packet[8..24]);
+    let destination = Ipv6Addr::from(octets);
+    if destination.is_unspecified() || destination.is_multicast() {
+        return None;
+    }
+
+    let quote = &packet[..cmp::min(packet.len(), IPV6_MIN_MTU - IPV6_HEADER_LEN - 8)];
+    let icmp_len = 8 + quote.len();
+    let mut reply = vec![0u8; IPV6_HEADER_LEN + 8];
+    reply[0] = 0x60;
+    NetworkEndian::write_u16(&mut reply[4..6], icmp_len as u16);
+    reply[6] = IPPROTO_ICMPV6;
+    reply[7] = 64;
+    reply[8..24].copy_from_slice(&source.octets());
+    reply[24..40].copy_from_slice(&destination.octets());
+
+    reply[40] = 2;
+    NetworkEndian::write_u32(&mut reply[44..48], mtu as u32);
+    reply.extend_from_slice(quote);
+    // Pseudo header: addresses, upper layer length and next header.
+    let mut sum = 0u32;
+    for chunk in reply[8..40].chunks(2) {
+        sum += NetworkEndian::read_u16(chunk) as u32;
+    }
+    sum += icmp_len as u32 + IPPROTO_ICMPV6 as u32;
+    let sum = checksum(sum, &reply[IPV6_HEADER_LEN..]);
+    NetworkEndian::write_u16(&mut reply[42..44], sum);
+    Some(reply)
+}
+
+/// Options copied into every fragment, the others stay in the first one (RFC 791).
+fn copied_options(options: &[u8]) -> Vec<u8> {
+    let mut copied = vec![];
+    let mut pos = 0;
+    while pos < options.len() {
+        let kind = options[pos];
+        match kind {
+            0 => break,
+            1 => pos += 1,
+            _ => {
+                let len = match options.get(pos + 1) {
+                    Some(&len) if len >= 2 && pos + len as usize <= options.len() => len as usize,
+                    _ => break
+                };
+                if kind & 0x80 != 0 {
+                    copied.extend_from_slice(&options[pos..pos + len]);
+                }
+                pos += len;
+            }
+        }
+    }
+    while copied.len() % 4 != 0 {
+        copied.push(0);
+    }
+    copied
+}
+
+/// Split an IPv4 packet without DF into fragments of at most `mtu` bytes.
+fn fragment_ipv4(packet: &[u8], mtu: usize) -> Option<Vec<Vec<u8>>> {
+    let header_len = ((packet[0] & 0x0f) as usize) * 4;
+    let total_len = NetworkEndian::read_u16(&packet[2..4]) as usize;
+    if header_len < IPV4_HEADER_LEN || total_len < header_len || total_len > packet.len() {
+        return None;
+    }
+    let flags = NetworkEndian::read_u16(&packet[6..8]);
+    let offset = (flags & IPV4_OFFSET) as usize * 8;
+    let more = flags & IPV4_MF != 0;
+
+    let first_header = &packet[..header_len];
+    let mut next_header = packet[..IPV4_HEADER_LEN].to_vec();
+    next_header.extend(copied_options(&packet[IPV4_HEADER_LEN..header_len]));
+    next_header[0] = 0x40 | (next_header.len() / 4) as u8;
+
+    let payload = &packet[header_len..total_len];
+    let mut fragments = vec![];
+    let mut pos = 0;
+    while pos < payload.len() {
+        let header = if pos == 0 { first_header } else { &next_header[..] };
+        if mtu < header.len() + 8 {
+            return None;
+        }
+        let room = (mtu - header.len()) & !7;
+        let end = cmp::min(pos + room, payload.len());
+        let last = end == payload.len();
+
+        let mut fragment = header.to_vec();
+        fragment.extend_from_slice(&payload[pos..end]);
+        let len = fragment.len() as u16;
+        NetworkEndian::write_u16(&mut fragment[2..4], len);
+        let mut flags = ((offset + pos) / 8) as u16 & IPV4_OFFSET;
+        if more || !last {
+            flags |= IPV4_MF;
+        }
+        NetworkEndian::write_u16(&mut fragment[6..8], flags);
+        NetworkEndian::write_u16(&mut fragment[10..12], 0);
+        let sum = checksum(0, &fragment[..header.len()]);
+        NetworkEndian::write_u16(&mut fragment[10..12], sum);
+        fragments.push(fragment);
+        pos = end;
+    }
+    Some(fragments)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ipv4_packet(len: usize, flags: u16, protocol: u8) -> Vec<u8> {
+        let mut packet = vec![0u8; len];
+        packet[0] = 0x45;
+        NetworkEndian::write_u16(&mut packet[2..4], len as u16);
+        NetworkEndian::write_u16(&mut packet[4..6], 0x1234);
+        NetworkEndian::write_u16(&mut packet[6..8], flags);
+        packet[8] = 64;
+        packet[9] = protocol;
+        packet[12..16].copy_from_slice(&[192, 168, 10, 2]);
+        packet[16..20].copy_from_slice(&[8, 8, 8, 8]);
+        let sum = checksum(0, &packet[..IPV4_HEADER_LEN]);
+        NetworkEndian::write_u16(&mut packet[10..12], sum);
+        for (i, byte) in packet[IPV4_HEADER_LEN..].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        packet
+    }
+
+    #[test]
+    fn test_tunnel_mtu() {
+        assert_eq!(overhead(false, false), 48);
+        assert_eq!(tunnel_mtu(1500, false, true), 1436);
+        assert_eq!(tunnel_mtu(1500, true, true), 1416);
+        assert_eq!(tunnel_mtu(40, false, true), 0);
+        assert_eq!(tun_mtu(None, 1200, true, true), IPV6_MIN_MTU);
+        assert_eq!(tun_mtu(None, 1200, false, true), 1200);
+        assert_eq!(tun_mtu(Some(1400), 1200, true, true), 1400);
+    }
+
+    #[test]
+    fn test_prober() {
+        let start = Instant::now();
+        let mut prober = Prober::new(1500, false);
+        assert_eq!(prober.path_mtu(), 1500);
+
+        let (nonce, len) = prober.poll(start).unwrap();
+        assert_eq!(len, 1472);
+        assert_eq!(prober.poll(start), None);
+        // Lost twice, the next plateau is tried.
+        let (_, len) = prober.poll(start + PROBE_TIMEOUT).unwrap();
+        assert_eq!(len, 1472);
+        let (nonce2, len) = prober.poll(start + PROBE_TIMEOUT * 2).unwrap();
+        assert_eq!(len, 1464);
+        // A late answer to an earlier probe doesn't count.
+        assert!(!prober.ack(nonce, 1472, start + PROBE_TIMEOUT * 2));
+        assert!(!prober.ack(nonce2, 1472, start + PROBE_TIMEOUT * 2));
+        assert!(prober.ack(nonce2, 1464, start + PROBE_TIMEOUT * 2));
+        assert_eq!(prober.path_mtu(), 1492);
+
+        assert_eq!(prober.poll(start + PROBE_TIMEOUT * 3), None);
+        let (_, len) = prober.poll(start + PROBE_TIMEOUT * 2 + REPROBE_INTERVAL).unwrap();
+        assert_eq!(len, 1472);
+
+        // A peer which never answers leaves the path MTU alone.
+        let mut prober = Prober::new(1280, true);
+        let mut now = start;
+        while let Some(_) = prober.poll(now) {
+            now += PROBE_TIMEOUT;
+        }
+        assert_eq!(prober.path_mtu(), 1280);
+        assert!(prober.settled.is_some());
+    }
+
+    #[test]
+    fn test_fragmentation_needed() {
+        let source = Ipv4Addr::new(172, 16, 0, 1);
+        let packet = ipv4_packet(1500, IPV4_DF, 6);
+        let reply = match oversized(&packet, 1400, source, None) {
+            Oversized::Reply(reply) => reply,
+            other => panic!("{:?}", other)
+        };
+        assert_eq!(reply.len(), IPV4_MIN_MTU);
+        assert_eq!(checksum(0, &reply[..IPV4_HEADER_LEN]), 0);
+        assert_eq!(checksum(0, &reply[IPV4_HEADER_LEN..]), 0);
+        assert_eq!(&reply[12..16], &[172, 16, 0, 1]);
+        assert_eq!(&reply[16..20], &[192, 168, 10, 2]);
+        assert_eq!((reply[20], reply[21]), (3, 4));
+        assert_eq!(NetworkEndian::read_u16(&reply[26..28]), 1400);
+        assert_eq!(&reply[28..48], &packet[..20]);
+
+        // ICMP errors are not answered, echo requests are.
+        let mut error = ipv4_packet(1500, IPV4_DF, IPPROTO_ICMP);
+        error[IPV4_HEADER_LEN] = 3;
+        assert_eq!(oversized(&error, 1400, source, None), Oversized::Drop);
+        let mut echo = ipv4_packet(1500, IPV4_DF, IPPROTO_ICMP);
+        echo[IPV4_HEADER_LEN] = 8;
+        assert!(match oversized(&echo, 1400, source, None) { Oversized::Reply(_) => true, _ => false });
+    }
+
+    #[test]
+    fn test_fragment_ipv4() {
+        let packet = ipv4_packet(3000, 0, 17);
+        let fragments = match oversized(&packet, 1400, Ipv4Addr::new(172, 16, 0, 1), None) {
+            Oversized::Fragments(fragments) => fragments,
+            other => panic!("{:?}", other)
+        };
+        assert_eq!(fragments.len(), 3);
+        let mut payload = vec![];
+        for (i, fragment) in fragments.iter().enumerate() {
+            assert!(fragment.len() <= 1400);
+            assert_eq!(checksum(0, &fragment[..IPV4_HEADER_LEN]), 0);
+            assert_eq!(NetworkEndian::read_u16(&fragment[2..4]) as usize, fragment.len());
+            assert_eq!(&fragment[4..6], &[0x12, 0x34]);
+            let flags = NetworkEndian::read_u16(&fragment[6..8]);
+            assert_eq!(flags & IPV4_MF != 0, i < 2);
+            assert_eq!((flags & IPV4_OFFSET) as usize * 8, payload.len());
+            payload.extend_from_slice(&fragment[IPV4_HEADER_LEN..]);
+        }
+        assert_eq!(&payload[..], &packet[IPV4_HEADER_LEN..]);
+
+        assert_eq!(copied_options(&[0x07, 3, 0, 1, 0x83, 3, 1, 0]), vec![0x83, 3, 1, 0]);
+    }
+
+    #[test]
+    fn test_packet_too_big() {
+        let source6: Ipv6Addr = "fd00::1".parse().unwrap();
+        let mut packet = vec![0u8; 1500];
+        packet[0] = 0x60;
+        NetworkEndian::write_u16(&mut packet[4..6], 1460);
+        packet[6] = 6;
+        packet[7] = 64;
+        packet[8..24].copy_from_slice(&"fd00::2".parse::<Ipv6Addr>().unwrap().octets());
+        packet[24..40].copy_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+
+        assert_eq!(oversized(&packet, 1400, Ipv4Addr::new(172, 16, 0, 1), None), Oversized::Drop);
+        let reply = match oversized(&packet, 1400, Ipv4Addr::new(172, 16, 0, 1), Some(source6)) {
+            Oversized::Reply(reply) => reply,
+            other => panic!("{:?}", other)
+        };
+        assert_eq!(reply.len(), IPV6_MIN_MTU);
+        assert_eq!(&reply[24..40], &packet[8..24]);
+        assert_eq!(reply[40], 2);
+        assert_eq!(NetworkEndian::read_u32(&reply[44..48]), 1400);
+        let mut sum = 0u32;
+        for chunk in reply[8..40].chunks(2) {
+            sum += NetworkEndian::read_u16(chunk) as u32;
+        }
+        sum += (reply.len() - IPV6_HEADER_LEN) as u32 + IPPROTO_ICMPV6 as u32;
+        assert_eq!(checksum(sum, &reply[IPV6_HEADER_LEN..]), 0);
+    }
+}
@@ -0,0 +1,229 @@
+/// Tunnel address pool of vpnd.
+///
+/// Leases are keyed by an opaque owner id, the client key fingerprint when
+/// crypto is enabled, so a reconnecting client keeps its address as long as
+/// its lease did not expire.
+use std::io;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+use std::collections::HashMap;
+
+use ipnetwork::Ipv4Network;
+
+
+#[derive(Debug, Clone)]
+struct Lease {
+    owner: Vec<u8>,
+    last_seen: Instant,
+}
+
+#[derive(Debug)]
+pub struct AddressPool {
+    network: Ipv4Network,
+    server_ip: Ipv4Addr,
+    idle_timeout: Duration,
+    // owner -> address, never handed out to anybody else.
+    reservations: HashMap<Vec<u8>, Ipv4Addr>,
+    leases: HashMap<Ipv4Addr, Lease>,
+    // Next address to try, so released addresses are not reused right away.
+    cursor: u32,
+}
+
+impl AddressPool {
+    pub fn new(network: Ipv4Network, server_ip: Ipv4Addr, idle_timeout: Duration) -> AddressPool {
+        let first = u32::from(network.network()).saturating_add(1);
+        AddressPool {
+            network: network,
+            server_ip: server_ip,
+            idle_timeout: idle_timeout,
+            reservations: HashMap::new(),
+            leases: HashMap::new(),
+            cursor: first,
+        }
+    }
+
+    /// First and last assignable address, `None` when the network has no host addresses.
+    fn host_range(&self) -> Option<(u32, u32)> {
+        if self.network.prefix() >= 31 {
+            return None;
+        }
+        Some((u32::from(self.network.network()) + 1, u32::from(self.network.broadcast()) - 1))
+    }
+
+    fn is_assignable(&self, addr: Ipv4Addr) -> bool {
+        match self.host_range() {
+            Some((first, last)) => {
+                let n = u32::from(addr);
+                n >= first && n <= last && addr != self.server_ip
+            },
+            None => false
+        }
+    }
+
+    fn is_reserved(&self, addr: Ipv4Addr) -> bool {
+        self.reservations.values().any(|reserved| *reserved == addr)
+    }
+
+    /// Pin `addr` to `owner`.
+    pub fn reserve(&mut self, owner: Vec<u8>, addr: Ipv4Addr) -> Result<(), io::Error> {
+        if !self.is_assignable(addr) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("{} is not an assignable address of {}", addr, self.network)));
+        }
+        match self.reservations.iter().find(|&(_, reserved)| *reserved == addr) {
+            Some((other, _)) if *other != owner => {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                          format!("{} is already reserved", addr)));
+            },
+            _ => { }
+        }
+        self.reservations.insert(owner, addr);
+        Ok(())
+    }
+
+    /// Lease an address to `owner`, the same one if it already holds a lease.
+    /// Idle leases are not reclaimed here, call `expire` first.
+    pub fn allocate(&mut self, owner: &[u8], now: Instant) -> Result<Ipv4Addr, io::Error> {
+        let held = self.leases.iter()
+                       .find(|&(_, lease)| &lease.owner[..] == owner)
+                       .map(|(addr, _)| *addr);
+        if let Some(addr) = held {
+            self.touch(addr, now);
+            return Ok(addr);
+        }
+
+        if let Some(addr) = self.reservations.get(owner).cloned() {
+            // Only a stale lease of the same owner could sit on a reserved address.
+            self.leases.insert(addr, Lease { owner: owner.to_vec(), last_seen: now });
+            return Ok(addr);
+        }
+
+        let (first, last) = match self.host_range() {
+            Some(range) => range,
+            None => return Err(exhausted())
+        };
+        if self.cursor < first || self.cursor > last {
+            self.cursor = first;
+        }
+
+        let size = last - first + 1;
+        for i in 0..size {
+            let n = first + (self.cursor - first + i) % size;
+            let addr = Ipv4Addr::from(n);
+            if addr == self.server_ip || self.leases.contains_key(&addr) || self.is_reserved(addr) {
+                continue;
+            }
+            self.cursor = if n == last { first } else { n + 1 };
+            self.leases.insert(addr, Lease { owner: owner.to_vec(), last_seen: now });
+            return Ok(addr);
+        }
+
+        Err(exhausted())
+    }
+
+    /// Record activity on `addr`, keeps its lease alive.
+    pub fn touch(&mut self, addr: Ipv4Addr, now: Instant) {
+        if let Some(lease) = self.leases.get_mut(&addr) {
+            lease.last_seen = now;
+        }
+    }
+
+    pub fn release(&mut self, addr: Ipv4Addr) {
+        self.leases.remove(&addr);
+    }
+
+    /// Drop leases idle for longer than the timeout and return their addresses.
+    pub fn expire(&mut self, now: Instant) -> Vec<Ipv4Addr> {
+        let idle_timeout = self.idle_timeout;
+        let expired: Vec<Ipv4Addr> = self.leases.iter()
+            .filter(|&(_, lease)| now.duration_since(lease.last_seen) > idle_timeout)
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in expired.iter() {
+            self.leases.remove(addr);
+        }
+        expired
+    }
+
+    pub fn len(&self) -> usize {
+        self.leases.len()
+    }
+}
+
+fn exhausted() -> io::Error {
+    io::Error::new(io::ErrorKind::AddrNotAvailable, "address pool exhausted")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn new_pool(network: &str, server_ip: &str) -> AddressPool {
+        AddressPool::new(Ipv4Network::from_str(network).unwrap(),
+                         Ipv4Addr::from_str(server_ip).unwrap(),
+                         Duration::from_secs(60))
+    }
+
+    #[test]
+    fn test_exhaustion() {
+        // .0 network, .1 server, .3 broadcast: a single client fits.
+        let mut pool = new_pool("10.0.0.0/30", "10.0.0.1");
+        let now = Instant::now();
+        assert_eq!(pool.allocate(b"a", now).unwrap(), Ipv4Addr::new(10, 0, 0, 2));
+        let e = pool.allocate(b"b", now).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AddrNotAvailable);
+
+        let mut pool = new_pool("10.0.0.1/32", "10.0.0.1");
+        assert_eq!(pool.allocate(b"a", now).unwrap_err().kind(), io::ErrorKind::AddrNotAvailable);
+    }
+
+    #[test]
+    fn test_reuse() {
+        let mut pool = new_pool("10.0.0.0/29", "10.0.0.1");
+        let now = Instant::now();
+        let a = pool.allocate(b"a", now).unwrap();
+        assert_eq!(pool.allocate(b"a", now).unwrap(), a);
+
+        let b = pool.allocate(b"b", now).unwrap();
+        assert!(a != b);
+        pool.release(a);
+        assert_eq!(pool.len(), 1);
+
+        // Every host address is eventually reused once freed.
+        let mut seen = vec![];
+        for owner in 0..4u8 {
+            seen.push(pool.allocate(&[owner], now).unwrap());
+        }
+        assert!(seen.contains(&a));
+        assert_eq!(pool.allocate(b"c", now).unwrap_err().kind(), io::ErrorKind::AddrNotAvailable);
+    }
+
+    #[test]
+    fn test_expiry() {
+        let mut pool = new_pool("10.0.0.0/30", "10.0.0.1");
+        let now = Instant::now();
+        let a = pool.allocate(b"a", now).unwrap();
+        pool.touch(a, now + Duration::from_secs(30));
+        assert!(pool.expire(now + Duration::from_secs(61)).is_empty());
+
+        let later = now + Duration::from_secs(91);
+        assert!(pool.allocate(b"b", later).is_err());
+        assert_eq!(pool.expire(later), vec![a]);
+        assert_eq!(pool.allocate(b"b", later).unwrap(), a);
+    }
+
+    #[test]
+    fn test_reservation() {
+        let mut pool = new_pool("10.0.0.0/29", "10.0.0.1");
+        let now = Instant::now();
+        assert!(pool.reserve(b"r".to_vec(), Ipv4Addr::new(10, 0, 0, 1)).is_err());
+        assert!(pool.reserve(b"r".to_vec(), Ipv4Addr::new(10, 0, 0, 7)).is_err());
+        pool.reserve(b"r".to_vec(), Ipv4Addr::new(10, 0, 0, 2)).unwrap();
+        assert!(pool.reserve(b"s".to_vec(), Ipv4Addr::new(10, 0, 0, 2)).is_err());
+
+        assert_eq!(pool.allocate(b"a", now).unwrap(), Ipv4Addr::new(10, 0, 0, 3));
+        assert_eq!(pool.allocate(b"r", now).unwrap(), Ipv4Addr::new(10, 0, 0, 2));
+    }
+}
@@ -0,0 +1,773 @@
+/// Wire protocol shared by vpn and vpnd.
+///
+///     0         1         2         3         4                    12
+///     +---------+---------+---------+---------+--------------------+
+///     | version |  kind   |  flags  | reserved|     session id     |
+///     +---------+---------+---------+---------+--------------------+
+///     | body ...
+///
+/// All integers are in network byte order. Variable length fields inside a
+/// body are prefixed with their length as a `u16`.
+use crypto;
+use crypto::aead::SessionCipher;
+
+use std::io;
+use std::str;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use byteorder::{ByteOrder, NetworkEndian};
+
+
+pub const VERSION: u8 = 3;
+pub const HEADER_LEN: usize = 12;
+pub const COUNTER_LEN: usize = 8;
+/// Header and data counter, authenticated as associated data when encrypted.
+pub const DATA_HEADER_LEN: usize = HEADER_LEN + COUNTER_LEN;
+pub const LEASE_LEN: usize = 16;
+/// IPv6 part of a lease, appended when the server has an IPv6 prefix.
+pub const LEASE6_LEN: usize = 33;
+/// Header and nonce of a probe, the padding makes up the rest.
+pub const PROBE_LEN: usize = HEADER_LEN + 8;
+
+
+bitflags! {
+    pub struct Flags: u8 {
+        /// Payload was compressed before encryption. On hello and lease
+        /// packets it tells the peer compression is enabled on this side.
+        const COMPRESSED = 0b0000_0001;
+        /// Payload is sealed with the session cipher.
+        const ENCRYPTED  = 0b0000_0010;
+        /// Payload is a piece of a larger packet, see `fragment`.
+        const FRAGMENT   = 0b0000_0100;
+        /// On an empty sealed data packet: the sender closes the session.
+        const CLOSE      = 0b0000_1000;
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Kind {
+    Hello,
+    Lease,
+    Data,
+    Keepalive,
+    Close,
+    Error,
+    Probe,
+    ProbeAck,
+}
+
+impl Kind {
+    pub fn new(n: u8) -> Result<Self, &'static str> {
+        match n {
+            1 => Ok(Kind::Hello),
+            2 => Ok(Kind::Lease),
+            3 => Ok(Kind::Data),
+            4 => Ok(Kind::Keepalive),
+            5 => Ok(Kind::Close),
+            6 => Ok(Kind::Error),
+            7 => Ok(Kind::Probe),
+            8 => Ok(Kind::ProbeAck),
+            _ => Err("unknow message kind"),
+        }
+    }
+
+    pub fn to_u8(&self) -> u8 {
+        match *self {
+            Kind::Hello => 1,
+            Kind::Lease => 2,
+            Kind::Data => 3,
+            Kind::Keepalive => 4,
+            Kind::Close => 5,
+            Kind::Error => 6,
+            Kind::Probe => 7,
+            Kind::ProbeAck => 8,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// Handshake failed or the peer is not allowed to connect.
+    Unauthorized,
+    /// The message could not be decoded.
+    Malformed,
+    /// Both sides do not agree on the session options.
+    Unsupported,
+    /// The session id is not known (anymore), a new hello is needed.
+    UnknownSession,
+    Other,
+}
+
+impl ErrorCode {
+    pub fn from_u8(n: u8) -> Self {
+        match n {
+            1 => ErrorCode::Unauthorized,
+            2 => ErrorCode::Malformed,
+            3 => ErrorCode::Unsupported,
+            4 => ErrorCode::UnknownSession,
+            _ => ErrorCode::Other,
+        }
+    }
+
+    pub fn to_u8(&self) -> u8 {
+        match *self {
+            ErrorCode::Unauthorized => 1,
+            ErrorCode::Malformed => 2,
+            ErrorCode::Unsupported => 3,
+            ErrorCode::UnknownSession => 4,
+            ErrorCode::Other => 255,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Lease6 {
+    pub tun_ip: Ipv6Addr,
+    pub server_tun_ip: Ipv6Addr,
+    pub prefix_len: u8,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Lease {
+    pub tun_ip: Ipv4Addr,
+    pub public_ip: Ipv4Addr,
+    pub server_tun_ip: Ipv4Addr,
+    pub tun_netmask: Ipv4Addr,
+    pub ipv6: Option<Lease6>,
+}
+
+impl Lease {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![0u8; LEASE_LEN];
+        NetworkEndian::write_u32(&mut buf[0..4], u32::from(self.tun_ip));
+        NetworkEndian::write_u32(&mut buf[4..8], u32::from(self.public_ip));
+        NetworkEndian::write_u32(&mut buf[8..12], u32::from(self.server_tun_ip));
+        NetworkEndian::write_u32(&mut buf[12..16], u32::from(self.tun_netmask));
+        if let Some(ref ipv6) = self.ipv6 {
+            buf.extend_from_slice(&ipv6.tun_ip.octets());
+            buf.extend_from_slice(&ipv6.server_tun_ip.octets());
+            buf.push(ipv6.prefix_len);
+        }
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Lease> {
+        let ipv6 = match buf.len() {
+            LEASE_LEN => None,
+            len if len == LEASE_LEN + LEASE6_LEN => {
+                let mut tun_ip = [0u8; 16];
+                let mut server_tun_ip = [0u8; 16];
+                tun_ip.copy_from_slice(&buf[16..32]);
+                server_tun_ip.copy_from_slice(&buf[32..48]);
+                let prefix_len = buf[48];
+                if prefix_len > 128 {
+                    return None;
+                }
+                Some(Lease6 {
+                    tun_ip: Ipv6Addr::from(tun_ip),
+                    server_tun_ip: Ipv6Addr::from(server_tun_ip),
+                    prefix_len: prefix_len,
+                })
+            },
+            _ => return None
+        };
+        Some(Lease {
+            tun_ip: Ipv4Addr::from(NetworkEndian::read_u32(&buf[0..4])),
+            public_ip: Ipv4Addr::from(NetworkEndian::read_u32(&buf[4..8])),
+            server_tun_ip: Ipv4Addr::from(NetworkEndian::read_u32(&buf[8..12])),
+            tun_netmask: Ipv4Addr::from(NetworkEndian::read_u32(&buf[12..16])),
+            ipv6: ipv6,
+        })
+    }
+}
+
+/// Handshake steps, see `handshake` for the exchange itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Hello {
+    /// Unauthenticated hello, only accepted when crypto is disabled.
+    Plain,
+    Init {
+        pubkey: Vec<u8>,
+        random: Vec<u8>,
+        share: Vec<u8>,
+    },
+    Challenge {
+        pubkey: Vec<u8>,
+        share: Vec<u8>,
+        sealed_random: Vec<u8>,
+        signature: Vec<u8>,
+    },
+    Response {
+        sealed_secret: Vec<u8>,
+        signature: Vec<u8>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Hello(Hello),
+    Lease {
+        lease: Lease,
+        /// Binds the lease to the session key, empty when crypto is disabled.
+        confirm: Vec<u8>,
+    },
+    Data {
+        counter: u64,
+        payload: Vec<u8>,
+    },
+    Keepalive,
+    Close,
+    Error {
+        code: ErrorCode,
+        reason: String,
+    },
+    /// Path MTU probe, padded with `padding` zeros up to the size being tried.
+    Probe {
+        nonce: u64,
+        padding: usize,
+    },
+    /// The probe with this nonce arrived, `size` bytes long.
+    ProbeAck {
+        nonce: u64,
+        size: u16,
+    },
+}
+
+impl Message {
+    pub fn kind(&self) -> Kind {
+        match *self {
+            Message::Hello(_) => Kind::Hello,
+            Message::Lease { .. } => Kind::Lease,
+            Message::Data { .. } => Kind::Data,
+            Message::Keepalive => Kind::Keepalive,
+            Message::Close => Kind::Close,
+            Message::Error { .. } => Kind::Error,
+            Message::Probe { .. } => Kind::Probe,
+            Message::ProbeAck { .. } => Kind::ProbeAck,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub flags: Flags,
+    pub session_id: u64,
+    pub message: Message,
+}
+
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn write_u16(buf: &mut Vec<u8>, n: u16) {
+    let mut bytes = [0u8; 2];
+    NetworkEndian::write_u16(&mut bytes, n);
+    buf.extend_from_slice(&bytes);
+}
+
+fn write_u64(buf: &mut Vec<u8>, n: u64) {
+    let mut bytes = [0u8; 8];
+    NetworkEndian::write_u64(&mut bytes, n);
+    buf.extend_from_slice(&bytes);
+}
+
+fn write_field(buf: &mut Vec<u8>, data: &[u8]) {
+    assert!(data.len() <= u16::max_value() as usize);
+    write_u16(buf, data.len() as u16);
+    buf.extend_from_slice(data);
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader { buf: buf, pos: 0 }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], io::Error> {
+        if len > self.buf.len() - self.pos {
+            return Err(invalid("truncated message"));
+        }
+        let start = self.pos;
+        self.pos += len;
+        Ok(&self.buf[start..self.pos])
+    }
+
+    fn u8(&mut self) -> Result<u8, io::Error> {
+        match self.bytes(1) {
+            Ok(bytes) => Ok(bytes[0]),
+            Err(e) => Err(e)
+        }
+    }
+
+    fn u16(&mut self) -> Result<u16, io::Error> {
+        match self.bytes(2) {
+            Ok(bytes) => Ok(NetworkEndian::read_u16(bytes)),
+            Err(e) => Err(e)
+        }
+    }
+
+    fn u64(&mut self) -> Result<u64, io::Error> {
+        match self.bytes(8) {
+            Ok(bytes) => Ok(NetworkEndian::read_u64(bytes)),
+            Err(e) => Err(e)
+        }
+    }
+
+    fn field(&mut self) -> Result<&'a [u8], io::Error> {
+        let len = match self.bytes(2) {
+            Ok(bytes) => NetworkEndian::read_u16(bytes) as usize,
+            Err(e) => return Err(e)
+        };
+        self.bytes(len)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        let start = self.pos;
+        self.pos = self.buf.len();
+        &self.buf[start..]
+    }
+
+    fn finish(&self) -> Result<(), io::Error> {
+        if self.pos != self.buf.len() {
+            return Err(invalid("trailing bytes after message"));
+        }
+        Ok(())
+    }
+}
+
+
+impl Packet {
+    pub fn new(session_id: u64, message: Message) -> Packet {
+        Packet {
+            flags: Flags::empty(),
+            session_id: session_id,
+            message: message,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf: Vec<u8> = Vec::with_capacity(HEADER_LEN);
+        buf.push(VERSION);
+        buf.push(self.message.kind().to_u8());
+        buf.push(self.flags.bits());
+        buf.push(0);
+        write_u64(&mut buf, self.session_id);
+
+        match self.message {
+            Message::Hello(ref hello) => match *hello {
+                Hello::Plain => {
+                    buf.push(0);
+                },
+                Hello::Init { ref pubkey, ref random, ref share } => {
+                    buf.push(1);
+                    write_field(&mut buf, pubkey);
+                    write_field(&mut buf, random);
+                    write_field(&mut buf, share);
+                },
+                Hello::Challenge { ref pubkey, ref share, ref sealed_random, ref signature } => {
+                    buf.push(2);
+                    write_field(&mut buf, pubkey);
+                    write_field(&mut buf, share);
+                    write_field(&mut buf, sealed_random);
+                    write_field(&mut buf, signature);
+                },
+                Hello::Response { ref sealed_secret, ref signature } => {
+                    buf.push(3);
+                    write_field(&mut buf, sealed_secret);
+                    write_field(&mut buf, signature);
+                },
+            },
+            Message::Lease { ref lease, ref confirm } => {
+                write_field(&mut buf, &lease.to_bytes());
+                write_field(&mut buf, confirm);
+            },
+            Message::Data { counter, ref payload } => {
+                write_u64(&mut buf, counter);
+                buf.extend_from_slice(payload);
+            },
+            Message::Keepalive | Message::Close => { },
+            Message::Error { code, ref reason } => {
+                buf.push(code.to_u8());
+                write_field(&mut buf, reason.as_bytes());
+            },
+            Message::Probe { nonce, padding } => {
+                write_u64(&mut buf, nonce);
+                let len = buf.len() + padding;
+                buf.resize(len, 0);
+            },
+            Message::ProbeAck { nonce, size } => {
+                write_u64(&mut buf, nonce);
+                write_u16(&mut buf, size);
+            },
+        }
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Packet, io::Error> {
+        let mut reader = Reader::new(buf);
+
+        let version = try!(reader.u8());
+        if version != VERSION {
+            return Err(invalid("unsupported protocol version"));
+        }
+        let kind = match Kind::new(try!(reader.u8())) {
+            Ok(kind) => kind,
+            Err(e) => return Err(invalid(e))
+        };
+        let flags = match Flags::from_bits(try!(reader.u8())) {
+            Some(flags) => flags,
+            None => return Err(invalid("unknow flags"))
+        };
+        let _reserved = try!(reader.u8());
+        let session_id = try!(reader.u64());
+
+        let message = match kind {
+            Kind::Hello => {
+                let hello = match try!(reader.u8()) {
+                    0 => Hello::Plain,
+                    1 => Hello::Init {
+                        pubkey: try!(reader.field()).to_vec(),
+                        random: try!(reader.field()).to_vec(),
+                        share: try!(reader.field()).to_vec(),
+                    },
+                    2 => Hello::Challenge {
+                        pubkey: try!(reader.field()).to_vec(),
+                        share: try!(reader.field()).to_vec(),
+                        sealed_random: try!(reader.field()).to_vec(),
+                        signature: try!(reader.field()).to_vec(),
+                    },
+                    3 => Hello::Response {
+                        sealed_secret: try!(reader.field()).to_vec(),
+                        signature: try!(reader.field()).to_vec(),
+                    },
+                    _ => return Err(invalid("unknow handshake step"))
+                };
+                try!(reader.finish());
+                Message::Hello(hello)
+            },
+            Kind::Lease => {
+                let lease = match Lease::from_bytes(try!(reader.field())) {
+                    Some(lease) => lease,
+                    None => return Err(invalid("malformed lease"))
+                };
+                let confirm = try!(reader.field()).to_vec();
+                try!(reader.finish());
+                Message::Lease { lease: lease, confirm: confirm }
+            },
+            Kind::Data => {
+                let counter = try!(reader.u64());
+                Message::Data { counter: counter, payload: reader.rest().to_vec() }
+            },
+            Kind::Keepalive => {
+                try!(reader.finish());
+                Message::Keepalive
+            },
+            Kind::Close => {
+                try!(reader.finish());
+                Message::Close
+            },
+            Kind::Error => {
+                let code = ErrorCode::from_u8(try!(reader.u8()));
+                let reason = match str::from_utf8(try!(reader.field())) {
+                    Ok(reason) => reason.to_string(),
+                    Err(_) => return Err(invalid("error reason is not utf-8"))
+                };
+                try!(reader.finish());
+                Message::Error { code: code, reason: reason }
+            },
+            Kind::Probe => {
+                let nonce = try!(reader.u64());
+                Message::Probe { nonce: nonce, padding: reader.rest().len() }
+            },
+            Kind::ProbeAck => {
+                let nonce = try!(reader.u64());
+                let size = try!(reader.u16());
+                try!(reader.finish());
+                Message::ProbeAck { nonce: nonce, size: size }
+            },
+        };
+
+        Ok(Packet {
+            flags: flags,
+            session_id: session_id,
+            message: message,
+        })
+    }
+}
+
+
+/// Random non-zero session id, zero is used before the handshake completes.
+pub fn new_session_id() -> u64 {
+    loop {
+        let session_id = NetworkEndian::read_u64(&crypto::random_bytes(8));
+        if session_id != 0 {
+            return session_id;
+        }
+    }
+}
+
+/// Flags carried by hello and lease packets to negotiate optional features.
+pub fn features(disable_compression: bool) -> Flags {
+    if disable_compression {
+        Flags::empty()
+    } else {
+        Flags::COMPRESSED
+    }
+}
+
+/// Explain a compression mismatch, `local` is the flag of the side writing the message.
+pub fn compression_mismatch(local: Flags, remote: Flags) -> Option<String> {
+    let state = |flags: Flags| if flags.contains(Flags::COMPRESSED) { "enabled" } else { "disabled" };
+    if local.contains(Flags::COMPRESSED) == remote.contains(Flags::COMPRESSED) {
+        None
+    } else {
+        Some(format!("compression mismatch: {} here but {} on the peer, \
+                      both sides must agree on --disable-compression",
+                     state(local), state(remote)))
+    }
+}
+
+pub fn error(session_id: u64, code: ErrorCode, reason: &str) -> Vec<u8> {
+    Packet::new(session_id, Message::Error { code: code, reason: reason.to_string() }).encode()
+}
+
+pub fn plain_data(session_id: u64, flags: Flags, payload: &[u8]) -> Vec<u8> {
+    let packet = Packet {
+        flags: flags,
+        session_id: session_id,
+        message: Message::Data { counter: 0, payload: payload.to_vec() },
+    };
+    packet.encode()
+}
+
+/// Encode a data packet whose payload is sealed with the session cipher.
+/// The header and counter are authenticated along with the payload.
+pub fn seal_data(cipher: &mut SessionCipher, session_id: u64, flags: Flags, plaintext: &[u8])
+        -> Result<Vec<u8>, io::Error> {
+    let counter = try!(cipher.next_counter());
+    let packet = Packet {
+        flags: flags | Flags::ENCRYPTED,
+        session_id: session_id,
+        message: Message::Data { counter: counter, payload: vec![] },
+    };
+    let mut buf = packet.encode();
+    let sealed = try!(cipher.seal(counter, &buf, plaintext));
+    buf.extend_from_slice(&sealed);
+    Ok(buf)
+}
+
+/// Data packet of a session, sealed when it has a cipher.
+pub fn data(cipher: Option<&mut SessionCipher>, session_id: u64, flags: Flags, payload: &[u8])
+        -> Result<Vec<u8>, io::Error> {
+    match cipher {
+        Some(cipher) => seal_data(cipher, session_id, flags, payload),
+        None => Ok(plain_data(session_id, flags, payload))
+    }
+}
+
+/// Path MTU probe `len` bytes long, headers included.
+pub fn probe(session_id: u64, nonce: u64, len: usize) -> Vec<u8> {
+    let padding = len.saturating_sub(PROBE_LEN);
+    Packet::new(session_id, Message::Probe { nonce: nonce, padding: padding }).encode()
+}
+
+/// Keepalive of a session. Encrypted sessions send an empty sealed data packet
+/// instead: being authenticated, it also moves the session to the address it
+/// came from, after a NAT rebinding for instance.
+pub fn keepalive(cipher: Option<&mut SessionCipher>, session_id: u64) -> Result<Vec<u8>, io::Error> {
+    match cipher {
+        Some(cipher) => seal_data(cipher, session_id, Flags::empty(), &[]),
+        None => Ok(Packet::new(session_id, Message::Keepalive).encode())
+    }
+}
+
+/// Close a session. Encrypted sessions send an empty sealed data packet with
+/// the `CLOSE` flag, a plain `Close` would let anybody on the path end them.
+pub fn close(cipher: Option<&mut SessionCipher>, session_id: u64) -> Result<Vec<u8>, io::Error> {
+    match cipher {
+        Some(cipher) => seal_data(cipher, session_id, Flags::CLOSE, &[]),
+        None => Ok(Packet::new(session_id, Message::Close).encode())
+    }
+}
+
+/// Decrypt the payload of a data packet previously decoded from `raw`.
+pub fn open_data(cipher: &mut SessionCipher, raw: &[u8], counter: u64, payload: &[u8])
+        -> Result<Vec<u8>, io::Error> {
+    if raw.len() < DATA_HEADER_LEN {
+        return Err(invalid("truncated message"));
+    }
+    cipher.open(counter, &raw[..DATA_HEADER_LEN], payload)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::Rng;
+
+    fn lease(ipv6: bool) -> Lease {
+        Lease {
+            tun_ip: Ipv4Addr::new(172, 16, 0, 2),
+            public_ip: Ipv4Addr::new(203, 0, 113, 1),
+            server_tun_ip: Ipv4Addr::new(172, 16, 0, 1),
+            tun_netmask: Ipv4Addr::new(255, 255, 0, 0),
+            ipv6: if ipv6 {
+                Some(Lease6 {
+                    tun_ip: "fd00:6578:6f64::2".parse().unwrap(),
+                    server_tun_ip: "fd00:6578:6f64::1".parse().unwrap(),
+                    prefix_len: 64,
+                })
+            } else {
+                None
+            },
+        }
+    }
+
+    fn messages() -> Vec<Message> {
+        vec![
+            Message::Hello(Hello::Plain),
+            Message::Hello(Hello::Init { pubkey: vec![1; 294], random: vec![2; 32], share: vec![4; 65] }),
+            Message::Hello(Hello::Challenge { pubkey: vec![3; 294], share: vec![4; 65],
+                                              sealed_random: vec![4; 256], signature: vec![5; 256] }),
+            Message::Hello(Hello::Response { sealed_secret: vec![6; 256], signature: vec![7; 256] }),
+            Message::Lease { lease: lease(false), confirm: vec![] },
+            Message::Lease { lease: lease(true), confirm: vec![8; 32] },
+            Message::Data { counter: 0, payload: vec![] },
+            Message::Data { counter: 42, payload: vec![9; 1400] },
+            Message::Keepalive,
+            Message::Close,
+            Message::Error { code: ErrorCode::UnknownSession, reason: "unknown session".to_string() },
+            Message::Error { code: ErrorCode::Other, reason: String::new() },
+            Message::Probe { nonce: 7, padding: 0 },
+            Message::Probe { nonce: u64::max_value(), padding: 1452 },
+            Message::ProbeAck { nonce: 7, size: 1472 },
+        ]
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for message in messages() {
+            let packet = Packet {
+                flags: Flags::COMPRESSED | Flags::FRAGMENT,
+                session_id: 0x0102_0304_0506_0708,
+                message: message,
+            };
+            let buf = packet.encode();
+            assert_eq!(buf[0], VERSION);
+            assert_eq!(Packet::decode(&buf).unwrap(), packet);
+        }
+    }
+
+    #[test]
+    fn test_decode_malformed() {
+        for message in messages() {
+            let buf = Packet::new(1, message.clone()).encode();
+
+            // Data and probes end with whatever follows the header and counter.
+            let open_ended = match message {
+                Message::Data { .. } | Message::Probe { .. } => true,
+                _ => false
+            };
+            let min_len = if open_ended { HEADER_LEN + 8 } else { buf.len() };
+            for len in 0..min_len {
+                assert!(Packet::decode(&buf[..len]).is_err(), "{:?} truncated to {}", message, len);
+            }
+            if !open_ended {
+                let mut long = buf.clone();
+                long.push(0);
+                assert!(Packet::decode(&long).is_err(), "{:?} with a trailing byte", message);
+            }
+
+            let mut wrong_version = buf.clone();
+            for version in [0, 1, 2, 4, 255].iter() {
+                wrong_version[0] = *version;
+                assert!(Packet::decode(&wrong_version).is_err());
+            }
+            let mut wrong_kind = buf.clone();
+            wrong_kind[1] = 0;
+            assert!(Packet::decode(&wrong_kind).is_err());
+            let mut wrong_flags = buf.clone();
+            wrong_flags[2] = 0x80;
+            assert!(Packet::decode(&wrong_flags).is_err());
+        }
+
+        let mut buf = Packet::new(1, Message::Error { code: ErrorCode::Other, reason: "ab".to_string() }).encode();
+        let len = buf.len();
+        buf[len - 1] = 0xff;
+        assert!(Packet::decode(&buf).is_err());
+    }
+
+    #[test]
+    fn test_decode_random() {
+        let mut rng = Rng::new();
+        for _ in 0..20000 {
+            let len = (rng.next() % 96) as usize;
+            let mut buf: Vec<u8> = (0..len).map(|_| rng.next() as u8).collect();
+            if buf.len() >= 2 && rng.next() % 2 == 0 {
+                // Past the version and kind checks, into the bodies.
+                buf[0] = VERSION;
+                buf[1] = (rng.next() % 9) as u8;
+                if buf.len() >= 3 {
+                    buf[2] &= 0b0000_0111;
+                }
+                let _ = Packet::decode(&buf);
+            } else if buf.first() != Some(&VERSION) {
+                assert!(Packet::decode(&buf).is_err());
+            }
+        }
+
+        // Bits flipped in valid packets.
+        for message in messages() {
+            let buf = Packet::new(1, message).encode();
+            for _ in 0..200 {
+                let mut buf = buf.clone();
+                let pos = (rng.next() as usize) % buf.len();
+                buf[pos] ^= 1 << (rng.next() % 8);
+                let _ = Packet::decode(&buf);
+            }
+        }
+    }
+
+    #[test]
+    fn test_compression_mismatch() {
+        let on = features(false);
+        let off = features(true);
+        assert_eq!(on, Flags::COMPRESSED);
+        assert_eq!(off, Flags::empty());
+
+        assert_eq!(compression_mismatch(on, on), None);
+        assert_eq!(compression_mismatch(off, off), None);
+        // Other flags don't matter.
+        assert_eq!(compression_mismatch(on, on | Flags::ENCRYPTED), None);
+
+        let reason = compression_mismatch(on, off).unwrap();
+        assert!(reason.contains("enabled here but disabled on the peer"), "{}", reason);
+        let reason = compression_mismatch(off, on).unwrap();
+        assert!(reason.contains("disabled here but enabled on the peer"), "{}", reason);
+    }
+
+    #[test]
+    fn test_lease_bytes() {
+        for ipv6 in [false, true].iter() {
+            let bytes = lease(*ipv6).to_bytes();
+            assert_eq!(Lease::from_bytes(&bytes), Some(lease(*ipv6)));
+            assert_eq!(Lease::from_bytes(&bytes[..bytes.len() - 1]), None);
+        }
+
+        let mut bytes = lease(true).to_bytes();
+        bytes[LEASE_LEN + LEASE6_LEN - 1] = 128;
+        assert!(Lease::from_bytes(&bytes).is_some());
+        bytes[LEASE_LEN + LEASE6_LEN - 1] = 129;
+        assert_eq!(Lease::from_bytes(&bytes), None);
+
+        let mut buf = Packet::new(1, Message::Lease { lease: lease(true), confirm: vec![] }).encode();
+        // Header, field length, then the lease.
+        buf[HEADER_LEN + 2 + LEASE_LEN + LEASE6_LEN - 1] = 200;
+        assert!(Packet::decode(&buf).is_err());
+    }
+}
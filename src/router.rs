@@ -0,0 +1,272 @@
+/// Longest prefix match routing table of vpnd.
+///
+/// Networks map to peers the way allowed IPs do: a packet read from the tun
+/// device goes to the peer owning the most specific network containing its
+/// destination, and a packet from a peer is only accepted when its source
+/// address routes back to that same peer.
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use ipnetwork::{IpNetwork, Ipv4Network, Ipv6Network};
+use smoltcp::wire;
+
+
+/// Network address of `addr/prefix`, `bits` being the address width.
+fn mask(bits: u32, addr: u128, prefix: u8) -> u128 {
+    match (!0u128).checked_shl(bits - prefix as u32) {
+        Some(mask) => addr & mask,
+        None => 0
+    }
+}
+
+#[derive(Debug)]
+struct Table<T> {
+    bits: u32,
+    // prefix length -> network address -> value, walked from the longest prefix.
+    prefixes: BTreeMap<u8, HashMap<u128, T>>,
+}
+
+impl<T: PartialEq> Table<T> {
+    fn new(bits: u32) -> Table<T> {
+        Table { bits: bits, prefixes: BTreeMap::new() }
+    }
+
+    fn mask(&self, addr: u128, prefix: u8) -> u128 {
+        mask(self.bits, addr, prefix)
+    }
+
+    fn insert(&mut self, addr: u128, prefix: u8, value: T) -> Option<T> {
+        let key = self.mask(addr, prefix);
+        self.prefixes.entry(prefix).or_insert_with(HashMap::new).insert(key, value)
+    }
+
+    fn get(&self, addr: u128, prefix: u8) -> Option<&T> {
+        match self.prefixes.get(&prefix) {
+            Some(routes) => routes.get(&self.mask(addr, prefix)),
+            None => None
+        }
+    }
+
+    fn remove(&mut self, addr: u128, prefix: u8) -> Option<T> {
+        let key = self.mask(addr, prefix);
+        let (value, empty) = match self.prefixes.get_mut(&prefix) {
+            Some(routes) => (routes.remove(&key), routes.is_empty()),
+            None => return None
+        };
+        if empty {
+            self.prefixes.remove(&prefix);
+        }
+        value
+    }
+
+    fn lookup(&self, addr: u128) -> Option<&T> {
+        for (prefix, routes) in self.prefixes.iter().rev() {
+            if let Some(value) = routes.get(&self.mask(addr, *prefix)) {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    fn remove_all(&mut self, value: &T) {
+        for routes in self.prefixes.values_mut() {
+            routes.retain(|_, v| v != value);
+        }
+        let empty: Vec<u8> = self.prefixes.iter()
+            .filter(|&(_, routes)| routes.is_empty())
+            .map(|(prefix, _)| *prefix)
+            .collect();
+        for prefix in empty {
+            self.prefixes.remove(&prefix);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.prefixes.values().map(|routes| routes.len()).sum()
+    }
+}
+
+
+#[derive(Debug)]
+pub struct Router<T> {
+    ipv4: Table<T>,
+    ipv6: Table<T>,
+}
+
+impl<T: PartialEq> Router<T> {
+    pub fn new() -> Router<T> {
+        Router { ipv4: Table::new(32), ipv6: Table::new(128) }
+    }
+
+    /// Route `network` to `value`, returns the value it was routed to before.
+    pub fn insert(&mut self, network: IpNetwork, value: T) -> Option<T> {
+        match network {
+            IpNetwork::V4(net) => self.ipv4.insert(u32::from(net.ip()) as u128, net.prefix(), value),
+            IpNetwork::V6(net) => self.ipv6.insert(u128::from(net.ip()), net.prefix(), value),
+        }
+    }
+
+    /// Exact match on `network`, no prefix matching.
+    pub fn get(&self, network: &IpNetwork) -> Option<&T> {
+        match *network {
+            IpNetwork::V4(net) => self.ipv4.get(u32::from(net.ip()) as u128, net.prefix()),
+            IpNetwork::V6(net) => self.ipv6.get(u128::from(net.ip()), net.prefix()),
+        }
+    }
+
+    pub fn remove(&mut self, network: &IpNetwork) -> Option<T> {
+        match *network {
+            IpNetwork::V4(net) => self.ipv4.remove(u32::from(net.ip()) as u128, net.prefix()),
+            IpNetwork::V6(net) => self.ipv6.remove(u128::from(net.ip()), net.prefix()),
+        }
+    }
+
+    /// Most specific route containing `addr`.
+    pub fn lookup(&self, addr: &IpAddr) -> Option<&T> {
+        match *addr {
+            IpAddr::V4(addr) => self.ipv4.lookup(u32::from(addr) as u128),
+            IpAddr::V6(addr) => self.ipv6.lookup(u128::from(addr)),
+        }
+    }
+
+    /// Remove every route to `value`.
+    pub fn remove_all(&mut self, value: &T) {
+        self.ipv4.remove_all(value);
+        self.ipv6.remove_all(value);
+    }
+
+    pub fn len(&self) -> usize {
+        self.ipv4.len() + self.ipv6.len()
+    }
+}
+
+/// Single address network, `/32` or `/128`.
+pub fn host(addr: IpAddr) -> IpNetwork {
+    match addr {
+        IpAddr::V4(addr) => IpNetwork::V4(Ipv4Network::new(addr, 32).unwrap()),
+        IpAddr::V6(addr) => IpNetwork::V6(Ipv6Network::new(addr, 128).unwrap()),
+    }
+}
+
+/// Whether one of the networks contains the other.
+pub fn overlaps(a: &IpNetwork, b: &IpNetwork) -> bool {
+    match (*a, *b) {
+        (IpNetwork::V4(a), IpNetwork::V4(b)) => {
+            let prefix = cmp::min(a.prefix(), b.prefix());
+            mask(32, u32::from(a.ip()) as u128, prefix) == mask(32, u32::from(b.ip()) as u128, prefix)
+        },
+        (IpNetwork::V6(a), IpNetwork::V6(b)) => {
+            let prefix = cmp::min(a.prefix(), b.prefix());
+            mask(128, u128::from(a.ip()), prefix) == mask(128, u128::from(b.ip()), prefix)
+        },
+        _ => false
+    }
+}
+
+/// Network address of `network`, `10.1.2.3/16` is `10.1.0.0/16`.
+pub fn truncate(network: &IpNetwork) -> IpNetwork {
+    match *network {
+        IpNetwork::V4(n) => {
+            let addr = mask(32, u32::from(n.ip()) as u128, n.prefix()) as u32;
+            IpNetwork::V4(Ipv4Network::new(Ipv4Addr::from(addr), n.prefix()).unwrap())
+        },
+        IpNetwork::V6(n) => {
+            let addr = mask(128, u128::from(n.ip()), n.prefix());
+            IpNetwork::V6(Ipv6Network::new(Ipv6Addr::from(addr), n.prefix()).unwrap())
+        },
+    }
+}
+
+/// Source and destination of an IP packet, `None` when it is not one.
+pub fn addresses(packet: &[u8]) -> Option<(IpAddr, IpAddr)> {
+    match wire::IpVersion::of_packet(packet) {
+        Ok(wire::IpVersion::Ipv4) => match wire::Ipv4Packet::new_checked(packet) {
+            Ok(ipv4_packet) => Some((IpAddr::V4(Ipv4Addr::from(ipv4_packet.src_addr().0)),
+                                     IpAddr::V4(Ipv4Addr::from(ipv4_packet.dst_addr().0)))),
+            Err(_) => None
+        },
+        Ok(wire::IpVersion::Ipv6) => match wire::Ipv6Packet::new_checked(packet) {
+            Ok(ipv6_packet) => Some((IpAddr::V6(Ipv6Addr::from(ipv6_packet.src_addr().0)),
+                                     IpAddr::V6(Ipv6Addr::from(ipv6_packet.dst_addr().0)))),
+            Err(_) => None
+        },
+        _ => None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn net(s: &str) -> IpNetwork {
+        IpNetwork::from_str(s).unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        IpAddr::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_longest_prefix() {
+        let mut router = Router::new();
+        router.insert(net("10.0.0.0/8"), "a");
+        router.insert(net("10.1.0.0/16"), "b");
+        router.insert(net("10.1.2.3/32"), "c");
+        router.insert(net("fd00::/64"), "a");
+        router.insert(net("fd00::2/128"), "b");
+
+        assert_eq!(router.lookup(&ip("10.200.0.1")), Some(&"a"));
+        assert_eq!(router.lookup(&ip("10.1.9.9")), Some(&"b"));
+        assert_eq!(router.lookup(&ip("10.1.2.3")), Some(&"c"));
+        assert_eq!(router.lookup(&ip("192.168.0.1")), None);
+        assert_eq!(router.lookup(&ip("fd00::1")), Some(&"a"));
+        assert_eq!(router.lookup(&ip("fd00::2")), Some(&"b"));
+        assert_eq!(router.lookup(&ip("fd01::2")), None);
+
+        // Host bits of the network are ignored.
+        assert_eq!(router.get(&net("10.1.255.255/16")), Some(&"b"));
+        assert_eq!(router.remove(&net("10.1.0.0/16")), Some("b"));
+        assert_eq!(router.lookup(&ip("10.1.9.9")), Some(&"a"));
+    }
+
+    #[test]
+    fn test_default_route() {
+        let mut router = Router::new();
+        router.insert(net("0.0.0.0/0"), 1);
+        router.insert(net("::/0"), 2);
+        assert_eq!(router.lookup(&ip("1.2.3.4")), Some(&1));
+        assert_eq!(router.lookup(&ip("2001:db8::1")), Some(&2));
+    }
+
+    #[test]
+    fn test_remove_all() {
+        let mut router = Router::new();
+        router.insert(net("172.16.0.2/32"), "a");
+        router.insert(net("192.168.10.0/24"), "a");
+        router.insert(net("fd00::2/128"), "a");
+        router.insert(net("172.16.0.3/32"), "b");
+        router.remove_all(&"a");
+        assert_eq!(router.len(), 1);
+        assert_eq!(router.lookup(&ip("192.168.10.1")), None);
+        assert_eq!(router.lookup(&ip("172.16.0.3")), Some(&"b"));
+    }
+
+    #[test]
+    fn test_overlaps() {
+        assert!(overlaps(&net("172.16.0.0/16"), &net("172.16.5.0/24")));
+        assert!(overlaps(&net("172.16.5.0/24"), &net("172.16.0.0/16")));
+        assert!(!overlaps(&net("172.16.0.0/16"), &net("192.168.0.0/16")));
+        assert!(overlaps(&net("fd00::/48"), &net("fd00:0:0:1::/64")));
+        assert!(!overlaps(&net("fd00::/64"), &net("10.0.0.0/8")));
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(truncate(&net("10.1.2.3/16")), net("10.1.0.0/16"));
+        assert_eq!(truncate(&net("10.1.2.3/32")), net("10.1.2.3/32"));
+        assert_eq!(truncate(&net("fd00::1:2/64")), net("fd00::/64"));
+    }
+}
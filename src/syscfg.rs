@@ -1,6 +1,15 @@
 
+use std::io;
+#[cfg(target_os = "macos")]
 use std::process;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+#[cfg(target_os = "linux")]
+use ipnetwork::{IpNetwork, Ipv6Network};
+#[cfg(target_os = "linux")]
+use netif::netlink;
+#[cfg(target_os = "macos")]
+use netif::route;
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct DefaultDNS {
@@ -44,65 +53,73 @@ pub fn get_default_networkservice(default_ifname: &str) -> Option<String> {
 
 #[cfg(target_os = "macos")]
 pub fn get_default_route() -> Option<(String, Ipv4Addr)> {
-    // route get default
-    match process::Command::new("route")
-            .arg("-n")
-            .arg("get")
-            .arg("default")
-            .output() {
-        Ok(output) => {
-            if output.status.success() {
-                let mut ifname: Option<String> = None;
-                let mut gateway: Option<Ipv4Addr> = None;
-
-                for line in String::from_utf8(output.stdout).unwrap().lines() {
-                    let s = line.trim();
-                    if s.starts_with("gateway") {
-                        if gateway.is_none() {
-                            let ip: Ipv4Addr = s.split(":").nth(1).unwrap().trim().parse().unwrap();
-                            gateway = Some(ip);
-                        }
-                    } else if s.starts_with("interface") {
-                        if ifname.is_none() {
-                            let ss = s.split(":").nth(1).unwrap().trim().to_string();
-                            ifname = Some(ss);
-                        }
-                    }
-                }
-                if ifname.is_some() && gateway.is_some() {
-                    return Some((ifname.unwrap(), gateway.unwrap()))
-                }
-            }
+    match route::get(&"0.0.0.0".parse().unwrap()) {
+        Ok(table) => match *table.gateway() {
+            route::Gateway::IpAddress(IpAddr::V4(gateway)) => Some((table.ifname().to_string(), gateway)),
+            _ => None
         },
-        Err(_) => { }
+        Err(_) => None
     }
+}
 
-    None
+#[cfg(target_os = "linux")]
+fn get_default_gateways() -> Vec<(String, IpAddr)> {
+    let (routes, links) = match (netlink::routes(), netlink::links()) {
+        (Ok(routes), Ok(links)) => (routes, links),
+        _ => return vec![]
+    };
+    let mut defaults = routes.into_iter()
+        .filter(|route| route.is_default() && route.table == netlink::RT_TABLE_MAIN)
+        .collect::<Vec<netlink::Route>>();
+    // The kernel picks the lowest metric first.
+    defaults.sort_by_key(|route| route.priority.unwrap_or(0));
+
+    defaults.iter().filter_map(|route| {
+        let link = links.iter().find(|link| Some(link.index) == route.ifindex);
+        match (link, route.gateway) {
+            (Some(link), Some(gateway)) => Some((link.name.clone(), gateway)),
+            _ => None
+        }
+    }).collect()
 }
 
 #[cfg(target_os = "linux")]
 pub fn get_default_route() -> Option<(String, Ipv4Addr)> {
-    // ip route
-    match process::Command::new("ip")
-            .arg("route")
-            .output() {
-        Ok(output) => {
-            if output.status.success() {
-                for line in String::from_utf8(output.stdout).unwrap().lines() {
-                    let s = line.trim();
-                    if s.starts_with("default") {
-                        let mut _tmp = s.split(" ").collect::<Vec<&str>>();
-                        assert_eq!(_tmp.len(), 5);
-                        let gateway_ip: Ipv4Addr = _tmp[2].trim().parse().unwrap();
-                        let ifname = _tmp[4].trim().to_string();
-                        return Some((ifname, gateway_ip))
-                    }
+    for (ifname, gateway) in get_default_gateways() {
+        if let IpAddr::V4(gateway) = gateway {
+            return Some((ifname, gateway));
+        }
+    }
+    None
+}
+
+#[cfg(target_os = "macos")]
+pub fn get_default_route6() -> Option<(String, Ipv6Addr)> {
+    match route::get(&"::".parse().unwrap()) {
+        Ok(table) => match *table.gateway() {
+            route::Gateway::IpAddress(IpAddr::V6(gateway)) => {
+                // The kernel embeds the interface index of link local gateways in the second word.
+                let mut segments = gateway.segments();
+                if segments[0] & 0xffc0 == 0xfe80 {
+                    segments[1] = 0;
                 }
-            }
+                let gateway = Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3],
+                                            segments[4], segments[5], segments[6], segments[7]);
+                Some((table.ifname().to_string(), gateway))
+            },
+            _ => None
         },
-        Err(_) => { }
+        Err(_) => None
     }
+}
 
+#[cfg(target_os = "linux")]
+pub fn get_default_route6() -> Option<(String, Ipv6Addr)> {
+    for (ifname, gateway) in get_default_gateways() {
+        if let IpAddr::V6(gateway) = gateway {
+            return Some((ifname, gateway));
+        }
+    }
     None
 }
 
@@ -132,6 +149,55 @@ pub fn get_default_dns() -> Option<DefaultDNS> {
     }
 }
 
+/// The tun crate only knows about IPv4, the IPv6 address is added afterwards.
+#[cfg(target_os = "linux")]
+pub fn add_ipv6_address(ifname: &str, addr: Ipv6Addr, prefix_len: u8) -> Result<(), io::Error> {
+    // sudo ip -6 addr add fd00::2/64 dev tun9
+    let network = match Ipv6Network::new(addr, prefix_len) {
+        Ok(network) => IpNetwork::V6(network),
+        Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                            format!("invalid IPv6 prefix {}/{}", addr, prefix_len)))
+    };
+    let link = try!(netlink::link(ifname));
+    netlink::add_address(link.index, &network)
+        .map_err(|e| io::Error::new(e.kind(), format!("can't add {} to {}: {}", network, ifname, e)))
+}
+
+#[cfg(target_os = "macos")]
+pub fn add_ipv6_address(ifname: &str, addr: Ipv6Addr, prefix_len: u8) -> Result<(), io::Error> {
+    // sudo ifconfig utun9 inet6 fd00::2 prefixlen 64
+    let status = try!(process::Command::new("ifconfig")
+                          .args(&[ifname, "inet6", &format!("{}", addr)])
+                          .args(&["prefixlen", &format!("{}", prefix_len)])
+                          .status());
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::Other, format!("can't add {}/{} to {}", addr, prefix_len, ifname)))
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub fn set_mtu(ifname: &str, mtu: usize) -> Result<(), io::Error> {
+    // sudo ip link set dev tun9 mtu 1400
+    let link = try!(netlink::link(ifname));
+    netlink::set_mtu(link.index, mtu as u32)
+        .map_err(|e| io::Error::new(e.kind(), format!("can't set the MTU of {} to {}: {}", ifname, mtu, e)))
+}
+
+#[cfg(target_os = "macos")]
+pub fn set_mtu(ifname: &str, mtu: usize) -> Result<(), io::Error> {
+    // sudo ifconfig utun9 mtu 1400
+    let status = try!(process::Command::new("ifconfig")
+                          .args(&[ifname, "mtu", &format!("{}", mtu)])
+                          .status());
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::Other, format!("can't set the MTU of {} to {}", ifname, mtu)))
+    }
+}
+
 // #[cfg(target_os = "macos")]
 // pub fn set_default_dns(networkservice: String, dns_server: Ipv4Addr) -> Result<(), io::Error> {
     
@@ -0,0 +1,23 @@
+/// Helpers shared by the unit tests.
+
+
+/// xorshift64*, deterministic noise without a rand dependency in the tests.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new() -> Rng {
+        Rng(0x9e37_79b9_7f4a_7c15)
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// `len` bytes below `modulo`.
+    pub fn bytes(&mut self, len: usize, modulo: u64) -> Vec<u8> {
+        (0..len).map(|_| (self.next() % modulo) as u8).collect()
+    }
+}
@@ -0,0 +1,184 @@
+/// UDP socket of vpnd, dual-stack when the host has IPv6.
+///
+/// IPv4 peers show up as `::ffff:a.b.c.d` on an IPv6 socket. They are mapped
+/// back to plain IPv4 addresses, so a peer has the same key whichever socket
+/// it came through, and mapped again on the way out.
+use libc;
+use mio;
+use mtu;
+
+use std::io;
+use std::mem;
+use std::net::{self, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+
+
+pub struct Listener {
+    socket: mio::net::UdpSocket,
+    ipv6: bool,
+}
+
+impl Listener {
+    /// Bind `[::]:port` accepting IPv4 as well, `0.0.0.0:port` when IPv6 is not available.
+    /// Packets leave with DF set, see `mtu`.
+    pub fn bind(port: u16) -> Result<Listener, io::Error> {
+        let listener = match bind_dual_stack(port) {
+            Ok(socket) => Listener {
+                socket: try!(mio::net::UdpSocket::from_socket(socket)),
+                ipv6: true,
+            },
+            Err(e) => {
+                warn!("can't bind [::]:{}: {}, accept IPv4 peers only", port, e);
+                let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port);
+                Listener {
+                    socket: try!(mio::net::UdpSocket::bind(&addr)),
+                    ipv6: false,
+                }
+            }
+        };
+        try!(mtu::set_dont_fragment(listener.socket.as_raw_fd(), listener.ipv6));
+        Ok(listener)
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.socket.local_addr()
+    }
+
+    pub fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr), io::Error> {
+        let (size, addr) = try!(self.socket.recv_from(buf));
+        Ok((size, unmap(addr)))
+    }
+
+    pub fn send_to(&self, buf: &[u8], target: &SocketAddr) -> Result<usize, io::Error> {
+        match *target {
+            SocketAddr::V4(ref v4) if self.ipv6 => {
+                let mapped = SocketAddr::V6(SocketAddrV6::new(v4.ip().to_ipv6_mapped(), v4.port(), 0, 0));
+                self.socket.send_to(buf, &mapped)
+            },
+            SocketAddr::V6(_) if !self.ipv6 => {
+                Err(io::Error::new(io::ErrorKind::InvalidInput, "IPv6 peer on an IPv4 socket"))
+            },
+            _ => self.socket.send_to(buf, target)
+        }
+    }
+}
+
+impl mio::Evented for Listener {
+    fn register(&self, poll: &mio::Poll, token: mio::Token, interest: mio::Ready,
+                opts: mio::PollOpt) -> io::Result<()> {
+        self.socket.register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &mio::Poll, token: mio::Token, interest: mio::Ready,
+                  opts: mio::PollOpt) -> io::Result<()> {
+        self.socket.reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+        self.socket.deregister(poll)
+    }
+}
+
+/// `::ffff:a.b.c.d` -> `a.b.c.d`, other addresses are left alone.
+pub fn unmap(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match ipv4_mapped(v6.ip()) {
+            Some(ip) => SocketAddr::new(IpAddr::V4(ip), v6.port()),
+            None => addr
+        },
+        _ => addr
+    }
+}
+
+// `Ipv6Addr::to_ipv4` accepts the deprecated compatible form too, which turns `::1` into `0.0.0.1`.
+fn ipv4_mapped(ip: &Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = ip.segments();
+    if segments[..5] == [0, 0, 0, 0, 0] && segments[5] == 0xffff {
+        let octets = ip.octets();
+        Some(Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]))
+    } else {
+        None
+    }
+}
+
+fn bind_dual_stack(port: u16) -> Result<net::UdpSocket, io::Error> {
+    unsafe {
+        let fd = libc::socket(libc::AF_INET6, libc::SOCK_DGRAM, 0);
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // Owns the descriptor from here on, it is closed on error.
+        let socket = net::UdpSocket::from_raw_fd(fd);
+
+        // Defaults to the `net.ipv6.bindv6only` sysctl on Linux, and to on for the BSDs.
+        let off: libc::c_int = 0;
+        if libc::setsockopt(fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY,
+                            &off as *const libc::c_int as *const libc::c_void,
+                            mem::size_of::<libc::c_int>() as libc::socklen_t) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut addr: libc::sockaddr_in6 = mem::zeroed();
+        addr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+        addr.sin6_port = port.to_be();
+        if libc::bind(fd, &addr as *const libc::sockaddr_in6 as *const libc::sockaddr,
+                      mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(socket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use std::thread;
+    use std::time::Duration;
+
+    fn addr(s: &str) -> SocketAddr {
+        SocketAddr::from_str(s).unwrap()
+    }
+
+    /// The listener socket doesn't block.
+    fn recv(listener: &Listener, buf: &mut [u8]) -> (usize, SocketAddr) {
+        for _ in 0..100 {
+            match listener.recv_from(buf) {
+                Ok(received) => return received,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(10)),
+                Err(e) => panic!("{}", e)
+            }
+        }
+        panic!("nothing received");
+    }
+
+    #[test]
+    fn test_unmap() {
+        assert_eq!(unmap(addr("[::ffff:1.2.3.4]:9")), addr("1.2.3.4:9"));
+        assert_eq!(unmap(addr("1.2.3.4:9")), addr("1.2.3.4:9"));
+        assert_eq!(unmap(addr("[2001:db8::1]:9")), addr("[2001:db8::1]:9"));
+        assert_eq!(unmap(addr("[::ffff:0:1.2.3.4]:9")), addr("[::ffff:0:1.2.3.4]:9"));
+        // Compatible addresses are not mapped ones.
+        assert_eq!(unmap(addr("[::1]:9")), addr("[::1]:9"));
+        assert_eq!(unmap(addr("[::1.2.3.4]:9")), addr("[::1.2.3.4]:9"));
+    }
+
+    #[test]
+    fn test_ipv4_peer() {
+        let listener = Listener::bind(0).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+        client.send_to(b"ping", ("127.0.0.1", port)).unwrap();
+        let mut buf = [0u8; 16];
+        let (size, from) = recv(&listener, &mut buf);
+        assert_eq!(&buf[..size], b"ping");
+        assert_eq!(from, client.local_addr().unwrap());
+
+        listener.send_to(b"pong", &from).unwrap();
+        let (size, from) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..size], b"pong");
+        assert_eq!(from, addr(&format!("127.0.0.1:{}", port)));
+    }
+}
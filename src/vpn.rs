@@ -3,7 +3,10 @@
 #[allow(unused_imports)]
 #[macro_use]
 extern crate logging;
+#[macro_use]
+extern crate bitflags;
 extern crate clap;
+extern crate toml;
 extern crate ctrlc;
 extern crate byteorder;
 extern crate mio;
@@ -21,150 +24,154 @@ extern crate tun;
 extern crate ipnetwork;
 extern crate smoltcp;
 extern crate netif;
+extern crate iana;
 
 
 pub mod signal;
 pub mod syscfg;
+#[cfg(target_os = "linux")]
+pub mod firewall;
 pub mod crypto;
 pub mod compression;
+pub mod protocol;
+pub mod fragment;
+pub mod mtu;
+pub mod mss;
+pub mod handshake;
+pub mod config;
+pub mod daemon;
+pub mod journal;
+pub mod cidr;
+#[cfg(test)]
+pub mod testutil;
 
 
 use std::env;
 use std::process;
-use std::time::Duration;
-use std::fs::{File, OpenOptions};
+use std::time::{Duration, Instant};
+use std::fs::File;
+use std::path::{Path, PathBuf};
 
 use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
 
 use std::str::FromStr;
 use std::io::{self, Read, Write};
 use std::os::unix::io::{RawFd, AsRawFd};
 
 
-use byteorder::{ByteOrder, NetworkEndian};
-
 use mio::Evented;
-// use mio::unix::EventedFd;
+use mio::unix::EventedFd;
 
 use smoltcp::wire;
 use tun::platform::Device as TunDevice;
-use ipnetwork::Ipv4Network;
+use ipnetwork::{IpNetwork, Ipv4Network};
 
 use netif::{LinkLayer, RawSocket};
 use netif::interface::Interface;
+use netif::route::{self, Destination, Gateway};
+
+use protocol::{Flags, Hello, Message, Packet};
 
 
 const TUN_TOKEN: mio::Token = mio::Token(0);
 const UDP_TOKEN: mio::Token = mio::Token(1);
 const GATEWAY_TOKEN: mio::Token = mio::Token(2);
 
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+/// Routed through the tunnel when no `--include` is given, IPv6 only when leased.
+const FULL_TUNNEL: [&'static str; 2] = ["0.0.0.0/0", "::/0"];
+const DEFAULT_JOURNAL: &'static str = "/var/run/vpn.journal";
+#[cfg(target_os = "linux")]
+const RESOLV_CONF: &'static str = "/etc/resolv.conf";
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct SystemDns {
     dns_server: Ipv4Addr,
+    /// Only used when the lease has an IPv6 address.
+    dns_server6: Option<Ipv6Addr>,
     #[cfg(target_os = "macos")]
     default_networkservice: String,
-    #[cfg(target_os = "linux")]
-    resolv_conf: String
 }
 
 impl SystemDns {
     #[cfg(target_os = "macos")]
-    pub fn new(networkservice: String, dns_server: Ipv4Addr) -> Result<SystemDns, io::Error> {
+    pub fn new(networkservice: String, dns_server: Ipv4Addr,
+               dns_server6: Option<Ipv6Addr>) -> Result<SystemDns, io::Error> {
         Ok(SystemDns {
             dns_server: dns_server,
+            dns_server6: dns_server6,
             default_networkservice: networkservice
         })
     }
 
     #[cfg(target_os = "linux")]
-    pub fn new(dns_server: Ipv4Addr) -> Result<SystemDns, io::Error> {
-        match File::open("/etc/resolv.conf") {
-            Ok(file) => {
-                let mut contents = String::new();
-                match file.read_to_string(&mut contents) {
-                    Ok(_) => Ok(SystemDns {
-                            dns_server: dns_server,
-                            resolv_conf: contents
-                    }),
-                    Err(e) => Err(e)
-                }
-            }
-            Err(e) => Err(e)
-        }
-    }
-
-    #[cfg(target_os = "macos")]
-    pub fn execute(&self) -> Result<(), io::Error> {
-         match process::Command::new("networksetup")
-                .arg("-setdnsservers")
-                .arg(&self.default_networkservice)
-                .arg(format!("{}", self.dns_server))
-                .status() {
-            Ok(status) => {
-                if status.success() {
-                    Ok(())
-                } else {
-                    Err(io::Error::new(io::ErrorKind::Other, "Ooops ..."))
-                }
-            },
-            Err(e) => Err(e)
-        }
+    pub fn new(dns_server: Ipv4Addr, dns_server6: Option<Ipv6Addr>) -> Result<SystemDns, io::Error> {
+        // The journal reads it again when replacing it, fail early if we can't.
+        try!(File::open(RESOLV_CONF));
+        Ok(SystemDns {
+            dns_server: dns_server,
+            dns_server6: dns_server6
+        })
     }
 
-    #[cfg(target_os = "linux")]
-    pub fn execute(&self) -> Result<(), io::Error> {
-        let data = format!("nameserver {}\n", self.dns_server);
-        match OpenOptions::new().write(true).create(true).open("/etc/resolv.conf") {
-            Ok(file) => file.write_all(&data.as_bytes()),
-            Err(e) => Err(e)
+    fn servers(&self, ipv6: bool) -> Vec<String> {
+        let mut servers = vec![format!("{}", self.dns_server)];
+        if let (true, Some(dns_server6)) = (ipv6, self.dns_server6) {
+            servers.push(format!("{}", dns_server6));
         }
+        servers
     }
 
     #[cfg(target_os = "macos")]
-    pub fn recover(&self) -> Result<(), io::Error> {
-        // sudo networksetup -setdnsservers Wi-Fi Empty
-        match process::Command::new("networksetup")
-                .arg("-setdnsservers")
-                .arg(&self.default_networkservice)
-                .arg("Empty")
-                .status() {
-            Ok(status) => {
-                if status.success() {
-                    Ok(())
-                } else {
-                    Err(io::Error::new(io::ErrorKind::Other, "Ooops ..."))
-                }
-            },
-            Err(e) => Err(e)
-        }
+    pub fn execute(&self, journal: &mut journal::Journal, ipv6: bool) -> Result<(), io::Error> {
+        journal.set_dns_servers(&self.default_networkservice, &self.servers(ipv6))
     }
 
     #[cfg(target_os = "linux")]
-    pub fn recover(&self) -> Result<(), io::Error> {
-        match OpenOptions::new().write(true).create(true).open("/etc/resolv.conf") {
-            Ok(file) => file.write_all(self.resolv_conf.as_bytes()),
-            Err(e) => Err(e)
-        }
+    pub fn execute(&self, journal: &mut journal::Journal, ipv6: bool) -> Result<(), io::Error> {
+        let data: String = self.servers(ipv6).iter()
+            .map(|server| format!("nameserver {}\n", server))
+            .collect();
+        journal.write_file(Path::new(RESOLV_CONF), &data)
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+/// Routes and nameservers pointed at the tunnel by auto config.
+///
+/// Every change is journaled first, `recover` puts them back. So does dropping it,
+/// and `vpn --recover` after a crash.
+#[derive(Debug)]
 pub struct AutoSystemConfig {
-    pub server_ipv4: Ipv4Addr,
-    pub tun_ipv4: Ipv4Addr,
-
-    pub default_ifname: String,
-    pub default_gateway: Ipv4Addr,
-    pub defaul_dns: SystemDns,
+    journal: journal::Journal,
 }
 
 impl AutoSystemConfig {
-    pub fn execute(&self) -> Result<(), io::Error> {
-        Ok(())
+    /// All or nothing, a failed step rolls back the ones before it.
+    pub fn execute(config: &ClientConfig, lease: &protocol::Lease,
+                   default_route: &(String, IpAddr)) -> Result<AutoSystemConfig, io::Error> {
+        let mut journal = try!(journal::Journal::create(&config.journal));
+        if let Err(e) = configure(&mut journal, config, lease, default_route) {
+            if let Err(e) = journal.rollback() {
+                error!("{}", e);
+            }
+            return Err(e);
+        }
+        Ok(AutoSystemConfig { journal: journal })
+    }
+
+    /// Reach the server through another default route, once the host moved networks.
+    pub fn reroute(&mut self, config: &ClientConfig, default_route: &(String, IpAddr)) -> Result<(), io::Error> {
+        let (ref ifname, gateway) = *default_route;
+        // sudo ip route replace <server_ip>/32 via 172.20.10.1 dev wlan1
+        self.journal.replace_route(&Destination::IpAddress(config.server_socket_addr.ip()),
+                                   &Gateway::IpAddress(gateway), Some(ifname))
     }
 
-    pub fn recover(&self) -> Result<(), io::Error> {
+    pub fn recover(mut self) -> Result<(), io::Error> {
+        try!(self.journal.rollback());
+        info!("restore default routing table and dns setting    [OK]");
         Ok(())
     }
 }
@@ -172,32 +179,87 @@ impl AutoSystemConfig {
 #[derive(Debug)]
 pub struct ClientConfig {
     pub verbose: String,
+    pub daemon: daemon::Options,
 
     pub no_autoconfig: bool,
     pub tun_ifname: String,
+    /// MTU of the tun device, derived from the outer interface when `None`.
+    pub mtu: Option<usize>,
+    /// Split packets too big for the tunnel rather than answer them with ICMP errors.
+    pub fragment: bool,
+    /// Lower the MSS of TCP connections through the tunnel to fit its MTU.
+    pub mss_clamp: bool,
     pub default_ifname: String,
     pub default_gateway: Ipv4Addr,
+    /// Interface and gateway of the IPv6 default route, only for an IPv6 server.
+    pub default_route6: Option<(String, Ipv6Addr)>,
     pub default_networkservice: Option<String>,
     
     pub dns_server: Option<Ipv4Addr>,
+    pub dns_server6: Option<Ipv6Addr>,
+    /// Nameserver setting to apply and restore, `None` with `--no-auto-config`.
+    pub system_dns: Option<SystemDns>,
+    /// Networks routed through the tunnel, everything when empty.
+    pub include: Vec<IpNetwork>,
+    /// Networks kept off the tunnel, such as the local LAN.
+    pub exclude: Vec<IpNetwork>,
+    /// Country codes whose addresses go through the tunnel, on top of `include`.
+    pub include_countries: Vec<String>,
+    /// Country codes whose addresses stay off the tunnel, on top of `exclude`.
+    pub exclude_countries: Vec<String>,
+    /// Country delegations, the compiled-in tables updated by `--delegation-file`.
+    pub delegations: iana::number::Database,
+    /// Where auto config records how to undo its changes.
+    pub journal: PathBuf,
 
     pub server_socket_addr: SocketAddr,
     pub local_udp_port: u16,
+    /// Send a keepalive when nothing was sent for this long.
+    pub keepalive_interval: Duration,
+    /// Reconnect when nothing was received from the server for this long.
+    pub dead_peer_timeout: Duration,
     
     pub disable_compression: bool,
     pub disable_crypto: bool,
     pub prikey: Option<crypto::rsa::PriKey>,
+    pub server_pubkey: Option<crypto::rsa::PubKey>,
 }
 
 
+#[cfg(target_os = "macos")]
+const DEFAULT_TUN_IFNAME: &'static str = "utun9";
 #[cfg(target_os = "linux")]
-fn boot() -> Result<ClientConfig, io::Error> {
-    error!("VPN client does not yet support your platform");
-    unimplemented!()
+const DEFAULT_TUN_IFNAME: &'static str = "tun9";
+
+#[cfg(target_os = "macos")]
+fn detect_networkservice(ifname: &str) -> Option<String> {
+    match syscfg::get_default_networkservice(ifname) {
+        Some(networkservice) => Some(networkservice),
+        None => {
+            println!("Can't get default networkservice.");
+            process::exit(1);
+        }
+    }
 }
 
+#[cfg(target_os = "linux")]
+fn detect_networkservice(_ifname: &str) -> Option<String> {
+    None
+}
 
 #[cfg(target_os = "macos")]
+fn load_system_dns(default_networkservice: &Option<String>, dns_server: Ipv4Addr,
+                   dns_server6: Option<Ipv6Addr>) -> Result<SystemDns, io::Error> {
+    SystemDns::new(default_networkservice.clone().unwrap(), dns_server, dns_server6)
+}
+
+#[cfg(target_os = "linux")]
+fn load_system_dns(_default_networkservice: &Option<String>, dns_server: Ipv4Addr,
+                   dns_server6: Option<Ipv6Addr>) -> Result<SystemDns, io::Error> {
+    SystemDns::new(dns_server, dns_server6)
+}
+
+
 fn boot() -> Result<ClientConfig, io::Error> {
     use clap::{App, Arg};
 
@@ -213,6 +275,12 @@ fn boot() -> Result<ClientConfig, io::Error> {
                 .takes_value(true)
                 .help("Use a custom config file")
         )
+        .arg(
+            Arg::with_name("check-config")
+                .long("check-config")
+                .required(false)
+                .help("Validate the configuration and exit")
+        )
         .arg(
             Arg::with_name("verbose")
                 .short("v")
@@ -228,12 +296,91 @@ fn boot() -> Result<ClientConfig, io::Error> {
                 .required(false)
                 .help("VPN client running process into the background")
         )
+        .arg(
+            Arg::with_name("pidfile")
+                .long("pidfile")
+                .required(false)
+                .takes_value(true)
+                .help("Write the process id to this file, refuse to start if another instance holds it")
+        )
+        .arg(
+            Arg::with_name("log-file")
+                .long("log-file")
+                .required(false)
+                .takes_value(true)
+                .help("Append logs to this file instead of stderr")
+        )
+        .arg(
+            Arg::with_name("user")
+                .long("user")
+                .required(false)
+                .takes_value(true)
+                .help("Drop root privileges to this user once the tun device and sockets are opened")
+        )
         .arg(
             Arg::with_name("no-autoconfig")
                 .long("no-auto-config")
                 .required(false)
                 .help("Auto config system routing table and nameserver")
         )
+        .arg(
+            Arg::with_name("include")
+                .long("include")
+                .required(false)
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Only route this network through the tunnel, may be repeated (e.g 10.0.0.0/8)")
+        )
+        .arg(
+            Arg::with_name("exclude")
+                .long("exclude")
+                .required(false)
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Keep this network off the tunnel, may be repeated (e.g 192.168.0.0/16)")
+        )
+        .arg(
+            Arg::with_name("include-country")
+                .long("include-country")
+                .required(false)
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Route the addresses of this country through the tunnel, may be repeated (e.g US)")
+        )
+        .arg(
+            Arg::with_name("exclude-country")
+                .long("exclude-country")
+                .required(false)
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Keep the addresses of this country off the tunnel, may be repeated (e.g CN)")
+        )
+        .arg(
+            Arg::with_name("delegation-file")
+                .long("delegation-file")
+                .required(false)
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Read country delegations from this RIR delegated-extended file or packdb database, may be repeated")
+        )
+        .arg(
+            Arg::with_name("journal")
+                .long("journal")
+                .required(false)
+                .takes_value(true)
+                .help("Record how to undo the auto config changes in this file")
+        )
+        .arg(
+            Arg::with_name("recover")
+                .long("recover")
+                .required(false)
+                .help("Undo the auto config changes left behind by a crashed client and exit")
+        )
         .arg(
             Arg::with_name("dns")
                 .long("dns")
@@ -242,6 +389,13 @@ fn boot() -> Result<ClientConfig, io::Error> {
                 .default_value("8.8.8.8")
                 .help("Use a custom nameserver")
         )
+        .arg(
+            Arg::with_name("dns6")
+                .long("dns6")
+                .required(false)
+                .takes_value(true)
+                .help("Also use this IPv6 nameserver when the server leases an IPv6 address")
+        )
         .arg(
             Arg::with_name("default-ifname")
                 .long("default-ifname")
@@ -268,9 +422,28 @@ fn boot() -> Result<ClientConfig, io::Error> {
                 .long("tun-ifname")
                 .required(false)
                 .takes_value(true)
-                .default_value("utun9")
+                .default_value(DEFAULT_TUN_IFNAME)
                 .help("Specify the tun network device name")
         )
+        .arg(
+            Arg::with_name("mtu")
+                .long("mtu")
+                .required(false)
+                .takes_value(true)
+                .help("MTU of the tun device, the outer interface MTU minus the tunnel overhead by default")
+        )
+        .arg(
+            Arg::with_name("fragment")
+                .long("fragment")
+                .required(false)
+                .help("Split packets too big for the tunnel instead of answering ICMP errors to their sender")
+        )
+        .arg(
+            Arg::with_name("no-mss-clamp")
+                .long("no-mss-clamp")
+                .required(false)
+                .help("Leave the MSS of TCP connections through the tunnel alone")
+        )
         .arg(
             Arg::with_name("disable-compression")
                 .long("disable-compression")
@@ -291,12 +464,28 @@ fn boot() -> Result<ClientConfig, io::Error> {
                 .default_value("9050")
                 .help("UDP Port")
         )
+        .arg(
+            Arg::with_name("keepalive")
+                .long("keepalive")
+                .required(false)
+                .takes_value(true)
+                .default_value("10")
+                .help("Keepalive interval in seconds")
+        )
+        .arg(
+            Arg::with_name("dead-peer-timeout")
+                .long("dead-peer-timeout")
+                .required(false)
+                .takes_value(true)
+                .default_value("60")
+                .help("Reconnect when the server stays silent for this many seconds")
+        )
         .arg(
             Arg::with_name("server-addr")
                 .long("server-addr")
-                .required(true)
+                .required(false)
                 .takes_value(true)
-                .help("VPN server ipv4 address and port. (e.g 35.200.200.111:9050)")
+                .help("VPN server address and port. (e.g 35.200.200.111:9050 or [2001:db8::1]:9050)")
         )
         .arg(
             Arg::with_name("key")
@@ -305,87 +494,113 @@ fn boot() -> Result<ClientConfig, io::Error> {
                 .takes_value(true)
                 .help("RSA private key (PEM Format)")
         )
+        .arg(
+            Arg::with_name("server-key")
+                .long("server-key")
+                .required(false)
+                .takes_value(true)
+                .help("VPN server RSA public key (PEM Format)")
+        )
         .get_matches();
 
+    let config_file = match matches.value_of("config") {
+        Some(filename) => {
+            let file = try!(config::ConfigFile::load(filename));
+            try!(file.check_keys(config::CLIENT_KEYS));
+            Some(file)
+        },
+        None => None
+    };
+    let settings = config::Settings::new(&matches, config_file);
+
+    let verbose: String = try!(settings.require::<String>("verbose", "verbose")).to_lowercase();
+    let daemon_options = try!(daemon::Options::from_settings(&settings));
 
-    if matches.is_present("daemon") {
-        let mut args = vec![];
-        for arg in env::args() {
-            if arg != "--daemon" {
-                args.push(arg);
+    let journal_path: PathBuf = match try!(settings.string("journal", "journal")) {
+        Some(path) => daemon::absolute(&path),
+        None => PathBuf::from(DEFAULT_JOURNAL)
+    };
+    if matches.is_present("recover") {
+        logging::init(Some(&verbose)).unwrap();
+        match journal::recover(&journal_path) {
+            Ok(true) => println!("restored the system settings recorded in {}", journal_path.display()),
+            Ok(false) => println!("nothing to recover, {} does not exist", journal_path.display()),
+            Err(e) => {
+                println!("Can't recover from {}.\n{}", journal_path.display(), e);
+                process::exit(1);
             }
         }
-        let executable = &args[0];
-        let child = process::Command::new(executable)
-                            .args(&args[1..])
-                            .spawn()
-                            .expect("Child process failed to start.");
-        let pid = child.id();
-        println!("child pid: {}", pid);
         process::exit(0);
     }
 
-    let verbose: String = matches.value_of("verbose").unwrap().to_lowercase();
-
-    let tun_ifname: String = matches.value_of("tun-ifname").unwrap().to_string();
-    let local_udp_port: u16 = matches.value_of("port").unwrap().parse().unwrap();
-    let server_socket_addr: SocketAddr = {
-        let sa: SocketAddr = matches.value_of("server-addr").unwrap().parse().unwrap();
-        if !sa.ip().is_ipv4() {
-            println!("Sry, Only Supported IPv4 Network.");
-            process::exit(1);
-        } else {
-            sa
+    let tun_ifname: String = try!(settings.require("tun-ifname", "tun.ifname"));
+    let tun_mtu: Option<usize> = try!(settings.parse("mtu", "tun.mtu"));
+    if let Some(tun_mtu) = tun_mtu {
+        if tun_mtu < mtu::IPV4_MIN_MTU || tun_mtu > fragment::MAX_PACKET_LEN {
+            return Err(config::ConfigError::new("tun.mtu", &format!("must be between {} and {}", mtu::IPV4_MIN_MTU,
+                                                                    fragment::MAX_PACKET_LEN)).into());
         }
-    };
+    }
+    let fragment: bool = try!(settings.flag("fragment", "tun.fragment"));
+    let no_mss_clamp: bool = try!(settings.flag("no-mss-clamp", "tun.no_mss_clamp"));
+    let local_udp_port: u16 = try!(settings.require("port", "port"));
+    let server_socket_addr: SocketAddr = try!(settings.require("server-addr", "server.addr"));
+
+    let keepalive_interval: u64 = try!(settings.require("keepalive", "keepalive.interval"));
+    let dead_peer_timeout: u64 = try!(settings.require("dead-peer-timeout", "keepalive.timeout"));
+    if keepalive_interval == 0 || dead_peer_timeout <= keepalive_interval {
+        return Err(config::ConfigError::new("keepalive.timeout",
+                                            "must be longer than the keepalive interval").into());
+    }
 
-    let disable_compression: bool = if matches.is_present("disable-compression") { true } else { false };
-    let disable_crypto: bool;
-    let prikey: Option<crypto::rsa::PriKey> = if matches.is_present("disable-crypto") {
-        disable_crypto = true;
+    let disable_compression: bool = try!(settings.flag("disable-compression", "compression.disable"));
+    let disable_crypto: bool = try!(settings.flag("disable-crypto", "crypto.disable"));
+    let server_pubkey: Option<crypto::rsa::PubKey> = if disable_crypto {
         None
     } else {
-        disable_crypto = false;
-        match matches.value_of("key") {
-            Some(key_file_name) => match crypto::rsa::PriKey::from_file(key_file_name) {
-                Ok(rsa_prikey) => Some(rsa_prikey),
-                Err(e) => {
-                    println!("Can't load RSA private key.\n{:?}", e);
-                    process::exit(1);
-                }
-            },
-            None => {
-                println!("{}", matches.usage());
+        let key_file_name: String = try!(settings.require("server-key", "server.key"));
+        match crypto::rsa::PubKey::from_file(&key_file_name) {
+            Ok(rsa_pubkey) => Some(rsa_pubkey),
+            Err(e) => {
+                println!("Can't load server RSA public key.\n{:?}", e);
                 process::exit(1);
             }
         }
     };
-
-    let (default_ifname, default_gateway, default_networkservice) = if matches.is_present("no-autoconfig") {
-        let default_ifname = match matches.value_of("default-ifname") {
-            Some(ifname) => ifname.to_string(),
-            None => {
-                println!("{}", matches.usage());
-                process::exit(1);
-            }
-        };
-        let default_gateway: Ipv4Addr = match matches.value_of("default-gateway") {
-            Some(gateway) => gateway.parse().unwrap(),
-            None => {
-                println!("{}", matches.usage());
+    let prikey: Option<crypto::rsa::PriKey> = if disable_crypto {
+        None
+    } else {
+        let key_file_name: String = try!(settings.require("key", "crypto.key"));
+        match crypto::rsa::PriKey::from_file(&key_file_name) {
+            Ok(rsa_prikey) => Some(rsa_prikey),
+            Err(e) => {
+                println!("Can't load RSA private key.\n{:?}", e);
                 process::exit(1);
             }
-        };
-        let default_networkservice = None;
+        }
+    };
+
+    let no_autoconfig: bool = try!(settings.flag("no-autoconfig", "no_auto_config"));
+    if daemon_options.user.is_some() && !no_autoconfig {
+        // Restoring the routing table and nameserver at exit needs root.
+        return Err(config::ConfigError::new("user", "requires no_auto_config (--no-auto-config)").into());
+    }
+    let (default_ifname, default_gateway, default_networkservice) = if no_autoconfig {
+        let default_ifname: String = try!(settings.require("default-ifname", "network.default_ifname"));
+        let default_gateway: Ipv4Addr = try!(settings.require("default-gateway", "network.default_gateway"));
+        let default_networkservice: Option<String> = try!(settings.string("default-networkservice",
+                                                                          "network.default_networkservice"));
         (default_ifname, default_gateway, default_networkservice)
     } else {
+        if journal_path.exists() {
+            println!("{} is left from a previous run, restore it with `vpn --recover` first.",
+                     journal_path.display());
+            process::exit(1);
+        }
         match syscfg::get_default_route() {
-            Some((ifname, gateway)) => match syscfg::get_default_networkservice(&ifname) {
-                Some(networkservice) => (ifname, gateway, Some(networkservice)),
-                None => {
-                    println!("Can't get default networkservice.");
-                    process::exit(1);
-                }
+            Some((ifname, gateway)) => {
+                let default_networkservice = detect_networkservice(&ifname);
+                (ifname, gateway, default_networkservice)
             },
             None => {
                 println!("Can't get default gateway.");
@@ -394,279 +609,811 @@ fn boot() -> Result<ClientConfig, io::Error> {
         }
     };
 
-    let no_autoconfig: bool;
-    let dns_server: Option<Ipv4Addr> = if matches.is_present("no-autoconfig") {
-        no_autoconfig = true;
+    // An IPv6 server is reached through the IPv6 default route.
+    let default_route6: Option<(String, Ipv6Addr)> = if no_autoconfig || server_socket_addr.is_ipv4() {
         None
     } else {
-        no_autoconfig = false;
-        Some(matches.value_of("dns").unwrap().parse().unwrap())
+        match syscfg::get_default_route6() {
+            Some(default_route6) => Some(default_route6),
+            None => {
+                println!("Can't get IPv6 default gateway.");
+                process::exit(1);
+            }
+        }
     };
-    
+
+    let dns_server: Option<Ipv4Addr> = if no_autoconfig {
+        None
+    } else {
+        Some(try!(settings.require("dns", "dns")))
+    };
+    let dns_server6: Option<Ipv6Addr> = if no_autoconfig {
+        None
+    } else {
+        try!(settings.parse("dns6", "dns6"))
+    };
+    let system_dns: Option<SystemDns> = match dns_server {
+        Some(dns_server) => match load_system_dns(&default_networkservice, dns_server, dns_server6) {
+            Ok(system_dns) => Some(system_dns),
+            Err(e) => {
+                println!("Can't read system nameserver setting.\n{:?}", e);
+                process::exit(1);
+            }
+        },
+        None => None
+    };
+
+    let include: Vec<IpNetwork> = try!(settings.list("include", "routes.include"));
+    let exclude: Vec<IpNetwork> = try!(settings.list("exclude", "routes.exclude"));
+    let include_countries: Vec<String> = try!(settings.list("include-country", "routes.include_countries"));
+    let exclude_countries: Vec<String> = try!(settings.list("exclude-country", "routes.exclude_countries"));
+    for (key, codes) in vec![("routes.include_countries", &include_countries),
+                             ("routes.exclude_countries", &exclude_countries)] {
+        for code in codes.iter() {
+            if iana::number::country_index(code).is_none() {
+                return Err(config::ConfigError::new(key, &format!("unknown country code {:?}", code)).into());
+            }
+        }
+    }
+    let mut delegations = iana::number::Database::builtin();
+    let delegation_files: Vec<String> = try!(settings.list("delegation-file", "routes.delegation_files"));
+    for filename in delegation_files.iter() {
+        if let Err(e) = delegations.load(filename) {
+            println!("Can't load country delegations.\n{}", e);
+            process::exit(1);
+        }
+    }
+
+    if matches.is_present("check-config") {
+        println!("config OK");
+        process::exit(0);
+    }
+
+
     Ok(ClientConfig {
         verbose: verbose,
+        daemon: daemon_options,
 
         tun_ifname: tun_ifname,
+        mtu: tun_mtu,
+        fragment: fragment,
+        mss_clamp: !no_mss_clamp,
         server_socket_addr: server_socket_addr,
         local_udp_port: local_udp_port,
+        keepalive_interval: Duration::from_secs(keepalive_interval),
+        dead_peer_timeout: Duration::from_secs(dead_peer_timeout),
 
         no_autoconfig: no_autoconfig,
 
         default_ifname: default_ifname,
         default_gateway: default_gateway,
+        default_route6: default_route6,
         default_networkservice: default_networkservice,
         
         // default_dns_config: 
 
         dns_server: dns_server,
+        dns_server6: dns_server6,
+        system_dns: system_dns,
+        include: include,
+        exclude: exclude,
+        include_countries: include_countries,
+        exclude_countries: exclude_countries,
+        delegations: delegations,
+        journal: journal_path,
 
         disable_crypto: disable_crypto,
         disable_compression: disable_compression,
-        prikey: prikey
+        prikey: prikey,
+        server_pubkey: server_pubkey
     })
 }
 
 
-fn run (config: &ClientConfig) {
-    let gateway_interface = Interface::with_name(&config.default_ifname).unwrap();
-    let gateway_interface_ip = gateway_interface.addr().unwrap();
-
-    assert_eq!(config.server_socket_addr.ip().is_ipv4(), true);
-
-    info!("use default interface {:?}", config.default_ifname);
-    let udp_socket = {
-        let local_udp_socket_addr = SocketAddr::new(IpAddr::V4(gateway_interface_ip), config.local_udp_port);
-        let s = UdpSocket::bind(&local_udp_socket_addr).unwrap();
-        info!("bind on interface {} {}", config.default_ifname, local_udp_socket_addr);
-        s.set_read_timeout(Some(Duration::new(10, 0))).unwrap();
-        s.set_write_timeout(Some(Duration::new(10, 0))).unwrap();
-        s.connect(&config.server_socket_addr).unwrap();
-        info!("connect to {}", config.server_socket_addr);
-        s
-    };
+fn write_tun(tun_device: &mut TunDevice, packet: &[u8]) -> Result<usize, io::Error> {
+    if cfg!(target_os = "macos") {
+        // IPv4: [0, 0, 0, 2] (AF_INET), IPv6: [0, 0, 0, 30] (AF_INET6)
+        let family = match wire::IpVersion::of_packet(packet) {
+            Ok(wire::IpVersion::Ipv6) => 30,
+            _ => 2
+        };
+        let mut frame = vec![0u8, 0, 0, family];
+        frame.extend_from_slice(packet);
+        tun_device.write(&frame)
+    } else {
+        tun_device.write(packet)
+    }
+}
 
-    let mut udp_buf = [0u8; 1600];
-    let mut tun_buf = [0u8; 1600];
+/// Next handshake reply, packets left over from a previous session are skipped.
+fn recv_packet(udp_socket: &UdpSocket, udp_buf: &mut [u8]) -> Result<Packet, io::Error> {
+    let start = Instant::now();
+    loop {
+        if start.elapsed() > HELLO_TIMEOUT {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "no handshake reply"));
+        }
+        let size = try!(udp_socket.recv(udp_buf));
+        let packet = try!(Packet::decode(&udp_buf[..size]));
+        match packet.message {
+            Message::Data { .. } | Message::Keepalive | Message::Close
+                | Message::Probe { .. } | Message::ProbeAck { .. } => continue,
+            // Handshake errors carry no session, these are about the previous one.
+            Message::Error { .. } if packet.session_id != 0 => continue,
+            _ => return Ok(packet)
+        }
+    }
+}
 
-    let (tun_ip, public_ip, server_tun_ip, tun_netmask) = {
-        let msg = [1u8];
-        let size = udp_socket.send(&msg).expect("couldn't send message");
-        assert_eq!(size, 1);
+fn send_hello(config: &ClientConfig, udp_socket: &UdpSocket, message: Message) -> Result<usize, io::Error> {
+    let mut packet = Packet::new(0, message);
+    packet.flags = protocol::features(config.disable_compression);
+    udp_socket.send(&packet.encode())
+}
 
-        let size = udp_socket.recv(&mut udp_buf).expect("recv function failed");
-        if size == 0 {
-            error!("tun device dhcp failed.");
-            process::exit(2);
-        }
+fn check_lease(config: &ClientConfig, packet: &Packet) -> Result<(), io::Error> {
+    match protocol::compression_mismatch(protocol::features(config.disable_compression), packet.flags) {
+        Some(reason) => Err(io::Error::new(io::ErrorKind::InvalidData, reason)),
+        None => Ok(())
+    }
+}
+
+/// Authenticate against vpnd and obtain the tunnel address lease.
+///
+/// Returns the lease, the session id and the session key.
+fn hello(config: &ClientConfig, udp_socket: &UdpSocket, udp_buf: &mut [u8])
+        -> Result<(protocol::Lease, u64, Option<Vec<u8>>), io::Error> {
+    if config.disable_crypto {
+        try!(send_hello(config, udp_socket, Message::Hello(Hello::Plain)));
+        let packet = try!(recv_packet(udp_socket, udp_buf));
+        return match packet.message {
+            Message::Lease { lease, .. } => {
+                try!(check_lease(config, &packet));
+                Ok((lease, packet.session_id, None))
+            },
+            Message::Error { reason, .. } => Err(io::Error::new(io::ErrorKind::PermissionDenied, reason)),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unknow protocol."))
+        };
+    }
+
+    let prikey = config.prikey.as_ref().unwrap();
+    let server_pubkey = config.server_pubkey.as_ref().unwrap();
+    let mut client = handshake::Client::new(prikey, server_pubkey);
+
+    try!(send_hello(config, udp_socket, client.init()));
+    let packet = try!(recv_packet(udp_socket, udp_buf));
+    let response = try!(client.respond(&packet.message));
+
+    try!(send_hello(config, udp_socket, response));
+    let packet = try!(recv_packet(udp_socket, udp_buf));
+    let (lease, key) = try!(client.finish(&packet.message));
+    try!(check_lease(config, &packet));
 
-        if udp_buf[0] != 1 {
-            error!("unknow protocol.");
-            process::exit(2);
+    info!("handshake with {} succeeded", config.server_socket_addr);
+    Ok((lease, packet.session_id, Some(key)))
+}
+
+/// Retry delays for the handshake, doubling up to `max`.
+struct Backoff {
+    min: Duration,
+    max: Duration,
+    delay: Duration,
+}
+
+impl Backoff {
+    fn new(min: Duration, max: Duration) -> Backoff {
+        Backoff { min: min, max: max, delay: min }
+    }
+
+    fn next(&mut self) -> Duration {
+        let delay = self.delay;
+        self.delay = ::std::cmp::min(self.delay * 2, self.max);
+        delay
+    }
+
+    fn reset(&mut self) {
+        self.delay = self.min;
+    }
+}
+
+/// Sleep, but wake up early on shutdown. Returns `false` if we are shutting down.
+fn sleep_while_running(duration: Duration) -> bool {
+    let start = Instant::now();
+    while signal::is_running() {
+        let elapsed = start.elapsed();
+        if elapsed >= duration {
+            return true;
         }
+        ::std::thread::sleep(::std::cmp::min(duration - elapsed, Duration::from_millis(200)));
+    }
+    false
+}
 
-        let local_tun_ip = Ipv4Addr::from(NetworkEndian::read_u32(&udp_buf[1..5]));
-        let local_public_ip = Ipv4Addr::from(NetworkEndian::read_u32(&udp_buf[5..9]));
-        let server_gateway_ip = Ipv4Addr::from(NetworkEndian::read_u32(&udp_buf[9..14]));
-        let tun_netmask = Ipv4Addr::from(NetworkEndian::read_u32(&udp_buf[14..18]));
-        (local_tun_ip, local_public_ip, server_gateway_ip, tun_netmask)
-    };
+/// Only the tunnel addresses matter, the public address may change between sessions.
+fn same_tunnel(a: &protocol::Lease, b: &protocol::Lease) -> bool {
+    a.tun_ip == b.tun_ip && a.server_tun_ip == b.server_tun_ip && a.tun_netmask == b.tun_netmask
+        && a.ipv6 == b.ipv6
+}
+
+fn create_tun(config: &ClientConfig, lease: &protocol::Lease, mtu: usize) -> TunDevice {
+    let mut tun_config = tun::Configuration::default();
+    tun_config
+        .address(lease.tun_ip)
+        .netmask(lease.tun_netmask)
+        .destination(lease.server_tun_ip)
+        .mtu(mtu as i32)
+        .name(config.tun_ifname.clone())
+        .up();
+    let tun_device = tun::create(&tun_config).expect("can't create tun device.");
+    info!("tun device running at {} --> {} netmask: {} mtu: {}", lease.tun_ip, lease.server_tun_ip,
+          lease.tun_netmask, mtu);
+    if let Some(ref ipv6) = lease.ipv6 {
+        syscfg::add_ipv6_address(&config.tun_ifname, ipv6.tun_ip, ipv6.prefix_len)
+            .expect("can't add IPv6 address to tun device.");
+        info!("tun device running at {}/{} --> {}", ipv6.tun_ip, ipv6.prefix_len, ipv6.server_tun_ip);
+    }
+    tun_device
+}
+
+/// Default route the server is reached through, the IPv6 one for an IPv6 server.
+fn default_route(config: &ClientConfig) -> Option<(String, IpAddr)> {
+    match config.server_socket_addr {
+        SocketAddr::V4(_) => syscfg::get_default_route().map(|(ifname, gateway)| (ifname, IpAddr::V4(gateway))),
+        SocketAddr::V6(_) => syscfg::get_default_route6().map(|(ifname, gateway)| (ifname, IpAddr::V6(gateway)))
+    }
+}
 
-    let mut tun_device: TunDevice = {
-        let mut tun_config = tun::Configuration::default();
-        tun_config
-            .address(tun_ip)
-            .netmask(tun_netmask)
-            .destination(server_tun_ip)
-            .mtu(1500)
-            .name(config.tun_ifname.clone())
-            .up();
-        tun::create(&tun_config).expect("can't create tun device.")
+/// UDP socket connected to the server. For an IPv4 server it is bound to the
+/// address of `ifname`, for IPv6 the route to the server picks the source address.
+/// Packets leave with DF set, see `mtu`.
+fn connect(config: &ClientConfig, ifname: &str) -> Result<UdpSocket, io::Error> {
+    let local_udp_socket_addr = match config.server_socket_addr {
+        SocketAddr::V4(_) => {
+            let interface = try!(Interface::with_name(ifname));
+            match interface.addr() {
+                Some(addr) => SocketAddr::new(IpAddr::V4(addr), config.local_udp_port),
+                None => return Err(io::Error::new(io::ErrorKind::AddrNotAvailable,
+                                                  format!("{} has no IPv4 address", ifname)))
+            }
+        },
+        SocketAddr::V6(_) => {
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)), config.local_udp_port)
+        }
     };
+    let socket = try!(UdpSocket::bind(&local_udp_socket_addr));
+    info!("bind on {}", local_udp_socket_addr);
+    try!(mtu::set_dont_fragment(socket.as_raw_fd(), config.server_socket_addr.is_ipv6()));
+    try!(socket.set_read_timeout(Some(HELLO_TIMEOUT)));
+    try!(socket.set_write_timeout(Some(HELLO_TIMEOUT)));
+    try!(socket.connect(&config.server_socket_addr));
+    info!("connect to {}", config.server_socket_addr);
+    Ok(socket)
+}
 
-    info!("tun device running at {} --> {} netmask: {}", tun_ip, server_tun_ip, tun_netmask);
+/// The socket to the server and the default route it leaves through.
+struct Uplink {
+    default_route: (String, IpAddr),
+    udp_socket: UdpSocket,
+    /// Shares the socket, the handshake runs in blocking mode on `udp_socket`.
+    udp_socket_raw_fd: mio::net::UdpSocket,
+}
 
-    let udp_socket_raw_fd = mio::net::UdpSocket::from_socket(udp_socket).unwrap();
+impl Uplink {
+    fn new(config: &ClientConfig, poll: &mio::Poll, default_route: (String, IpAddr)) -> Result<Uplink, io::Error> {
+        let udp_socket = try!(connect(config, &default_route.0));
+        let udp_socket_raw_fd = try!(mio::net::UdpSocket::from_socket(try!(udp_socket.try_clone())));
+        try!(poll.register(&udp_socket_raw_fd, UDP_TOKEN, mio::Ready::readable(), mio::PollOpt::level()));
+        Ok(Uplink { default_route: default_route, udp_socket: udp_socket, udp_socket_raw_fd: udp_socket_raw_fd })
+    }
 
+    /// Send from the network of `default_route` from now on.
+    fn rebind(&mut self, config: &ClientConfig, poll: &mio::Poll,
+              default_route: (String, IpAddr)) -> Result<(), io::Error> {
+        match config.server_socket_addr {
+            // Bound to the unspecified address, connecting again picks a new source address.
+            SocketAddr::V6(_) => try!(self.udp_socket.connect(&config.server_socket_addr)),
+            SocketAddr::V4(_) => {
+                let addr = try!(Interface::with_name(&default_route.0)).addr().map(IpAddr::V4);
+                if addr.is_some() && addr == self.udp_socket.local_addr().ok().map(|local| local.ip()) {
+                    // Only the gateway changed.
+                    self.default_route = default_route;
+                    return Ok(());
+                }
+                let _ = poll.deregister(&self.udp_socket_raw_fd);
+                match Uplink::new(config, poll, default_route.clone()) {
+                    Ok(uplink) => *self = uplink,
+                    Err(e) => {
+                        try!(poll.register(&self.udp_socket_raw_fd, UDP_TOKEN,
+                                           mio::Ready::readable(), mio::PollOpt::level()));
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        self.default_route = default_route;
+        Ok(())
+    }
+}
 
-    // Auto Config
-    auto_config(&config, &tun_ip);
+/// Follow the default route when the host moves networks: the socket is rebound
+/// to the new one and the server routed through it. Returns whether it moved,
+/// the server moves the session along with the next authenticated packet.
+fn follow_default_route(config: &ClientConfig, poll: &mio::Poll, uplink: &mut Uplink,
+                        system_config: &mut Option<AutoSystemConfig>) -> bool {
+    let default_route = match default_route(config) {
+        Some(default_route) => default_route,
+        // Between two networks, the next change brings the new one.
+        None => return false
+    };
+    if default_route == uplink.default_route {
+        return false;
+    }
+    info!("default route moved from {} via {} to {} via {}", uplink.default_route.0, uplink.default_route.1,
+          default_route.0, default_route.1);
+    if let Err(e) = uplink.rebind(config, poll, default_route.clone()) {
+        // Retried on the next change, the address may still be on its way.
+        warn!("can't rebind to {}: {}", default_route.0, e);
+        return false;
+    }
+    if let Some(ref mut system_config) = *system_config {
+        if let Err(e) = system_config.reroute(config, &default_route) {
+            error!("can't route {} through {}: {}", config.server_socket_addr.ip(), default_route.0, e);
+        }
+    }
+    true
+}
 
+/// Why a session ended.
+#[derive(Debug)]
+enum Disconnect {
+    Shutdown,
+    DeadPeer,
+}
+
+fn run (config: &ClientConfig) {
+    let mut udp_buf = vec![0u8; mtu::BUFFER_LEN];
+    let mut tun_buf = vec![0u8; mtu::BUFFER_LEN];
+    let outer_ipv6 = config.server_socket_addr.is_ipv6();
 
     let mut events = mio::Events::with_capacity(1024);
     let poll = mio::Poll::new().unwrap();
 
-    poll.register(&udp_socket_raw_fd, UDP_TOKEN, mio::Ready::readable(), mio::PollOpt::level()).unwrap();
-    poll.register(&tun_device, TUN_TOKEN, mio::Ready::readable(), mio::PollOpt::level()).unwrap();
+    // The source address is picked by the route to the server, pinned by `auto_config`.
+    let default_route = match (config.server_socket_addr, &config.default_route6) {
+        (SocketAddr::V6(_), &Some((ref ifname, gateway))) => (ifname.clone(), IpAddr::V6(gateway)),
+        _ => (config.default_ifname.clone(), IpAddr::V4(config.default_gateway))
+    };
+    info!("use default interface {:?}", default_route.0);
+    let mut uplink = match Uplink::new(&config, &poll, default_route) {
+        Ok(uplink) => uplink,
+        Err(e) => {
+            error!("can't connect to {}: {}", config.server_socket_addr, e);
+            return;
+        }
+    };
+    // Auto config follows the default route to other networks, the interface is fixed otherwise.
+    let monitor = if config.no_autoconfig {
+        None
+    } else {
+        // Seccomp or a restricted container may forbid the subscription, the tunnel works without it.
+        let monitor = route::Monitor::new().and_then(|monitor| {
+            try!(poll.register(&EventedFd(&monitor.as_raw_fd()), GATEWAY_TOKEN,
+                               mio::Ready::readable(), mio::PollOpt::level()));
+            Ok(monitor)
+        });
+        match monitor {
+            Ok(monitor) => Some(monitor),
+            Err(e) => {
+                warn!("can't watch the routing table, route changes are not followed: {}", e);
+                None
+            }
+        }
+    };
 
-    info!("Ready for transmission.");
-    let timeout = Some(Duration::new(2, 0));
-    loop {
-        if !signal::is_running() {
-            break;
+    let mut compressor = if config.disable_compression {
+        None
+    } else {
+        Some(compression::Compressor::new())
+    };
+    let mut fragmenter = fragment::Fragmenter::new();
+    let mut backoff = Backoff::new(Duration::from_secs(1), MAX_RETRY_DELAY);
+    let mut tunnel: Option<(TunDevice, protocol::Lease)> = None;
+    let mut tun_mtu: usize = 0;
+    // System settings touched by auto config, kept across reconnects.
+    // Dropped before the tun device, a panic rolls them back too.
+    let mut system_config: Option<AutoSystemConfig> = None;
+    let mut privileged = true;
+
+    while signal::is_running() {
+        if let Some(ref monitor) = monitor {
+            monitor.drain();
+            follow_default_route(&config, &poll, &mut uplink, &mut system_config);
         }
-        match poll.poll(&mut events, timeout) {
-            Ok(_) => {},
-            Err(_) => continue
+        uplink.udp_socket.set_nonblocking(false).unwrap();
+        let ret = hello(&config, &uplink.udp_socket, &mut udp_buf);
+        uplink.udp_socket.set_nonblocking(true).unwrap();
+        let (lease, session_id, session_key) = match ret {
+            Ok(ret) => ret,
+            Err(e) => {
+                let delay = backoff.next();
+                warn!("handshake with {} failed: {}, retry in {}s", config.server_socket_addr, e, delay.as_secs());
+                sleep_while_running(delay);
+                continue;
+            }
         };
-        for event in events.iter() {
-            match event.token() {
-                UDP_TOKEN => {
-                    let size = match udp_socket_raw_fd.recv(&mut udp_buf[4..]) {
-                        Ok(size) => size,
-                        Err(_) => continue
-                    };
-                    let cmd = udp_buf[4];
-                    if cmd != 2 {
-                        continue;
+        backoff.reset();
+        // Probed again every session, the server may be behind another path by now.
+        let mut prober = mtu::Prober::new(mtu::interface_mtu(&uplink.default_route.0), outer_ipv6);
+
+        let reuse = match tunnel {
+            Some((_, ref current)) => same_tunnel(current, &lease),
+            None => false
+        };
+        if !reuse {
+            if let Some((tun_device, _)) = tunnel.take() {
+                if !privileged {
+                    error!("the server changed our tunnel address, can't reconfigure without root");
+                    break;
+                }
+                warn!("the server changed our tunnel address, reconfigure tun device");
+                if let Some(system_config) = system_config.take() {
+                    if let Err(e) = system_config.recover() {
+                        error!("{}", e);
                     }
-                    let packet = if cfg!(target_os = "macos") {
-                        // IPv4: [0, 0, 0, 2]
-                        udp_buf[1] = 0;
-                        udp_buf[2] = 0;
-                        udp_buf[3] = 0;
-                        udp_buf[4] = 2;
-                        &mut udp_buf[1..size+5]
-                    } else if cfg!(target_os = "linux") {
-                        &mut udp_buf[5..size+5]
-                    } else {
-                        panic!("oops...");
-                    };
-                    let _ = tun_device.write(&packet);
-                },
-                TUN_TOKEN => {
-                    let size: usize = match tun_device.read(&mut tun_buf[1..]){
-                        Ok(size) => size,
-                        Err(_) => continue
-                    };
-
-                    if size == 0 {
-                        continue;
+                }
+                let _ = poll.deregister(&tun_device);
+                drop(tun_device);
+            }
+            tun_mtu = mtu::tun_mtu(config.mtu, mtu::tunnel_mtu(prober.path_mtu(), outer_ipv6, session_key.is_some()),
+                                   lease.ipv6.is_some(), config.fragment);
+            let tun_device = create_tun(&config, &lease, tun_mtu);
+            poll.register(&tun_device, TUN_TOKEN, mio::Ready::readable(), mio::PollOpt::level()).unwrap();
+
+            // Auto Config
+            if !config.no_autoconfig {
+                match AutoSystemConfig::execute(&config, &lease, &uplink.default_route) {
+                    Ok(ret) => system_config = Some(ret),
+                    Err(e) => {
+                        error!("auto config failed: {}", e);
+                        break;
                     }
-                    let data = if cfg!(target_os = "macos") {
-                        tun_buf[4] = 2;
-                        &tun_buf[4..size+1]
-                    } else if cfg!(target_os = "linux") {
-                        tun_buf[0] = 2;
-                        &tun_buf[..size+1]
-                    } else {
-                        panic!("oops ...");
-                    };
-
-                    // let packet = &data[1..];
-                    let _ = udp_socket_raw_fd.send(&data);
-                },
-                _ => { }
+                }
             }
-        }
-    }
-    drop(tun_device);
-}
 
+            if privileged {
+                if let Err(e) = daemon::drop_privileges(&config.daemon) {
+                    error!("can't drop privileges: {}", e);
+                    break;
+                }
+                privileged = config.daemon.user.is_none();
+            }
+            tunnel = Some((tun_device, lease));
+        }
+        // ICMP errors about packets too big for the tunnel come from its far end.
+        let (icmp_source, icmp_source6) = {
+            let lease = &tunnel.as_ref().unwrap().1;
+            (lease.server_tun_ip, lease.ipv6.map(|ipv6| ipv6.server_tun_ip))
+        };
+        let tun_device = &mut tunnel.as_mut().unwrap().0;
+        let mut reassembler = fragment::Reassembler::new();
 
-#[cfg(target_os = "linux")]
-fn auto_config(config: &ClientConfig, tun_ip: &Ipv4Addr) {
-    unimplemented!()
-}
+        let mut cipher = match session_key {
+            Some(ref key) => Some(crypto::aead::SessionCipher::new(key, crypto::aead::Role::Client)),
+            None => None
+        };
 
-#[cfg(target_os = "macos")]
-fn auto_config(config: &ClientConfig, tun_ip: &Ipv4Addr) {
-    if !config.no_autoconfig {
-        // route -n get default | grep interface | awk '{print $2}'
-        let server_ip: Ipv4Addr = match config.server_socket_addr.ip() {
-            IpAddr::V4(ipv4_addr) => ipv4_addr,
-            _ => unreachable!()
+        info!("Ready for transmission.");
+        let timeout = Some(Duration::new(1, 0));
+        let mut last_received = Instant::now();
+        let mut last_keepalive = Instant::now();
+        let reason = loop {
+            if !signal::is_running() {
+                break Disconnect::Shutdown;
+            }
+            if last_received.elapsed() > config.dead_peer_timeout {
+                break Disconnect::DeadPeer;
+            }
+            if last_keepalive.elapsed() >= config.keepalive_interval {
+                last_keepalive = Instant::now();
+                if let Ok(msg) = protocol::keepalive(cipher.as_mut(), session_id) {
+                    let _ = uplink.udp_socket_raw_fd.send(&msg);
+                }
+            }
+            if let Some((nonce, len)) = prober.poll(Instant::now()) {
+                let _ = uplink.udp_socket_raw_fd.send(&protocol::probe(session_id, nonce, len));
+            }
+            match poll.poll(&mut events, timeout) {
+                Ok(_) => {},
+                Err(_) => continue
+            };
+            for event in events.iter() {
+                match event.token() {
+                    UDP_TOKEN => {
+                        let size = match uplink.udp_socket_raw_fd.recv(&mut udp_buf) {
+                            Ok(size) => size,
+                            Err(_) => continue
+                        };
+                        let packet = match Packet::decode(&udp_buf[..size]) {
+                            Ok(packet) => packet,
+                            Err(e) => {
+                                debug!("drop packet: {}", e);
+                                continue;
+                            }
+                        };
+                        if packet.session_id != session_id {
+                            continue;
+                        }
+                        let packet_flags = packet.flags;
+                        let (counter, payload) = match packet.message {
+                            Message::Data { counter, payload } => (counter, payload),
+                            Message::Keepalive => {
+                                // Anybody can send these, encrypted sessions only trust sealed ones.
+                                if cipher.is_none() {
+                                    last_received = Instant::now();
+                                }
+                                continue;
+                            },
+                            Message::Error { code, reason } => {
+                                // Not authenticated either, a dead session shows as a dead peer.
+                                warn!("server error {:?}: {}", code, reason);
+                                continue;
+                            },
+                            Message::Probe { nonce, .. } => {
+                                let ack = Message::ProbeAck { nonce: nonce, size: size as u16 };
+                                let _ = uplink.udp_socket_raw_fd.send(&Packet::new(session_id, ack).encode());
+                                continue;
+                            },
+                            Message::ProbeAck { nonce, size: len } => {
+                                if prober.ack(nonce, len as usize, Instant::now()) {
+                                    let tunnel_mtu = mtu::tunnel_mtu(prober.path_mtu(), outer_ipv6, cipher.is_some());
+                                    info!("path MTU to {} is {}, tunnel MTU {}", config.server_socket_addr,
+                                          prober.path_mtu(), tunnel_mtu);
+                                    let new_mtu = mtu::tun_mtu(config.mtu, tunnel_mtu, icmp_source6.is_some(),
+                                                               config.fragment);
+                                    if new_mtu != tun_mtu {
+                                        match syscfg::set_mtu(&config.tun_ifname, new_mtu) {
+                                            Ok(()) => tun_mtu = new_mtu,
+                                            Err(e) => warn!("{}", e)
+                                        }
+                                    }
+                                }
+                                continue;
+                            },
+                            _ => continue
+                        };
+                        let packet: Vec<u8> = match cipher {
+                            Some(ref mut cipher) => {
+                                if !packet_flags.contains(Flags::ENCRYPTED) {
+                                    debug!("drop unencrypted packet");
+                                    continue;
+                                }
+                                match protocol::open_data(cipher, &udp_buf[..size], counter, &payload) {
+                                    Ok(packet) => packet,
+                                    Err(e) => {
+                                        debug!("drop packet: {}", e);
+                                        continue;
+                                    }
+                                }
+                            },
+                            None => payload
+                        };
+                        if packet.is_empty() {
+                            // Keepalive of an encrypted session.
+                            last_received = Instant::now();
+                            continue;
+                        }
+                        let packet: Vec<u8> = if packet_flags.contains(Flags::FRAGMENT) {
+                            match reassembler.push(&packet, Instant::now()) {
+                                Ok(Some(packet)) => packet,
+                                Ok(None) => {
+                                    last_received = Instant::now();
+                                    continue;
+                                },
+                                Err(e) => {
+                                    debug!("drop fragment: {}", e);
+                                    continue;
+                                }
+                            }
+                        } else {
+                            packet
+                        };
+                        let mut packet: Vec<u8> = if packet_flags.contains(Flags::COMPRESSED) {
+                            match compressor {
+                                Some(ref mut compressor) => match compressor.decompress(&packet) {
+                                    Ok(packet) => packet,
+                                    Err(e) => {
+                                        debug!("drop packet: {}", e);
+                                        continue;
+                                    }
+                                },
+                                None => {
+                                    debug!("drop compressed packet, compression is disabled");
+                                    continue;
+                                }
+                            }
+                        } else {
+                            packet
+                        };
+                        if config.mss_clamp {
+                            let tunnel_mtu = mtu::tunnel_mtu(prober.path_mtu(), outer_ipv6, cipher.is_some());
+                            mss::clamp(&mut packet, ::std::cmp::min(tunnel_mtu, tun_mtu));
+                        }
+                        last_received = Instant::now();
+                        let _ = write_tun(tun_device, &packet);
+                    },
+                    TUN_TOKEN => {
+                        let size: usize = match tun_device.read(&mut tun_buf){
+                            Ok(size) => size,
+                            Err(_) => continue
+                        };
+
+                        let packet = if cfg!(target_os = "macos") {
+                            if size <= 4 {
+                                continue;
+                            }
+                            &mut tun_buf[4..size]
+                        } else if cfg!(target_os = "linux") {
+                            if size == 0 {
+                                continue;
+                            }
+                            &mut tun_buf[..size]
+                        } else {
+                            panic!("oops ...");
+                        };
+
+                        let tunnel_mtu = mtu::tunnel_mtu(prober.path_mtu(), outer_ipv6, cipher.is_some());
+                        if config.mss_clamp {
+                            mss::clamp(packet, ::std::cmp::min(tunnel_mtu, tun_mtu));
+                        }
+                        let packet: &[u8] = packet;
+                        let compressed = match compressor {
+                            Some(ref mut compressor) => compressor.compress(packet),
+                            None => None
+                        };
+                        let (flags, payload) = match compressed {
+                            Some(ref compressed) => (Flags::COMPRESSED, &compressed[..]),
+                            None => (Flags::empty(), packet)
+                        };
+
+                        let payloads: Vec<(Flags, Vec<u8>)> = if payload.len() <= tunnel_mtu {
+                            vec![(flags, payload.to_vec())]
+                        } else if config.fragment {
+                            match fragmenter.split(payload, tunnel_mtu) {
+                                Some(pieces) => {
+                                    pieces.into_iter().map(|piece| (flags | Flags::FRAGMENT, piece)).collect()
+                                },
+                                None => {
+                                    debug!("drop packet of {} bytes, too big to fragment", packet.len());
+                                    continue;
+                                }
+                            }
+                        } else {
+                            match mtu::oversized(packet, tunnel_mtu, icmp_source, icmp_source6) {
+                                mtu::Oversized::Reply(reply) => {
+                                    let _ = write_tun(tun_device, &reply);
+                                    continue;
+                                },
+                                mtu::Oversized::Fragments(fragments) => {
+                                    fragments.into_iter().map(|fragment| (Flags::empty(), fragment)).collect()
+                                },
+                                mtu::Oversized::Drop => {
+                                    debug!("drop packet of {} bytes, too big for the tunnel", packet.len());
+                                    continue;
+                                }
+                            }
+                        };
+
+                        for (flags, payload) in payloads {
+                            match protocol::data(cipher.as_mut(), session_id, flags, &payload) {
+                                Ok(data) => {
+                                    let _ = uplink.udp_socket_raw_fd.send(&data);
+                                },
+                                Err(e) => {
+                                    error!("can't seal packet: {}", e);
+                                    break;
+                                }
+                            }
+                        }
+                    },
+                    GATEWAY_TOKEN => {
+                        let moved = match monitor {
+                            Some(ref monitor) => monitor.drain() &&
+                                follow_default_route(&config, &poll, &mut uplink, &mut system_config),
+                            None => false
+                        };
+                        if moved {
+                            prober = mtu::Prober::new(mtu::interface_mtu(&uplink.default_route.0), outer_ipv6);
+                            // Tells the server where we are now, without waiting for traffic.
+                            last_keepalive = Instant::now();
+                            if let Ok(msg) = protocol::keepalive(cipher.as_mut(), session_id) {
+                                let _ = uplink.udp_socket_raw_fd.send(&msg);
+                            }
+                        }
+                    },
+                    _ => { }
+                }
+            }
         };
-         // sudo route add <server_ip> 192.168.199.1
-        process::Command::new("route")
-            .arg("add")
-            .arg(format!("{}", server_ip))
-            .arg(format!("{}", config.default_gateway))
-            .status()
-            .expect("failed to auto config route");
-
-        // sudo route delete default
-        process::Command::new("route")
-            .arg("delete")
-            .arg("default")
-            .status()
-            .expect("failed to auto config route");
-        // sudo route add default 172.16.10.13
-        process::Command::new("route")
-            .arg("add")
-            .arg("default")
-            .arg(format!("{}", tun_ip))
-            .status()
-            .expect("failed to auto config route");
-
-        info!("auto config routing table    [OK]");
-
-        assert_eq!(config.dns_server.is_some(), true);
-        assert_eq!(config.default_networkservice.is_some(), true);
-        // networksetup -setdnsservers "Wi-Fi" "8.8.8.8"
-        process::Command::new("networksetup")
-            .arg("-setdnsservers")
-            .arg(config.default_networkservice.clone().unwrap())
-            .arg(format!("{}", config.dns_server.unwrap()))
-            .status()
-            .expect("failed to auto config dns");
-        info!("auto config dns server       [OK]");
+
+        match reason {
+            Disconnect::Shutdown => {
+                if let Ok(msg) = protocol::close(cipher.as_mut(), session_id) {
+                    let _ = uplink.udp_socket_raw_fd.send(&msg);
+                }
+                break;
+            },
+            Disconnect::DeadPeer => {
+                warn!("no answer from {} for {}s, reconnect", config.server_socket_addr,
+                      config.dead_peer_timeout.as_secs());
+            },
+        }
     }
-}
 
-#[cfg(target_os = "linux")]
-fn cleanup(config: &Config) {
-    unimplemented!()
+    if let Some(system_config) = system_config.take() {
+        if let Err(e) = system_config.recover() {
+            error!("{}", e);
+        }
+    }
+    if let Some(ref compressor) = compressor {
+        info!("compression {}", compressor.stats);
+    }
+    drop(tunnel);
 }
 
 
-#[cfg(target_os = "macos")]
-fn cleanup(config: &ClientConfig) {
-    if !config.no_autoconfig {
-        // 恢复默认路由表设定
-        // sudo route delete default
-        process::Command::new("route")
-            .arg("delete")
-            .arg("default")
-            .status()
-            .expect("failed to restore default route");
-        // sudo route add default 192.168.199.1
-        process::Command::new("route")
-            .arg("add")
-            .arg("default")
-            .arg(format!("{}", config.default_gateway))
-            .status()
-            .expect("failed to restore default route");
-        
-        let server_ip: Ipv4Addr = match config.server_socket_addr.ip() {
-            IpAddr::V4(ipv4_addr) => ipv4_addr,
-            _ => unreachable!()
-        };
-        process::Command::new("route")
-            .arg("delete")
-            .arg(format!("{}", server_ip))
-            .status()
-            .expect("failed to restore default route");
-
-        info!("restore default routing table    [OK]");
-
-        // 恢复系统DNS设定
-        assert_eq!(config.dns_server.is_some(), true);
-        assert_eq!(config.default_networkservice.is_some(), true);
-        // sudo networksetup -setdnsservers Wi-Fi Empty
-        process::Command::new("networksetup")
-            .arg("-setdnsservers")
-            .arg(config.default_networkservice.clone().unwrap())
-            .arg("Empty")
-            .status()
-            .expect("failed to restore dns setting");
-        info!("restore default dns setting      [OK]");
+/// What to route through the tunnel, IPv6 only when the lease has an address.
+fn tunnel_routes(config: &ClientConfig, lease: &protocol::Lease) -> Vec<IpNetwork> {
+    let include: Vec<IpNetwork> = if config.include.is_empty() && config.include_countries.is_empty() {
+        FULL_TUNNEL.iter().map(|network| network.parse().unwrap()).collect()
+    } else {
+        let mut include = config.include.clone();
+        include.extend(country_networks(&config.delegations, &config.include_countries));
+        include
+    };
+    let include: Vec<IpNetwork> = include.into_iter()
+        .filter(|network| lease.ipv6.is_some() || network.ip().is_ipv4())
+        .collect();
+    let mut exclude = config.exclude.clone();
+    exclude.extend(country_networks(&config.delegations, &config.exclude_countries));
+    cidr::routes(&include, &exclude)
+}
+
+/// Addresses the registries delegated to these countries.
+fn country_networks(delegations: &iana::number::Database, codes: &[String]) -> Vec<IpNetwork> {
+    let mut networks = vec![];
+    for code in codes.iter() {
+        networks.extend(cidr::from_ipv4_ranges(&delegations.by_country(code).ipv4_ranges()));
+        networks.extend(cidr::from_ipv6_ranges(&delegations.by_country(code).ipv6_ranges()));
     }
+    networks
+}
+
+fn configure(journal: &mut journal::Journal, config: &ClientConfig,
+             lease: &protocol::Lease, default_route: &(String, IpAddr)) -> Result<(), io::Error> {
+    // Keep reaching the server through the current default gateway.
+    // sudo ip route replace <server_ip>/32 via 192.168.199.1 dev eth0
+    let server = Destination::IpAddress(config.server_socket_addr.ip());
+    let (ref ifname, gateway) = *default_route;
+    try!(journal.replace_route(&server, &Gateway::IpAddress(gateway), Some(ifname)));
+
+    // The default routes stay, halves and more specific networks win over them.
+    // sudo ip route replace 0.0.0.0/1 dev tun9
+    // sudo ip route replace 128.0.0.0/1 dev tun9
+    let tun = Gateway::Interface(config.tun_ifname.clone());
+    let networks: Vec<Destination> = tunnel_routes(config, lease).into_iter()
+        .map(Destination::IpNetwork)
+        .collect();
+    try!(journal.replace_routes(&networks, &tun, None));
+
+    info!("auto config routing table, {} routes    [OK]", networks.len());
+
+    // networksetup -setdnsservers "Wi-Fi" "8.8.8.8"
+    try!(config.system_dns.as_ref().unwrap().execute(journal, lease.ipv6.is_some()));
+    info!("auto config dns server       [OK]");
+    Ok(())
 }
 
 
 fn main (){
-    signal::init();
-
     let config = boot();
     if config.is_err() {
         println!("Config err: {:?}", config);
@@ -674,8 +1421,19 @@ fn main (){
     }
     let config = config.unwrap();
 
-    logging::init(Some(config.verbose.as_str())).unwrap();
+    let _pidfile = match daemon::start(&config.daemon) {
+        Ok(pidfile) => pidfile,
+        Err(e) => {
+            println!("Can't start: {}", e);
+            process::exit(1);
+        }
+    };
+    match config.daemon.log_file {
+        Some(ref filename) => logging::init_with_file(Some(&config.verbose), filename).unwrap(),
+        None => logging::init(Some(&config.verbose)).unwrap()
+    };
+    // Spawns the signal thread, after `daemon::start` forked.
+    signal::init();
 
     run(&config);
-    cleanup(&config);
-}
\ No newline at end of file
+}
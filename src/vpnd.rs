@@ -3,7 +3,10 @@
 #[allow(unused_imports)]
 #[macro_use]
 extern crate logging;
+#[macro_use]
+extern crate bitflags;
 extern crate clap;
+extern crate toml;
 extern crate ctrlc;
 extern crate byteorder;
 extern crate mio;
@@ -25,16 +28,32 @@ extern crate netif;
 
 pub mod signal;
 pub mod syscfg;
+pub mod firewall;
 pub mod crypto;
 pub mod compression;
+pub mod protocol;
+pub mod fragment;
+pub mod mtu;
+pub mod mss;
+pub mod handshake;
+pub mod pool;
+pub mod router;
+pub mod transport;
+pub mod config;
+pub mod daemon;
+pub mod journal;
+#[cfg(test)]
+pub mod testutil;
 
 
 use std::env;
 use std::process;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use std::fs;
+use std::path::PathBuf;
 use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
 use std::str::FromStr;
 use std::io::{self, Read, Write};
@@ -42,27 +61,70 @@ use std::io::{self, Read, Write};
 
 use smoltcp::wire;
 use tun::platform::Device as TunDevice;
-use ipnetwork::Ipv4Network;
+use ipnetwork::{IpNetwork, Ipv4Network, Ipv6Network};
+
+use netif::route;
 
+use protocol::{ErrorCode, Flags, Hello, Message, Packet};
 
 const TUN_TOKEN: mio::Token = mio::Token(0);
 const UDP_TOKEN: mio::Token = mio::Token(1);
 
+const DEFAULT_JOURNAL: &'static str = "/var/run/vpnd.journal";
+
 
 #[derive(Debug)]
 pub struct ServerConfig {
     pub verbose: String,
+    pub daemon: daemon::Options,
 
     pub no_autoconfig: bool,
+    /// Undo records of the auto config changes.
+    pub journal: PathBuf,
     pub tun_ifname: String,
+    /// MTU of the tun device, derived from the outer interface when `None`.
+    pub mtu: Option<usize>,
+    /// Split packets too big for the tunnel rather than answer them with ICMP errors.
+    pub fragment: bool,
+    /// Lower the MSS of TCP connections through the tunnel to fit its MTU.
+    pub mss_clamp: bool,
     pub tun_network: Ipv4Network,
+    /// Unique local prefix the client IPv6 addresses are derived from.
+    pub tun_network6: Option<Ipv6Network>,
 
     pub default_ifname: String,
     
     pub server_udp_port: u16,
     
     pub disable_compression: bool,
-    pub disable_crypto: bool
+    pub disable_crypto: bool,
+    pub prikey: Option<crypto::rsa::PriKey>,
+    pub authorized_keys: Vec<crypto::rsa::PubKey>,
+
+    /// Idle time after which a client lease is reclaimed.
+    pub lease_timeout: Duration,
+    /// Idle time after which a session is dropped, the lease outlives it.
+    pub dead_peer_timeout: Duration,
+    /// Static addresses, keyed by client key fingerprint.
+    pub reservations: Vec<(Vec<u8>, Ipv4Addr)>,
+    /// Networks behind a client, keyed by client key fingerprint.
+    pub peer_networks: HashMap<Vec<u8>, Vec<IpNetwork>>,
+}
+
+/// A client which completed the hello, keyed by its session id.
+pub struct Peer {
+    /// Where the last authenticated packet came from, downstream packets go there.
+    pub endpoint: SocketAddr,
+    pub tun_ip: Ipv4Addr,
+    pub tun_ip6: Option<Ipv6Addr>,
+    pub session_id: u64,
+    pub session: Option<handshake::Session>,
+    pub cipher: Option<crypto::aead::SessionCipher>,
+    /// Path MTU discovery towards the endpoint.
+    pub prober: mtu::Prober,
+    pub reassembler: fragment::Reassembler,
+    /// Last valid packet from this peer.
+    pub last_seen: Instant,
 }
 
 
@@ -85,6 +147,201 @@ fn get_public_ip() -> Option<Ipv4Addr> {
 }
 
 
+/// Load every `*.pem` client public key found in `dirname`.
+fn load_authorized_keys(dirname: &str) -> Result<Vec<crypto::rsa::PubKey>, io::Error> {
+    let mut keys = vec![];
+    for entry in try!(fs::read_dir(dirname)) {
+        let path = try!(entry).path();
+        if path.extension().map(|ext| ext == "pem") != Some(true) {
+            continue;
+        }
+        match path.to_str() {
+            Some(filename) => keys.push(try!(crypto::rsa::PubKey::from_file(filename))),
+            None => continue
+        }
+    }
+    Ok(keys)
+}
+
+/// Load static address reservations, one `<client key sha256 fingerprint> <ipv4 addr>` per line.
+fn load_reservations(filename: &str) -> Result<Vec<(Vec<u8>, Ipv4Addr)>, io::Error> {
+    let data = try!(crypto::read_file(filename));
+    let text = match String::from_utf8(data) {
+        Ok(text) => text,
+        Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "reservations file is not UTF-8"))
+    };
+    let mut reservations = vec![];
+    for (lineno, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("#") {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let reservation = if fields.len() == 2 {
+            match (crypto::from_hex(fields[0]), fields[1].parse::<Ipv4Addr>()) {
+                (Some(fingerprint), Ok(addr)) => Some((fingerprint, addr)),
+                _ => None
+            }
+        } else {
+            None
+        };
+        match reservation {
+            Some(reservation) => reservations.push(reservation),
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("{}:{}: expected `<fingerprint> <ipv4 addr>`",
+                                                      filename, lineno + 1)))
+        }
+    }
+    Ok(reservations)
+}
+
+/// Read the `[[peers]]` of the config file, adding their static addresses to `reservations`.
+/// Returns the networks routed to each client fingerprint.
+fn load_peers(file: &config::ConfigFile, tun_network: &Ipv4Network, tun_network6: Option<Ipv6Network>,
+              reservations: &mut Vec<(Vec<u8>, Ipv4Addr)>) -> Result<HashMap<Vec<u8>, Vec<IpNetwork>>, io::Error> {
+    let mut peer_networks: HashMap<Vec<u8>, Vec<IpNetwork>> = HashMap::new();
+    for peer in try!(file.tables("peers")) {
+        let fingerprint: String = try!(peer.parse("fingerprint"));
+        let fingerprint = match crypto::from_hex(&fingerprint) {
+            Some(fingerprint) => fingerprint,
+            None => return Err(config::ConfigError::new(&format!("{}.fingerprint", peer.key),
+                                                        "expected a hex encoded SHA-256 digest").into())
+        };
+        if let Some(address) = try!(peer.optional::<Ipv4Addr>("address")) {
+            reservations.push((fingerprint.clone(), address));
+        }
+        let mut networks: Vec<IpNetwork> = vec![];
+        for (i, network) in try!(peer.list::<IpNetwork>("networks")).iter().enumerate() {
+            let key = format!("{}.networks[{}]", peer.key, i);
+            let network = router::truncate(network);
+            if network.prefix() == 0 {
+                return Err(config::ConfigError::new(&key, "would take over the default route").into());
+            }
+            let tun_overlap = router::overlaps(&network, &IpNetwork::V4(*tun_network)) ||
+                              match tun_network6 {
+                                  Some(tun_network6) => router::overlaps(&network, &IpNetwork::V6(tun_network6)),
+                                  None => false
+                              };
+            if tun_overlap {
+                return Err(config::ConfigError::new(&key, "overlaps the tunnel network").into());
+            }
+            let taken = peer_networks.iter()
+                                     .filter(|&(other, _)| *other != fingerprint)
+                                     .any(|(_, others)| others.iter().any(|other| router::overlaps(&network, other)));
+            if taken {
+                return Err(config::ConfigError::new(&key, "overlaps a network routed to another peer").into());
+            }
+            networks.push(network);
+        }
+        peer_networks.entry(fingerprint).or_insert_with(Vec::new).extend(networks);
+    }
+    Ok(peer_networks)
+}
+
+/// Routes tun traffic to peer sessions: the tunnel addresses of every peer
+/// and the networks behind it. Sessions outlive the endpoint of the peer.
+#[derive(Debug)]
+pub struct Registry {
+    router: router::Router<u64>,
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry { router: router::Router::new() }
+    }
+
+    pub fn insert(&mut self, tun_ip: Ipv4Addr, tun_ip6: Option<Ipv6Addr>, networks: &[IpNetwork],
+                  session_id: u64) {
+        self.remove(&tun_ip);
+        self.router.insert(router::host(IpAddr::V4(tun_ip)), session_id);
+        if let Some(tun_ip6) = tun_ip6 {
+            self.router.insert(router::host(IpAddr::V6(tun_ip6)), session_id);
+        }
+        for network in networks.iter() {
+            self.router.insert(*network, session_id);
+        }
+    }
+
+    /// Remove the session leasing `tun_ip`, its other routes go along.
+    pub fn remove(&mut self, tun_ip: &Ipv4Addr) -> Option<u64> {
+        let session_id = match self.router.get(&router::host(IpAddr::V4(*tun_ip))) {
+            Some(session_id) => *session_id,
+            None => return None
+        };
+        self.router.remove_all(&session_id);
+        Some(session_id)
+    }
+
+    pub fn get(&self, dst_ip: &IpAddr) -> Option<u64> {
+        self.router.lookup(dst_ip).cloned()
+    }
+
+    /// Whether `src_ip` routes back to `session_id`, anything else is spoofed.
+    pub fn allows(&self, session_id: u64, src_ip: &IpAddr) -> bool {
+        self.router.lookup(src_ip) == Some(&session_id)
+    }
+}
+
+/// IPv6 address of the host with IPv4 address `addr`: the host part of `addr` inside `network`
+/// appended to `network6`, so it follows the IPv4 lease and its reservations.
+fn ipv6_for(network: &Ipv4Network, network6: &Ipv6Network, addr: Ipv4Addr) -> Ipv6Addr {
+    let host = u32::from(addr) - u32::from(network.network());
+    let prefix = u128::from(network6.ip()) & (!0u128).checked_shl(128 - network6.prefix() as u32).unwrap_or(0);
+    Ipv6Addr::from(prefix | host as u128)
+}
+
+/// Forget the peers whose lease went idle.
+fn expire_peers(pool: &mut pool::AddressPool,
+                registry: &mut Registry,
+                peers: &mut HashMap<u64, Peer>,
+                now: Instant) {
+    for addr in pool.expire(now) {
+        if let Some(session_id) = registry.remove(&addr) {
+            if let Some(peer) = peers.remove(&session_id) {
+                info!("lease of {} for {} expired", addr, peer.endpoint);
+            }
+        }
+    }
+}
+
+/// Forget a session its client closed and release its lease.
+fn close_session(pool: &mut pool::AddressPool,
+                 registry: &mut Registry,
+                 peers: &mut HashMap<u64, Peer>,
+                 session_id: u64) {
+    if let Some(peer) = peers.remove(&session_id) {
+        registry.remove(&peer.tun_ip);
+        pool.release(peer.tun_ip);
+        info!("peer {} closed the session", peer.endpoint);
+    }
+}
+
+/// Forget the sessions which went silent, their leases are kept so the
+/// clients get the same address back when they reconnect.
+fn expire_sessions(registry: &mut Registry,
+                   peers: &mut HashMap<u64, Peer>,
+                   timeout: Duration) {
+    let dead: Vec<u64> = peers.iter()
+        .filter(|&(_, peer)| peer.last_seen.elapsed() > timeout)
+        .map(|(session_id, _)| *session_id)
+        .collect();
+    for session_id in dead {
+        if let Some(peer) = peers.remove(&session_id) {
+            registry.remove(&peer.tun_ip);
+            info!("session of {} ({}) timed out", peer.endpoint, peer.tun_ip);
+        }
+    }
+}
+
+/// Send the path MTU probes which are due.
+fn probe_peers(udp_socket: &transport::Listener, peers: &mut HashMap<u64, Peer>, now: Instant) {
+    for peer in peers.values_mut() {
+        if let Some((nonce, len)) = peer.prober.poll(now) {
+            let _ = udp_socket.send_to(&protocol::probe(peer.session_id, nonce, len), &peer.endpoint);
+        }
+    }
+}
+
 #[cfg(target_os = "linux")]
 fn boot() -> Result<ServerConfig, io::Error> {
     use clap::{App, Arg};
@@ -101,6 +358,12 @@ fn boot() -> Result<ServerConfig, io::Error> {
                 .takes_value(true)
                 .help("Use a custom config file")
         )
+        .arg(
+            Arg::with_name("check-config")
+                .long("check-config")
+                .required(false)
+                .help("Validate the configuration and exit")
+        )
         .arg(
             Arg::with_name("verbose")
                 .short("v")
@@ -116,12 +379,46 @@ fn boot() -> Result<ServerConfig, io::Error> {
                 .required(false)
                 .help("VPN server running process into the background")
         )
+        .arg(
+            Arg::with_name("pidfile")
+                .long("pidfile")
+                .required(false)
+                .takes_value(true)
+                .help("Write the process id to this file, refuse to start if another instance holds it")
+        )
+        .arg(
+            Arg::with_name("log-file")
+                .long("log-file")
+                .required(false)
+                .takes_value(true)
+                .help("Append logs to this file instead of stderr")
+        )
+        .arg(
+            Arg::with_name("user")
+                .long("user")
+                .required(false)
+                .takes_value(true)
+                .help("Drop root privileges to this user once the tun device and sockets are opened")
+        )
         .arg(
             Arg::with_name("no-autoconfig")
                 .long("no-auto-config")
                 .required(false)
                 .help("Auto config system routing table and nameserver")
         )
+        .arg(
+            Arg::with_name("journal")
+                .long("journal")
+                .required(false)
+                .takes_value(true)
+                .help("Record how to undo the auto config changes in this file")
+        )
+        .arg(
+            Arg::with_name("cleanup")
+                .long("cleanup")
+                .required(false)
+                .help("Undo the auto config changes left behind by a crashed server and exit")
+        )
         .arg(
             Arg::with_name("default-ifname")
                 .long("default-ifname")
@@ -137,13 +434,39 @@ fn boot() -> Result<ServerConfig, io::Error> {
                 .default_value("utun9")
                 .help("Specify the tun network device name")
         )
+        .arg(
+            Arg::with_name("mtu")
+                .long("mtu")
+                .required(false)
+                .takes_value(true)
+                .help("MTU of the tun device, the outer interface MTU minus the tunnel overhead by default")
+        )
+        .arg(
+            Arg::with_name("fragment")
+                .long("fragment")
+                .required(false)
+                .help("Split packets too big for the tunnel instead of answering ICMP errors to their sender")
+        )
+        .arg(
+            Arg::with_name("no-mss-clamp")
+                .long("no-mss-clamp")
+                .required(false)
+                .help("Leave the MSS of TCP connections through the tunnel alone")
+        )
         .arg(
             Arg::with_name("tun-network")
                 .long("tun-network")
-                .required(true)
+                .required(false)
                 .takes_value(true)
                 .help("Specify the default gateway")
         )
+        .arg(
+            Arg::with_name("tun-network6")
+                .long("tun-network6")
+                .required(false)
+                .takes_value(true)
+                .help("Unique local IPv6 prefix for the tunnel (e.g fd00:6578:6f64::/64)")
+        )
         .arg(
             Arg::with_name("disable-compression")
                 .long("disable-compression")
@@ -164,47 +487,165 @@ fn boot() -> Result<ServerConfig, io::Error> {
                 .default_value("9050")
                 .help("UDP Port")
         )
+        .arg(
+            Arg::with_name("key")
+                .long("key")
+                .required(false)
+                .takes_value(true)
+                .help("RSA private key (PEM Format)")
+        )
+        .arg(
+            Arg::with_name("authorized-keys")
+                .long("authorized-keys")
+                .required(false)
+                .takes_value(true)
+                .help("Directory of client RSA public keys allowed to connect (PEM Format)")
+        )
+        .arg(
+            Arg::with_name("lease-timeout")
+                .long("lease-timeout")
+                .required(false)
+                .takes_value(true)
+                .default_value("300")
+                .help("Seconds without traffic before a client address is reclaimed")
+        )
+        .arg(
+            Arg::with_name("dead-peer-timeout")
+                .long("dead-peer-timeout")
+                .required(false)
+                .takes_value(true)
+                .default_value("120")
+                .help("Seconds without traffic before a client session is dropped")
+        )
+        .arg(
+            Arg::with_name("reservations")
+                .long("reservations")
+                .required(false)
+                .takes_value(true)
+                .help("File of static client addresses, `<client key sha256 fingerprint> <ipv4 addr>` per line")
+        )
         .get_matches();
 
+    let config_file = match matches.value_of("config") {
+        Some(filename) => {
+            let file = try!(config::ConfigFile::load(filename));
+            try!(file.check_keys(config::SERVER_KEYS));
+            Some(file)
+        },
+        None => None
+    };
+    let settings = config::Settings::new(&matches, config_file);
+
+    let verbose: String = try!(settings.require::<String>("verbose", "verbose")).to_lowercase();
+    let daemon_options = try!(daemon::Options::from_settings(&settings));
 
-    if matches.is_present("daemon") {
-        let mut args = vec![];
-        for arg in env::args() {
-            if arg != "--daemon" {
-                args.push(arg);
+    let journal_path: PathBuf = match try!(settings.string("journal", "journal")) {
+        Some(path) => daemon::absolute(&path),
+        None => PathBuf::from(DEFAULT_JOURNAL)
+    };
+    if matches.is_present("cleanup") {
+        logging::init(Some(&verbose)).unwrap();
+        match journal::recover(&journal_path) {
+            Ok(true) => println!("restored the system settings recorded in {}", journal_path.display()),
+            Ok(false) => println!("nothing to clean up, {} does not exist", journal_path.display()),
+            Err(e) => {
+                println!("Can't clean up from {}.\n{}", journal_path.display(), e);
+                process::exit(1);
             }
         }
-        let executable = &args[0];
-        let child = process::Command::new(executable)
-                            .args(&args[1..])
-                            .spawn()
-                            .expect("Child process failed to start.");
-        let pid = child.id();
-        println!("child pid: {}", pid);
         process::exit(0);
     }
 
-    let verbose: String = matches.value_of("verbose").unwrap().to_lowercase();
-
-    let tun_ifname: String = matches.value_of("tun-ifname").unwrap().to_string();
-    let tun_network: Ipv4Network = Ipv4Network::from_str(matches.value_of("tun-network").unwrap()).unwrap();
+    let tun_ifname: String = try!(settings.require("tun-ifname", "tun.ifname"));
+    let tun_mtu: Option<usize> = try!(settings.parse("mtu", "tun.mtu"));
+    if let Some(tun_mtu) = tun_mtu {
+        if tun_mtu < mtu::IPV4_MIN_MTU || tun_mtu > fragment::MAX_PACKET_LEN {
+            return Err(config::ConfigError::new("tun.mtu", &format!("must be between {} and {}", mtu::IPV4_MIN_MTU,
+                                                                    fragment::MAX_PACKET_LEN)).into());
+        }
+    }
+    let fragment: bool = try!(settings.flag("fragment", "tun.fragment"));
+    let no_mss_clamp: bool = try!(settings.flag("no-mss-clamp", "tun.no_mss_clamp"));
+    let tun_network: Ipv4Network = try!(settings.require("tun-network", "tun.network"));
+    let tun_network6: Option<Ipv6Network> = try!(settings.parse("tun-network6", "tun.network6"));
+    if let Some(ref tun_network6) = tun_network6 {
+        if tun_network6.ip().segments()[0] & 0xfe00 != 0xfc00 {
+            return Err(config::ConfigError::new("tun.network6", "must be a unique local prefix (fc00::/7)").into());
+        }
+        if tun_network6.prefix() as u32 + (32 - tun_network.prefix() as u32) > 128 {
+            return Err(config::ConfigError::new("tun.network6",
+                                                "too long to hold every address of tun.network").into());
+        }
+    }
     
-    let server_udp_port: u16 = matches.value_of("port").unwrap().parse().unwrap();
+    let server_udp_port: u16 = try!(settings.require("port", "port"));
 
-    let no_autoconfig: bool = if matches.is_present("no-autoconfig") { true } else { false };
+    let no_autoconfig: bool = try!(settings.flag("no-autoconfig", "no_auto_config"));
+    if daemon_options.user.is_some() && !no_autoconfig {
+        // Restoring forwarding, routes and masquerading at exit needs root.
+        return Err(config::ConfigError::new("user", "requires no_auto_config (--no-auto-config)").into());
+    }
 
-    let disable_compression: bool = if matches.is_present("disable-compression") { true } else { false };
-    let disable_crypto: bool = if matches.is_present("disable-crypto") { true } else { false };
+    let disable_compression: bool = try!(settings.flag("disable-compression", "compression.disable"));
+    let disable_crypto: bool = try!(settings.flag("disable-crypto", "crypto.disable"));
 
-    let default_ifname: String = if no_autoconfig {
-        match matches.value_of("default-ifname") {
-            Some(ifname) => ifname.to_string(),
-            None => {
-                println!("{}", matches.usage());
+    let (prikey, authorized_keys) = if disable_crypto {
+        (None, vec![])
+    } else {
+        let key_file_name: String = try!(settings.require("key", "crypto.key"));
+        let prikey = match crypto::rsa::PriKey::from_file(&key_file_name) {
+            Ok(rsa_prikey) => rsa_prikey,
+            Err(e) => {
+                println!("Can't load RSA private key.\n{:?}", e);
+                process::exit(1);
+            }
+        };
+        let dirname: String = try!(settings.require("authorized-keys", "crypto.authorized_keys"));
+        let authorized_keys = match load_authorized_keys(&dirname) {
+            Ok(keys) => keys,
+            Err(e) => {
+                println!("Can't load authorized keys.\n{:?}", e);
                 process::exit(1);
             }
+        };
+        if authorized_keys.is_empty() {
+            println!("No client public key is authorized, every handshake would be rejected.");
+            process::exit(1);
         }
+        (Some(prikey), authorized_keys)
+    };
+
+    let lease_timeout: Duration = match try!(settings.require::<u64>("lease-timeout", "lease.timeout")) {
+        0 => return Err(config::ConfigError::new("lease.timeout", "must be a positive number of seconds").into()),
+        secs => Duration::from_secs(secs)
+    };
+    let dead_peer_timeout: Duration = match try!(settings.require::<u64>("dead-peer-timeout", "keepalive.timeout")) {
+        0 => return Err(config::ConfigError::new("keepalive.timeout", "must be a positive number of seconds").into()),
+        secs => Duration::from_secs(secs)
+    };
+    let mut reservations = match try!(settings.string("reservations", "lease.reservations")) {
+        Some(filename) => match load_reservations(&filename) {
+            Ok(reservations) => reservations,
+            Err(e) => {
+                println!("Can't load reservations.\n{}", e);
+                process::exit(1);
+            }
+        },
+        None => vec![]
+    };
+    let peer_networks = match settings.file() {
+        Some(file) => try!(load_peers(file, &tun_network, tun_network6, &mut reservations)),
+        None => HashMap::new()
+    };
+
+    let default_ifname: String = if no_autoconfig {
+        try!(settings.require("default-ifname", "network.default_ifname"))
     } else {
+        if journal_path.exists() {
+            println!("{} is left from a previous run, restore it with `vpnd --cleanup` first.",
+                     journal_path.display());
+            process::exit(1);
+        }
         match syscfg::get_default_route() {
             Some((ifname, _)) => ifname,
             None => {
@@ -214,20 +655,39 @@ fn boot() -> Result<ServerConfig, io::Error> {
         }
     };
 
+    if matches.is_present("check-config") {
+        println!("config OK");
+        process::exit(0);
+    }
+
+
     Ok(ServerConfig{
         verbose: verbose,
+        daemon: daemon_options,
 
         no_autoconfig: no_autoconfig,
+        journal: journal_path,
 
         default_ifname: default_ifname,
 
         tun_ifname: tun_ifname,
+        mtu: tun_mtu,
+        fragment: fragment,
+        mss_clamp: !no_mss_clamp,
         tun_network: tun_network,
+        tun_network6: tun_network6,
 
         server_udp_port: server_udp_port,
 
         disable_compression: disable_compression,
-        disable_crypto: disable_crypto
+        disable_crypto: disable_crypto,
+        prikey: prikey,
+        authorized_keys: authorized_keys,
+
+        lease_timeout: lease_timeout,
+        dead_peer_timeout: dead_peer_timeout,
+        reservations: reservations,
+        peer_networks: peer_networks,
     })
 }
 
@@ -238,45 +698,85 @@ fn boot() -> Result<ServerConfig, io::Error> {
 
 #[cfg(target_os = "linux")]
 fn run(config: &ServerConfig) {
-    let gateway_interface_ip = Ipv4Addr::new(0, 0, 0, 0);
-    let server_socket_addr = SocketAddr::new(IpAddr::V4(gateway_interface_ip), config.server_udp_port);
-
     let tun_ip = Ipv4Addr::from(u32::from(config.tun_network.ip()) + 1);
     let tun_octets = tun_ip.octets();
     let tun_netmask = config.tun_network.mask();
     let tun_netmask_octets = tun_netmask.octets();
     let tun_ip_addr = wire::Ipv4Address::from_bytes(&tun_octets);
+    // Peers reached over IPv6 have less room, their packets are checked one by one.
+    let outer_mtu = mtu::interface_mtu(&config.default_ifname);
+    let tun_mtu = mtu::tun_mtu(config.mtu, mtu::tunnel_mtu(outer_mtu, false, !config.disable_crypto),
+                               config.tun_network6.is_some(), config.fragment);
     let mut tun_device: TunDevice = {
         let mut tun_config = tun::Configuration::default();
         tun_config
             .address(tun_ip)
             .netmask(tun_netmask)
             .destination(Ipv4Addr::new(0, 0, 0, 0))
-            .mtu(1500)
+            .mtu(tun_mtu as i32)
             .name(config.tun_ifname.clone())
             .up();
         tun::create(&tun_config).expect("can't create tun device.")
     };
+    let tun_ip6: Option<(Ipv6Addr, u8)> = match config.tun_network6 {
+        Some(ref tun_network6) => {
+            let tun_ip6 = ipv6_for(&config.tun_network, tun_network6, tun_ip);
+            if let Err(e) = syscfg::add_ipv6_address(&config.tun_ifname, tun_ip6, tun_network6.prefix()) {
+                error!("{}", e);
+                process::exit(1);
+            }
+            info!("tun device running at: {}/{}", tun_ip6, tun_network6.prefix());
+            Some((tun_ip6, tun_network6.prefix()))
+        },
+        None => None
+    };
+
+    let udp_socket_raw_fd = transport::Listener::bind(config.server_udp_port).unwrap();
+    info!("bind at {} ...", udp_socket_raw_fd.local_addr().unwrap());
+    info!("tun device running at: {} --> 0.0.0.0 netmask: {} mtu: {}", tun_ip, tun_netmask, tun_mtu);
+
+    let mut pool = pool::AddressPool::new(config.tun_network, tun_ip, config.lease_timeout);
+    for &(ref fingerprint, addr) in config.reservations.iter() {
+        if let Err(e) = pool.reserve(fingerprint.clone(), addr) {
+            error!("can't reserve {}: {}", addr, e);
+            process::exit(1);
+        }
+    }
 
-    let udp_socket_raw_fd = mio::net::UdpSocket::bind(&server_socket_addr).unwrap();
-    info!("bind at {} ...", &server_socket_addr);
-    info!("tun device running at: {} --> 0.0.0.0 netmask: {}", tun_ip, tun_netmask);
+    // Auto Config, rolled back when dropped, panics included.
+    let system_config = auto_config(&config);
 
-    // Auto Config
-    auto_config(&config);
+    if let Err(e) = daemon::drop_privileges(&config.daemon) {
+        error!("can't drop privileges: {}", e);
+        process::exit(1);
+    }
 
-    let mut udp_buf = [0u8; 1600];
-    let mut tun_buf = [0u8; 1600];
+    let mut udp_buf = vec![0u8; mtu::BUFFER_LEN];
+    let mut tun_buf = vec![0u8; mtu::BUFFER_LEN];
 
     let mut events = mio::Events::with_capacity(1024);
-    let mut registry: HashMap<Ipv4Addr, SocketAddr> = HashMap::new();
+    let mut registry = Registry::new();
+    // Peers which completed the hello by session id, data from anybody else is dropped.
+    let mut peers: HashMap<u64, Peer> = HashMap::new();
+    let mut handshake_server = match config.prikey {
+        Some(ref prikey) => Some(handshake::Server::new(prikey, &config.authorized_keys)),
+        None => None
+    };
 
     let poll = mio::Poll::new().unwrap();
     poll.register(&udp_socket_raw_fd, UDP_TOKEN, mio::Ready::readable(), mio::PollOpt::level()).unwrap();
     poll.register(&tun_device, TUN_TOKEN, mio::Ready::readable(), mio::PollOpt::level()).unwrap();
 
+    let features = protocol::features(config.disable_compression);
+    let mut compressor = if config.disable_compression {
+        None
+    } else {
+        Some(compression::Compressor::new())
+    };
+    let mut fragmenter = fragment::Fragmenter::new();
+
     info!("Ready for transmission.");
-    let mut best_ip_number = u32::from(tun_ip) + 5;
+    let mut last_expire = Instant::now();
 
     let timeout = Duration::new(2, 0);
     loop {
@@ -288,81 +788,426 @@ fn run(config: &ServerConfig) {
             Err(_) => continue
         };
 
+        if last_expire.elapsed() >= timeout {
+            last_expire = Instant::now();
+            expire_peers(&mut pool, &mut registry, &mut peers, last_expire);
+            expire_sessions(&mut registry, &mut peers, config.dead_peer_timeout);
+            probe_peers(&udp_socket_raw_fd, &mut peers, last_expire);
+        }
+
         for event in events.iter() {
             match event.token() {
                 UDP_TOKEN => {
                     let (size, remote_socket_addr) = udp_socket_raw_fd.recv_from(&mut udp_buf).unwrap();
-                    if size == 0 || remote_socket_addr.ip().is_ipv6() {
+                    if size == 0 {
                         debug!("Error Pakcet: {:?}", &udp_buf[..size]);
                         continue;
                     }
-                    let cmd = udp_buf[0];
-                    match cmd {
-                        1 => {
+                    let packet = match Packet::decode(&udp_buf[..size]) {
+                        Ok(packet) => packet,
+                        Err(e) => {
+                            // No answer, the source address may well be spoofed.
+                            debug!("drop packet from {}: {}", remote_socket_addr, e);
+                            continue;
+                        }
+                    };
+                    let packet_flags = packet.flags;
+                    match packet.message {
+                        Message::Hello(hello) => {
                             // DHCP
-                            best_ip_number += 1;
-                            if let IpAddr::V4(remote_ip) = remote_socket_addr.ip() {
-                                let remote_octets = remote_ip.octets();
-                                let best_octets = Ipv4Addr::from(best_ip_number).octets();
-                                
-                                let msg = [1,
-                                    best_octets[0], best_octets[1], best_octets[2], best_octets[3],
-                                    remote_octets[0], remote_octets[1], remote_octets[2], remote_octets[3],
-                                    tun_octets[0], tun_octets[1], tun_octets[2], tun_octets[3],
-                                    tun_netmask_octets[0], tun_netmask_octets[1], tun_netmask_octets[2], 
-                                    tun_netmask_octets[3]
-                                ];
-
-                                udp_socket_raw_fd.send_to(&msg, &remote_socket_addr).unwrap();
-                                let client_tun_ip = Ipv4Addr::from(best_ip_number);
-                                debug!("为 {:?} 分配虚拟地址 {:?}", remote_socket_addr, client_tun_ip);
-                                registry.insert(client_tun_ip, remote_socket_addr);
+                            // The lease only has room for an IPv4 public address.
+                            let remote_ip = match remote_socket_addr.ip() {
+                                IpAddr::V4(remote_ip) => remote_ip,
+                                IpAddr::V6(_) => Ipv4Addr::new(0, 0, 0, 0)
+                            };
+                            if let Some(reason) = protocol::compression_mismatch(features, packet_flags) {
+                                warn!("refuse {}: {}", remote_socket_addr, reason);
+                                let msg = protocol::error(0, ErrorCode::Unsupported, &reason);
+                                let _ = udp_socket_raw_fd.send_to(&msg, &remote_socket_addr);
+                                continue;
+                            }
+
+                            let session = match handshake_server {
+                                Some(ref mut hs) => match hs.handle(remote_socket_addr, &hello) {
+                                    Ok(handshake::Step::Reply(reply)) => {
+                                        let _ = udp_socket_raw_fd.send_to(&Packet::new(0, reply).encode(),
+                                                                          &remote_socket_addr);
+                                        continue;
+                                    },
+                                    Ok(handshake::Step::Established(session)) => Some(session),
+                                    Err(e) => {
+                                        warn!("handshake with {} failed: {}", remote_socket_addr, e);
+                                        let reject = Packet::new(0, handshake::reject(&format!("{}", e)));
+                                        let _ = udp_socket_raw_fd.send_to(&reject.encode(), &remote_socket_addr);
+                                        continue;
+                                    }
+                                },
+                                None => match hello {
+                                    Hello::Plain => None,
+                                    _ => {
+                                        let msg = protocol::error(0, ErrorCode::Unsupported,
+                                                                  "crypto is disabled on this server");
+                                        let _ = udp_socket_raw_fd.send_to(&msg, &remote_socket_addr);
+                                        continue;
+                                    }
+                                }
+                            };
+
+                            let owner: Vec<u8> = match session {
+                                Some(ref session) => session.pubkey.fingerprint(),
+                                None => format!("{}", remote_socket_addr).into_bytes()
+                            };
+                            let now = Instant::now();
+                            expire_peers(&mut pool, &mut registry, &mut peers, now);
+                            let client_tun_ip = match pool.allocate(&owner, now) {
+                                Ok(addr) => addr,
+                                Err(e) => {
+                                    warn!("can't lease an address to {}: {}", remote_socket_addr, e);
+                                    let msg = protocol::error(0, ErrorCode::Other, &format!("{}", e));
+                                    let _ = udp_socket_raw_fd.send_to(&msg, &remote_socket_addr);
+                                    continue;
+                                }
+                            };
+
+                            // A new hello from this endpoint replaces its session, and a client
+                            // coming back from another endpoint takes its address along.
+                            let replaced: Vec<u64> = peers.iter()
+                                .filter(|&(_, peer)| peer.endpoint == remote_socket_addr)
+                                .map(|(session_id, _)| *session_id)
+                                .collect();
+                            for session_id in replaced {
+                                if let Some(old_peer) = peers.remove(&session_id) {
+                                    registry.remove(&old_peer.tun_ip);
+                                    if old_peer.tun_ip != client_tun_ip {
+                                        pool.release(old_peer.tun_ip);
+                                    }
+                                }
+                            }
+                            if let Some(old_session_id) = registry.remove(&client_tun_ip) {
+                                peers.remove(&old_session_id);
+                            }
+                            let client_tun_ip6 = match config.tun_network6 {
+                                Some(ref tun_network6) => Some(ipv6_for(&config.tun_network, tun_network6,
+                                                                        client_tun_ip)),
+                                None => None
+                            };
+                            let lease = protocol::Lease {
+                                tun_ip: client_tun_ip,
+                                public_ip: remote_ip,
+                                server_tun_ip: tun_ip,
+                                tun_netmask: tun_netmask,
+                                ipv6: match (client_tun_ip6, tun_ip6) {
+                                    (Some(client_tun_ip6), Some((tun_ip6, prefix_len))) => Some(protocol::Lease6 {
+                                        tun_ip: client_tun_ip6,
+                                        server_tun_ip: tun_ip6,
+                                        prefix_len: prefix_len,
+                                    }),
+                                    _ => None
+                                },
+                            };
+                            let msg = match (&handshake_server, &session) {
+                                (&Some(ref hs), &Some(ref session)) => hs.lease(session, &lease),
+                                _ => handshake::plain_lease(&lease)
+                            };
+                            let mut session_id = protocol::new_session_id();
+                            while peers.contains_key(&session_id) {
+                                session_id = protocol::new_session_id();
                             }
+
+                            let mut lease_packet = Packet::new(session_id, msg);
+                            lease_packet.flags = features;
+                            if let Err(e) = udp_socket_raw_fd.send_to(&lease_packet.encode(), &remote_socket_addr) {
+                                // The lease never reached the client, don't keep a session for it.
+                                warn!("can't send the lease to {}: {}", remote_socket_addr, e);
+                                pool.release(client_tun_ip);
+                                continue;
+                            }
+                            match session {
+                                Some(_) => info!("为 {:?} 分配虚拟地址 {:?} (key fingerprint: {})",
+                                                 remote_socket_addr, client_tun_ip, crypto::to_hex(&owner)),
+                                None => info!("为 {:?} 分配虚拟地址 {:?}", remote_socket_addr, client_tun_ip)
+                            }
+                            let cipher = match session {
+                                Some(ref session) => Some(crypto::aead::SessionCipher::new(&session.key,
+                                                                                          crypto::aead::Role::Server)),
+                                None => None
+                            };
+                            let networks: &[IpNetwork] = match (&session, config.peer_networks.get(&owner)) {
+                                (&Some(_), Some(networks)) => &networks[..],
+                                _ => &[]
+                            };
+                            registry.insert(client_tun_ip, client_tun_ip6, networks, session_id);
+                            peers.insert(session_id, Peer {
+                                endpoint: remote_socket_addr,
+                                tun_ip: client_tun_ip,
+                                tun_ip6: client_tun_ip6,
+                                session_id: session_id,
+                                session: session,
+                                cipher: cipher,
+                                prober: mtu::Prober::new(outer_mtu, remote_socket_addr.is_ipv6()),
+                                reassembler: fragment::Reassembler::new(),
+                                last_seen: Instant::now(),
+                            });
                         },
-                        2 => {
-                            let mut packet = &mut udp_buf[1..size];
+                        Message::Data { counter, payload } => {
+                            let session_id = packet.session_id;
+                            if packet_flags.contains(Flags::CLOSE) {
+                                let authentic = match peers.get_mut(&session_id) {
+                                    Some(peer) => match peer.cipher {
+                                        Some(ref mut cipher) => {
+                                            protocol::open_data(cipher, &udp_buf[..size], counter, &payload).is_ok()
+                                        },
+                                        None => peer.endpoint == remote_socket_addr
+                                    },
+                                    None => false
+                                };
+                                if authentic {
+                                    close_session(&mut pool, &mut registry, &mut peers, session_id);
+                                }
+                                continue;
+                            }
+                            let peer = match peers.get_mut(&session_id) {
+                                Some(peer) => peer,
+                                None => {
+                                    // The client notices the silence and says hello again.
+                                    debug!("drop packet from unauthenticated peer {}", remote_socket_addr);
+                                    continue;
+                                }
+                            };
+                            // Without crypto nothing proves a packet from another endpoint
+                            // belongs to the session, the client has to say hello again.
+                            if peer.cipher.is_none() && peer.endpoint != remote_socket_addr {
+                                debug!("drop packet from {}: session bound to {}", remote_socket_addr, peer.endpoint);
+                                continue;
+                            }
+                            let packet: Vec<u8> = match peer.cipher {
+                                Some(ref mut cipher) => {
+                                    if !packet_flags.contains(Flags::ENCRYPTED) {
+                                        debug!("drop unencrypted packet from {}", remote_socket_addr);
+                                        continue;
+                                    }
+                                    match protocol::open_data(cipher, &udp_buf[..size], counter, &payload) {
+                                        Ok(packet) => packet,
+                                        Err(e) => {
+                                            debug!("drop packet from {}: {}", remote_socket_addr, e);
+                                            continue;
+                                        }
+                                    }
+                                },
+                                None => payload
+                            };
+                            // Authenticated, the session follows the client to its new address.
+                            if peer.endpoint != remote_socket_addr {
+                                info!("session of {} moved from {} to {}", peer.tun_ip, peer.endpoint,
+                                      remote_socket_addr);
+                                peer.endpoint = remote_socket_addr;
+                                peer.prober = mtu::Prober::new(outer_mtu, remote_socket_addr.is_ipv6());
+                            }
+                            if packet.is_empty() {
+                                // Keepalive of an encrypted session, answered sealed.
+                                peer.last_seen = Instant::now();
+                                pool.touch(peer.tun_ip, peer.last_seen);
+                                if let Ok(reply) = protocol::keepalive(peer.cipher.as_mut(), session_id) {
+                                    let _ = udp_socket_raw_fd.send_to(&reply, &remote_socket_addr);
+                                }
+                                continue;
+                            }
+                            let packet: Vec<u8> = if packet_flags.contains(Flags::FRAGMENT) {
+                                match peer.reassembler.push(&packet, Instant::now()) {
+                                    Ok(Some(packet)) => packet,
+                                    Ok(None) => continue,
+                                    Err(e) => {
+                                        debug!("drop fragment from {}: {}", remote_socket_addr, e);
+                                        continue;
+                                    }
+                                }
+                            } else {
+                                packet
+                            };
+                            let mut packet: Vec<u8> = if packet_flags.contains(Flags::COMPRESSED) {
+                                match compressor {
+                                    Some(ref mut compressor) => match compressor.decompress(&packet) {
+                                        Ok(packet) => packet,
+                                        Err(e) => {
+                                            debug!("drop packet from {}: {}", remote_socket_addr, e);
+                                            continue;
+                                        }
+                                    },
+                                    None => {
+                                        debug!("drop compressed packet from {}, compression is disabled",
+                                               remote_socket_addr);
+                                        continue;
+                                    }
+                                }
+                            } else {
+                                packet
+                            };
+                            let allowed = match router::addresses(&packet) {
+                                Some((src_ip, _)) => registry.allows(session_id, &src_ip),
+                                None => false
+                            };
+                            if !allowed {
+                                debug!("drop packet from {}: source address not routed to it", remote_socket_addr);
+                                continue;
+                            }
+                            if config.mss_clamp {
+                                let tunnel_mtu = mtu::tunnel_mtu(peer.prober.path_mtu(), remote_socket_addr.is_ipv6(),
+                                                                 peer.cipher.is_some());
+                                mss::clamp(&mut packet, ::std::cmp::min(tunnel_mtu, tun_mtu));
+                            }
+                            peer.last_seen = Instant::now();
+                            pool.touch(peer.tun_ip, peer.last_seen);
                             let _ = tun_device.write(&packet);
                         },
+                        Message::Keepalive => {
+                            // Not authenticated, only known at the endpoint of a session without
+                            // crypto. Encrypted sessions keep alive with sealed data packets.
+                            let known = match peers.get_mut(&packet.session_id) {
+                                Some(ref mut peer) if peer.endpoint == remote_socket_addr && peer.cipher.is_none() => {
+                                    peer.last_seen = Instant::now();
+                                    pool.touch(peer.tun_ip, peer.last_seen);
+                                    true
+                                },
+                                _ => false
+                            };
+                            if known {
+                                let reply = Packet::new(packet.session_id, Message::Keepalive).encode();
+                                let _ = udp_socket_raw_fd.send_to(&reply, &remote_socket_addr);
+                            }
+                        },
+                        Message::Probe { nonce, .. } => {
+                            // Answered like a keepalive, only at the endpoint of the session.
+                            let known = match peers.get(&packet.session_id) {
+                                Some(peer) => peer.endpoint == remote_socket_addr,
+                                None => false
+                            };
+                            if known {
+                                let ack = Message::ProbeAck { nonce: nonce, size: size as u16 };
+                                let _ = udp_socket_raw_fd.send_to(&Packet::new(packet.session_id, ack).encode(),
+                                                                  &remote_socket_addr);
+                            }
+                        },
+                        Message::ProbeAck { nonce, size: len } => {
+                            if let Some(peer) = peers.get_mut(&packet.session_id) {
+                                if peer.endpoint == remote_socket_addr &&
+                                   peer.prober.ack(nonce, len as usize, Instant::now()) {
+                                    info!("path MTU to {} is {}", remote_socket_addr, peer.prober.path_mtu());
+                                }
+                            }
+                        },
+                        Message::Close => {
+                            // Encrypted sessions only close with a sealed packet, see `protocol::close`.
+                            let closed = match peers.get(&packet.session_id) {
+                                Some(peer) => peer.cipher.is_none() && peer.endpoint == remote_socket_addr,
+                                None => false
+                            };
+                            if closed {
+                                close_session(&mut pool, &mut registry, &mut peers, packet.session_id);
+                            }
+                        },
                         _ => { }
                     }
                 },
                 TUN_TOKEN => {
-                    let size: usize = tun_device.read(&mut tun_buf[1..]).unwrap();
-                    let data = if cfg!(target_os = "macos") {
-                        if size < 4 {
+                    let size: usize = tun_device.read(&mut tun_buf).unwrap();
+                    let packet = if cfg!(target_os = "macos") {
+                        if size <= 4 {
                             continue;
                         }
-                        tun_buf[4] = 2;
-                        &tun_buf[4..size+1]
+                        &mut tun_buf[4..size]
                     } else if cfg!(target_os = "linux") {
                         if size == 0 {
                             continue;
                         }
-                        tun_buf[0] = 2;
-                        &tun_buf[..size+1]
+                        &mut tun_buf[..size]
                     } else {
                         panic!("oops ...");
                     };
 
-                    let packet = &data[1..];
-                    if packet[0] == 69 {
-                        // Ipv4
-                        let ipv4_packet = wire::Ipv4Packet::new(&packet);
-                        let dst_ip = Ipv4Addr::from(ipv4_packet.dst_addr().0);
-                        match registry.get(&dst_ip) {
-                            Some(remote_socket_addr) => {
-                                udp_socket_raw_fd.send_to(&data, remote_socket_addr).unwrap();
+                    let dst_ip: IpAddr = match router::addresses(packet) {
+                        Some((_, dst_ip)) => dst_ip,
+                        None => continue
+                    };
+                    let session_id = match registry.get(&dst_ip) {
+                        Some(session_id) => session_id,
+                        None => continue
+                    };
+                    let peer = match peers.get_mut(&session_id) {
+                        Some(peer) => peer,
+                        None => continue
+                    };
+                    let remote_socket_addr = peer.endpoint;
+                    let tunnel_mtu = mtu::tunnel_mtu(peer.prober.path_mtu(), remote_socket_addr.is_ipv6(),
+                                                     peer.cipher.is_some());
+                    if config.mss_clamp {
+                        mss::clamp(packet, ::std::cmp::min(tunnel_mtu, tun_mtu));
+                    }
+                    let packet: &[u8] = packet;
+                    let compressed = match compressor {
+                        Some(ref mut compressor) => compressor.compress(packet),
+                        None => None
+                    };
+                    let (flags, payload) = match compressed {
+                        Some(ref compressed) => (Flags::COMPRESSED, &compressed[..]),
+                        None => (Flags::empty(), packet)
+                    };
+
+                    let payloads: Vec<(Flags, Vec<u8>)> = if payload.len() <= tunnel_mtu {
+                        vec![(flags, payload.to_vec())]
+                    } else if config.fragment {
+                        match fragmenter.split(payload, tunnel_mtu) {
+                            Some(pieces) => {
+                                pieces.into_iter().map(|piece| (flags | Flags::FRAGMENT, piece)).collect()
+                            },
+                            None => {
+                                debug!("drop packet of {} bytes for {}, too big to fragment", packet.len(),
+                                       remote_socket_addr);
+                                continue;
+                            }
+                        }
+                    } else {
+                        // The peer is the far end of the tunnel, the ICMP error comes from it.
+                        match mtu::oversized(packet, tunnel_mtu, peer.tun_ip, peer.tun_ip6) {
+                            mtu::Oversized::Reply(reply) => {
+                                let _ = tun_device.write(&reply);
+                                continue;
+                            },
+                            mtu::Oversized::Fragments(fragments) => {
+                                fragments.into_iter().map(|fragment| (Flags::empty(), fragment)).collect()
+                            },
+                            mtu::Oversized::Drop => {
+                                debug!("drop packet of {} bytes for {}, too big for the tunnel", packet.len(),
+                                       remote_socket_addr);
+                                continue;
+                            }
+                        }
+                    };
+
+                    for (flags, payload) in payloads {
+                        match protocol::data(peer.cipher.as_mut(), peer.session_id, flags, &payload) {
+                            Ok(msg) => {
+                                let _ = udp_socket_raw_fd.send_to(&msg, &remote_socket_addr);
+                            },
+                            Err(e) => {
+                                error!("can't seal packet for {}: {}", remote_socket_addr, e);
+                                break;
                             }
-                            None => { }
                         }
-                    } else if packet[0] == 96 {
-                        continue;
                     }
                 },
                 _ => { }
             }
         }
     }
+    if let Some(ref compressor) = compressor {
+        info!("compression {}", compressor.stats);
+    }
+    if let Some(mut journal) = system_config {
+        match journal.rollback() {
+            Ok(()) => info!("restore forwarding, routes and masquerading    [OK]"),
+            Err(e) => error!("{}", e)
+        }
+    }
 }
 
 #[cfg(target_os = "macos")]
@@ -371,81 +1216,131 @@ fn run(config: &ServerConfig) {
 }
 
 
+/// Every change is journaled first, a failed step rolls back the ones before it.
 #[cfg(target_os = "linux")]
-fn auto_config(config: &ServerConfig) {
-    if !config.no_autoconfig {
-        // sudo sysctl -w net.ipv4.ip_forward=1
-        process::Command::new("sysctl")
-            .arg("-w")
-            .arg("net.ipv4.ip_forward=1")
-            .status()
-            .expect("failed to execute process");
-
-        // sudo route add -net 172.16.10.0/24  dev utun10
-        process::Command::new("route")
-            .arg("add")
-            .arg("-net")
-            .arg(format!("{}", config.tun_network))
-            .arg("dev")
-            .arg(&config.tun_ifname)
-            .status()
-            .expect("failed to execute process");
-
-        // sudo iptables -t nat -A POSTROUTING -s 172.16.10.1/24 -o enp0s3 -j MASQUERADE
-        process::Command::new("iptables")
-            .arg("-t")
-            .arg("nat")
-            .arg("-A")
-            .arg("POSTROUTING")
-            .arg("-s")
-            .arg(format!("{}", config.tun_network))
-            .arg("-o")
-            .arg(&config.default_ifname)
-            .arg("-j")
-            .arg("MASQUERADE")
-            .status()
-            .expect("failed to execute process");
-
-        // sudo iptables -A OUTPUT -o utun10 -j ACCEPT
-        process::Command::new("iptables")
-            .arg("-A")
-            .arg("OUTPUT")
-            .arg("-o")
-            .arg(&config.tun_ifname)
-            .arg("-j")
-            .arg("ACCEPT")
-            .status()
-            .expect("failed to execute process");
+fn auto_config(config: &ServerConfig) -> Option<journal::Journal> {
+    if config.no_autoconfig {
+        return None;
     }
-}
-
-#[cfg(target_os = "macos")]
-fn auto_config(config: &ServerConfig) {
-    // unimplemented!()
+    let mut journal = match journal::Journal::create(&config.journal) {
+        Ok(journal) => journal,
+        Err(e) => {
+            error!("auto config failed, {}", e);
+            process::exit(1);
+        }
+    };
+    if let Err(e) = configure(&mut journal, config) {
+        error!("auto config failed, {}", e);
+        if let Err(e) = journal.rollback() {
+            error!("{}", e);
+        }
+        process::exit(1);
+    }
+    Some(journal)
 }
 
 #[cfg(target_os = "linux")]
-fn cleanup(config: &ServerConfig) {
-    // unimplemented!()
+fn configure(journal: &mut journal::Journal, config: &ServerConfig) -> Result<(), io::Error> {
+    // sudo sysctl -w net.ipv4.ip_forward=1
+    try!(journal.write_file(firewall::forwarding_path(false), "1\n"));
+
+    // The kernel adds it along with the tun address most of the time.
+    // sudo ip route add 172.16.10.0/24 dev tun10
+    let tun = route::Gateway::Interface(config.tun_ifname.clone());
+    try!(journal.add_route(&route::Destination::IpNetwork(IpNetwork::V4(config.tun_network)), &tun, None));
+
+    let backend = try!(firewall::Backend::detect());
+    info!("masquerading {} through {} with {}", config.tun_network, config.default_ifname, backend);
+    try!(journal.masquerade(backend, &IpNetwork::V4(config.tun_network), &config.default_ifname));
+
+    if let Some(tun_network6) = config.tun_network6 {
+        // sudo sysctl -w net.ipv6.conf.all.forwarding=1
+        try!(journal.write_file(firewall::forwarding_path(true), "1\n"));
+        // Unique local addresses are not routed on the internet.
+        try!(journal.masquerade(backend, &IpNetwork::V6(tun_network6), &config.default_ifname));
+    }
+
+    // sudo ip route replace 192.168.10.0/24 dev tun9
+    let networks: Vec<route::Destination> = config.peer_networks.values()
+        .flat_map(|networks| networks.iter())
+        .map(|network| route::Destination::IpNetwork(*network))
+        .collect();
+    journal.replace_routes(&networks, &tun, None)
 }
 
 #[cfg(target_os = "macos")]
-fn cleanup(config: &ServerConfig) {
+fn auto_config(config: &ServerConfig) -> Option<journal::Journal> {
     // unimplemented!()
+    None
 }
 
 
 fn main (){
-    signal::init();
-
     let config = boot();
     if config.is_err() {
         println!("Config err: {:?}", config);
         process::exit(1);
     }
     let config = config.unwrap();
-    logging::init(Some(&config.verbose)).unwrap();
+
+    let _pidfile = match daemon::start(&config.daemon) {
+        Ok(pidfile) => pidfile,
+        Err(e) => {
+            println!("Can't start: {}", e);
+            process::exit(1);
+        }
+    };
+    match config.daemon.log_file {
+        Some(ref filename) => logging::init_with_file(Some(&config.verbose), filename).unwrap(),
+        None => logging::init(Some(&config.verbose)).unwrap()
+    };
+    // Spawns the signal thread, after `daemon::start` forked.
+    signal::init();
 
     run(&config);
-    cleanup(&config);
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FINGERPRINT_A: &'static str = "9f2b6c1e4a0d8f7e3b5a6c9d2e1f0a8b7c6d5e4f3a2b1c0d9e8f7a6b5c4d3e2f";
+    const FINGERPRINT_B: &'static str = "0000000000000000000000000000000000000000000000000000000000000001";
+
+    fn peers(networks_a: &str, networks_b: &str) -> Result<HashMap<Vec<u8>, Vec<IpNetwork>>, io::Error> {
+        let text = format!("[[peers]]\nfingerprint = \"{}\"\nnetworks = [{}]\n\
+                            [[peers]]\nfingerprint = \"{}\"\nnetworks = [{}]\n",
+                           FINGERPRINT_A, networks_a, FINGERPRINT_B, networks_b);
+        let file = config::ConfigFile::parse(&text).unwrap();
+        file.check_keys(config::SERVER_KEYS).unwrap();
+        let tun_network = Ipv4Network::from_str("172.16.0.0/16").unwrap();
+        let mut reservations = vec![];
+        load_peers(&file, &tun_network, None, &mut reservations)
+    }
+
+    #[test]
+    fn test_load_peers() {
+        let peer_networks = peers("\"10.1.2.3/16\"", "\"10.2.0.0/16\", \"192.168.0.0/24\"").unwrap();
+        let a = crypto::from_hex(FINGERPRINT_A).unwrap();
+        assert_eq!(peer_networks[&a], vec![IpNetwork::from_str("10.1.0.0/16").unwrap()]);
+        let b = crypto::from_hex(FINGERPRINT_B).unwrap();
+        assert_eq!(peer_networks[&b].len(), 2);
+
+        let file = config::ConfigFile::parse(include_str!("../conf/vpnd.toml")).unwrap();
+        let mut reservations = vec![];
+        load_peers(&file, &Ipv4Network::from_str("172.16.0.0/16").unwrap(), None, &mut reservations).unwrap();
+        assert_eq!(reservations, vec![(a, Ipv4Addr::new(172, 16, 0, 10))]);
+    }
+
+    #[test]
+    fn test_overlapping_peer_networks() {
+        let err = peers("\"10.0.0.0/8\"", "\"10.1.0.0/16\"").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("peers[1].networks[0]"), "{}", err);
+        assert!(peers("\"10.1.0.0/16\"", "\"192.168.0.0/24\", \"10.0.0.0/8\"").is_err());
+        assert!(peers("\"10.1.2.0/24\"", "\"10.1.5.0/24\"").is_ok());
+
+        let err = peers("\"172.16.5.0/24\"", "").unwrap_err();
+        assert!(err.to_string().contains("tunnel network"), "{}", err);
+    }
+}